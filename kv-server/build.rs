@@ -1,10 +1,10 @@
 fn main() {
     let mut config = prost_build::Config::new();
-    config.bytes(&["."]);
+    config.bytes(["."]);
     config.type_attribute(".", "#[derive(PartialOrd)]");
-    config
+    tonic_build::configure()
         .out_dir("src/pb")
-        .compile_protos(&["abi.proto"], &["."])
+        .compile_with_config(config, &["abi.proto"], &["."])
         .unwrap();
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=abi.proto");
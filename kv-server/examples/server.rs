@@ -1,13 +1,13 @@
 use anyhow::Result;
 use async_prost::AsyncProstStream;
 use futures::prelude::*;
-use kv_server::{CommandRequest, CommandResponse, MemTable, Service};
+use kv_server::{CommandRequest, CommandResponse, Inline, MemTable, Service};
 use tokio::net::TcpListener;
 use tracing::info;
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let service = Service::new(MemTable::new());
+    let service = Service::new(Inline::new(MemTable::new()));
     let addr = "127.0.0.1:9527";
     let listener = TcpListener::bind(addr).await?;
     info!("Start listening on {}", addr);
@@ -21,8 +21,10 @@ async fn main() -> Result<()> {
             while let Some(Ok(cmd)) = stream.next().await {
                 info!("Got a new command: {:?}", cmd);
                 // 创建一个 404 response 返回给客户端
-                let res = svc.execute(cmd);
-                stream.send(res).await.unwrap();
+                let mut res = svc.execute(cmd).await;
+                while let Some(data) = res.next().await {
+                    stream.send((*data).clone()).await.unwrap();
+                }
             }
             info!("Client {:?} disconnected", addr);
         });
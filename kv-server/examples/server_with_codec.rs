@@ -1,6 +1,6 @@
 use anyhow::Result;
 use futures::prelude::*;
-use kv_server::{CommandRequest, MemTable, Service, ServiceInner};
+use kv_server::{CommandRequest, Inline, MemTable, Service, ServiceInner};
 use prost::Message;
 use tokio::net::TcpListener;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
@@ -8,7 +8,7 @@ use tracing::info;
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let service: Service = ServiceInner::new(MemTable::new()).into();
+    let service: Service = ServiceInner::new(Inline::new(MemTable::new())).into();
     let addr = "127.0.0.1:9527";
     let listener = TcpListener::bind(addr).await?;
     info!("Start listening on {}", addr);
@@ -21,10 +21,12 @@ async fn main() -> Result<()> {
             while let Some(Ok(mut buf)) = stream.next().await {
                 let cmd = CommandRequest::decode(&buf[..]).unwrap();
                 info!("Got a new command: {:?}", cmd);
-                let res = svc.execute(cmd);
-                buf.clear();
-                res.encode(&mut buf).unwrap();
-                stream.send(buf.freeze()).await.unwrap();
+                let mut res = svc.execute(cmd).await;
+                while let Some(data) = res.next().await {
+                    buf.clear();
+                    data.encode(&mut buf).unwrap();
+                    stream.send(buf.clone().freeze()).await.unwrap();
+                }
             }
             info!("Client {:?} disconnected", addr);
         });
@@ -1,21 +1,22 @@
 use anyhow::Result;
 use async_prost::AsyncProstStream;
 use futures::prelude::*;
-use kv_server::{CommandRequest, CommandResponse, Service, ServiceInner, SledDb};
+use kv_server::{BlockingStorage, CommandRequest, CommandResponse, Service, ServiceInner, SledDb};
 use tokio::net::TcpListener;
 use tracing::info;
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let service: Service<SledDb> = ServiceInner::new(SledDb::new("/tmp/kvserver"))
-        .fn_before_send(|res| {
-            match res.message.as_ref() {
-                "" => res.message = "altered. Original message is empty.".into(),
-                s => res.message = format!("altered: {s}"),
-            }
-            Ok(())
-        })
-        .into();
+    let service: Service<BlockingStorage<SledDb>> =
+        ServiceInner::new(BlockingStorage::new(SledDb::new("/tmp/kvserver")))
+            .fn_before_send(|res| {
+                match res.message.as_ref() {
+                    "" => res.message = "altered. Original message is empty.".into(),
+                    s => res.message = format!("altered: {s}"),
+                }
+                Ok(())
+            })
+            .into();
     let addr = "127.0.0.1:9527";
     let listener = TcpListener::bind(addr).await?;
     info!("Start listening on {}", addr);
@@ -29,8 +30,10 @@ async fn main() -> Result<()> {
             while let Some(Ok(cmd)) = stream.next().await {
                 info!("Got a new command: {:?}", cmd);
                 // 创建一个 404 response 返回给客户端
-                let res = svc.execute(cmd);
-                stream.send(res).await.unwrap();
+                let mut res = svc.execute(cmd).await;
+                while let Some(data) = res.next().await {
+                    stream.send((*data).clone()).await.unwrap();
+                }
             }
             info!("Client {:?} disconnected", addr);
         });
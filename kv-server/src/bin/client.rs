@@ -1,19 +1,641 @@
-use anyhow::Result;
-use kv_server::{CommandRequest, ProstClientStream};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{Parser, Subcommand};
+use comfy_table::Table;
+use futures::{StreamExt, TryStreamExt};
+use kv_server::{
+    value, Codec, CommandRequest, CommandResponse, Kvpair, ProstClientStream, TlsClientConnector,
+    Value, WatchedKey,
+};
+use rustyline::{error::ReadlineError, DefaultEditor};
+use std::{env, ops::Bound, path::PathBuf};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tracing::info;
+
+/// kv client。给出命令时执行这一个命令，否则进入交互模式
+#[derive(Debug, Parser)]
+#[command(name = "kvc", version)]
+struct Args {
+    /// 服务器的地址
+    #[arg(long, env = "KV_ADDR", default_value = "127.0.0.1:9527")]
+    addr: String,
+    /// 连接之后先用这个 token 认证
+    #[arg(long, env = "KV_AUTH_TOKEN", conflicts_with = "user")]
+    token: Option<String>,
+    /// 连接之后先用这个用户名和 --password 认证
+    #[arg(long, requires = "password")]
+    user: Option<String>,
+    #[arg(long, env = "KV_PASSWORD", requires = "user")]
+    password: Option<String>,
+    /// 用这个 CA 验证服务器证书，并启用 TLS
+    #[arg(long, env = "KV_TLS_CA")]
+    tls_ca: Option<PathBuf>,
+    /// 服务器证书里的域名
+    #[arg(long, env = "KV_TLS_DOMAIN", default_value = "localhost")]
+    tls_domain: String,
+    /// 双向认证时客户端的证书，和 --tls-client-key 一起使用
+    #[arg(long, env = "KV_TLS_CLIENT_CERT", requires = "tls_client_key")]
+    tls_client_cert: Option<PathBuf>,
+    #[arg(long, env = "KV_TLS_CLIENT_KEY", requires = "tls_client_cert")]
+    tls_client_key: Option<PathBuf>,
+    /// 和服务器协商的压缩算法，按偏好排列，用逗号分隔，比如 zstd,lz4。不设置时使用 gzip
+    #[arg(long, env = "KV_CODECS", value_delimiter = ',')]
+    codecs: Vec<Codec>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// 交互模式下的一行输入
+#[derive(Debug, Parser)]
+#[command(name = "kvc", no_binary_name = true, disable_version_flag = true)]
+struct Line {
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// 每个命令对应一种 CommandRequest（REPLICATE 只在 replica 和 primary 之间使用）。
+/// value 可以用 类型:值 指定类型，比如 string:42、int:42、float:1.5、bool:true、binary:aGk=（base64）；
+/// 没有指定类型时，依次尝试整数、浮点数、布尔值，都不是就当作字符串
+#[derive(Debug, Subcommand)]
+#[command(disable_help_subcommand = true)]
+enum Command {
+    /// 读取一个 key
+    Hget { table: String, key: String },
+    /// 读取整个 table，指定 chunk-size 时分块获取
+    Hgetall {
+        table: String,
+        #[arg(long)]
+        chunk_size: Option<u32>,
+    },
+    /// 从 cursor 开始遍历 table，pattern 是 key 的 glob 模式
+    Hscan {
+        table: String,
+        #[arg(long, default_value = "")]
+        cursor: String,
+        #[arg(long, default_value_t = 10)]
+        count: u64,
+        #[arg(long, default_value = "")]
+        pattern: String,
+    },
+    /// 按 key 的顺序读取 start 到 end 之间的 kv pair，没有指定的一端不限制
+    Hrange {
+        table: String,
+        #[arg(long)]
+        start: Option<String>,
+        #[arg(long)]
+        end: Option<String>,
+        /// 不包含 start 这个 key
+        #[arg(long, requires = "start")]
+        start_exclusive: bool,
+        /// 不包含 end 这个 key
+        #[arg(long, requires = "end")]
+        end_exclusive: bool,
+        /// 从大到小返回
+        #[arg(long)]
+        reverse: bool,
+        /// 最多返回的数量，0 表示不限制
+        #[arg(long, default_value_t = 0)]
+        limit: u64,
+    },
+    /// 读取多个 key
+    Hmget {
+        table: String,
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// 设置一个 key，ttl 是毫秒
+    Hset {
+        table: String,
+        key: String,
+        #[arg(value_parser = parse_value, allow_hyphen_values = true)]
+        value: Value,
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+    /// 设置多个 key，每个是 key=value
+    Hmset {
+        table: String,
+        #[arg(required = true, value_parser = parse_pair)]
+        pairs: Vec<Kvpair>,
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+    /// 删除一个 key
+    Hdel { table: String, key: String },
+    /// 删除多个 key
+    Hmdel {
+        table: String,
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// 列出所有的 table
+    ListTables,
+    /// 删除整个 table
+    DropTable { table: String },
+    /// 把 table 改名，to 中原有的数据会被覆盖
+    RenameTable { from: String, to: String },
+    /// table 中 key 的数量
+    Hlen { table: String },
+    /// key 是否存在
+    Hexist { table: String, key: String },
+    /// 多个 key 是否存在
+    Hmexist {
+        table: String,
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// ttl 毫秒之后过期
+    Hexpire {
+        table: String,
+        key: String,
+        ttl: u64,
+    },
+    /// 在这个时间点（UNIX 时间戳，毫秒）过期
+    Hexpireat {
+        table: String,
+        key: String,
+        timestamp: u64,
+    },
+    /// 剩余的生存时间（毫秒）
+    Httl { table: String, key: String },
+    /// 去掉过期时间
+    Hpersist { table: String, key: String },
+    /// 整数加上 delta
+    Hincrby {
+        table: String,
+        key: String,
+        #[arg(allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// 数值加上 delta
+    Hincrbyfloat {
+        table: String,
+        key: String,
+        #[arg(allow_negative_numbers = true)]
+        delta: f64,
+    },
+    /// 读取 key 的版本，用于事务的 --watch
+    Watch {
+        table: String,
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// 原子地执行一组命令，每个命令是一个带引号的参数，比如 "hset t1 k1 v1"
+    Transaction {
+        #[arg(required = true)]
+        commands: Vec<String>,
+        /// table:key=version，版本变了事务就不会执行
+        #[arg(long = "watch", value_parser = parse_watch)]
+        watches: Vec<WatchedKey>,
+    },
+    /// 在服务器的快照目录中创建快照文件，path 是文件名
+    Snapshot { path: String },
+    /// 从服务器快照目录中的快照文件恢复数据
+    Restore { path: String },
+    /// 订阅一个 topic，直到 Ctrl-C
+    Subscribe { topic: String },
+    /// 取消订阅
+    Unsubscribe { topic: String, id: u32 },
+    /// 向 topic 发布数据
+    Publish {
+        topic: String,
+        #[arg(required = true, value_parser = parse_value, allow_hyphen_values = true)]
+        data: Vec<Value>,
+    },
+    /// 认证当前连接：auth <username> <password> 或者 auth --token <token>
+    Auth {
+        #[arg(required_unless_present = "token", requires = "password")]
+        username: Option<String>,
+        password: Option<String>,
+        #[arg(long, conflicts_with = "username")]
+        token: Option<String>,
+    },
+}
+
+impl Command {
+    fn into_request(self) -> Result<CommandRequest> {
+        let cmd = match self {
+            Command::Hget { table, key } => CommandRequest::new_hget(table, key),
+            Command::Hgetall { table, chunk_size } => match chunk_size {
+                Some(n) => CommandRequest::new_hgetall_chunked(table, n),
+                None => CommandRequest::new_hgetall(table),
+            },
+            Command::Hscan {
+                table,
+                cursor,
+                count,
+                pattern,
+            } => CommandRequest::new_hscan(table, cursor, count, pattern),
+            Command::Hrange {
+                table,
+                start,
+                end,
+                start_exclusive,
+                end_exclusive,
+                reverse,
+                limit,
+            } => {
+                let start = bound(start.as_deref(), start_exclusive);
+                let end = bound(end.as_deref(), end_exclusive);
+                CommandRequest::new_hrange(table, start, end, reverse, limit)
+            }
+            Command::Hmget { table, keys } => CommandRequest::new_hmget(table, keys),
+            Command::Hset {
+                table,
+                key,
+                value,
+                ttl,
+            } => match ttl {
+                Some(ttl) => CommandRequest::new_hset_with_ttl(table, key, value, ttl),
+                None => CommandRequest::new_hset(table, key, value),
+            },
+            Command::Hmset { table, pairs, ttl } => match ttl {
+                Some(ttl) => CommandRequest::new_hmset_with_ttl(table, pairs, ttl),
+                None => CommandRequest::new_hmset(table, pairs),
+            },
+            Command::Hdel { table, key } => CommandRequest::new_hdel(table, key),
+            Command::Hmdel { table, keys } => CommandRequest::new_hmdel(table, keys),
+            Command::ListTables => CommandRequest::new_list_tables(),
+            Command::DropTable { table } => CommandRequest::new_drop_table(table),
+            Command::RenameTable { from, to } => CommandRequest::new_rename_table(from, to),
+            Command::Hlen { table } => CommandRequest::new_hlen(table),
+            Command::Hexist { table, key } => CommandRequest::new_hexist(table, key),
+            Command::Hmexist { table, keys } => CommandRequest::new_hmexist(table, keys),
+            Command::Hexpire { table, key, ttl } => CommandRequest::new_hexpire(table, key, ttl),
+            Command::Hexpireat {
+                table,
+                key,
+                timestamp,
+            } => CommandRequest::new_hexpireat(table, key, timestamp),
+            Command::Httl { table, key } => CommandRequest::new_httl(table, key),
+            Command::Hpersist { table, key } => CommandRequest::new_hpersist(table, key),
+            Command::Hincrby { table, key, delta } => {
+                CommandRequest::new_hincrby(table, key, delta)
+            }
+            Command::Hincrbyfloat { table, key, delta } => {
+                CommandRequest::new_hincrbyfloat(table, key, delta)
+            }
+            Command::Watch { table, keys } => CommandRequest::new_watch(table, keys),
+            Command::Transaction { commands, watches } => {
+                let commands = commands
+                    .iter()
+                    .map(|line| parse_line(line)?.into_request())
+                    .collect::<Result<_>>()?;
+                CommandRequest::new_transaction(commands, watches)
+            }
+            Command::Snapshot { path } => CommandRequest::new_snapshot(path),
+            Command::Restore { path } => CommandRequest::new_restore(path),
+            Command::Subscribe { topic } => CommandRequest::new_subscribe(topic),
+            Command::Unsubscribe { topic, id } => CommandRequest::new_unsubscribe(topic, id),
+            Command::Publish { topic, data } => CommandRequest::new_publish(topic, data),
+            Command::Auth {
+                username,
+                password,
+                token,
+            } => match token {
+                Some(token) => CommandRequest::new_auth_token(token),
+                None => CommandRequest::new_auth(
+                    username.unwrap_or_default(),
+                    password.unwrap_or_default(),
+                ),
+            },
+        };
+        Ok(cmd)
+    }
+}
+
+/// 客户端的连接，可能是 TLS 的
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+type Client = ProstClientStream<Box<dyn Io>>;
+
+/// 建立连接：按需要做 TLS 握手，然后认证
+struct Connector {
+    addr: String,
+    tls: Option<TlsClientConnector>,
+    auth: Option<CommandRequest>,
+    codecs: Vec<Codec>,
+}
+
+impl Connector {
+    fn new(args: &Args) -> Result<Self> {
+        let tls = match &args.tls_ca {
+            Some(ca) => {
+                let identity = args
+                    .tls_client_cert
+                    .as_ref()
+                    .zip(args.tls_client_key.as_ref());
+                Some(TlsClientConnector::from_files(
+                    &args.tls_domain,
+                    identity,
+                    ca,
+                )?)
+            }
+            None => None,
+        };
+        let auth = match (&args.token, &args.user, &args.password) {
+            (Some(token), _, _) => Some(CommandRequest::new_auth_token(token)),
+            (None, Some(user), Some(password)) => Some(CommandRequest::new_auth(user, password)),
+            _ => None,
+        };
+        Ok(Self {
+            addr: args.addr.clone(),
+            tls,
+            auth,
+            codecs: args.codecs.clone(),
+        })
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        let stream: Box<dyn Io> = match &self.tls {
+            Some(tls) => Box::new(tls.connect(stream).await?),
+            None => Box::new(stream),
+        };
+        let mut client = ProstClientStream::new(stream);
+        if !self.codecs.is_empty() {
+            client.handshake(&self.codecs, 0).await?;
+        }
+        if let Some(auth) = &self.auth {
+            let res = client.execute(auth.clone()).await?;
+            if res.status != 200 {
+                return Err(anyhow!("Failed to authenticate: {}", res.message));
+            }
+        }
+        Ok(client)
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let addr = "127.0.0.1:9527";
-    // 连接服务器
-    let stream = TcpStream::connect(addr).await?;
-    let mut client = ProstClientStream::new(stream);
-    // 生成一个 HSET 命令
-    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
-    // 发送 HSET 命令
-    let data = client.execute(cmd).await?;
-    info!("Got response {:?}", data);
+    let args = Args::parse();
+    let connector = Connector::new(&args)?;
+    match args.command {
+        Some(cmd) => {
+            let mut client = connector.connect().await?;
+            run(&connector, &mut client, cmd).await
+        }
+        None => repl(&connector).await,
+    }
+}
+
+/// 执行一个命令并打印结果。SUBSCRIBE 使用单独的连接，一直打印收到的数据，直到 Ctrl-C
+async fn run(connector: &Connector, client: &mut Client, cmd: Command) -> Result<()> {
+    match cmd {
+        Command::Subscribe { topic } => {
+            let client = connector.connect().await?;
+            let cmd = CommandRequest::new_subscribe(&topic);
+            let mut stream = client.execute_streaming(cmd).await?;
+            println!("Subscribed to {} with id {}", topic, stream.id);
+            loop {
+                tokio::select! {
+                    res = stream.next() => match res {
+                        Some(res) => print_response(&res?),
+                        None => break,
+                    },
+                    _ = tokio::signal::ctrl_c() => break,
+                }
+            }
+        }
+        Command::Hgetall {
+            table,
+            chunk_size: Some(n),
+        } => {
+            let pairs: Vec<_> = client
+                .hgetall_chunked(table, n)
+                .await?
+                .try_collect()
+                .await?;
+            print_pairs(&pairs);
+        }
+        cmd => {
+            let res = client.execute(cmd.into_request()?).await?;
+            print_response(&res);
+        }
+    }
     Ok(())
 }
+
+/// 交互模式，历史记录保存在 ~/.kvc_history
+async fn repl(connector: &Connector) -> Result<()> {
+    let mut client = connector.connect().await?;
+    let mut editor = DefaultEditor::new()?;
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvc_history"));
+    if let Some(path) = &history {
+        // 第一次运行时还没有这个文件
+        let _ = editor.load_history(path);
+    }
+    loop {
+        let line = match editor.readline("kvc> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+        if matches!(line, "exit" | "quit") {
+            break;
+        }
+        let cmd = match parse_line(line) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        if let Err(e) = run(connector, &mut client, cmd).await {
+            println!("(error) {}", e);
+            // 连接可能已经断了，重新连接
+            client = connector.connect().await?;
+        }
+    }
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+/// 把一行输入按 shell 的规则拆分，再解析成命令
+fn parse_line(line: &str) -> Result<Command> {
+    let words = shlex::split(line).ok_or_else(|| anyhow!("Unbalanced quotes in: {}", line))?;
+    Ok(Line::try_parse_from(words)?.command)
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    if let Some((kind, v)) = s.split_once(':') {
+        let value = match kind {
+            "string" => v.into(),
+            "int" => Value::integer(v.parse().map_err(|e| format!("{}: {}", v, e))?),
+            "float" => v
+                .parse::<f64>()
+                .map_err(|e| format!("{}: {}", v, e))?
+                .into(),
+            "bool" => v
+                .parse::<bool>()
+                .map_err(|e| format!("{}: {}", v, e))?
+                .into(),
+            "binary" => {
+                let data = STANDARD.decode(v).map_err(|e| format!("{}: {}", v, e))?;
+                bytes::Bytes::from(data).into()
+            }
+            // 不是类型前缀，比如 http://...
+            _ => s.into(),
+        };
+        return Ok(value);
+    }
+    if let Ok(i) = s.parse::<i64>() {
+        return Ok(Value::integer(i));
+    }
+    if let Ok(f) = s.parse::<f64>() {
+        return Ok(f.into());
+    }
+    match s.parse::<bool>() {
+        Ok(b) => Ok(b.into()),
+        Err(_) => Ok(s.into()),
+    }
+}
+
+fn bound(key: Option<&str>, exclusive: bool) -> Bound<&str> {
+    match (key, exclusive) {
+        (None, _) => Bound::Unbounded,
+        (Some(key), true) => Bound::Excluded(key),
+        (Some(key), false) => Bound::Included(key),
+    }
+}
+
+/// key=value
+fn parse_pair(s: &str) -> Result<Kvpair, String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expect key=value, got {}", s))?;
+    Ok(Kvpair::new(key, parse_value(value)?))
+}
+
+/// table:key=version
+fn parse_watch(s: &str) -> Result<WatchedKey, String> {
+    let err = || format!("expect table:key=version, got {}", s);
+    let (name, version) = s.rsplit_once('=').ok_or_else(err)?;
+    let (table, key) = name.split_once(':').ok_or_else(err)?;
+    Ok(WatchedKey {
+        table: table.into(),
+        key: key.into(),
+        version: version.parse().map_err(|_| err())?,
+    })
+}
+
+fn print_response(res: &CommandResponse) {
+    if res.status != 200 {
+        println!("(error {}) {}", res.status, res.message);
+        return;
+    }
+    // 事务：依次打印每个命令的结果
+    if !res.responses.is_empty() {
+        for (i, res) in res.responses.iter().enumerate() {
+            print!("{}) ", i + 1);
+            print_response(res);
+        }
+        return;
+    }
+    if !res.pairs.is_empty() {
+        print_pairs(&res.pairs);
+    }
+    match res.values.as_slice() {
+        [] if res.pairs.is_empty() => println!("OK"),
+        [] => {}
+        [v] => match describe(Some(v)) {
+            ("nil", v) => println!("{}", v),
+            (kind, v) => println!("({}) {}", kind, v),
+        },
+        values => {
+            let mut table = Table::new();
+            table.set_header(vec!["#", "type", "value"]);
+            for (i, v) in values.iter().enumerate() {
+                let (kind, v) = describe(Some(v));
+                table.add_row(vec![(i + 1).to_string(), kind.into(), v]);
+            }
+            println!("{}", table);
+        }
+    }
+}
+
+fn print_pairs(pairs: &[Kvpair]) {
+    let mut table = Table::new();
+    table.set_header(vec!["key", "type", "value"]);
+    for pair in pairs {
+        let (kind, v) = describe(pair.value.as_ref());
+        table.add_row(vec![pair.key.clone(), kind.into(), v]);
+    }
+    println!("{}", table);
+}
+
+/// value 的类型和显示的内容，binary 用 base64 显示
+fn describe(v: Option<&Value>) -> (&'static str, String) {
+    match v.and_then(|v| v.value.as_ref()) {
+        Some(value::Value::String(s)) => ("string", s.clone()),
+        Some(value::Value::Binary(b)) => ("binary", STANDARD.encode(b)),
+        Some(value::Value::Integer(i)) => ("int", i.to_string()),
+        Some(value::Value::Float(f)) => ("float", f.to_string()),
+        Some(value::Value::Bool(b)) => ("bool", b.to_string()),
+        None => ("nil", "(nil)".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kv_server::command_request::RequestData;
+
+    #[test]
+    fn parse_value_should_infer_and_respect_type() {
+        assert_eq!(parse_value("42").unwrap(), Value::integer(42));
+        assert_eq!(parse_value("-1.5").unwrap(), (-1.5).into());
+        assert_eq!(parse_value("true").unwrap(), true.into());
+        assert_eq!(parse_value("hello").unwrap(), "hello".into());
+        assert_eq!(parse_value("string:42").unwrap(), "42".into());
+        assert_eq!(parse_value("binary:aGk=").unwrap(), b"hi".into());
+        assert_eq!(parse_value("http://a").unwrap(), "http://a".into());
+        assert!(parse_value("int:x").is_err());
+    }
+
+    #[test]
+    fn parse_line_should_build_requests() {
+        let cmd = parse_line("hmset t1 k1=1 'k2=hello world'").unwrap();
+        let expected = CommandRequest::new_hmset(
+            "t1",
+            vec![
+                Kvpair::new("k1", Value::integer(1)),
+                Kvpair::new("k2", "hello world".into()),
+            ],
+        );
+        assert_eq!(cmd.into_request().unwrap(), expected);
+
+        let cmd = parse_line("rename-table t1 t2").unwrap();
+        let expected = CommandRequest::new_rename_table("t1", "t2");
+        assert_eq!(cmd.into_request().unwrap(), expected);
+
+        let cmd = parse_line("hincrby t1 k1 -3").unwrap();
+        let expected = CommandRequest::new_hincrby("t1", "k1", -3);
+        assert_eq!(cmd.into_request().unwrap(), expected);
+
+        let line = r#"transaction "hset t1 k1 v1" "hdel t1 k2" --watch t1:k1=3"#;
+        let cmd = parse_line(line).unwrap().into_request().unwrap();
+        match cmd.request_data {
+            Some(RequestData::Transaction(tx)) => {
+                assert_eq!(tx.commands.len(), 2);
+                assert_eq!(tx.watches[0].version, 3);
+            }
+            _ => panic!("expect a transaction"),
+        }
+
+        assert!(parse_line("hget t1").is_err());
+        assert!(parse_line("transaction 'hget t1'")
+            .unwrap()
+            .into_request()
+            .is_err());
+    }
+}
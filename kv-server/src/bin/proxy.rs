@@ -0,0 +1,41 @@
+use anyhow::{anyhow, Result};
+use kv_server::{HashRing, ProxyServerStream};
+use std::{env, sync::Arc};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let addr = env::var("KV_ADDR").unwrap_or_else(|_| "127.0.0.1:9530".into());
+    // KV_BACKENDS 是逗号分隔的 kvs 地址，所有代理的配置必须一样，这样 key 才会被路由到同样的 backend
+    let backends: Vec<String> = env::var("KV_BACKENDS")
+        .map_err(|_| anyhow!("KV_BACKENDS is required, e.g. 127.0.0.1:9527,127.0.0.1:9528"))?
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if backends.is_empty() {
+        return Err(anyhow!(
+            "KV_BACKENDS must contain at least one backend address"
+        ));
+    }
+    let ring = Arc::new(HashRing::new(backends));
+
+    let listener = TcpListener::bind(&addr).await?;
+    info!(
+        "Proxy listening on {}, backends: {:?}",
+        addr,
+        ring.backends()
+    );
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("Client {:?} connected", addr);
+        let stream = ProxyServerStream::new(stream, ring.clone());
+        tokio::spawn(async move {
+            if let Err(e) = stream.process().await {
+                warn!("Proxy connection {:?} failed: {:?}", addr, e);
+            }
+        });
+    }
+}
@@ -1,24 +1,426 @@
-use anyhow::Result;
-use kv_server::{MemTable, ProstServerStream, Service, ServiceInner};
-use tokio::net::TcpListener;
-use tracing::info;
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use futures::StreamExt;
+use kv_server::{
+    http_router, kv_service_server::KvServiceServer, metrics_router, set_compression_limit,
+    set_frame_limit, AsyncStorage, Authenticator, BlockingStorage, Codec, CommandRequest,
+    GrpcService, Inline, KvError, Listener, MemTable, Permission, ProstReplicaStream,
+    ProstServerStream, RespServerStream, ServerConfig, Service, ServiceInner, SledDb,
+    StorageBackend, TlsClientConnector, TlsConfig, TlsServerAcceptor, ALL_TABLES,
+};
+use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tracing::{info, warn, Level};
+
+/// 客户端认证用的 token，primary 和 replica 使用同一个
+const AUTH_TOKEN_ENV: &str = "KV_AUTH_TOKEN";
+
+/// kv server。配置来自 TOML 文件，命令行参数（或者对应的环境变量）会覆盖文件里的配置。
+/// auth_token 不能通过命令行传入（会出现在进程列表里），只能写在配置文件或者环境变量 KV_AUTH_TOKEN 里
+#[derive(Debug, Parser)]
+#[command(name = "kvs", version)]
+struct Args {
+    /// TOML 配置文件
+    #[arg(short, long, env = "KV_CONFIG")]
+    config: Option<PathBuf>,
+    /// 帧协议监听的地址
+    #[arg(long, env = "KV_ADDR")]
+    addr: Option<String>,
+    /// 日志级别：trace/debug/info/warn/error
+    #[arg(long, env = "KV_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// 存储的后端：memtable 或 sled
+    #[arg(long, env = "KV_STORAGE")]
+    storage: Option<StorageBackend>,
+    /// sled 的目录，或者 memtable 的 AOF 文件
+    #[arg(long, env = "KV_STORAGE_PATH")]
+    storage_path: Option<PathBuf>,
+    /// SNAPSHOT / RESTORE 读写的快照文件所在的目录
+    #[arg(long, env = "KV_SNAPSHOT_DIR")]
+    snapshot_dir: Option<PathBuf>,
+    /// 作为这个 primary 的只读 replica 运行
+    #[arg(long, env = "KV_REPLICA_OF")]
+    replica_of: Option<String>,
+    /// 用这个 CA 验证 primary 的证书，通过 TLS 连接 primary
+    #[arg(long, env = "KV_PRIMARY_CA", requires = "replica_of")]
+    primary_ca: Option<PathBuf>,
+    /// 验证 primary 的证书时使用的域名，默认是 --replica-of 里的主机名
+    #[arg(long, env = "KV_PRIMARY_DOMAIN", requires = "replica_of")]
+    primary_domain: Option<String>,
+    /// 允许 replica 连接到这个 primary
+    #[arg(long, env = "KV_ACCEPT_REPLICAS")]
+    accept_replicas: bool,
+    /// TLS 证书，和 --tls-key 一起使用
+    #[arg(long, env = "KV_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, env = "KV_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// 要求客户端提供由这个 CA 签发的证书
+    #[arg(long, env = "KV_TLS_CLIENT_CA")]
+    tls_client_ca: Option<PathBuf>,
+    /// RESP（redis 协议）监听的地址
+    #[arg(long, env = "KV_RESP_ADDR")]
+    resp_addr: Option<String>,
+    /// HTTP/JSON 网关监听的地址
+    #[arg(long, env = "KV_HTTP_ADDR")]
+    http_addr: Option<String>,
+    /// gRPC 监听的地址
+    #[arg(long, env = "KV_GRPC_ADDR")]
+    grpc_addr: Option<String>,
+    /// Prometheus /metrics 监听的地址
+    #[arg(long, env = "KV_METRICS_ADDR")]
+    metrics_addr: Option<String>,
+    /// payload 超过这个字节数时压缩
+    #[arg(long)]
+    compression_threshold: Option<usize>,
+    /// 客户端可以选择的压缩算法，用逗号分隔，比如 zstd,lz4,gzip
+    #[arg(long, value_delimiter = ',')]
+    compression_codecs: Vec<Codec>,
+    /// 帧协议、RESP、HTTP 和 gRPC 加起来同时处理的最大连接数
+    #[arg(long)]
+    max_connections: Option<usize>,
+    /// frame 的最大字节数
+    #[arg(long)]
+    max_frame_size: Option<usize>,
+}
+
+impl Args {
+    /// 读取配置文件，再用命令行参数覆盖
+    fn into_config(self) -> Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        override_with(&mut config.general.addr, self.addr);
+        override_with(&mut config.general.log_level, self.log_level);
+        override_with(&mut config.general.replica_of, self.replica_of.map(Some));
+        override_with(&mut config.general.primary_ca, self.primary_ca.map(Some));
+        override_with(
+            &mut config.general.primary_domain,
+            self.primary_domain.map(Some),
+        );
+        config.general.accept_replicas |= self.accept_replicas;
+        override_with(
+            &mut config.general.auth_token,
+            std::env::var(AUTH_TOKEN_ENV).ok().map(Some),
+        );
+        override_with(&mut config.storage.backend, self.storage);
+        override_with(&mut config.storage.path, self.storage_path.map(Some));
+        override_with(
+            &mut config.storage.snapshot_dir,
+            self.snapshot_dir.map(Some),
+        );
+        if let (Some(cert), Some(key)) = (self.tls_cert, self.tls_key) {
+            let client_ca = config.tls.and_then(|tls| tls.client_ca);
+            config.tls = Some(TlsConfig {
+                cert,
+                key,
+                client_ca,
+            });
+        }
+        if let Some(client_ca) = self.tls_client_ca {
+            let tls = config
+                .tls
+                .as_mut()
+                .ok_or_else(|| anyhow!("--tls-client-ca requires a TLS certificate and key"))?;
+            tls.client_ca = Some(client_ca);
+        }
+        override_with(&mut config.listeners.resp, self.resp_addr.map(Some));
+        override_with(&mut config.listeners.http, self.http_addr.map(Some));
+        override_with(&mut config.listeners.grpc, self.grpc_addr.map(Some));
+        override_with(&mut config.listeners.metrics, self.metrics_addr.map(Some));
+        override_with(
+            &mut config.compression.threshold,
+            self.compression_threshold,
+        );
+        if !self.compression_codecs.is_empty() {
+            config.compression.codecs = self.compression_codecs;
+        }
+        override_with(
+            &mut config.limits.max_connections,
+            self.max_connections.map(Some),
+        );
+        override_with(
+            &mut config.limits.max_frame_size,
+            self.max_frame_size.map(Some),
+        );
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn override_with<T>(target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *target = value;
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let addr = "127.0.0.1:9527";
-    let service: Service = ServiceInner::new(MemTable::new())
-        .fn_before_send(|_| {
-            info!("response sent");
-            Ok(())
-        })
-        .into();
-    let listener = TcpListener::bind(addr).await?;
-    info!("Start listening on {}", addr);
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        info!("Client {:?} connected", addr);
-        let stream = ProstServerStream::new(stream, service.clone());
-        tokio::spawn(async move { stream.process().await });
+    let config = Args::parse().into_config()?;
+    tracing_subscriber::fmt()
+        .with_max_level(Level::from_str(&config.general.log_level)?)
+        .init();
+    set_compression_limit(config.compression.threshold);
+    if let Some(size) = config.limits.max_frame_size {
+        set_frame_limit(size);
+    }
+    // MemTable 的操作很快，直接在 worker 上执行；sled 要读写磁盘，放在 blocking 线程上执行
+    match config.storage.backend {
+        StorageBackend::MemTable => {
+            // 设置了 path 时，把修改记录到这个 AOF 里，重启时从中恢复数据
+            let store = match &config.storage.path {
+                Some(path) => {
+                    info!("Restoring data from AOF {:?}", path);
+                    MemTable::with_aof(path, config.storage.fsync)?
+                }
+                None => MemTable::new(),
+            };
+            let service = build_service(&config, Inline::new(store));
+            if config.storage.path.is_some() {
+                // AOF 比上次重写之后大了一倍时，在后台重写
+                let min_size = config.storage.aof_rewrite_min_size;
+                service.spawn_aof_rewrite_task(Duration::from_secs(1), min_size);
+            }
+            serve(config, service).await
+        }
+        StorageBackend::Sled => {
+            let path = config.storage.path.clone();
+            let path = path.ok_or_else(|| anyhow!("sled backend requires a storage path"))?;
+            info!("Opening sled db {:?}", path);
+            let service = build_service(&config, BlockingStorage::new(SledDb::new(path)));
+            serve(config, service).await
+        }
+    }
+}
+
+/// 按配置创建 Service
+fn build_service<Store>(config: &ServerConfig, store: Store) -> Service<Store>
+where
+    Store: AsyncStorage + Send + Sync + 'static,
+{
+    let general = &config.general;
+    let mut inner = ServiceInner::new(store).fn_after_send(|| {
+        info!("response sent");
+        Ok(())
+    });
+    // 设置了 auth_token 时，客户端必须先用这个 token 认证，认证后可以执行所有的命令
+    if let Some(token) = &general.auth_token {
+        let auth = Authenticator::new().token(token, "admin").grant(
+            "admin",
+            ALL_TABLES,
+            Permission::Admin,
+        );
+        inner = inner.with_auth(auth);
+    }
+    if let Some(dir) = &config.storage.snapshot_dir {
+        inner = inner.with_snapshot_dir(dir);
+    }
+    // 设置了 replica_of 时作为只读的 replica 运行；开启 accept_replicas 时允许 replica 连接过来
+    if general.replica_of.is_some() {
+        inner = inner.read_only();
+    } else if general.accept_replicas {
+        inner = inner.enable_replication();
+    }
+    inner.into()
+}
+
+async fn serve<Store>(config: ServerConfig, service: Service<Store>) -> Result<()>
+where
+    Store: AsyncStorage + Send + Sync + 'static,
+{
+    let general = &config.general;
+    if let Some(primary) = &general.replica_of {
+        let tls = match (&general.primary_ca, config.primary_domain()) {
+            (Some(ca), Some(domain)) => {
+                let identity = config.tls.as_ref().map(|tls| (&tls.cert, &tls.key));
+                Some(TlsClientConnector::from_files(domain, identity, ca)?)
+            }
+            _ => None,
+        };
+        let token = general.auth_token.clone();
+        spawn_replica(primary.clone(), token, tls, service.clone());
+    }
+    // 定期清除过期的 key
+    service.spawn_expiry_task(Duration::from_secs(1));
+    let binder = Binder::new(&config)?;
+    let listeners = &config.listeners;
+    if let Some(resp_addr) = &listeners.resp {
+        let listener = binder.bind(resp_addr, "RESP", None).await?;
+        spawn_resp_listener(listener, service.clone());
+    }
+    if let Some(http_addr) = &listeners.http {
+        let listener = binder.bind(http_addr, "HTTP", Some("http/1.1")).await?;
+        spawn_http_gateway(listener, service.clone());
+    }
+    if let Some(grpc_addr) = &listeners.grpc {
+        let listener = binder.bind(grpc_addr, "gRPC", Some("h2")).await?;
+        spawn_grpc_server(listener, service.clone());
+    }
+    if let Some(metrics_addr) = &listeners.metrics {
+        spawn_metrics_server(metrics_addr)?;
+    }
+    let listener = binder.bind(&general.addr, "frame", None).await?;
+    let mut incoming = Box::pin(listener.incoming());
+    while let Some(conn) = incoming.next().await {
+        let conn = conn?;
+        let addr = conn.peer_addr();
+        let stream = ProstServerStream::new(conn, service.clone())
+            .with_peer_addr(addr)
+            .with_codecs(config.compression.codecs.clone());
+        tokio::spawn(stream.process());
+    }
+    Ok(())
+}
+
+/// 按 [tls] 和 max_connections 创建各个协议的 Listener。所有的 Listener 共用同一个连接数的限制
+struct Binder {
+    acceptor: Option<TlsServerAcceptor>,
+    limit: Option<Arc<Semaphore>>,
+}
+
+impl Binder {
+    fn new(config: &ServerConfig) -> Result<Self> {
+        let acceptor = match &config.tls {
+            Some(tls) => Some(TlsServerAcceptor::from_files(
+                &tls.cert,
+                &tls.key,
+                tls.client_ca.as_ref(),
+            )?),
+            None => None,
+        };
+        let limit = config
+            .limits
+            .max_connections
+            .map(Semaphore::new)
+            .map(Arc::new);
+        Ok(Self { acceptor, limit })
+    }
+
+    /// alpn 为 None 时使用帧协议的 ALPN
+    async fn bind(&self, addr: &str, name: &str, alpn: Option<&str>) -> Result<Listener> {
+        let mut listener = Listener::bind(addr).await?;
+        if let Some(acceptor) = &self.acceptor {
+            listener = match alpn {
+                Some(alpn) => listener.with_tls(acceptor.with_alpn(&[alpn])),
+                None => listener.with_tls(acceptor.clone()),
+            };
+        }
+        if let Some(limit) = &self.limit {
+            listener = listener.with_limit(limit.clone());
+        }
+        info!(
+            "Start listening {} on {} (tls: {})",
+            name,
+            addr,
+            self.acceptor.is_some()
+        );
+        Ok(listener)
+    }
+}
+
+/// 接受 redis 协议的连接
+fn spawn_resp_listener<Store>(listener: Listener, service: Service<Store>)
+where
+    Store: AsyncStorage + Send + Sync + 'static,
+{
+    tokio::spawn(listener.incoming().for_each(move |conn| {
+        if let Ok(conn) = conn {
+            let addr = conn.peer_addr();
+            let stream = RespServerStream::new(conn, service.clone()).with_peer_addr(addr);
+            tokio::spawn(stream.process());
+        }
+        futures::future::ready(())
+    }));
+}
+
+/// 提供 HTTP/JSON 网关
+fn spawn_http_gateway<Store>(listener: Listener, service: Service<Store>)
+where
+    Store: AsyncStorage + Send + Sync + 'static,
+{
+    let accept = hyper::server::accept::from_stream(listener.incoming());
+    let server = axum::Server::builder(accept).serve(http_router(service).into_make_service());
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("HTTP gateway stopped: {:?}", e);
+        }
+    });
+}
+
+/// 在这个地址上提供 Prometheus 的 /metrics，不使用 TLS，也不占用连接数
+fn spawn_metrics_server(addr: &str) -> Result<()> {
+    let server =
+        axum::Server::try_bind(&addr.parse()?)?.serve(metrics_router().into_make_service());
+    info!("Start listening metrics on {}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("Metrics server stopped: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+/// 提供 gRPC 服务
+fn spawn_grpc_server<Store>(listener: Listener, service: Service<Store>)
+where
+    Store: AsyncStorage + Send + Sync + 'static,
+{
+    let server = tonic::transport::Server::builder()
+        .add_service(KvServiceServer::new(GrpcService::new(service)))
+        .serve_with_incoming(listener.incoming());
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("gRPC server stopped: {:?}", e);
+        }
+    });
+}
+
+/// 从 primary 同步数据，连接断开后每秒重试一次；primary 和 replica 使用同样的 auth_token
+fn spawn_replica<Store>(
+    primary: String,
+    token: Option<String>,
+    tls: Option<TlsClientConnector>,
+    service: Service<Store>,
+) where
+    Store: AsyncStorage + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            info!("Replicating from {} (tls: {})", primary, tls.is_some());
+            let result = match TcpStream::connect(&primary).await {
+                Ok(stream) => match &tls {
+                    Some(tls) => match tls.connect(stream).await {
+                        Ok(stream) => replicate(stream, token.as_deref(), service.clone()).await,
+                        Err(e) => Err(e),
+                    },
+                    None => replicate(stream, token.as_deref(), service.clone()).await,
+                },
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                warn!("Replication from {} stopped: {:?}", primary, e);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+/// 在连接好的 stream 上认证，然后从 primary 同步数据
+async fn replicate<S, Store>(
+    stream: S,
+    token: Option<&str>,
+    service: Service<Store>,
+) -> Result<(), KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: AsyncStorage + Send + Sync + 'static,
+{
+    let mut replica = ProstReplicaStream::new(stream, service);
+    if let Some(token) = token {
+        replica = replica.with_auth(CommandRequest::new_auth_token(token));
     }
+    replica.sync().await
 }
@@ -0,0 +1,289 @@
+use crate::{Codec, FsyncPolicy, KvError, DEFAULT_COMPRESSION_LIMIT};
+use serde::Deserialize;
+use std::{fs, path::Path, path::PathBuf, str::FromStr};
+
+/// kvs 的配置，从 TOML 文件读取，没有写的字段使用默认值：
+///
+/// ```toml
+/// [general]
+/// addr = "0.0.0.0:9527"
+/// log_level = "info"
+///
+/// [storage]
+/// backend = "sled"
+/// path = "/var/lib/kvs"
+///
+/// [tls]
+/// cert = "server.crt"
+/// key = "server.key"
+///
+/// [listeners]
+/// metrics = "0.0.0.0:9100"
+///
+/// [compression]
+/// codecs = ["zstd", "lz4", "gzip"]
+///
+/// [limits]
+/// max_connections = 1024
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub general: GeneralConfig,
+    pub storage: StorageConfig,
+    /// 没有这一节时不启用 TLS。帧协议、RESP、HTTP 和 gRPC 都使用 TLS，metrics 不使用
+    pub tls: Option<TlsConfig>,
+    pub listeners: ListenerConfig,
+    pub compression: CompressionConfig,
+    pub limits: LimitConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneralConfig {
+    /// 帧协议监听的地址
+    pub addr: String,
+    /// trace/debug/info/warn/error
+    pub log_level: String,
+    /// 设置时作为这个 primary 的只读 replica 运行
+    pub replica_of: Option<String>,
+    /// 设置时通过 TLS 连接 primary，用这个 CA 验证 primary 的证书。
+    /// 配置了 [tls] 时，用其中的证书作为客户端证书
+    pub primary_ca: Option<PathBuf>,
+    /// 验证 primary 的证书时使用的域名，不设置时使用 replica_of 里的主机名
+    pub primary_domain: Option<String>,
+    /// 允许 replica 连接过来。开启后所有修改数据的命令都会串行执行，所以默认不开启
+    pub accept_replicas: bool,
+    /// 设置时客户端必须先用这个 token 认证，认证后可以执行所有的命令。
+    /// 也可以通过环境变量 KV_AUTH_TOKEN 设置，不能通过命令行参数设置
+    pub auth_token: Option<String>,
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:9527".into(),
+            log_level: "info".into(),
+            replica_of: None,
+            primary_ca: None,
+            primary_domain: None,
+            accept_replicas: false,
+            auth_token: None,
+        }
+    }
+}
+
+/// 存储使用的后端
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    MemTable,
+    Sled,
+}
+
+impl FromStr for StorageBackend {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memtable" => Ok(Self::MemTable),
+            "sled" => Ok(Self::Sled),
+            _ => Err(KvError::ConfigError(format!(
+                "unknown storage backend {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// sled 的目录；memtable 的 AOF 文件，不设置时数据只在内存里
+    pub path: Option<PathBuf>,
+    /// AOF 什么时候刷盘，只对 memtable 有效
+    pub fsync: FsyncPolicy,
+    /// AOF 至少有这么多字节，并且比上次重写之后大了一倍时自动重写，只对 memtable 有效
+    pub aof_rewrite_min_size: u64,
+    /// SNAPSHOT / RESTORE 读写的快照文件都在这个目录里，不设置时不能使用这两个命令
+    pub snapshot_dir: Option<PathBuf>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::default(),
+            path: None,
+            fsync: FsyncPolicy::default(),
+            aof_rewrite_min_size: 64 * 1024 * 1024,
+            snapshot_dir: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// 设置时要求客户端提供由这个 CA 签发的证书
+    pub client_ca: Option<PathBuf>,
+}
+
+/// 其它协议监听的地址，不设置就不启动
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub resp: Option<String>,
+    pub http: Option<String>,
+    pub grpc: Option<String>,
+    pub metrics: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// payload 超过这个字节数时压缩
+    pub threshold: usize,
+    /// 客户端 Handshake 时可以选择的压缩算法，默认是所有的算法
+    pub codecs: Vec<Codec>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_COMPRESSION_LIMIT,
+            codecs: Codec::ALL.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitConfig {
+    /// 帧协议、RESP、HTTP 和 gRPC 加起来同时处理的最大连接数，不设置时不限制
+    pub max_connections: Option<usize>,
+    /// frame 的最大字节数，不设置时是 2G
+    pub max_frame_size: Option<usize>,
+}
+
+impl ServerConfig {
+    /// 从 TOML 文件读取配置
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// 连接 primary 时验证证书使用的域名
+    pub fn primary_domain(&self) -> Option<&str> {
+        let general = &self.general;
+        general.primary_domain.as_deref().or_else(|| {
+            let primary = general.replica_of.as_deref()?;
+            let host = primary.rsplit_once(':').map_or(primary, |(host, _)| host);
+            Some(host.trim_start_matches('[').trim_end_matches(']'))
+        })
+    }
+
+    /// 检查互相冲突的配置
+    pub fn validate(&self) -> Result<(), KvError> {
+        let general = &self.general;
+        if general.replica_of.is_none()
+            && (general.primary_ca.is_some() || general.primary_domain.is_some())
+        {
+            return Err(KvError::ConfigError(
+                "primary_ca and primary_domain require replica_of".into(),
+            ));
+        }
+        if self.general.replica_of.is_some() && self.general.accept_replicas {
+            return Err(KvError::ConfigError(
+                "A replica cannot accept replicas".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ServerConfig {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|e| KvError::ConfigError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_should_parse_with_defaults() {
+        let config: ServerConfig = r#"
+            [general]
+            addr = "0.0.0.0:9527"
+
+            [storage]
+            backend = "sled"
+            path = "/tmp/kvs"
+
+            [tls]
+            cert = "server.crt"
+            key = "server.key"
+
+            [compression]
+            codecs = ["lz4", "none"]
+
+            [limits]
+            max_connections = 16
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.general.addr, "0.0.0.0:9527");
+        assert_eq!(config.general.log_level, "info");
+        assert_eq!(config.storage.backend, StorageBackend::Sled);
+        assert_eq!(config.storage.path, Some("/tmp/kvs".into()));
+        assert_eq!(config.storage.fsync, FsyncPolicy::EverySec);
+        assert_eq!(config.tls.unwrap().client_ca, None);
+        assert_eq!(config.listeners, ListenerConfig::default());
+        assert_eq!(config.compression.threshold, DEFAULT_COMPRESSION_LIMIT);
+        assert_eq!(config.compression.codecs, vec![Codec::Lz4, Codec::None]);
+        assert_eq!(config.limits.max_connections, Some(16));
+
+        assert_eq!("".parse::<ServerConfig>().unwrap(), ServerConfig::default());
+    }
+
+    #[test]
+    fn config_should_reject_unknown_fields() {
+        let result = "[general]\nadress = \"0.0.0.0:9527\"".parse::<ServerConfig>();
+        assert!(matches!(result, Err(KvError::ConfigError(_))));
+        let result = "[storage]\nbackend = \"rocksdb\"".parse::<ServerConfig>();
+        assert!(matches!(result, Err(KvError::ConfigError(_))));
+    }
+
+    #[test]
+    fn config_should_reject_replica_accepting_replicas() {
+        let config: ServerConfig = "[general]\naccept_replicas = true".parse().unwrap();
+        assert!(config.validate().is_ok());
+        let config: ServerConfig =
+            "[general]\nreplica_of = \"127.0.0.1:9527\"\naccept_replicas = true"
+                .parse()
+                .unwrap();
+        assert!(matches!(config.validate(), Err(KvError::ConfigError(_))));
+    }
+
+    #[test]
+    fn config_should_find_primary_domain() {
+        let config: ServerConfig = "[general]\nprimary_ca = \"ca.crt\"".parse().unwrap();
+        assert!(matches!(config.validate(), Err(KvError::ConfigError(_))));
+
+        let mut config: ServerConfig = "[general]\nreplica_of = \"kv.acme.inc:9527\""
+            .parse()
+            .unwrap();
+        assert_eq!(config.primary_domain(), Some("kv.acme.inc"));
+        config.general.replica_of = Some("[::1]:9527".into());
+        assert_eq!(config.primary_domain(), Some("::1"));
+        config.general.primary_domain = Some("primary.acme.inc".into());
+        assert_eq!(config.primary_domain(), Some("primary.acme.inc"));
+        assert!(config.validate().is_ok());
+    }
+}
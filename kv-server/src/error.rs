@@ -4,9 +4,13 @@ use thiserror::Error;
 pub enum KvError {
     #[error("Not found for table: {0}, key: {1}")]
     NotFound(String, String),
+    #[error("Subscription {1} not found in topic: {0}")]
+    SubscriptionNotFound(String, u32),
+    #[error("Watched key {1} in table {0} has changed")]
+    WatchConflict(String, String),
     #[error("Cannot parse command: `{0}`")]
     InvalidCommand(String),
-    #[error("Cannot convert value {1} to {1}")]
+    #[error("Cannot convert value {0:?} to {1}")]
     ConvertError(Value, &'static str),
     #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {3}")]
     StorageError(&'static str, String, String, String),
@@ -16,8 +20,24 @@ pub enum KvError {
     DecodeError(#[from] prost::DecodeError),
     #[error("Failed to access sled db")]
     SledError(#[from] sled::Error),
+    #[error("Not the raft leader, leader hint: {0:?}")]
+    NotLeader(Option<u64>),
+    #[error("Cannot write to a read-only replica")]
+    ReadOnly,
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+    #[error("Permission denied for user {0} on table: {1}")]
+    PermissionDenied(String, String),
+    #[error("Invalid snapshot: {0}")]
+    SnapshotError(&'static str),
     #[error("Frame is larger than max size")]
     FrameError,
+    #[error("Certificate parse error: error to load {0} {1}")]
+    CertificateParseError(&'static str, &'static str),
+    #[error("TLS error: {0}")]
+    TlsError(#[from] tokio_rustls::rustls::Error),
+    #[error("Invalid config: {0}")]
+    ConfigError(String),
     #[error("Internal error: {0}")]
     Internal(String),
 
@@ -1,11 +1,17 @@
+mod config;
 mod error;
+mod metrics;
 mod network;
 mod pb;
+mod raft;
 mod service;
 mod storage;
 
+pub use config::*;
 pub use error::*;
+pub use metrics::{gather_metrics, metrics_router};
 pub use network::*;
 pub use pb::abi::*;
+pub use raft::*;
 pub use service::*;
 pub use storage::*;
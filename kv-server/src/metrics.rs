@@ -0,0 +1,158 @@
+use axum::{http::header, response::IntoResponse, routing::get, Router};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+
+/// 进程内所有的指标，在第一次使用时注册
+pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// 每种命令收到的请求数
+    pub requests: IntCounterVec,
+    /// 每种状态码的 response 数，流式的 response 每一个都算
+    pub responses: IntCounterVec,
+    /// dispatch 执行每种命令的耗时
+    pub latency: HistogramVec,
+    /// 编码（out）和解码（in）的 frame 的字节数
+    pub frame_bytes: IntCounterVec,
+    /// 压缩的 frame，压缩后和压缩前的大小的比例
+    pub compression_ratio: Histogram,
+    /// ProstServerStream 正在处理的连接数
+    pub connections: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("kv_requests_total", "Requests received by command"),
+            &["command"],
+        );
+        let responses = IntCounterVec::new(
+            Opts::new("kv_responses_total", "Responses by status code"),
+            &["status"],
+        );
+        let latency = HistogramVec::new(
+            HistogramOpts::new("kv_command_duration_seconds", "Command latency in dispatch"),
+            &["command"],
+        );
+        let frame_bytes = IntCounterVec::new(
+            Opts::new(
+                "kv_frame_bytes_total",
+                "Frame bytes encoded (out) and decoded (in)",
+            ),
+            &["direction"],
+        );
+        let compression_ratio = Histogram::with_opts(
+            HistogramOpts::new(
+                "kv_frame_compression_ratio",
+                "Compressed size divided by original size",
+            )
+            .buckets(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0]),
+        );
+        let connections = IntGauge::new("kv_active_connections", "Active client connections");
+        // 指标的定义是固定的，这里出错说明代码写错了
+        let metrics = Self {
+            registry: Registry::new(),
+            requests: requests.expect("invalid metric"),
+            responses: responses.expect("invalid metric"),
+            latency: latency.expect("invalid metric"),
+            frame_bytes: frame_bytes.expect("invalid metric"),
+            compression_ratio: compression_ratio.expect("invalid metric"),
+            connections: connections.expect("invalid metric"),
+        };
+        let collectors: [Box<dyn prometheus::core::Collector>; 6] = [
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.responses.clone()),
+            Box::new(metrics.latency.clone()),
+            Box::new(metrics.frame_bytes.clone()),
+            Box::new(metrics.compression_ratio.clone()),
+            Box::new(metrics.connections.clone()),
+        ];
+        for c in collectors {
+            metrics.registry.register(c).expect("duplicated metric");
+        }
+        metrics
+    }
+}
+
+/// 在连接的生命周期内持有，用于统计连接数
+pub(crate) struct ConnectionGuard;
+
+impl ConnectionGuard {
+    pub fn new() -> Self {
+        METRICS.connections.inc();
+        Self
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        METRICS.connections.dec();
+    }
+}
+
+/// Prometheus 文本格式的所有指标
+pub fn gather_metrics() -> String {
+    let mut buf = vec![];
+    // 写到 Vec 里不会出错
+    let _ = TextEncoder::new().encode(&METRICS.registry.gather(), &mut buf);
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+/// 提供 GET /metrics 的路由，一般放在单独的端口上
+pub fn metrics_router() -> Router {
+    Router::new().route("/metrics", get(metrics))
+}
+
+async fn metrics() -> impl IntoResponse {
+    let content_type = TextEncoder::new().format_type().to_string();
+    ([(header::CONTENT_TYPE, content_type)], gather_metrics())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, FrameCoder, Inline, MemTable, Service, ServiceInner, Value};
+    use axum::{body::Body, http::Request};
+    use bytes::BytesMut;
+    use futures::StreamExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn metrics_endpoint_should_export_metrics() {
+        let service: Service = ServiceInner::new(Inline::new(MemTable::new())).into();
+        let cmd = CommandRequest::new_hset("t1", "k1", Value::integer(1));
+        service.execute(cmd).await.next().await.unwrap();
+        service
+            .execute(CommandRequest::new_hget("t1", "k2"))
+            .await
+            .next()
+            .await
+            .unwrap();
+        // 足够大的 frame 会被压缩
+        let mut buf = BytesMut::new();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v".repeat(4096).into());
+        cmd.encode_frame(&mut buf).unwrap();
+
+        let req = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let res = metrics_router().oneshot(req).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        for expected in [
+            r#"kv_requests_total{command="hset"}"#,
+            r#"kv_responses_total{status="404"}"#,
+            r#"kv_command_duration_seconds_count{command="hget"}"#,
+            r#"kv_frame_bytes_total{direction="out"}"#,
+            "kv_frame_compression_ratio_count",
+            "kv_active_connections",
+        ] {
+            assert!(text.contains(expected), "missing {}", expected);
+        }
+    }
+}
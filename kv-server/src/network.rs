@@ -1,56 +1,286 @@
 mod frame;
+mod gateway;
+mod grpc;
+mod listener;
+mod multiplex;
+mod proxy;
+mod replication;
+mod resp;
+mod stream_result;
+/// 网络层测试共用的服务器和客户端
+#[cfg(test)]
+mod test_utils;
+mod tls;
 
-use crate::{CommandRequest, CommandResponse, KvError, Service};
+use crate::command_request::RequestData;
+use crate::metrics::ConnectionGuard;
+use crate::{
+    AsyncStorage, Auth, CommandRequest, CommandResponse, ConnectionContext, Handshake, Inline,
+    KvError, Kvpair, MemTable, Service, StreamingResponse, Value,
+};
 use bytes::BytesMut;
 pub use frame::*;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tracing::info;
+use futures::{stream, Stream, StreamExt};
+pub use gateway::http_router;
+pub use grpc::GrpcService;
+pub use listener::{Connection, Listener};
+pub use multiplex::*;
+pub use proxy::{HashRing, ProxyServerStream};
+use replication::serve_replica;
+pub use replication::ProstReplicaStream;
+pub use resp::RespServerStream;
+use std::{net::SocketAddr, sync::Arc};
+pub use stream_result::*;
+pub use tls::*;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// 一个连接上，等待写回客户端的 response 的最大数量
+const RESPONSE_CAPACITY: usize = 128;
 
 /// 处理服务器端的某个 accept 下来的 socket 的读写
-pub struct ProstServerStream<S> {
+pub struct ProstServerStream<S, Store = Inline<Arc<MemTable>>> {
     inner: S,
-    service: Service,
+    service: Service<Store>,
+    /// 对端地址，以及这个连接通过 Auth 认证得到的用户名
+    ctx: ConnectionContext,
+    /// Handshake 时允许客户端选择的压缩算法
+    codecs: Vec<Codec>,
+    /// 写 response 时使用的压缩算法和阈值，Handshake 之后会改变
+    compression: FrameCompression,
 }
 /// 处理客户端 socket 的读写
 pub struct ProstClientStream<S> {
     inner: S,
+    /// 写请求时使用的压缩算法和阈值，Handshake 之后会改变
+    compression: FrameCompression,
 }
 
-impl<S> ProstServerStream<S>
+impl<S, Store> ProstServerStream<S, Store>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: AsyncStorage + Send + Sync + 'static,
 {
-    pub fn new(stream: S, service: Service) -> Self {
+    pub fn new(stream: S, service: Service<Store>) -> Self {
         Self {
             inner: stream,
             service,
+            ctx: ConnectionContext::default(),
+            codecs: Codec::ALL.to_vec(),
+            compression: FrameCompression::default(),
         }
     }
 
+    /// 限制 Handshake 时客户端可以选择的压缩算法，默认允许所有的算法
+    pub fn with_codecs(mut self, codecs: Vec<Codec>) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    /// 设置对端的地址，中间件可以从 ConnectionContext 里拿到
+    pub fn with_peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.ctx.peer_addr = Some(addr);
+        self
+    }
+
+    /// 处理这个连接上的所有请求。带 id 的请求在单独的 task 里执行，
+    /// response 带上请求的 id，按完成的顺序写回，所以一个连接上可以同时有多个请求；
+    /// id 为 0 的请求没法和 response 对应，按读到的顺序依次执行并写回。
+    /// AUTH 在读取请求时直接处理，之后的请求都以认证得到的身份执行。
+    /// 连接开始时的 HANDSHAKE 决定之后的 response 使用的压缩算法，它的 response 还用原来的算法。
+    /// AUTH 和 HANDSHAKE 也和其它请求一样经过中间件，被中间件拦下时不认证、不协商。
+    /// 第一个不是 AUTH 或 HANDSHAKE 的请求是 REPLICATE 时，这个连接用于向 replica 同步数据
     pub async fn process(mut self) -> Result<(), KvError> {
-        while let Ok(cmd) = self.recv().await {
-            info!("Got a new command: {:?}", cmd);
-            let res = self.service.execute(cmd);
-            self.send(res).await?
+        let _guard = ConnectionGuard::new();
+        let first = loop {
+            match read_message::<_, CommandRequest>(&mut self.inner).await {
+                Ok(cmd) => match &cmd.request_data {
+                    Some(RequestData::Auth(auth)) => {
+                        let res = authenticate(&self.service, &cmd, auth, &mut self.ctx).await;
+                        write_message_with(&mut self.inner, &res, &self.compression).await?;
+                        self.service.after_send(&self.ctx, &res).await;
+                    }
+                    Some(RequestData::Handshake(handshake)) => {
+                        let (res, compression) =
+                            negotiate(&self.service, &self.ctx, &self.codecs, &cmd, handshake)
+                                .await;
+                        write_message_with(&mut self.inner, &res, &self.compression).await?;
+                        self.service.after_send(&self.ctx, &res).await;
+                        if let Some(compression) = compression {
+                            self.compression = compression;
+                        }
+                    }
+                    _ => break cmd,
+                },
+                Err(_) => return Ok(()),
+            }
+        };
+        if let Some(RequestData::Replicate(_)) = first.request_data {
+            if let Err(e) = self
+                .service
+                .authorize(self.ctx.principal.as_deref(), &first)
+            {
+                return write_message(&mut self.inner, &CommandResponse::from(e)).await;
+            }
+            return serve_replica(self.inner, self.service).await;
         }
-        Ok(())
+        let (mut reader, mut writer) = io::split(self.inner);
+        // response 和产生它的请求所在的上下文一起交给 write_loop，写出之后调用 after_send
+        let (tx, mut rx) =
+            mpsc::channel::<(Arc<ConnectionContext>, CommandResponse)>(RESPONSE_CAPACITY);
+        let (service, mut ctx) = (self.service, Arc::new(self.ctx));
+        let (writer_service, compression) = (service.clone(), self.compression);
+
+        let read_loop = async move {
+            let mut next = Some(first);
+            // 订阅推送数据的 task，读结束（客户端不会再取消订阅）时停止
+            let mut subscriptions = vec![];
+            loop {
+                let cmd = match next.take() {
+                    Some(cmd) => cmd,
+                    None => match read_message::<_, CommandRequest>(&mut reader).await {
+                        Ok(cmd) => cmd,
+                        Err(_) => break,
+                    },
+                };
+                info!("Got a new command: {:?}", cmd);
+                // 认证要在读取下一个请求之前完成
+                if let Some(RequestData::Auth(auth)) = &cmd.request_data {
+                    let res = authenticate(&service, &cmd, auth, Arc::make_mut(&mut ctx)).await;
+                    if tx.send((ctx.clone(), res)).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                let subscribe = matches!(cmd.request_data, Some(RequestData::Subscribe(_)));
+                if cmd.id != 0 {
+                    let (service, tx, ctx) = (service.clone(), tx.clone(), ctx.clone());
+                    let handle = tokio::spawn(async move {
+                        let id = cmd.id;
+                        // 对于 SUBSCRIBE，这个流会一直持续，直到取消订阅或者连接断开
+                        let res = service.execute_with(&ctx, cmd).await;
+                        forward(res, id, ctx, tx).await;
+                    });
+                    if subscribe {
+                        subscriptions.push(handle.abort_handle());
+                    }
+                    continue;
+                }
+                let mut res = service.execute_with(&ctx, cmd).await;
+                if subscribe {
+                    // 订阅的流会一直持续，写回第一个 response（订阅的 id）之后，
+                    // 推送的数据放到单独的 task 里，不挡住之后的请求
+                    let first = match res.next().await {
+                        Some(data) => (*data).clone(),
+                        None => continue,
+                    };
+                    if tx.send((ctx.clone(), first)).await.is_err() {
+                        break;
+                    }
+                    let handle = tokio::spawn(forward(res, 0, ctx.clone(), tx.clone()));
+                    subscriptions.push(handle.abort_handle());
+                } else if !forward(res, 0, ctx.clone(), tx.clone()).await {
+                    break;
+                }
+            }
+            for handle in subscriptions {
+                handle.abort();
+            }
+            Ok::<_, KvError>(())
+        };
+
+        let write_loop = async move {
+            while let Some((ctx, res)) = rx.recv().await {
+                write_message_with(&mut writer, &res, &compression).await?;
+                writer_service.after_send(&ctx, &res).await;
+            }
+            Ok::<_, KvError>(())
+        };
+
+        // 客户端关闭写的一端（读结束）之后，等所有还在执行的请求都写回 response 再结束；
+        // 写出错时直接结束，还在执行的请求在写回 response 时会发现 channel 已经关闭
+        tokio::pin!(write_loop);
+        let res = tokio::select! {
+            _ = read_loop => (&mut write_loop).await,
+            res = &mut write_loop => res,
+        };
+        if let Err(e) = &res {
+            warn!("Failed to write response: {:?}", e);
+        }
+        res
     }
+}
 
-    async fn send(&mut self, msg: CommandResponse) -> Result<(), KvError> {
-        let mut buf = BytesMut::new();
-        msg.encode_frame(&mut buf)?;
-        let encoded = buf.freeze();
-        self.inner.write_all(&encoded[..]).await?;
-        Ok(())
+/// 把一个请求的所有 response 带上请求的 id 交给 write_loop，连接关闭时返回 false
+async fn forward(
+    mut res: StreamingResponse,
+    id: u64,
+    ctx: Arc<ConnectionContext>,
+    tx: mpsc::Sender<(Arc<ConnectionContext>, CommandResponse)>,
+) -> bool {
+    while let Some(data) = res.next().await {
+        let mut data = (*data).clone();
+        data.id = id;
+        if tx.send((ctx.clone(), data)).await.is_err() {
+            return false;
+        }
     }
+    true
+}
 
-    async fn recv(&mut self) -> Result<CommandRequest, KvError> {
-        let mut buf = BytesMut::new();
-        let mut stream = &mut self.inner;
-        read_frame(&mut stream, &mut buf).await?;
+/// 认证成功时更新连接的身份，返回的 value 是用户名；失败或者被中间件拦下时连接的身份不变
+async fn authenticate<Store: AsyncStorage>(
+    service: &Service<Store>,
+    cmd: &CommandRequest,
+    auth: &Auth,
+    ctx: &mut ConnectionContext,
+) -> CommandResponse {
+    let mut res = match service.on_received(ctx, cmd).await {
+        Some(res) => res,
+        None => {
+            let res: CommandResponse = match service.authenticate(auth) {
+                Ok(name) => {
+                    let res = Value::from(name.as_str()).into();
+                    ctx.principal = Some(name);
+                    res
+                }
+                Err(e) => e.into(),
+            };
+            service.before_send(ctx, res).await
+        }
+    };
+    res.id = cmd.id;
+    res
+}
 
-        CommandRequest::decode_frame(&mut buf)
+/// 在客户端支持的算法中，选出第一个服务器也允许的，都不允许时不压缩。
+/// 阈值取双方中较大的那个。被中间件拦下时不协商，返回的算法是 None
+async fn negotiate<Store: AsyncStorage>(
+    service: &Service<Store>,
+    ctx: &ConnectionContext,
+    allowed: &[Codec],
+    cmd: &CommandRequest,
+    handshake: &Handshake,
+) -> (CommandResponse, Option<FrameCompression>) {
+    if let Some(mut res) = service.on_received(ctx, cmd).await {
+        res.id = cmd.id;
+        return (res, None);
     }
+    let codec = handshake
+        .codecs
+        .iter()
+        .filter_map(|c| Codec::try_from(*c).ok())
+        .find(|c| allowed.contains(c))
+        .unwrap_or(Codec::None);
+    let threshold = compression_limit().max(handshake.threshold as usize);
+    let values = vec![
+        Value::integer(codec as i64),
+        Value::integer(threshold as i64),
+    ];
+    let mut res = service.before_send(ctx, values.into()).await;
+    res.id = cmd.id;
+    (res, Some(FrameCompression { codec, threshold }))
 }
 
 impl<S> ProstClientStream<S>
@@ -58,7 +288,39 @@ where
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
     pub fn new(stream: S) -> Self {
-        Self { inner: stream }
+        Self {
+            inner: stream,
+            compression: FrameCompression::default(),
+        }
+    }
+
+    /// 和服务器协商压缩算法和阈值，codecs 按偏好排列，threshold 为 0 时使用服务器的设置。
+    /// 成功后双方都用协商的结果编码 frame；不支持 Handshake 的旧服务器会返回错误，这时继续使用 gzip
+    pub async fn handshake(
+        &mut self,
+        codecs: &[Codec],
+        threshold: usize,
+    ) -> Result<FrameCompression, KvError> {
+        let res = self
+            .execute(CommandRequest::new_handshake(codecs, threshold))
+            .await?;
+        if res.status != 200 {
+            return Err(KvError::Internal(res.message));
+        }
+        let values = res
+            .values
+            .iter()
+            .map(i64::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        let compression = match values[..] {
+            [codec, threshold] => FrameCompression {
+                codec: Codec::try_from(codec as u32)?,
+                threshold: threshold as usize,
+            },
+            _ => return Err(KvError::Internal("Invalid handshake response".into())),
+        };
+        self.compression = compression;
+        Ok(compression)
     }
 
     pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
@@ -67,31 +329,119 @@ where
         self.recv().await
     }
 
-    pub async fn send(&mut self, cmd: CommandRequest) -> Result<(), KvError> {
-        let mut buf = BytesMut::new();
-        cmd.encode_frame(&mut buf)?;
-        let encoded = buf.freeze();
+    /// 发送一个会返回多个 Response 的命令（比如 SUBSCRIBE），之后这个连接只用于接收数据
+    pub async fn execute_streaming(mut self, cmd: CommandRequest) -> Result<StreamResult, KvError>
+    where
+        S: 'static,
+    {
+        self.send(cmd).await?;
 
-        self.inner.write_all(&encoded[..]).await?;
-        Ok(())
+        // 连接出错后，把错误交给调用者，然后结束这个流
+        let stream = stream::unfold(Some(self), |client| async move {
+            let mut client = client?;
+            match client.recv().await {
+                Ok(res) => Some((Ok(res), Some(client))),
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+        StreamResult::new(Box::pin(stream)).await
+    }
+
+    /// 分块获取整个 table，每个 response 最多 chunk_size 个 kv pair。
+    /// 第一个 response 出错时直接返回错误；之后的错误作为流的最后一项返回。
+    /// 流正常结束之后，这个连接可以继续使用
+    pub async fn hgetall_chunked(
+        &mut self,
+        table: impl Into<String>,
+        chunk_size: u32,
+    ) -> Result<impl Stream<Item = Result<Kvpair, KvError>> + '_, KvError> {
+        let cmd = CommandRequest::new_hgetall_chunked(table, chunk_size);
+        self.send(cmd).await?;
+        let first = self.recv().await?;
+        if first.status != 200 {
+            return Err(KvError::Internal(first.message));
+        }
+
+        let next = (!first.end).then_some(self);
+        let rest = stream::unfold(next, |client| async move {
+            let client = client?;
+            match client.recv().await {
+                Ok(res) if res.end => None,
+                Ok(res) if res.status == 200 => {
+                    let pairs: Vec<_> = res.pairs.into_iter().map(Ok).collect();
+                    Some((stream::iter(pairs), Some(client)))
+                }
+                // 出错之后连接上可能还有这个流剩下的 response，不能继续使用
+                Ok(res) => Some((
+                    stream::iter(vec![Err(KvError::Internal(res.message))]),
+                    None,
+                )),
+                Err(e) => Some((stream::iter(vec![Err(e)]), None)),
+            }
+        });
+        Ok(stream::iter(first.pairs.into_iter().map(Ok)).chain(rest.flatten()))
+    }
+
+    pub async fn send(&mut self, cmd: CommandRequest) -> Result<(), KvError> {
+        write_message_with(&mut self.inner, &cmd, &self.compression).await
     }
 
     pub async fn recv(&mut self) -> Result<CommandResponse, KvError> {
-        let mut buf = BytesMut::new();
-        let stream = &mut self.inner;
-        read_frame(stream, &mut buf).await?;
-        CommandResponse::decode_frame(&mut buf)
+        read_message(&mut self.inner).await
     }
 }
 
+/// 把一个消息编码成 frame，写入 stream
+async fn write_message<W, M>(stream: &mut W, msg: &M) -> Result<(), KvError>
+where
+    W: AsyncWrite + Unpin + Send,
+    M: FrameCoder,
+{
+    write_message_with(stream, msg, &FrameCompression::default()).await
+}
+
+/// 用指定的压缩算法和阈值把一个消息编码成 frame，写入 stream
+async fn write_message_with<W, M>(
+    stream: &mut W,
+    msg: &M,
+    compression: &FrameCompression,
+) -> Result<(), KvError>
+where
+    W: AsyncWrite + Unpin + Send,
+    M: FrameCoder,
+{
+    let mut buf = BytesMut::new();
+    msg.encode_frame_with(&mut buf, compression)?;
+    let encoded = buf.freeze();
+    stream.write_all(&encoded[..]).await?;
+    Ok(())
+}
+
+/// 从 stream 中读取一个完整的 frame，并解码成消息
+async fn read_message<R, M>(stream: &mut R) -> Result<M, KvError>
+where
+    R: AsyncRead + Unpin + Send,
+    M: FrameCoder,
+{
+    let mut buf = BytesMut::new();
+    read_frame(stream, &mut buf).await?;
+    M::decode_frame(&mut buf)
+}
+
 #[cfg(test)]
 mod tests {
+    use super::test_utils::*;
     use super::*;
-    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+    use crate::{
+        assert_res_error, assert_res_ok, Authenticator, Inline, MemTable, Middleware, Permission,
+        ServiceInner, Value,
+    };
     use anyhow::Result;
     use bytes::Bytes;
-    use std::net::SocketAddr;
-    use tokio::net::{TcpListener, TcpStream};
+    use futures::future::{self, BoxFuture};
+    use futures::TryStreamExt;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
     #[tokio::test]
     async fn client_server_basic_communication_should_work() -> Result<()> {
         let addr = start_server().await?;
@@ -123,17 +473,314 @@ mod tests {
         assert_res_ok(res, &[v], &[]);
         Ok(())
     }
-    async fn start_server() -> Result<SocketAddr> {
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
-        tokio::spawn(async move {
-            loop {
-                let (stream, _) = listener.accept().await.unwrap();
-                let service: Service = ServiceInner::new(MemTable::new()).into();
-                let server = ProstServerStream::new(stream, service);
-                tokio::spawn(server.process());
+    #[tokio::test]
+    async fn client_server_handshake_should_negotiate_codec() -> Result<()> {
+        let service = memtable_service();
+        let addr = spawn_listener(move |stream, _| {
+            let server = ProstServerStream::new(stream, service.clone())
+                .with_codecs(vec![Codec::Lz4, Codec::Gzip]);
+            server.process()
+        })
+        .await?;
+        let mut client = connect(addr).await?;
+
+        // 选出客户端偏好的算法中，服务器允许的第一个
+        let compression = client.handshake(&[Codec::Zstd, Codec::Lz4], 0).await?;
+        assert_eq!(compression.codec, Codec::Lz4);
+        assert_eq!(compression.threshold, DEFAULT_COMPRESSION_LIMIT);
+
+        // 之后双方都用 lz4 压缩大的 frame
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        client
+            .execute(CommandRequest::new_hset("t1", "k1", v.clone()))
+            .await?;
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &[v], &[]);
+
+        // 已经开始处理请求之后，不能再协商
+        let res = client.handshake(&[Codec::Gzip], 0).await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_pub_sub_should_work() -> Result<()> {
+        let addr = start_server().await?;
+
+        // 一个连接订阅 lobby
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream);
+        let mut sub = client
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+        let id = sub.id;
+        assert!(id > 0);
+
+        // 另一个连接往 lobby 发布数据
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into(), 42.into()]);
+        let res = client.execute(cmd).await?;
+        assert_res_ok(res, &[], &[]);
+
+        // 订阅的连接能收到数据
+        let res = sub.next().await.unwrap()?;
+        assert_res_ok(res, &["hello".into(), 42.into()], &[]);
+
+        // 取消订阅后，订阅的连接不再收到数据
+        let res = client
+            .execute(CommandRequest::new_unsubscribe("lobby", id))
+            .await?;
+        assert_res_ok(res, &[], &[]);
+        let cmd = CommandRequest::new_publish("lobby", vec!["world".into()]);
+        client.execute(cmd).await?;
+        let next = tokio::time::timeout(Duration::from_millis(100), sub.next()).await;
+        assert!(next.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_reply_with_request_id() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        // 连续发送两个请求，response 的 id 和请求的 id 对应
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into()).with_id(42);
+        client.send(cmd).await?;
+        let cmd = CommandRequest::new_hexist("t1", "k2").with_id(43);
+        client.send(cmd).await?;
+
+        let mut ids = vec![client.recv().await?.id, client.recv().await?.id];
+        ids.sort();
+        assert_eq!(ids, vec![42, 43]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_without_id_should_be_processed_in_order() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        // 不等 response 连续发送，每个 HGET 都要看到它前面的 HSET
+        for i in 0..50 {
+            let cmd = CommandRequest::new_hset("t1", "k1", Value::integer(i));
+            client.send(cmd).await?;
+            client.send(CommandRequest::new_hget("t1", "k1")).await?;
+        }
+        for i in 0..50 {
+            client.recv().await?;
+            let res = client.recv().await?;
+            assert_res_ok(res, &[Value::integer(i)], &[]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_should_reply_after_client_half_closes() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        // 发完请求就关闭写的一端，还没写回的 response 也要都收到
+        for i in 1..=50 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), Value::integer(i));
+            client.send(cmd.with_id(i as u64)).await?;
+        }
+        client.send(CommandRequest::new_hget("t1", "k1")).await?;
+        client.inner.shutdown().await?;
+        let mut ids = vec![];
+        for _ in 0..51 {
+            ids.push(client.recv().await?.id);
+        }
+        ids.sort();
+        assert_eq!(ids, (0..=50).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_chunked_hgetall_should_work() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        let pairs: Vec<_> = (0..25)
+            .map(|i| Kvpair::new(format!("k{:02}", i), Value::integer(i)))
+            .collect();
+        client
+            .execute(CommandRequest::new_hmset("t1", pairs.clone()))
+            .await?;
+
+        let stream = client.hgetall_chunked("t1", 10).await?;
+        let mut data: Vec<_> = stream.try_collect().await?;
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(data, pairs);
+
+        // 空的 table 只有一个结束标记
+        let data: Vec<_> = client
+            .hgetall_chunked("t2", 10)
+            .await?
+            .try_collect()
+            .await?;
+        assert!(data.is_empty());
+
+        // 流结束之后，连接可以继续使用
+        let res = client
+            .execute(CommandRequest::new_hget("t1", "k00"))
+            .await?;
+        assert_res_ok(res, &[Value::integer(0)], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chunked_hgetall_should_return_errors_in_stream() -> Result<()> {
+        // 服务器先返回一块数据，然后返回错误
+        let addr = spawn_listener(|mut stream, _| async move {
+            let _: CommandRequest = read_message(&mut stream).await.unwrap();
+            let chunk: CommandResponse = vec![Kvpair::new("k1", "v1".into())].into();
+            write_message(&mut stream, &chunk).await.unwrap();
+            let error: CommandResponse = KvError::Internal("disk failure".into()).into();
+            write_message(&mut stream, &error).await.unwrap();
+        })
+        .await?;
+        let mut client = connect(addr).await?;
+        let data: Vec<_> = client.hgetall_chunked("t1", 10).await?.collect().await;
+        assert_eq!(data.len(), 2);
+        assert!(matches!(&data[0], Ok(pair) if pair.key == "k1"));
+        assert!(matches!(&data[1], Err(KvError::Internal(msg)) if msg.contains("disk failure")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_check_auth_per_connection() -> Result<()> {
+        let auth =
+            Authenticator::new()
+                .user("alice", "secret")?
+                .grant("alice", "t1", Permission::Write);
+        let service: Service = ServiceInner::new(Inline::new(MemTable::new()))
+            .with_auth(auth)
+            .into();
+        let addr = start_server_with(service).await?;
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(cmd.clone()).await?;
+        assert_res_error(res, 401, "Authentication required");
+        let res = client
+            .execute(CommandRequest::new_auth("alice", "wrong"))
+            .await?;
+        assert_res_error(res, 401, "Invalid credentials");
+
+        let res = client
+            .execute(CommandRequest::new_auth("alice", "secret"))
+            .await?;
+        assert_res_ok(res, &["alice".into()], &[]);
+        let res = client.execute(cmd).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = client.execute(CommandRequest::new_hget("t2", "k1")).await?;
+        assert_res_error(res, 403, "Permission denied");
+
+        // 身份只属于这个连接
+        let mut other = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let res = other.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.status, 401);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_call_after_send_with_connection_context() -> Result<()> {
+        // 每个写出去的 response 都会带着连接的上下文通知到中间件
+        struct Recorder(mpsc::UnboundedSender<(ConnectionContext, u32)>);
+        impl Middleware for Recorder {
+            fn on_after_send<'a>(
+                &'a self,
+                ctx: &'a ConnectionContext,
+                res: &'a CommandResponse,
+            ) -> BoxFuture<'a, ()> {
+                self.0.send((ctx.clone(), res.status)).unwrap();
+                Box::pin(future::ready(()))
             }
-        });
-        Ok(addr)
+        }
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let auth =
+            Authenticator::new()
+                .token("tok", "alice")
+                .grant("alice", "t1", Permission::Read);
+        let service = ServiceInner::new(Inline::new(MemTable::new()))
+            .with_auth(auth)
+            .middleware(Recorder(tx));
+        let addr = start_server_with(service.into()).await?;
+        let stream = TcpStream::connect(addr).await?;
+        let local = stream.local_addr()?;
+        let mut client = ProstClientStream::new(stream);
+
+        client
+            .execute(CommandRequest::new_auth_token("tok"))
+            .await?;
+        // AUTH 的 response 写出时，连接已经有了身份
+        let (ctx, status) = rx.recv().await.unwrap();
+        assert_eq!(ctx.peer_addr, Some(local));
+        assert_eq!(ctx.principal.as_deref(), Some("alice"));
+        assert_eq!(status, 200);
+
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_error(res, 404, "Not found");
+        let (ctx, status) = rx.recv().await.unwrap();
+        assert_eq!(ctx.principal.as_deref(), Some("alice"));
+        assert_eq!(status, 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_pass_auth_and_handshake_through_middleware() -> Result<()> {
+        // 异步地拒绝 alice 登录和所有的 HANDSHAKE
+        struct Blocker;
+        impl Middleware for Blocker {
+            fn on_received<'a>(
+                &'a self,
+                _ctx: &'a ConnectionContext,
+                cmd: &'a CommandRequest,
+            ) -> BoxFuture<'a, Option<CommandResponse>> {
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    match &cmd.request_data {
+                        Some(RequestData::Auth(auth)) if auth.username == "alice" => {
+                            Some(KvError::Unauthenticated("blocked".into()).into())
+                        }
+                        Some(RequestData::Handshake(_)) => Some(KvError::ReadOnly.into()),
+                        _ => None,
+                    }
+                })
+            }
+        }
+        let auth = Authenticator::new()
+            .user("alice", "secret")?
+            .user("bob", "secret")?
+            .grant("bob", "t1", Permission::Read);
+        let service = ServiceInner::new(Inline::new(MemTable::new()))
+            .with_auth(auth)
+            .middleware(Blocker);
+        let addr = start_server_with(service.into()).await?;
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+
+        let handshake = CommandRequest::new_handshake(&[Codec::Zstd], 0);
+        let res = client.execute(handshake).await?;
+        assert_res_error(res, 403, "read-only");
+        let res = client
+            .execute(CommandRequest::new_auth("alice", "secret"))
+            .await?;
+        assert_res_error(res, 401, "blocked");
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.status, 401);
+
+        // 拦下之后连接照常使用，没有被拦下的 AUTH 正常认证
+        let res = client
+            .execute(CommandRequest::new_auth("bob", "secret"))
+            .await?;
+        assert_res_ok(res, &["bob".into()], &[]);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.status, 404);
+        Ok(())
     }
 }
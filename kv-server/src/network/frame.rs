@@ -1,10 +1,14 @@
+use crate::metrics::METRICS;
 use crate::{CommandRequest, CommandResponse, KvError};
 use bytes::{Buf, BufMut, BytesMut};
 use flate2::bufread::GzDecoder;
 use flate2::Compression;
 use flate2::GzBuilder;
 use prost::Message;
-use std::io::{Read, Write};
+use serde::Deserialize;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::debug;
 
@@ -12,10 +16,150 @@ use tracing::debug;
 const LEN_LEN: usize = 4;
 /// 长度占 31 bit，所以最大的 frame 是 2G
 const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
-/// 如果 payload 超过了 1436 字节，就做压缩
-const COMPRESSION_LIMIT: usize = 1436;
+/// 默认情况下，如果 payload 超过了 1436 字节，就做压缩
+pub const DEFAULT_COMPRESSION_LIMIT: usize = 1436;
+static COMPRESSION_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_COMPRESSION_LIMIT);
+static FRAME_LIMIT: AtomicUsize = AtomicUsize::new(MAX_FRAME);
 /// 代表压缩的 bit（整个长度 4 字节的最高位）
 const COMPRESSION_BIT: usize = 1 << 31;
+/// 压缩的 frame 中，接下来的 3 bit 是压缩算法，0 是 gzip，和只有压缩 bit 的旧格式兼容
+const CODEC_SHIFT: usize = 28;
+const CODEC_MASK: usize = 0b111 << CODEC_SHIFT;
+/// 压缩的 frame 剩下 28 bit 是长度，所以压缩后最大是 256M
+const COMPRESSED_LEN_MASK: usize = (1 << CODEC_SHIFT) - 1;
+
+/// frame 的压缩算法，值和 Handshake 里的 codecs 一致
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    None = 0,
+    #[default]
+    Gzip = 1,
+    Zstd = 2,
+    Lz4 = 3,
+    Snappy = 4,
+}
+
+impl Codec {
+    /// 支持的所有算法，按服务器的偏好排列
+    pub const ALL: [Codec; 5] = [
+        Codec::Zstd,
+        Codec::Lz4,
+        Codec::Snappy,
+        Codec::Gzip,
+        Codec::None,
+    ];
+
+    /// 写在 frame 头里的值，gzip 用 0
+    fn header_id(self) -> usize {
+        match self {
+            Codec::Gzip => 0,
+            codec => codec as usize,
+        }
+    }
+
+    fn from_header_id(id: usize) -> Result<Self, KvError> {
+        match id {
+            0 | 1 => Ok(Codec::Gzip),
+            2 => Ok(Codec::Zstd),
+            3 => Ok(Codec::Lz4),
+            4 => Ok(Codec::Snappy),
+            _ => Err(KvError::FrameError),
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>, KvError> {
+        let out = Vec::with_capacity(data.len() / 2);
+        let compressed = match self {
+            Codec::None => data.to_vec(),
+            Codec::Gzip => {
+                // 处理 gzip 压缩，具体可以参考 flate2 文档
+                let mut encoder = GzBuilder::new().write(out, Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+            // 0 是 zstd 的默认级别
+            Codec::Zstd => zstd::bulk::compress(data, 0)?,
+            Codec::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(out);
+                encoder.write_all(data)?;
+                encoder.finish().map_err(io::Error::from)?
+            }
+            Codec::Snappy => {
+                let mut encoder = snap::write::FrameEncoder::new(out);
+                encoder.write_all(data)?;
+                encoder.into_inner().map_err(|e| e.into_error())?
+            }
+        };
+        Ok(compressed)
+    }
+
+    /// 解压后超过 frame 的最大长度时出错，避免恶意的 frame 耗尽内存
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>, KvError> {
+        let reader: Box<dyn Read + '_> = match self {
+            Codec::None => Box::new(data),
+            Codec::Gzip => Box::new(GzDecoder::new(data)),
+            Codec::Zstd => Box::new(zstd::Decoder::with_buffer(data)?),
+            Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(data)),
+            Codec::Snappy => Box::new(snap::read::FrameDecoder::new(data)),
+        };
+        let limit = FRAME_LIMIT.load(Ordering::Relaxed);
+        let mut buf = Vec::with_capacity(data.len() * 2);
+        reader.take(limit as u64 + 1).read_to_end(&mut buf)?;
+        if buf.len() > limit {
+            return Err(KvError::FrameError);
+        }
+        Ok(buf)
+    }
+}
+
+impl TryFrom<u32> for Codec {
+    type Error = KvError;
+
+    fn try_from(v: u32) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Gzip),
+            2 => Ok(Codec::Zstd),
+            3 => Ok(Codec::Lz4),
+            4 => Ok(Codec::Snappy),
+            _ => Err(KvError::InvalidCommand(format!("unknown codec {}", v))),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Codec::None),
+            "gzip" => Ok(Codec::Gzip),
+            "zstd" => Ok(Codec::Zstd),
+            "lz4" => Ok(Codec::Lz4),
+            "snappy" => Ok(Codec::Snappy),
+            _ => Err(KvError::ConfigError(format!("unknown codec {}", s))),
+        }
+    }
+}
+
+/// 一个连接编码 frame 时使用的压缩算法和阈值。
+/// 默认是 gzip 和进程的阈值，和没有协商过的旧客户端兼容
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCompression {
+    pub codec: Codec,
+    /// payload 超过这个字节数时才压缩
+    pub threshold: usize,
+}
+
+impl Default for FrameCompression {
+    fn default() -> Self {
+        Self {
+            codec: Codec::Gzip,
+            threshold: COMPRESSION_LIMIT.load(Ordering::Relaxed),
+        }
+    }
+}
 
 pub trait FrameCoder
 where
@@ -23,60 +167,69 @@ where
 {
     /// 把一个 Message encode 成一个 frame
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
+        self.encode_frame_with(buf, &FrameCompression::default())
+    }
+
+    /// 用指定的压缩算法和阈值把一个 Message encode 成一个 frame
+    fn encode_frame_with(
+        &self,
+        buf: &mut BytesMut,
+        compression: &FrameCompression,
+    ) -> Result<(), KvError> {
         let size = self.encoded_len();
-        if size > MAX_FRAME {
+        if size > FRAME_LIMIT.load(Ordering::Relaxed) {
             return Err(KvError::FrameError);
         }
-        // 我们先写入长度，如果需要压缩，再重写压缩后的长度
-        buf.put_u32(size as _);
 
-        if size > COMPRESSION_LIMIT {
+        let codec = compression.codec;
+        if codec != Codec::None && size > compression.threshold {
             let mut buf1 = Vec::with_capacity(size);
             self.encode(&mut buf1)?;
-
-            // BytesMut 支持逻辑上的 split（之后还能 unsplit）
-            // 所以我们先把长度这 4 字节拿走，清除
-            // payload 是右边剩下的部分
-            let payload = buf.split_off(LEN_LEN);
-            // buf 是左边的头
-            buf.clear();
-
-            // 处理 gzip 压缩，具体可以参考 flate2 文档
-            let mut encoder = GzBuilder::new().write(payload.writer(), Compression::default());
-            encoder.write_all(&buf1[..])?;
-
-            // 压缩完成后，从 gzip encoder 中把 BytesMut 再拿回来
-            let payload = encoder.finish()?.into_inner();
-            debug!("Encode a frame: size {}({})", size, payload.len());
-
-            // 写入压缩后的长度
-            buf.put_u32((payload.len() | COMPRESSION_BIT) as _);
-            // 把 BytesMut 再合并回来
-            buf.unsplit(payload);
-            Ok(())
+            let payload = codec.compress(&buf1)?;
+            // 压缩后的长度放不下时，就不压缩了
+            if payload.len() <= COMPRESSED_LEN_MASK {
+                debug!(
+                    "Encode a frame: size {}({}, {:?})",
+                    size,
+                    payload.len(),
+                    codec
+                );
+                METRICS
+                    .compression_ratio
+                    .observe(payload.len() as f64 / size as f64);
+                let header = payload.len() | codec.header_id() << CODEC_SHIFT | COMPRESSION_BIT;
+                buf.put_u32(header as _);
+                buf.put_slice(&payload);
+            } else {
+                buf.put_u32(size as _);
+                buf.put_slice(&buf1);
+            }
         } else {
+            buf.put_u32(size as _);
             self.encode(buf)?;
-            Ok(())
         }
+        let bytes_out = METRICS.frame_bytes.with_label_values(&["out"]);
+        bytes_out.inc_by(buf.len() as u64);
+        Ok(())
     }
-    /// 把一个完整的 frame decode 成一个 Message
+    /// 把一个完整的 frame decode 成一个 Message，压缩算法由 frame 头决定
     fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
-        // 先取 4 字节，从中拿出长度和 compression bit
+        // 先取 4 字节，从中拿出长度和压缩算法
         let header = buf.get_u32() as usize;
-        let (len, compressed) = decode_header(header);
-        debug!("Got a frame: msg len {}, compressed {}", len, compressed);
+        let (len, codec) = decode_header(header)?;
+        debug!("Got a frame: msg len {}, codec {:?}", len, codec);
+        let bytes_in = METRICS.frame_bytes.with_label_values(&["in"]);
+        bytes_in.inc_by((LEN_LEN + len) as u64);
 
-        if compressed {
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut buf1 = Vec::with_capacity(len * 2);
-            decoder.read_to_end(&mut buf1)?;
-            buf.advance(len);
-            // decode 成相应的消息
-            Ok(Self::decode(&buf1[..])?)
-        } else {
+        if codec == Codec::None {
             let msg = Self::decode(&buf[..len])?;
             buf.advance(len);
             Ok(msg)
+        } else {
+            let buf1 = codec.decompress(&buf[..len])?;
+            buf.advance(len);
+            // decode 成相应的消息
+            Ok(Self::decode(&buf1[..])?)
         }
     }
 }
@@ -84,10 +237,29 @@ where
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
 
-fn decode_header(header: usize) -> (usize, bool) {
-    let len = header & !COMPRESSION_BIT;
-    let compressed = header & COMPRESSION_BIT == COMPRESSION_BIT;
-    (len, compressed)
+/// 设置压缩的阈值，payload 超过它时才压缩，对整个进程生效。
+/// 连接可以通过 Handshake 协商更大的阈值
+pub fn set_compression_limit(limit: usize) {
+    COMPRESSION_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// 进程的压缩阈值
+pub fn compression_limit() -> usize {
+    COMPRESSION_LIMIT.load(Ordering::Relaxed)
+}
+
+/// 设置 frame 的最大长度（不超过 2G），对整个进程生效。编码或读取更大的 frame 时会出错
+pub fn set_frame_limit(limit: usize) {
+    FRAME_LIMIT.store(limit.min(MAX_FRAME), Ordering::Relaxed);
+}
+
+/// 没有压缩时长度占 31 bit；压缩时是 3 bit 的算法和 28 bit 的长度
+fn decode_header(header: usize) -> Result<(usize, Codec), KvError> {
+    if header & COMPRESSION_BIT == 0 {
+        return Ok((header, Codec::None));
+    }
+    let codec = Codec::from_header_id((header & CODEC_MASK) >> CODEC_SHIFT)?;
+    Ok((header & COMPRESSED_LEN_MASK, codec))
 }
 
 /// 从 stream 中读取一个完整的 frame
@@ -96,7 +268,10 @@ where
     S: AsyncRead + Unpin + Send,
 {
     let header = stream.read_u32().await? as usize;
-    let (len, _c) = decode_header(header);
+    let (len, _codec) = decode_header(header)?;
+    if len > FRAME_LIMIT.load(Ordering::Relaxed) {
+        return Err(KvError::FrameError);
+    }
     // 如果没有这么大的内存，就分配至少一个 frame 的内存，保证它可用
     buf.reserve(LEN_LEN + len);
     buf.put_u32(header as _);
@@ -172,7 +347,7 @@ mod tests {
     #[test]
     fn command_response_compressed_encode_decode_should_work() {
         let mut buf = BytesMut::new();
-        let value: Value = Bytes::from(vec![0u8; COMPRESSION_LIMIT + 1]).into();
+        let value: Value = Bytes::from(vec![0u8; DEFAULT_COMPRESSION_LIMIT + 1]).into();
         let res: CommandResponse = value.into();
         res.encode_frame(&mut buf).unwrap();
         // 最高位设置了
@@ -180,6 +355,34 @@ mod tests {
         let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
         assert_eq!(res, res1);
     }
+    #[test]
+    fn every_codec_should_encode_decode() {
+        let value: Value = Bytes::from(vec![0u8; DEFAULT_COMPRESSION_LIMIT + 1]).into();
+        let res: CommandResponse = value.into();
+        for codec in Codec::ALL {
+            let mut buf = BytesMut::new();
+            let compression = FrameCompression {
+                codec,
+                threshold: DEFAULT_COMPRESSION_LIMIT,
+            };
+            res.encode_frame_with(&mut buf, &compression).unwrap();
+            assert_eq!(is_compressed(&buf), codec != Codec::None);
+            let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
+            assert_eq!(res, res1);
+        }
+    }
+
+    #[test]
+    fn gzip_frame_should_keep_legacy_header() {
+        let mut buf = BytesMut::new();
+        let value: Value = Bytes::from(vec![0u8; DEFAULT_COMPRESSION_LIMIT + 1]).into();
+        CommandResponse::from(value).encode_frame(&mut buf).unwrap();
+        // 只有压缩 bit，算法的 3 bit 都是 0
+        let header = u32::from_be_bytes(buf[..LEN_LEN].try_into().unwrap()) as usize;
+        assert_eq!(header & CODEC_MASK, 0);
+        assert_eq!(decode_header(header).unwrap().1, Codec::Gzip);
+    }
+
     fn is_compressed(data: &[u8]) -> bool {
         if let &[v] = &data[..1] {
             v >> 7 == 1
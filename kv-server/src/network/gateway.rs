@@ -0,0 +1,297 @@
+use crate::{
+    value, AsyncStorage, Auth, CommandRequest, CommandResponse, ConnectionContext, KvError,
+    Service, Value,
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map};
+
+/// Value 的 JSON 表示：用变体的名字作为 key，比如 {"integer": 1}，binary 用 base64 编码。
+/// 不存在的 value 是 null
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JsonValue {
+    String(String),
+    Binary(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+/// HTTP/JSON 网关的路由，请求都通过 Service 执行：
+/// - GET /tables/{t}：table 中所有的 kv pair
+/// - GET /tables/{t}/keys/{k}：读取一个 key
+/// - PUT /tables/{t}/keys/{k}：body 是 JSON 表示的 value，返回之前的 value
+/// - DELETE /tables/{t}/keys/{k}：返回删除的 value
+///
+/// 启用了认证时，请求用 `Authorization: Bearer <token>` 认证，没有这个 header 的请求是匿名的。
+/// 出错时的 HTTP status 就是 CommandResponse 的 status，body 是 {"status": .., "message": ..}
+pub fn http_router<Store>(service: Service<Store>) -> Router
+where
+    Store: AsyncStorage + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/tables/:table", get(get_table::<Store>))
+        .route(
+            "/tables/:table/keys/:key",
+            get(get_key::<Store>)
+                .put(put_key::<Store>)
+                .delete(delete_key::<Store>),
+        )
+        .with_state(service)
+}
+
+async fn get_table<Store: AsyncStorage>(
+    State(service): State<Service<Store>>,
+    Path(table): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    match execute(&service, &headers, CommandRequest::new_hgetall(table)).await {
+        Ok(res) => {
+            let pairs: Map<_, _> = res
+                .pairs
+                .iter()
+                .map(|pair| (pair.key.clone(), to_json(pair.value.as_ref())))
+                .collect();
+            Json(pairs).into_response()
+        }
+        Err(res) => res,
+    }
+}
+
+async fn get_key<Store: AsyncStorage>(
+    State(service): State<Service<Store>>,
+    Path((table, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    reply_value(&service, &headers, CommandRequest::new_hget(table, key)).await
+}
+
+async fn put_key<Store: AsyncStorage>(
+    State(service): State<Service<Store>>,
+    Path((table, key)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let value = match serde_json::from_slice::<JsonValue>(&body) {
+        Ok(v) => match from_json(v) {
+            Some(v) => v,
+            None => return error(StatusCode::BAD_REQUEST, "Invalid base64 binary value"),
+        },
+        Err(e) => return error(StatusCode::BAD_REQUEST, &format!("Invalid value: {}", e)),
+    };
+    reply_value(
+        &service,
+        &headers,
+        CommandRequest::new_hset(table, key, value),
+    )
+    .await
+}
+
+async fn delete_key<Store: AsyncStorage>(
+    State(service): State<Service<Store>>,
+    Path((table, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    reply_value(&service, &headers, CommandRequest::new_hdel(table, key)).await
+}
+
+/// 执行命令，返回第一个 value 的 JSON
+async fn reply_value<Store: AsyncStorage>(
+    service: &Service<Store>,
+    headers: &HeaderMap,
+    cmd: CommandRequest,
+) -> Response {
+    match execute(service, headers, cmd).await {
+        Ok(res) => Json(to_json(res.values.first())).into_response(),
+        Err(res) => res,
+    }
+}
+
+/// 以请求的身份执行命令，status 不是 200 时返回对应的错误
+async fn execute<Store: AsyncStorage>(
+    service: &Service<Store>,
+    headers: &HeaderMap,
+    cmd: CommandRequest,
+) -> Result<CommandResponse, Response> {
+    let ctx = context(service, headers).map_err(|e| error_response(&e.into()))?;
+    let res = match service.execute_with(&ctx, cmd).await.next().await {
+        Some(res) => res,
+        None => return Err(error(StatusCode::INTERNAL_SERVER_ERROR, "No response")),
+    };
+    if res.status != StatusCode::OK.as_u16() as u32 {
+        return Err(error_response(&res));
+    }
+    Ok((*res).clone())
+}
+
+/// 有 Authorization header 时用其中的 bearer token 认证，认证失败返回 401
+fn context<Store: AsyncStorage>(
+    service: &Service<Store>,
+    headers: &HeaderMap,
+) -> Result<ConnectionContext, KvError> {
+    let mut ctx = ConnectionContext::default();
+    let header = match headers.get(AUTHORIZATION) {
+        Some(header) => header,
+        None => return Ok(ctx),
+    };
+    let token = header
+        .to_str()
+        .ok()
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| KvError::Unauthenticated("Invalid Authorization header".into()))?;
+    let auth = Auth {
+        token: token.trim().into(),
+        ..Default::default()
+    };
+    ctx.principal = Some(service.authenticate(&auth)?);
+    Ok(ctx)
+}
+
+fn error_response(res: &CommandResponse) -> Response {
+    let status = StatusCode::from_u16(res.status as _).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    error(status, &res.message)
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    let body = json!({ "status": status.as_u16(), "message": message });
+    (status, Json(body)).into_response()
+}
+
+fn to_json(v: Option<&Value>) -> serde_json::Value {
+    let v = match v.and_then(|v| v.value.as_ref()) {
+        Some(value::Value::String(s)) => JsonValue::String(s.clone()),
+        Some(value::Value::Binary(b)) => JsonValue::Binary(STANDARD.encode(b)),
+        Some(value::Value::Integer(i)) => JsonValue::Integer(*i),
+        Some(value::Value::Float(f)) => JsonValue::Float(*f),
+        Some(value::Value::Bool(b)) => JsonValue::Bool(*b),
+        None => return serde_json::Value::Null,
+    };
+    // JsonValue 一定能转换成 JSON
+    serde_json::to_value(v).unwrap_or_default()
+}
+
+/// binary 不是合法的 base64 时返回 None
+fn from_json(v: JsonValue) -> Option<Value> {
+    let v = match v {
+        JsonValue::String(s) => s.into(),
+        JsonValue::Binary(b) => bytes::Bytes::from(STANDARD.decode(b).ok()?).into(),
+        JsonValue::Integer(i) => Value::integer(i),
+        JsonValue::Float(f) => f.into(),
+        JsonValue::Bool(b) => b.into(),
+    };
+    Some(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Authenticator, Inline, MemTable, Permission, ServiceInner};
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    async fn call(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        call_with(router, method, uri, body, None).await
+    }
+
+    async fn call_with(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        body: &str,
+        authorization: Option<&str>,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(authorization) = authorization {
+            req = req.header(AUTHORIZATION, authorization);
+        }
+        let req = req.body(Body::from(body.to_string())).unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn http_gateway_should_work() {
+        let router = http_router(ServiceInner::new(Inline::new(MemTable::new())).into());
+
+        let (status, body) = call(&router, "PUT", "/tables/t1/keys/k1", r#"{"integer": 1}"#).await;
+        assert_eq!((status, body), (StatusCode::OK, json!(null)));
+        let (_, body) = call(&router, "PUT", "/tables/t1/keys/k1", r#"{"string": "v1"}"#).await;
+        assert_eq!(body, json!({"integer": 1}));
+        call(
+            &router,
+            "PUT",
+            "/tables/t1/keys/k2",
+            r#"{"binary": "aGk="}"#,
+        )
+        .await;
+
+        let (status, body) = call(&router, "GET", "/tables/t1/keys/k2", "").await;
+        assert_eq!((status, body), (StatusCode::OK, json!({"binary": "aGk="})));
+        let (status, body) = call(&router, "GET", "/tables/t1", "").await;
+        let expected = json!({"k1": {"string": "v1"}, "k2": {"binary": "aGk="}});
+        assert_eq!((status, body), (StatusCode::OK, expected));
+
+        let (_, body) = call(&router, "DELETE", "/tables/t1/keys/k1", "").await;
+        assert_eq!(body, json!({"string": "v1"}));
+
+        // 错误的 status 和 CommandResponse 一致
+        let (status, body) = call(&router, "GET", "/tables/t1/keys/k1", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], 404);
+        let (status, _) = call(&router, "PUT", "/tables/t1/keys/k1", r#"{"number": 1}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn http_gateway_should_authenticate_bearer_token() {
+        let auth =
+            Authenticator::new()
+                .token("tok", "alice")
+                .grant("alice", "t1", Permission::Write);
+        let service = ServiceInner::new(Inline::new(MemTable::new())).with_auth(auth);
+        let router = http_router(service.into());
+        let put = |authorization| {
+            call_with(
+                &router,
+                "PUT",
+                "/tables/t1/keys/k1",
+                r#"{"integer": 1}"#,
+                authorization,
+            )
+        };
+
+        let (status, body) = put(None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["message"], "Unauthenticated: Authentication required");
+        let (status, _) = put(Some("Bearer wrong")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = put(Some("Basic dG9rOg==")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = put(Some("Bearer tok")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) =
+            call_with(&router, "GET", "/tables/t1/keys/k1", "", Some("Bearer tok")).await;
+        assert_eq!((status, body), (StatusCode::OK, json!({"integer": 1})));
+        let (status, _) =
+            call_with(&router, "GET", "/tables/t2/keys/k1", "", Some("Bearer tok")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
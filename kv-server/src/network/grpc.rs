@@ -0,0 +1,368 @@
+use super::RESPONSE_CAPACITY;
+use crate::command_request::RequestData;
+use crate::kv_service_server::KvService;
+use crate::*;
+use futures::{Stream, StreamExt};
+use std::{pin::Pin, sync::Arc};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{info, warn};
+
+type ResponseStream = Pin<Box<dyn Stream<Item = Result<CommandResponse, Status>> + Send>>;
+
+/// gRPC 的 KvService，请求和帧协议一样通过 Service 执行。
+/// 启用了认证时，请求用 metadata 中的 `authorization: Bearer <token>` 认证，token 无效时返回
+/// UNAUTHENTICATED；其它命令执行的错误放在 CommandResponse 的 status 里
+pub struct GrpcService<Store = Inline<Arc<MemTable>>> {
+    service: Service<Store>,
+}
+
+impl<Store: AsyncStorage + Send + Sync + 'static> GrpcService<Store> {
+    pub fn new(service: Service<Store>) -> Self {
+        Self { service }
+    }
+
+    /// 请求所在的上下文，带着 bearer token 时以它认证的用户执行
+    #[allow(clippy::result_large_err)]
+    fn context<T>(&self, request: &Request<T>) -> Result<ConnectionContext, Status> {
+        let mut ctx = ConnectionContext::new(request.remote_addr());
+        let header = match request.metadata().get("authorization") {
+            Some(header) => header,
+            None => return Ok(ctx),
+        };
+        let token = header
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("Invalid authorization metadata"))?;
+        let auth = Auth {
+            token: token.trim().into(),
+            ..Default::default()
+        };
+        let name = self
+            .service
+            .authenticate(&auth)
+            .map_err(|e| Status::unauthenticated(e.to_string()))?;
+        ctx.principal = Some(name);
+        Ok(ctx)
+    }
+
+    async fn unary(
+        &self,
+        ctx: ConnectionContext,
+        data: RequestData,
+    ) -> Result<Response<CommandResponse>, Status> {
+        let mut cmd = CommandRequest {
+            request_data: Some(data),
+            ..Default::default()
+        };
+        // unary 只能返回一个 response，HGETALL 不分块
+        if let Some(RequestData::Hgetall(param)) = &mut cmd.request_data {
+            param.chunk_size = 0;
+        }
+        match self.service.execute_with(&ctx, cmd).await.next().await {
+            Some(res) => {
+                self.service.after_send(&ctx, &res).await;
+                Ok(Response::new((*res).clone()))
+            }
+            None => Err(Status::internal("No response")),
+        }
+    }
+}
+
+/// 每个 unary RPC 都是把请求包装成 CommandRequest 执行
+macro_rules! impl_kv_service {
+    ($($method:ident($param:ident)),* $(,)?) => {
+        // tonic::Status 比较大，但它是 KvService 规定的错误类型
+        #[allow(clippy::result_large_err)]
+        #[tonic::async_trait]
+        impl<Store: AsyncStorage + Send + Sync + 'static> KvService for GrpcService<Store> {
+            $(
+                async fn $method(
+                    &self,
+                    request: Request<$param>,
+                ) -> Result<Response<CommandResponse>, Status> {
+                    let ctx = self.context(&request)?;
+                    self.unary(ctx, RequestData::$param(request.into_inner())).await
+                }
+            )*
+
+            type SubscribeStream = ResponseStream;
+
+            async fn subscribe(
+                &self,
+                request: Request<Subscribe>,
+            ) -> Result<Response<Self::SubscribeStream>, Status> {
+                let ctx = self.context(&request)?;
+                let cmd = CommandRequest {
+                    request_data: Some(RequestData::Subscribe(request.into_inner())),
+                    ..Default::default()
+                };
+                let service = self.service.clone();
+                let stream = service.execute_with(&ctx, cmd).await.then(move |res| {
+                    let (service, ctx) = (service.clone(), ctx.clone());
+                    async move {
+                        service.after_send(&ctx, &res).await;
+                        Ok((*res).clone())
+                    }
+                });
+                Ok(Response::new(Box::pin(stream)))
+            }
+
+            type ExecuteStream = ResponseStream;
+
+            async fn execute(
+                &self,
+                request: Request<Streaming<CommandRequest>>,
+            ) -> Result<Response<Self::ExecuteStream>, Status> {
+                let ctx = self.context(&request)?;
+                let mut requests = request.into_inner();
+                let (tx, rx) = mpsc::channel(RESPONSE_CAPACITY);
+                let service = self.service.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let cmd = match requests.message().await {
+                            Ok(Some(cmd)) => cmd,
+                            Ok(None) => break,
+                            Err(e) => {
+                                warn!("Failed to read gRPC request: {:?}", e);
+                                break;
+                            }
+                        };
+                        info!("Got a new gRPC command: {:?}", cmd);
+                        // 和帧协议一样，有 id 的请求并发执行，id 为 0 的请求按顺序执行
+                        let id = cmd.id;
+                        if id != 0 {
+                            let (service, tx, ctx) = (service.clone(), tx.clone(), ctx.clone());
+                            tokio::spawn(async move {
+                                let res = service.execute_with(&ctx, cmd).await;
+                                forward(res, id, service, ctx, tx).await;
+                            });
+                            continue;
+                        }
+                        let subscribe =
+                            matches!(cmd.request_data, Some(RequestData::Subscribe(_)));
+                        let mut res = service.execute_with(&ctx, cmd).await;
+                        if subscribe {
+                            // 订阅的流会一直持续，写回第一个 response（订阅的 id）之后，
+                            // 推送的数据放到单独的 task 里，不挡住之后的请求
+                            let first = match res.next().await {
+                                Some(data) => (*data).clone(),
+                                None => continue,
+                            };
+                            if !respond(&service, &ctx, &tx, first, 0).await {
+                                break;
+                            }
+                            let (service, tx, ctx) = (service.clone(), tx.clone(), ctx.clone());
+                            tokio::spawn(forward(res, 0, service, ctx, tx));
+                        } else if !forward(res, 0, service.clone(), ctx.clone(), tx.clone()).await {
+                            break;
+                        }
+                    }
+                });
+                Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+            }
+        }
+    };
+}
+
+type ResponseSender = mpsc::Sender<Result<CommandResponse, Status>>;
+
+/// 把一个请求的所有 response 交给 tonic，连接关闭时返回 false
+async fn forward<Store: AsyncStorage>(
+    mut res: StreamingResponse,
+    id: u64,
+    service: Service<Store>,
+    ctx: ConnectionContext,
+    tx: ResponseSender,
+) -> bool {
+    while let Some(data) = res.next().await {
+        if !respond(&service, &ctx, &tx, (*data).clone(), id).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// 带上请求的 id 交给 tonic，交给 tonic 之后就算发出去了
+async fn respond<Store: AsyncStorage>(
+    service: &Service<Store>,
+    ctx: &ConnectionContext,
+    tx: &ResponseSender,
+    mut data: CommandResponse,
+    id: u64,
+) -> bool {
+    data.id = id;
+    if tx.send(Ok(data.clone())).await.is_err() {
+        return false;
+    }
+    service.after_send(ctx, &data).await;
+    true
+}
+
+impl_kv_service!(
+    hget(Hget),
+    hgetall(Hgetall),
+    hscan(Hscan),
+    hrange(Hrange),
+    hmget(Hmget),
+    hset(Hset),
+    hmset(Hmset),
+    hdel(Hdel),
+    hmdel(Hmdel),
+    list_tables(ListTables),
+    drop_table(DropTable),
+    rename_table(RenameTable),
+    hlen(Hlen),
+    hexist(Hexist),
+    hmexist(Hmexist),
+    hexpire(Hexpire),
+    hexpireat(Hexpireat),
+    httl(Httl),
+    hpersist(Hpersist),
+    hincrby(Hincrby),
+    hincrbyfloat(Hincrbyfloat),
+    watch(Watch),
+    transaction(Transaction),
+    snapshot(Snapshot),
+    restore(Restore),
+    unsubscribe(Unsubscribe),
+    publish(Publish),
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_service_client::KvServiceClient;
+    use crate::network::test_utils::*;
+    use anyhow::Result;
+    use std::net::SocketAddr;
+    use tonic::transport::Channel;
+
+    async fn start_server() -> Result<SocketAddr> {
+        start_grpc_server(memtable_service()).await
+    }
+
+    async fn connect(addr: SocketAddr) -> Result<KvServiceClient<Channel>> {
+        Ok(KvServiceClient::connect(format!("http://{}", addr)).await?)
+    }
+
+    #[tokio::test]
+    async fn grpc_unary_calls_should_work() -> Result<()> {
+        let mut client = connect(start_server().await?).await?;
+
+        let hset = Hset {
+            table: "t1".into(),
+            pair: Some(Kvpair::new("k1", "v1".into())),
+            ttl: 0,
+        };
+        let res = client.hset(hset).await?.into_inner();
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let hget = Hget {
+            table: "t1".into(),
+            key: "k1".into(),
+        };
+        let res = client.hget(hget).await?.into_inner();
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        // 命令执行的错误在 CommandResponse 里
+        let hget = Hget {
+            table: "t1".into(),
+            key: "k2".into(),
+        };
+        let res = client.hget(hget).await?.into_inner();
+        assert_res_error(res, 404, "Not found");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn grpc_execute_stream_should_work() -> Result<()> {
+        let addr = start_server().await?;
+        let mut client = connect(addr).await?;
+
+        let mut sub = CommandRequest::new_subscribe("lobby");
+        sub.id = 1;
+        let mut hset = CommandRequest::new_hset("t1", "k1", Value::integer(1));
+        hset.id = 2;
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(sub).await?;
+        tx.send(hset).await?;
+        let mut responses = client.execute(ReceiverStream::new(rx)).await?.into_inner();
+
+        // 两个请求的 response 都带着各自的 id
+        let mut ids = vec![];
+        for _ in 0..2 {
+            let res = responses.message().await?.unwrap();
+            assert_eq!(res.status, 200);
+            ids.push(res.id);
+        }
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2]);
+
+        // 订阅在这个流上持续返回数据
+        let publish = Publish {
+            topic: "lobby".into(),
+            data: vec!["hi".into()],
+        };
+        let mut other = connect(addr).await?;
+        let res = other.publish(publish).await?.into_inner();
+        assert_res_ok(res, &[], &[]);
+        let res = responses.message().await?.unwrap();
+        assert_eq!(res.id, 1);
+        assert_res_ok(res, &["hi".into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn grpc_execute_should_keep_order_without_id() -> Result<()> {
+        let mut client = connect(start_server().await?).await?;
+
+        // id 为 0 的请求按顺序执行，每个 HGET 都要看到它前面的 HSET；订阅不挡住后面的请求
+        let (tx, rx) = mpsc::channel(128);
+        tx.send(CommandRequest::new_subscribe("lobby")).await?;
+        for i in 0..50 {
+            tx.send(CommandRequest::new_hset("t1", "k1", Value::integer(i)))
+                .await?;
+            tx.send(CommandRequest::new_hget("t1", "k1")).await?;
+        }
+        let mut responses = client.execute(ReceiverStream::new(rx)).await?.into_inner();
+        assert_eq!(responses.message().await?.unwrap().status, 200);
+        for i in 0..50 {
+            responses.message().await?.unwrap();
+            let res = responses.message().await?.unwrap();
+            assert_res_ok(res, &[Value::integer(i)], &[]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn grpc_should_authenticate_bearer_token() -> Result<()> {
+        let auth =
+            Authenticator::new()
+                .token("tok", "alice")
+                .grant("alice", "t1", Permission::Write);
+        let service = ServiceInner::new(Inline::new(MemTable::new())).with_auth(auth);
+        let mut client = connect(start_grpc_server(service.into()).await?).await?;
+        let hget = |token: Option<&str>| {
+            let mut request = Request::new(Hget {
+                table: "t1".into(),
+                key: "k1".into(),
+            });
+            if let Some(token) = token {
+                let value = format!("Bearer {}", token).parse().unwrap();
+                request.metadata_mut().insert("authorization", value);
+            }
+            request
+        };
+
+        let res = client.hget(hget(None)).await?.into_inner();
+        assert_res_error(res, 401, "Authentication required");
+        let status = client.hget(hget(Some("wrong"))).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let res = client.hget(hget(Some("tok"))).await?.into_inner();
+        assert_res_error(res, 404, "Not found");
+        Ok(())
+    }
+}
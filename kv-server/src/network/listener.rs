@@ -0,0 +1,248 @@
+use crate::{KvError, TlsServerAcceptor};
+use futures::Stream;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tracing::{info, warn};
+
+/// 等待交给协议处理的连接的最大数量
+const PENDING_CAPACITY: usize = 128;
+
+/// 所有协议共用的监听：配置了 TLS 时先完成握手，设置了连接数限制时，达到上限之后等有连接断开再 accept。
+/// 多个 Listener 可以共用同一个限制
+pub struct Listener {
+    inner: TcpListener,
+    acceptor: Option<TlsServerAcceptor>,
+    limit: Option<Arc<Semaphore>>,
+}
+
+/// accept 下来的连接，可能是 TLS 的。连接 drop 时释放占用的连接数
+pub struct Connection {
+    stream: Box<dyn Io>,
+    peer_addr: SocketAddr,
+    info: TcpConnectInfo,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+trait Io: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Io for T {}
+
+impl Listener {
+    pub async fn bind(addr: &str) -> Result<Self, KvError> {
+        Ok(Self {
+            inner: TcpListener::bind(addr).await?,
+            acceptor: None,
+            limit: None,
+        })
+    }
+
+    /// 连接先完成 TLS 握手，再交给协议处理
+    pub fn with_tls(mut self, acceptor: TlsServerAcceptor) -> Self {
+        self.acceptor = Some(acceptor);
+        self
+    }
+
+    /// 同时处理的连接数不超过 limit 的 permit 数量
+    pub fn with_limit(mut self, limit: Arc<Semaphore>) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, KvError> {
+        Ok(self.inner.local_addr()?)
+    }
+
+    /// 在后台不断地 accept，返回准备好的连接。TLS 握手在单独的 task 里进行，
+    /// 握手慢或者失败的连接不会挡住其它的连接；返回的 stream 被 drop 之后停止 accept
+    pub fn incoming(self) -> impl Stream<Item = Result<Connection, io::Error>> {
+        let (tx, rx) = mpsc::channel(PENDING_CAPACITY);
+        tokio::spawn(async move {
+            loop {
+                let permit = match &self.limit {
+                    Some(limit) => match limit.clone().acquire_owned().await {
+                        Ok(permit) => Some(permit),
+                        Err(_) => return,
+                    },
+                    None => None,
+                };
+                let (stream, peer_addr) = match self.inner.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept connection: {:?}", e);
+                        continue;
+                    }
+                };
+                info!("Client {:?} connected", peer_addr);
+                let info = stream.connect_info();
+                let (acceptor, sender) = (self.acceptor.clone(), tx.clone());
+                tokio::spawn(async move {
+                    let stream: Box<dyn Io> = match acceptor {
+                        Some(acceptor) => match acceptor.accept(stream).await {
+                            Ok(stream) => Box::new(stream),
+                            Err(e) => {
+                                warn!("TLS handshake with {:?} failed: {:?}", peer_addr, e);
+                                return;
+                            }
+                        },
+                        None => Box::new(stream),
+                    };
+                    let conn = Connection {
+                        stream,
+                        peer_addr,
+                        info,
+                        _permit: permit,
+                    };
+                    let _ = sender.send(Ok(conn)).await;
+                });
+                if tx.is_closed() {
+                    return;
+                }
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+}
+
+impl Connection {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// gRPC 通过它拿到对端的地址
+impl Connected for Connection {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.info.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_service_client::KvServiceClient;
+    use crate::kv_service_server::KvServiceServer;
+    use crate::network::test_utils::*;
+    use crate::network::tls_utils::generate_certs;
+    use crate::{assert_res_ok, CommandRequest, GrpcService, Hset, Kvpair, RespServerStream};
+    use crate::{TlsClientConnector, Value};
+    use anyhow::Result;
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tonic::transport::Server;
+
+    const DOMAIN: &str = "kvserver.acme.inc";
+
+    #[tokio::test]
+    async fn listener_should_limit_connections() -> Result<()> {
+        let listener = Listener::bind("127.0.0.1:0")
+            .await?
+            .with_limit(Arc::new(Semaphore::new(1)));
+        let addr = listener.local_addr()?;
+        let mut incoming = Box::pin(listener.incoming());
+
+        let _c1 = TcpStream::connect(addr).await?;
+        let first = incoming.next().await.unwrap()?;
+        // 第二个连接要等第一个断开才会被 accept
+        let _c2 = TcpStream::connect(addr).await?;
+        let wait = tokio::time::timeout(Duration::from_millis(100), incoming.next());
+        assert!(wait.await.is_err());
+        drop(first);
+        assert!(incoming.next().await.unwrap().is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listener_should_serve_resp_over_tls() -> Result<()> {
+        let certs = generate_certs(DOMAIN);
+        let acceptor = TlsServerAcceptor::new(&certs.server_cert, &certs.server_key, None)?;
+        let listener = Listener::bind("127.0.0.1:0").await?.with_tls(acceptor);
+        let addr = listener.local_addr()?;
+        let service = memtable_service();
+        tokio::spawn(listener.incoming().for_each(move |conn| {
+            let service = service.clone();
+            async move {
+                tokio::spawn(RespServerStream::new(conn.unwrap(), service).process());
+            }
+        }));
+
+        // 不完成握手的连接不会挡住其它的连接
+        let _idle = TcpStream::connect(addr).await?;
+        let connector = TlsClientConnector::new(DOMAIN, None, &certs.ca_cert)?;
+        let mut stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        stream.write_all(b"PING\r\n").await?;
+        let mut buf = [0; 7];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"+PONG\r\n");
+
+        // 明文的客户端无法使用
+        let mut client = connect(addr).await?;
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            client.execute(CommandRequest::new_hget("t1", "k1")),
+        )
+        .await;
+        assert!(!matches!(result, Ok(Ok(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listener_should_serve_grpc() -> Result<()> {
+        let listener = Listener::bind("127.0.0.1:0")
+            .await?
+            .with_limit(Arc::new(Semaphore::new(4)));
+        let addr = listener.local_addr()?;
+        let server = Server::builder()
+            .add_service(KvServiceServer::new(GrpcService::new(memtable_service())))
+            .serve_with_incoming(listener.incoming());
+        tokio::spawn(server);
+
+        let mut client = KvServiceClient::connect(format!("http://{}", addr)).await?;
+        let hset = Hset {
+            table: "t1".into(),
+            pair: Some(Kvpair::new("k1", "v1".into())),
+            ttl: 0,
+        };
+        let res = client.hset(hset).await?.into_inner();
+        assert_res_ok(res, &[Value::default()], &[]);
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+use super::{read_message, write_message};
+use crate::{CommandRequest, CommandResponse, KvError, StreamResult};
+use dashmap::DashMap;
+use futures::StreamExt;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::warn;
+
+/// 每个请求最多缓存的 response 数量（SUBSCRIBE 会有多个 response）
+const PENDING_CAPACITY: usize = 128;
+
+/// 可以 clone 的客户端，多个 task 可以同时通过同一个连接发送请求，
+/// 服务器返回的 response 通过 id 和请求对应起来
+#[derive(Clone)]
+pub struct MultiplexClient {
+    /// 下一个请求的 id，0 留给不关心 id 的客户端
+    next_id: Arc<AtomicU64>,
+    /// 发送给写 task 的请求
+    requests: mpsc::Sender<CommandRequest>,
+    pending: Arc<Pending>,
+}
+
+/// 还在等待 response 的请求
+#[derive(Default)]
+struct Pending {
+    requests: DashMap<u64, mpsc::Sender<CommandResponse>>,
+    /// 连接是否已经断开
+    closed: AtomicBool,
+}
+
+impl Pending {
+    /// 连接断开后，所有还在等待的请求都会收到 None
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.requests.clear();
+    }
+}
+
+impl MultiplexClient {
+    /// 接管 stream，启动读和写两个 task
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (mut reader, mut writer) = io::split(stream);
+        let (tx, mut rx) = mpsc::channel::<CommandRequest>(PENDING_CAPACITY);
+        let pending = Arc::new(Pending::default());
+
+        // 写 task：所有的 client 都 drop 之后，关闭写端，服务器会随之关闭连接
+        tokio::spawn(async move {
+            while let Some(cmd) = rx.recv().await {
+                if let Err(e) = write_message(&mut writer, &cmd).await {
+                    warn!("Failed to send request {}: {:?}", cmd.id, e);
+                    break;
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+
+        // 读 task：把 response 交给 id 对应的请求
+        let reader_pending = pending.clone();
+        tokio::spawn(async move {
+            while let Ok(res) = read_message::<_, CommandResponse>(&mut reader).await {
+                let tx = match reader_pending.requests.get(&res.id) {
+                    Some(tx) => tx.clone(),
+                    None => {
+                        warn!("Got response for unknown request {}", res.id);
+                        continue;
+                    }
+                };
+                let id = res.id;
+                // 接收方已经不在了（比如 StreamResult 被 drop），不再需要这个请求的 response
+                if tx.send(res).await.is_err() {
+                    reader_pending.requests.remove(&id);
+                }
+            }
+            reader_pending.close();
+        });
+
+        Self {
+            next_id: Arc::new(AtomicU64::new(1)),
+            requests: tx,
+            pending,
+        }
+    }
+
+    /// 发送一个请求，等待它的 response
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let (id, mut rx) = self.send(cmd).await?;
+        let res = rx.recv().await;
+        self.pending.requests.remove(&id);
+        res.ok_or_else(closed)
+    }
+
+    /// 发送一个会返回多个 response 的请求（比如 SUBSCRIBE）
+    pub async fn execute_streaming(&self, cmd: CommandRequest) -> Result<StreamResult, KvError> {
+        let (_, rx) = self.send(cmd).await?;
+        StreamResult::new(ReceiverStream::new(rx).map(Ok)).await
+    }
+
+    async fn send(
+        &self,
+        cmd: CommandRequest,
+    ) -> Result<(u64, mpsc::Receiver<CommandResponse>), KvError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(PENDING_CAPACITY);
+        self.pending.requests.insert(id, tx);
+
+        // 先放入 pending 再检查，保证连接断开时不会有请求一直等待
+        if self.pending.closed.load(Ordering::SeqCst)
+            || self.requests.send(cmd.with_id(id)).await.is_err()
+        {
+            self.pending.requests.remove(&id);
+            return Err(closed());
+        }
+        Ok((id, rx))
+    }
+}
+
+fn closed() -> KvError {
+    KvError::Internal("Connection closed".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_utils::*;
+    use crate::{assert_res_ok, Value};
+    use anyhow::Result;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn multiplex_client_should_handle_concurrent_requests() -> Result<()> {
+        let addr = start_server().await?;
+        let client = MultiplexClient::new(TcpStream::connect(addr).await?);
+
+        let handles: Vec<_> = (0..50)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let key = format!("k{}", i);
+                    let value: Value = format!("v{}", i).into();
+                    let cmd = CommandRequest::new_hset("t1", &key, value.clone());
+                    let res = client.execute(cmd).await.unwrap();
+                    assert_res_ok(res, &[Value::default()], &[]);
+                    let res = client.execute(CommandRequest::new_hget("t1", &key)).await;
+                    assert_res_ok(res.unwrap(), &[value], &[]);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multiplex_client_should_subscribe_and_publish_on_one_connection() -> Result<()> {
+        let addr = start_server().await?;
+        let client = MultiplexClient::new(TcpStream::connect(addr).await?);
+
+        let mut sub = client
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+
+        // 订阅之后，同一个连接还可以继续发送请求
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        let res = client.execute(cmd).await?;
+        assert_res_ok(res, &[], &[]);
+
+        let res = sub.next().await.unwrap()?;
+        assert_res_ok(res, &["hello".into()], &[]);
+
+        let res = client
+            .execute(CommandRequest::new_unsubscribe("lobby", sub.id))
+            .await?;
+        assert_res_ok(res, &[], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multiplex_client_should_fail_after_connection_closed() -> Result<()> {
+        // 接受连接后直接关闭
+        let addr = spawn_listener(|stream, _| async move { drop(stream) }).await?;
+
+        let client = MultiplexClient::new(TcpStream::connect(addr).await?);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert!(res.is_err());
+
+        Ok(())
+    }
+}
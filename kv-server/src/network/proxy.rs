@@ -0,0 +1,917 @@
+use super::{read_message, write_message, RESPONSE_CAPACITY};
+use crate::command_request::RequestData;
+use crate::{
+    CommandRequest, CommandResponse, Hgetall, Hrange, Hscan, KvError, Kvpair, ProstClientStream,
+};
+use crate::{Transaction, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::sync::Arc;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// 每个 backend 在哈希环上的虚拟节点数量
+const VIRTUAL_NODES: usize = 160;
+
+/// 一致性哈希环。每个 backend 在环上有多个虚拟节点，增减 backend 时只有一小部分 key 需要移动
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    backends: Vec<String>,
+    ring: BTreeMap<u64, usize>,
+}
+
+impl HashRing {
+    pub fn new(backends: Vec<String>) -> Self {
+        let ring = backends
+            .iter()
+            .enumerate()
+            .flat_map(|(i, addr)| {
+                (0..VIRTUAL_NODES).map(move |n| (hash(&[addr.as_bytes(), &n.to_be_bytes()]), i))
+            })
+            .collect();
+        Self { backends, ring }
+    }
+
+    pub fn backends(&self) -> &[String] {
+        &self.backends
+    }
+
+    /// table 中的 key 所在的 backend 的序号。topic 相关的命令用空的 table 和 topic 来路由
+    pub fn route(&self, table: &str, key: &str) -> usize {
+        let hash = hash(&[table.as_bytes(), &[0], key.as_bytes()]);
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map_or(0, |(_, &i)| i)
+    }
+}
+
+/// FNV-1a，最后再混合一下，让相近的输入在环上也分散开
+fn hash(parts: &[&[u8]]) -> u64 {
+    let mut h = parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+            (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^ (h >> 33)
+}
+
+/// 处理代理上一个客户端的连接：按 table 和 key 把请求转发给对应的 backend，
+/// 涉及多个 key 的请求拆开发送再合并结果，涉及整个 table 的请求发给所有的 backend。
+/// 客户端的 AUTH 会转发给所有的 backend，之后这个客户端的 backend 连接都以同样的身份认证
+pub struct ProxyServerStream<S> {
+    inner: S,
+    router: Router,
+}
+
+/// 每个客户端连接有自己的一组 backend 连接，在第一次用到时建立
+struct Router {
+    ring: Arc<HashRing>,
+    conns: Vec<Option<ProstClientStream<TcpStream>>>,
+    /// 客户端最近一次认证成功的 AUTH，新建立的 backend 连接先用它认证
+    auth: Option<CommandRequest>,
+    /// 转发订阅数据的 task，客户端断开时结束
+    subscriptions: Vec<JoinHandle<()>>,
+}
+
+impl<S> ProxyServerStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S, ring: Arc<HashRing>) -> Self {
+        let conns = ring.backends().iter().map(|_| None).collect();
+        Self {
+            inner: stream,
+            router: Router {
+                ring,
+                conns,
+                auth: None,
+                subscriptions: vec![],
+            },
+        }
+    }
+
+    /// 依次处理这个连接上的请求，response 带上请求的 id
+    pub async fn process(self) -> Result<(), KvError> {
+        let (mut reader, mut writer) = io::split(self.inner);
+        let (tx, mut rx) = mpsc::channel::<CommandResponse>(RESPONSE_CAPACITY);
+        let mut router = self.router;
+
+        let read_loop = async {
+            while let Ok(cmd) = read_message::<_, CommandRequest>(&mut reader).await {
+                info!("Proxy got a new command: {:?}", cmd);
+                let id = cmd.id;
+                for mut res in router.execute(cmd, &tx).await {
+                    res.id = id;
+                    if tx.send(res).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        };
+
+        let write_loop = async move {
+            while let Some(res) = rx.recv().await {
+                write_message(&mut writer, &res).await?;
+            }
+            Ok::<_, KvError>(())
+        };
+
+        // 客户端关闭写的一端（读结束）之后，停止转发订阅的数据，把已经排队的 response 都写完再结束；
+        // 写出错时直接结束
+        tokio::pin!(write_loop);
+        let failed = tokio::select! {
+            _ = read_loop => None,
+            res = &mut write_loop => Some(res),
+        };
+        for handle in router.subscriptions.drain(..) {
+            handle.abort();
+        }
+        match failed {
+            Some(res) => res,
+            None => {
+                drop(tx);
+                write_loop.await
+            }
+        }
+    }
+}
+
+impl Router {
+    async fn execute(
+        &mut self,
+        cmd: CommandRequest,
+        tx: &mpsc::Sender<CommandResponse>,
+    ) -> Vec<CommandResponse> {
+        match cmd.request_data {
+            Some(RequestData::Hgetall(param)) => self.hgetall(param, cmd.id, tx).await,
+            Some(RequestData::Subscribe(_)) => self.subscribe(cmd, tx.clone()).await,
+            Some(RequestData::Auth(_)) => vec![self.auth(cmd).await],
+            _ => vec![self.route(cmd).await],
+        }
+    }
+
+    async fn route(&mut self, cmd: CommandRequest) -> CommandResponse {
+        match cmd.request_data {
+            Some(RequestData::Hmget(param)) => {
+                let table = param.table.clone();
+                self.fan_out(&param.table, param.keys, String::as_str, |keys| {
+                    CommandRequest::new_hmget(&table, keys)
+                })
+                .await
+            }
+            Some(RequestData::Hmset(param)) => {
+                let table = param.table.clone();
+                let ttl = param.ttl;
+                self.fan_out(&param.table, param.pairs, pair_key, |pairs| {
+                    CommandRequest::new_hmset_with_ttl(&table, pairs, ttl)
+                })
+                .await
+            }
+            Some(RequestData::Hmdel(param)) => {
+                let table = param.table.clone();
+                self.fan_out(&param.table, param.keys, String::as_str, |keys| {
+                    CommandRequest::new_hmdel(&table, keys)
+                })
+                .await
+            }
+            Some(RequestData::Hmexist(param)) => {
+                let table = param.table.clone();
+                self.fan_out(&param.table, param.keys, String::as_str, |keys| {
+                    CommandRequest::new_hmexist(&table, keys)
+                })
+                .await
+            }
+            Some(RequestData::Watch(param)) => {
+                let table = param.table.clone();
+                self.fan_out(&param.table, param.keys, String::as_str, |keys| {
+                    CommandRequest::new_watch(&table, keys)
+                })
+                .await
+            }
+            Some(RequestData::Hscan(param)) => self.hscan(param).await,
+            Some(RequestData::Hrange(param)) => self.hrange(param).await,
+            Some(RequestData::ListTables(_)) => self.list_tables(cmd).await,
+            // table 改名之后 key 会落到别的 backend 上，代理不支持
+            Some(RequestData::DropTable(_)) | Some(RequestData::Hlen(_)) => self.sum(cmd).await,
+            Some(RequestData::Transaction(ref param)) => match self.transaction_backend(param) {
+                Ok(backend) => self.forward(backend, cmd).await,
+                Err(e) => e.into(),
+            },
+            Some(RequestData::Unsubscribe(ref param)) => {
+                let backend = self.ring.route("", &param.topic);
+                self.forward(backend, cmd).await
+            }
+            Some(RequestData::Publish(ref param)) => {
+                let backend = self.ring.route("", &param.topic);
+                self.forward(backend, cmd).await
+            }
+            _ => match keys_of(&cmd).first() {
+                Some((table, key)) => {
+                    let backend = self.ring.route(table, key);
+                    self.forward(backend, cmd).await
+                }
+                None => {
+                    KvError::InvalidCommand(format!("{:?} is not supported by proxy", cmd)).into()
+                }
+            },
+        }
+    }
+
+    /// 把 items 按 key 分给各个 backend，每个 backend 执行一次，再把返回的 values 按原来的顺序合并。
+    /// 不同 backend 上的执行不是原子的：有 backend 出错时，其它 backend 上的修改（比如 HMSET、HMDEL）
+    /// 已经生效了。这时返回第一个错误，message 里列出所有没有成功的 key，成功的 key 的 values 照常返回
+    async fn fan_out<T>(
+        &mut self,
+        table: &str,
+        items: Vec<T>,
+        key: fn(&T) -> &str,
+        build: impl Fn(Vec<T>) -> CommandRequest,
+    ) -> CommandResponse {
+        let len = items.len();
+        let mut groups: BTreeMap<usize, (Vec<usize>, Vec<T>)> = BTreeMap::new();
+        for (i, item) in items.into_iter().enumerate() {
+            let group = groups
+                .entry(self.ring.route(table, key(&item)))
+                .or_default();
+            group.0.push(i);
+            group.1.push(item);
+        }
+
+        let mut values = vec![Value::default(); len];
+        let mut error: Option<CommandResponse> = None;
+        let mut failed = vec![];
+        for (backend, (positions, items)) in groups {
+            let keys: Vec<String> = items.iter().map(|item| key(item).to_owned()).collect();
+            let res = self.forward(backend, build(items)).await;
+            if res.status != 200 {
+                failed.extend(keys);
+                error.get_or_insert(res);
+                continue;
+            }
+            for (i, v) in positions.into_iter().zip(res.values) {
+                values[i] = v;
+            }
+        }
+        match error {
+            Some(mut res) => {
+                res.message = format!("{} (failed keys: {})", res.message, failed.join(", "));
+                res.values = values;
+                res
+            }
+            None => values.into(),
+        }
+    }
+
+    /// 从所有的 backend 取回整个 table。不分块时只能在代理上合并成一个 response；
+    /// 分块时依次把每个 backend 的分块直接转发给客户端，代理不保存整个 table，最后再发一个结束标记
+    async fn hgetall(
+        &mut self,
+        param: Hgetall,
+        id: u64,
+        tx: &mpsc::Sender<CommandResponse>,
+    ) -> Vec<CommandResponse> {
+        if param.chunk_size > 0 {
+            for backend in 0..self.conns.len() {
+                if let Some(res) = self.stream_chunks(backend, &param, id, tx).await {
+                    return vec![res];
+                }
+            }
+            return vec![CommandResponse::end()];
+        }
+
+        let mut pairs = vec![];
+        for backend in 0..self.conns.len() {
+            let cmd = CommandRequest::new_hgetall(&param.table);
+            let res = self.forward(backend, cmd).await;
+            if res.status != 200 {
+                return vec![res];
+            }
+            pairs.extend(res.pairs);
+        }
+        vec![pairs.into()]
+    }
+
+    /// 把一个 backend 上的分块转发给客户端，出错时返回错误的 response
+    async fn stream_chunks(
+        &mut self,
+        backend: usize,
+        param: &Hgetall,
+        id: u64,
+        tx: &mpsc::Sender<CommandResponse>,
+    ) -> Option<CommandResponse> {
+        let cmd = CommandRequest::new_hgetall_chunked(&param.table, param.chunk_size);
+        let result = async {
+            let conn = self.conn(backend).await?;
+            conn.send(cmd).await?;
+            loop {
+                let mut res = conn.recv().await?;
+                // backend 出错时只返回一个错误的 response，流就结束了
+                if res.status != 200 {
+                    return Ok(Some(res));
+                }
+                if res.end {
+                    return Ok(None);
+                }
+                res.id = id;
+                if tx.send(res).await.is_err() {
+                    return Err(KvError::Internal("Client disconnected".into()));
+                }
+            }
+        }
+        .await;
+        result.unwrap_or_else(|e| Some(self.drop_conn(backend, e)))
+    }
+
+    /// 依次扫描每个 backend，一个 backend 扫描完了，cursor 就指向下一个 backend 的开头。
+    /// 代理返回的 cursor 是 "backend 的序号:这个 backend 上的 cursor"
+    async fn hscan(&mut self, param: Hscan) -> CommandResponse {
+        let (backend, cursor) = match param.cursor.as_str() {
+            "" => (0, ""),
+            cursor => match cursor.split_once(':').map(|(b, c)| (b.parse::<usize>(), c)) {
+                Some((Ok(backend), cursor)) if backend < self.conns.len() => (backend, cursor),
+                _ => return KvError::InvalidCommand(format!("Invalid cursor {}", cursor)).into(),
+            },
+        };
+        let cmd = CommandRequest::new_hscan(&param.table, cursor, param.count, &param.pattern);
+        let mut res = self.forward(backend, cmd).await;
+        let next = match String::try_from(&res) {
+            Ok(next) => next,
+            Err(_) => return res,
+        };
+        let next = match next.as_str() {
+            "" if backend + 1 < self.conns.len() => format!("{}:", backend + 1),
+            "" => String::new(),
+            n => format!("{}:{}", backend, n),
+        };
+        res.values = vec![next.into()];
+        res
+    }
+
+    /// 每个 backend 返回它上面范围内的前 limit 个，合并排序之后再取前 limit 个
+    async fn hrange(&mut self, param: Hrange) -> CommandResponse {
+        let mut pairs = vec![];
+        for backend in 0..self.conns.len() {
+            let cmd = CommandRequest {
+                request_data: Some(RequestData::Hrange(param.clone())),
+                ..Default::default()
+            };
+            let res = self.forward(backend, cmd).await;
+            if res.status != 200 {
+                return res;
+            }
+            pairs.extend(res.pairs);
+        }
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        if param.reverse {
+            pairs.reverse();
+        }
+        if param.limit > 0 {
+            pairs.truncate(param.limit as usize);
+        }
+        pairs.into()
+    }
+
+    /// 在所有的 backend 上执行，有一个出错就返回这个错误
+    async fn broadcast(
+        &mut self,
+        cmd: CommandRequest,
+    ) -> Result<Vec<CommandResponse>, CommandResponse> {
+        let mut responses = vec![];
+        for backend in 0..self.conns.len() {
+            let res = self.forward(backend, cmd.clone()).await;
+            if res.status != 200 {
+                return Err(res);
+            }
+            responses.push(res);
+        }
+        Ok(responses)
+    }
+
+    /// 合并所有 backend 上的 table，去掉重复的名字
+    async fn list_tables(&mut self, cmd: CommandRequest) -> CommandResponse {
+        let responses = match self.broadcast(cmd).await {
+            Ok(v) => v,
+            Err(res) => return res,
+        };
+        let tables: BTreeSet<String> = responses
+            .into_iter()
+            .flat_map(|res| res.values)
+            .filter_map(|v| v.try_into().ok())
+            .collect();
+        tables
+            .into_iter()
+            .map(Value::from)
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    /// 把所有 backend 返回的数量加起来
+    async fn sum(&mut self, cmd: CommandRequest) -> CommandResponse {
+        let responses = match self.broadcast(cmd).await {
+            Ok(v) => v,
+            Err(res) => return res,
+        };
+        let mut sum = 0;
+        for res in &responses {
+            match i64::try_from(res) {
+                Ok(n) => sum += n,
+                Err(e) => return e.into(),
+            }
+        }
+        Value::integer(sum).into()
+    }
+
+    /// 事务里所有的 key 必须在同一个 backend 上
+    fn transaction_backend(&self, param: &Transaction) -> Result<usize, KvError> {
+        let mut backends = BTreeSet::new();
+        for cmd in &param.commands {
+            let keys = keys_of(cmd);
+            if keys.is_empty() {
+                let msg = format!("{:?} is not supported in transaction by proxy", cmd);
+                return Err(KvError::InvalidCommand(msg));
+            }
+            backends.extend(keys.iter().map(|(t, k)| self.ring.route(t, k)));
+        }
+        backends.extend(
+            param
+                .watches
+                .iter()
+                .map(|w| self.ring.route(&w.table, &w.key)),
+        );
+        match backends.len() {
+            0 | 1 => Ok(backends.into_iter().next().unwrap_or_default()),
+            _ => Err(KvError::InvalidCommand(
+                "Keys in transaction belong to different backends".into(),
+            )),
+        }
+    }
+
+    /// 订阅会一直占用一个连接，所以为它单独建立一个连接，把收到的数据转发给客户端。
+    /// 取消订阅之后这个连接不会再收到数据，客户端断开时一起关闭
+    async fn subscribe(
+        &mut self,
+        cmd: CommandRequest,
+        tx: mpsc::Sender<CommandResponse>,
+    ) -> Vec<CommandResponse> {
+        let topic = match &cmd.request_data {
+            Some(RequestData::Subscribe(param)) => param.topic.clone(),
+            _ => return vec![],
+        };
+        let mut client = match self.connect(self.ring.route("", &topic)).await {
+            Ok(client) => client,
+            Err(e) => return vec![e.into()],
+        };
+        let id = cmd.id;
+        if let Err(e) = client.send(cmd).await {
+            return vec![e.into()];
+        }
+        let handle = tokio::spawn(async move {
+            while let Ok(mut res) = client.recv().await {
+                res.id = id;
+                if tx.send(res).await.is_err() {
+                    break;
+                }
+            }
+        });
+        self.subscriptions.push(handle);
+        vec![]
+    }
+
+    /// 把请求发给一个 backend。连接出错时丢掉这个连接，下次重新连接
+    async fn forward(&mut self, backend: usize, cmd: CommandRequest) -> CommandResponse {
+        match self.conn(backend).await {
+            Ok(conn) => match conn.execute(cmd).await {
+                Ok(res) => res,
+                Err(e) => self.drop_conn(backend, e),
+            },
+            Err(e) => self.drop_conn(backend, e),
+        }
+    }
+
+    /// 在所有的 backend 上认证，都成功之后才替换原来的连接；有 backend 认证失败时返回它的错误，
+    /// 客户端的身份不变
+    async fn auth(&mut self, cmd: CommandRequest) -> CommandResponse {
+        let mut conns = Vec::with_capacity(self.conns.len());
+        let mut res = CommandResponse::default();
+        for backend in 0..self.conns.len() {
+            let mut conn = match self.dial(backend).await {
+                Ok(conn) => conn,
+                Err(e) => return e.into(),
+            };
+            res = match conn.execute(cmd.clone()).await {
+                Ok(res) if res.status == 200 => res,
+                Ok(res) => return res,
+                Err(e) => return e.into(),
+            };
+            conns.push(Some(conn));
+        }
+        self.conns = conns;
+        self.auth = Some(cmd);
+        // 所有 backend 返回的都是用户名，用最后一个
+        res
+    }
+
+    /// 到 backend 的连接，第一次用到时建立
+    async fn conn(&mut self, backend: usize) -> Result<&mut ProstClientStream<TcpStream>, KvError> {
+        let conn = match self.conns[backend].take() {
+            Some(conn) => conn,
+            None => self.connect(backend).await?,
+        };
+        Ok(self.conns[backend].insert(conn))
+    }
+
+    /// 建立到 backend 的连接，客户端认证过时用同样的身份认证
+    async fn connect(&self, backend: usize) -> Result<ProstClientStream<TcpStream>, KvError> {
+        let mut conn = self.dial(backend).await?;
+        if let Some(auth) = &self.auth {
+            let res = conn.execute(auth.clone()).await?;
+            if res.status != 200 {
+                return Err(KvError::Unauthenticated(res.message));
+            }
+        }
+        Ok(conn)
+    }
+
+    async fn dial(&self, backend: usize) -> Result<ProstClientStream<TcpStream>, KvError> {
+        let stream = TcpStream::connect(&self.ring.backends()[backend]).await?;
+        Ok(ProstClientStream::new(stream))
+    }
+
+    /// 连接出错之后上面可能还有没读完的 response，丢掉它，返回错误的 response
+    fn drop_conn(&mut self, backend: usize, e: KvError) -> CommandResponse {
+        warn!(
+            "Failed to forward to {}: {:?}",
+            self.ring.backends()[backend],
+            e
+        );
+        self.conns[backend] = None;
+        e.into()
+    }
+}
+
+fn pair_key(pair: &Kvpair) -> &str {
+    &pair.key
+}
+
+/// 一个命令读写的 (table, key)；涉及整个 table 或者和存储无关的命令返回空
+fn keys_of(cmd: &CommandRequest) -> Vec<(&str, &str)> {
+    match &cmd.request_data {
+        Some(RequestData::Hget(p)) => vec![(&p.table, &p.key)],
+        Some(RequestData::Hset(p)) => {
+            let key = p.pair.as_ref().map_or("", |pair| pair.key.as_str());
+            vec![(&p.table, key)]
+        }
+        Some(RequestData::Hdel(p)) => vec![(&p.table, &p.key)],
+        Some(RequestData::Hexist(p)) => vec![(&p.table, &p.key)],
+        Some(RequestData::Hexpire(p)) => vec![(&p.table, &p.key)],
+        Some(RequestData::Hexpireat(p)) => vec![(&p.table, &p.key)],
+        Some(RequestData::Httl(p)) => vec![(&p.table, &p.key)],
+        Some(RequestData::Hpersist(p)) => vec![(&p.table, &p.key)],
+        Some(RequestData::Hincrby(p)) => vec![(&p.table, &p.key)],
+        Some(RequestData::Hincrbyfloat(p)) => vec![(&p.table, &p.key)],
+        Some(RequestData::Hmget(p)) => p.keys.iter().map(|k| (&*p.table, &**k)).collect(),
+        Some(RequestData::Hmdel(p)) => p.keys.iter().map(|k| (&*p.table, &**k)).collect(),
+        Some(RequestData::Hmexist(p)) => p.keys.iter().map(|k| (&*p.table, &**k)).collect(),
+        Some(RequestData::Watch(p)) => p.keys.iter().map(|k| (&*p.table, &**k)).collect(),
+        Some(RequestData::Hmset(p)) => p.pairs.iter().map(|kv| (&*p.table, &*kv.key)).collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_utils::*;
+    use crate::{
+        assert_res_error, assert_res_ok, Authenticator, Inline, MemTable, Permission, ServiceInner,
+        WatchedKey,
+    };
+    use anyhow::Result;
+    use futures::{StreamExt, TryStreamExt};
+    use std::{net::SocketAddr, ops::Bound};
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn hash_ring_should_move_few_keys_when_backend_added() {
+        let backends: Vec<String> = (0..4).map(|i| format!("10.0.0.{}:9527", i)).collect();
+        let ring = HashRing::new(backends.clone());
+        let mut bigger = backends;
+        bigger.push("10.0.0.4:9527".into());
+        let bigger = HashRing::new(bigger);
+
+        let keys: Vec<String> = (0..10000).map(|i| format!("key{}", i)).collect();
+        let mut counts = [0; 4];
+        let mut moved = 0;
+        for key in &keys {
+            let backend = ring.route("t1", key);
+            counts[backend] += 1;
+            // 要么不动，要么移到新的 backend 上
+            match bigger.route("t1", key) {
+                b if b == backend => {}
+                4 => moved += 1,
+                b => panic!("key {} moved from {} to {}", key, backend, b),
+            }
+        }
+        assert!(counts.iter().all(|&c| c > 1500), "{:?}", counts);
+        assert!(moved > 1000 && moved < 3000, "{}", moved);
+    }
+
+    #[tokio::test]
+    async fn proxy_should_route_and_merge_commands() -> Result<()> {
+        let (proxy, backends) = start_cluster(3).await?;
+        let mut client = connect(proxy).await?;
+
+        let pairs: Vec<_> = (0..30)
+            .map(|i| Kvpair::new(format!("k{:02}", i), Value::integer(i)))
+            .collect();
+        let res = client
+            .execute(CommandRequest::new_hmset("t1", pairs.clone()))
+            .await?;
+        assert_eq!(res.values, vec![Value::default(); 30]);
+
+        // 数据分散在所有的 backend 上
+        let mut total = 0;
+        for addr in backends {
+            let mut backend = connect(addr).await?;
+            let res = backend.execute(CommandRequest::new_hgetall("t1")).await?;
+            assert!(!res.pairs.is_empty());
+            total += res.pairs.len();
+        }
+        assert_eq!(total, 30);
+
+        // 多个 key 的结果按请求的顺序返回
+        let keys = vec!["k05".into(), "nope".into(), "k17".into(), "k02".into()];
+        let res = client
+            .execute(CommandRequest::new_hmget("t1", keys.clone()))
+            .await?;
+        let expected = [
+            Value::integer(5),
+            Value::default(),
+            Value::integer(17),
+            Value::integer(2),
+        ];
+        assert_res_ok(res, &expected, &[]);
+
+        // 范围查询合并所有 backend 的结果，保持顺序
+        let cmd = CommandRequest::new_hrange(
+            "t1",
+            Bound::Included("k05"),
+            Bound::Excluded("k10"),
+            true,
+            3,
+        );
+        let res = client.execute(cmd).await?;
+        let range: Vec<_> = res.pairs.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(range, ["k09", "k08", "k07"]);
+
+        let res = client
+            .execute(CommandRequest::new_hincrby("t1", "k05", 10))
+            .await?;
+        assert_res_ok(res, &[Value::integer(15)], &[]);
+
+        let res = client.execute(CommandRequest::new_hgetall("t1")).await?;
+        assert_eq!(res.pairs.len(), 30);
+        let data: Vec<_> = client.hgetall_chunked("t1", 7).await?.try_collect().await?;
+        assert_eq!(data.len(), 30);
+
+        let res = client
+            .execute(CommandRequest::new_hmdel("t1", keys))
+            .await?;
+        assert_eq!(res.values[0], Value::integer(15));
+        assert_eq!(res.values[1], Value::default());
+        let res = client
+            .execute(CommandRequest::new_hexist("t1", "k17"))
+            .await?;
+        assert_res_ok(res, &[false.into()], &[]);
+
+        // 整个 table 的命令在所有的 backend 上执行，合并结果；改名不支持
+        let res = client.execute(CommandRequest::new_hlen("t1")).await?;
+        assert_res_ok(res, &[Value::integer(27)], &[]);
+        let res = client.execute(CommandRequest::new_list_tables()).await?;
+        assert_res_ok(res, &["t1".into()], &[]);
+        let res = client
+            .execute(CommandRequest::new_rename_table("t1", "t2"))
+            .await?;
+        assert_res_error(res, 400, "not supported by proxy");
+        let res = client.execute(CommandRequest::new_drop_table("t1")).await?;
+        assert_res_ok(res, &[Value::integer(27)], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_should_reply_after_client_half_closes() -> Result<()> {
+        let (proxy, _backends) = start_cluster(3).await?;
+        let mut client = connect(proxy).await?;
+
+        // 发完请求就关闭写的一端，所有的 response 都要收到
+        for i in 1..=50 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), Value::integer(i));
+            client.send(cmd.with_id(i as u64)).await?;
+        }
+        client.inner.shutdown().await?;
+        for i in 1..=50 {
+            assert_eq!(client.recv().await?.id, i);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_hscan_should_visit_all_backends() -> Result<()> {
+        let (proxy, _) = start_cluster(3).await?;
+        let mut client = connect(proxy).await?;
+        let pairs: Vec<_> = (0..20)
+            .map(|i| Kvpair::new(format!("k{}", i), Value::integer(i)))
+            .collect();
+        client
+            .execute(CommandRequest::new_hmset("t1", pairs))
+            .await?;
+
+        let mut keys = vec![];
+        let mut cursor = String::new();
+        loop {
+            let cmd = CommandRequest::new_hscan("t1", cursor, 4, "k1*");
+            let res = client.execute(cmd).await?;
+            keys.extend(res.pairs.iter().map(|p| p.key.clone()));
+            cursor = String::try_from(&res)?;
+            if cursor.is_empty() {
+                break;
+            }
+        }
+        keys.sort();
+        let mut expected: Vec<_> = std::iter::once(1)
+            .chain(10..20)
+            .map(|i| format!("k{}", i))
+            .collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_should_check_transaction_and_forward_topics() -> Result<()> {
+        let (proxy, backends) = start_cluster(3).await?;
+        let ring = HashRing::new(backends.iter().map(|a| a.to_string()).collect());
+        let mut client = connect(proxy).await?;
+
+        // 找两个在同一个 backend 上的 key，和一个在其它 backend 上的 key
+        let keys: Vec<String> = (0..100).map(|i| format!("k{}", i)).collect();
+        let first = ring.route("t1", &keys[0]);
+        let same = keys
+            .iter()
+            .skip(1)
+            .find(|k| ring.route("t1", k) == first)
+            .unwrap();
+        let other = keys.iter().find(|k| ring.route("t1", k) != first).unwrap();
+
+        let cmds = vec![
+            CommandRequest::new_hset("t1", &keys[0], "v1".into()),
+            CommandRequest::new_hincrby("t1", same, 1),
+        ];
+        let watches = vec![WatchedKey::new("t1", same, 0)];
+        let res = client
+            .execute(CommandRequest::new_transaction(cmds, watches))
+            .await?;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.responses.len(), 2);
+
+        let cmds = vec![
+            CommandRequest::new_hset("t1", &keys[0], "v2".into()),
+            CommandRequest::new_hset("t1", other, "v2".into()),
+        ];
+        let res = client
+            .execute(CommandRequest::new_transaction(cmds, vec![]))
+            .await?;
+        assert_res_error(res, 400, "different backends");
+
+        // 订阅和发布通过 topic 路由到同一个 backend
+        let stream = TcpStream::connect(proxy).await?;
+        let mut sub = ProstClientStream::new(stream)
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        assert_res_ok(client.execute(cmd).await?, &[], &[]);
+        let res = sub.next().await.unwrap()?;
+        assert_res_ok(res, &["hello".into()], &[]);
+
+        let res = client.execute(CommandRequest::new_snapshot("x")).await?;
+        assert_res_error(res, 400, "not supported by proxy");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_should_report_failed_keys_of_partial_writes() -> Result<()> {
+        // 第二个 backend 是只读的，发给它的修改都会失败
+        let read_only = ServiceInner::new(Inline::new(MemTable::new()))
+            .read_only()
+            .into();
+        let addrs = vec![start_server().await?, start_server_with(read_only).await?];
+        let ring = HashRing::new(addrs.iter().map(|a| a.to_string()).collect());
+        let proxy = start_proxy(addrs).await?;
+        let mut client = connect(proxy).await?;
+
+        let keys: Vec<String> = (0..10).map(|i| format!("k{}", i)).collect();
+        let pairs = keys.iter().map(|k| Kvpair::new(k, "v".into())).collect();
+        let res = client
+            .execute(CommandRequest::new_hmset("t1", pairs))
+            .await?;
+        assert_eq!(res.status, 403);
+        let (applied, failed): (Vec<_>, Vec<_>) =
+            keys.iter().partition(|k| ring.route("t1", k) == 0);
+        assert!(!applied.is_empty() && !failed.is_empty());
+        let failed: Vec<_> = failed.iter().map(|k| k.as_str()).collect();
+        let msg = format!("failed keys: {}", failed.join(", "));
+        assert!(res.message.contains(&msg), "{}", res.message);
+
+        // 其它 backend 上的修改已经生效了
+        let res = client
+            .execute(CommandRequest::new_hmexist("t1", keys.clone()))
+            .await?;
+        for (key, exist) in keys.iter().zip(res.values) {
+            assert_eq!(exist, applied.contains(&key).into(), "{}", key);
+        }
+
+        // 分块的 HGETALL 按 backend 依次转发分块，最后是一个结束标记
+        client
+            .send(CommandRequest::new_hgetall_chunked("t1", 2))
+            .await?;
+        let mut count = 0;
+        loop {
+            let res = client.recv().await?;
+            assert_eq!(res.status, 200);
+            if res.end {
+                break;
+            }
+            assert!(res.pairs.len() <= 2);
+            count += res.pairs.len();
+        }
+        assert_eq!(count, applied.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_should_forward_auth_to_backends() -> Result<()> {
+        let mut addrs = vec![];
+        for _ in 0..2 {
+            let auth =
+                Authenticator::new()
+                    .token("tok", "alice")
+                    .grant("alice", "t1", Permission::Write);
+            let service = ServiceInner::new(Inline::new(MemTable::new())).with_auth(auth);
+            addrs.push(start_server_with(service.into()).await?);
+        }
+        let mut client = connect(start_proxy(addrs).await?).await?;
+        let pairs: Vec<_> = (0..10)
+            .map(|i| Kvpair::new(format!("k{}", i), Value::integer(i)))
+            .collect();
+        let hmset = CommandRequest::new_hmset("t1", pairs);
+
+        let res = client.execute(hmset.clone()).await?;
+        assert_eq!(res.status, 401);
+        let res = client
+            .execute(CommandRequest::new_auth_token("wrong"))
+            .await?;
+        assert_res_error(res, 401, "Invalid credentials");
+
+        let res = client
+            .execute(CommandRequest::new_auth_token("tok"))
+            .await?;
+        assert_res_ok(res, &["alice".into()], &[]);
+        let res = client.execute(hmset).await?;
+        assert_eq!(res.status, 200);
+        let res = client.execute(CommandRequest::new_hlen("t1")).await?;
+        assert_res_ok(res, &[Value::integer(10)], &[]);
+        let res = client.execute(CommandRequest::new_hget("t2", "k1")).await?;
+        assert_res_error(res, 403, "Permission denied");
+
+        // 认证失败时身份不变
+        client
+            .execute(CommandRequest::new_auth_token("wrong"))
+            .await?;
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &[Value::integer(1)], &[]);
+        Ok(())
+    }
+
+    /// 启动 n 个 backend 和一个代理，返回代理和 backend 的地址
+    async fn start_cluster(n: usize) -> Result<(SocketAddr, Vec<SocketAddr>)> {
+        let mut addrs = vec![];
+        for _ in 0..n {
+            addrs.push(start_server().await?);
+        }
+        Ok((start_proxy(addrs.clone()).await?, addrs))
+    }
+
+    async fn start_proxy(addrs: Vec<SocketAddr>) -> Result<SocketAddr> {
+        let ring = Arc::new(HashRing::new(addrs.iter().map(|a| a.to_string()).collect()));
+        spawn_listener(move |stream, _| ProxyServerStream::new(stream, ring.clone()).process())
+            .await
+    }
+}
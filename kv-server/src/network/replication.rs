@@ -0,0 +1,238 @@
+use crate::network::{read_message, write_message};
+use crate::{
+    value, AsyncStorage, CommandRequest, CommandResponse, Inline, KvError, MemTable, Service, Value,
+};
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::info;
+
+/// 发送快照时，每个 response 最多带的字节数
+const SNAPSHOT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// replica 端：连接到 primary，加载快照后持续执行 primary 发来的修改
+pub struct ProstReplicaStream<S, Store = Inline<Arc<MemTable>>> {
+    inner: S,
+    service: Service<Store>,
+    /// primary 启用了认证时，同步之前先认证
+    auth: Option<CommandRequest>,
+}
+
+impl<S, Store> ProstReplicaStream<S, Store>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: AsyncStorage,
+{
+    pub fn new(stream: S, service: Service<Store>) -> Self {
+        Self {
+            inner: stream,
+            service,
+            auth: None,
+        }
+    }
+
+    /// 同步之前用这个 AUTH 请求认证，用户需要所有 table 的写权限
+    pub fn with_auth(mut self, auth: CommandRequest) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// 和 primary 同步，直到连接断开或者执行 primary 发来的修改失败。返回时 replica 上的数据
+    /// 可能已经落后或者不一致，调用者应该重新连接，重新连接时会再做一次全量同步
+    pub async fn sync(mut self) -> Result<(), KvError> {
+        if let Some(auth) = self.auth.take() {
+            write_message(&mut self.inner, &auth).await?;
+            let res: CommandResponse = read_message(&mut self.inner).await?;
+            if res.status != 200 {
+                return Err(KvError::Unauthenticated(res.message));
+            }
+        }
+        write_message(&mut self.inner, &CommandRequest::new_replicate()).await?;
+
+        let mut data = vec![];
+        loop {
+            let res: CommandResponse = read_message(&mut self.inner).await?;
+            if res.status != 200 {
+                return Err(KvError::Internal(res.message));
+            }
+            if res.end {
+                break;
+            }
+            for v in res.values {
+                if let Some(value::Value::Binary(chunk)) = v.value {
+                    data.extend_from_slice(&chunk);
+                }
+            }
+        }
+        let count = self.service.load_snapshot(data).await?;
+        info!("Loaded {} pairs from primary", count);
+
+        loop {
+            let cmd: CommandRequest = read_message(&mut self.inner).await?;
+            let res = self.service.apply_replicated(cmd).await;
+            if res.status != 200 {
+                let msg = format!("Failed to apply replicated command: {}", res.message);
+                return Err(KvError::Internal(msg));
+            }
+        }
+    }
+}
+
+/// primary 端：先发送快照，再依次发送之后所有的修改，直到 replica 断开
+pub(crate) async fn serve_replica<S, Store>(
+    mut stream: S,
+    service: Service<Store>,
+) -> Result<(), KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: AsyncStorage + Send + Sync + 'static,
+{
+    // 生成快照时会暂停所有的修改
+    let (data, mut rx) = match service.register_replica().await {
+        Ok(v) => v,
+        Err(e) => return write_message(&mut stream, &CommandResponse::from(e)).await,
+    };
+    info!(
+        "Replica registered, sending {} bytes of snapshot",
+        data.len()
+    );
+
+    for chunk in data.chunks(SNAPSHOT_CHUNK_SIZE) {
+        let value: Value = Bytes::copy_from_slice(chunk).into();
+        write_message(&mut stream, &CommandResponse::from(value)).await?;
+    }
+    write_message(&mut stream, &CommandResponse::end()).await?;
+
+    // primary 断开 replica 时（比如 RESTORE 之后），channel 会被关闭
+    while let Some(cmd) = rx.recv().await {
+        write_message(&mut stream, &cmd).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_utils::*;
+    use crate::ProstClientStream;
+    use crate::{assert_res_error, assert_res_ok, Inline, MemTable, ServiceInner, Storage};
+    use anyhow::Result;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn replica_should_follow_primary() -> Result<()> {
+        let primary: Service = ServiceInner::new(Inline::new(MemTable::new()))
+            .enable_replication()
+            .into();
+        let primary_addr = start_server_with(primary).await?;
+        let mut client = connect(primary_addr).await?;
+
+        // replica 连接之前的数据通过快照同步，快照会被分成多块发送
+        let big: Value = Bytes::from(vec![7u8; SNAPSHOT_CHUNK_SIZE * 2]).into();
+        client
+            .execute(CommandRequest::new_hset("t1", "big", big.clone()))
+            .await?;
+        client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+
+        let replica: Service = ServiceInner::new(Inline::new(MemTable::new()))
+            .read_only()
+            .into();
+        let stream = TcpStream::connect(primary_addr).await?;
+        tokio::spawn(ProstReplicaStream::new(stream, replica.clone()).sync());
+        let mut replica_client = connect(start_server_with(replica).await?).await?;
+        let res = wait_for(&mut replica_client, CommandRequest::new_hget("t1", "big")).await?;
+        assert_res_ok(res, &[big], &[]);
+
+        // 之后的修改通过命令同步
+        for cmd in [
+            CommandRequest::new_hset("t1", "k2", "v2".into()),
+            CommandRequest::new_hdel("t1", "k1"),
+            CommandRequest::new_hincrby("t1", "counter", 5),
+            CommandRequest::new_hset_with_ttl("t2", "k1", "v1".into(), 100),
+        ] {
+            assert_eq!(client.execute(cmd).await?.status, 200);
+        }
+        let res = wait_for(&mut replica_client, CommandRequest::new_hget("t2", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+        let keys = vec!["k1".into(), "k2".into(), "counter".into()];
+        let res = replica_client
+            .execute(CommandRequest::new_hmget("t1", keys))
+            .await?;
+        assert_res_ok(
+            res,
+            &[Value::default(), "v2".into(), Value::integer(5)],
+            &[],
+        );
+        let res = replica_client
+            .execute(CommandRequest::new_httl("t2", "k1"))
+            .await?;
+        assert!(matches!(res.values[0].value, Some(value::Value::Integer(ttl)) if ttl > 90));
+
+        // 普通客户端不能修改 replica 上的数据
+        let res = replica_client
+            .execute(CommandRequest::new_hset("t1", "k3", "v3".into()))
+            .await?;
+        assert_res_error(res, 403, "read-only");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replicate_without_replication_enabled_should_fail() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let replica: Service = ServiceInner::new(Inline::new(MemTable::new()))
+            .read_only()
+            .into();
+        let result = ProstReplicaStream::new(stream, replica).sync().await;
+        assert!(matches!(result, Err(KvError::Internal(msg)) if msg.contains("not enabled")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replica_should_disconnect_when_apply_fails() -> Result<()> {
+        // primary 发来的第二个修改在 replica 上执行会失败
+        let primary_addr = spawn_listener(|mut stream, _| async move {
+            let _: CommandRequest = read_message(&mut stream).await?;
+            let mut data = vec![];
+            MemTable::new().snapshot_to(&mut data)?;
+            let value: Value = Bytes::from(data).into();
+            write_message(&mut stream, &CommandResponse::from(value)).await?;
+            write_message(&mut stream, &CommandResponse::end()).await?;
+            for cmd in [
+                CommandRequest::new_hset("t1", "k1", "v1".into()),
+                CommandRequest::new_hincrby("t1", "k1", 1),
+            ] {
+                write_message(&mut stream, &cmd).await?;
+            }
+            read_message::<_, CommandRequest>(&mut stream).await
+        })
+        .await?;
+
+        let stream = TcpStream::connect(primary_addr).await?;
+        let replica: Service = ServiceInner::new(Inline::new(MemTable::new()))
+            .read_only()
+            .into();
+        let sync = ProstReplicaStream::new(stream, replica).sync();
+        let result = tokio::time::timeout(Duration::from_secs(1), sync).await?;
+        assert!(matches!(result, Err(KvError::Internal(msg)) if msg.contains("replicated")));
+        Ok(())
+    }
+
+    /// replica 是异步同步的，反复执行直到拿到数据
+    async fn wait_for(
+        client: &mut ProstClientStream<TcpStream>,
+        cmd: CommandRequest,
+    ) -> Result<CommandResponse> {
+        for _ in 0..100 {
+            let res = client.execute(cmd.clone()).await?;
+            if res.values.first().and_then(|v| v.value.as_ref()).is_some() {
+                return Ok(res);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        anyhow::bail!("Timeout waiting for replica")
+    }
+}
@@ -0,0 +1,714 @@
+use crate::command_request::RequestData;
+use crate::{
+    value, AsyncStorage, Auth, CommandRequest, CommandResponse, ConnectionContext, Inline, KvError,
+    Kvpair, MemTable, Service, Value,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::StreamExt;
+use std::{fmt::Write, net::SocketAddr, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, warn};
+
+/// 一个 bulk string 的最大长度，和 redis 一样是 512MB
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+/// inline 命令和长度行的最大长度，和 redis 一样是 64KB
+const MAX_INLINE_LEN: usize = 64 * 1024;
+/// 一个命令的最大长度，和 redis 的 client-query-buffer-limit 一样是 1GB
+const MAX_REQUEST_LEN: usize = 1024 * 1024 * 1024;
+/// 一个参数至少占 "$0\r\n\r\n" 6 个字节
+const MIN_ARG_LEN: usize = 6;
+
+/// 处理一个 RESP（redis 协议）连接：把 HGET/HSET 等 hash 命令转换成 CommandRequest，
+/// 通过 Service 执行，再把 CommandResponse 转换成 RESP 的回复。redis 的 key 对应 table，field 对应 key
+pub struct RespServerStream<S, Store = Inline<Arc<MemTable>>> {
+    inner: S,
+    service: Service<Store>,
+    /// 客户端通过 HELLO 3 切换到 RESP3
+    resp3: bool,
+    /// 对端地址，以及这个连接通过 AUTH 认证得到的用户名
+    ctx: ConnectionContext,
+    /// inline 命令已经查找过 CRLF 的长度，收到更多数据后从这里继续找
+    scanned: usize,
+}
+
+/// RESP 的回复
+#[derive(Debug, Clone, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// None 是 nil
+    Bulk(Option<Bytes>),
+    Array(Vec<Reply>),
+    /// RESP3 的 map，RESP2 中是 key 和 value 交替的数组
+    Map(Vec<(Reply, Reply)>),
+}
+
+/// 怎么把 CommandResponse 转换成 RESP 的回复
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReplyKind {
+    /// 第一个 value
+    Bulk,
+    /// 所有的 value
+    Array,
+    /// HSET：之前不存在的 field 的数量
+    Added,
+    /// HDEL：之前存在的 field 的数量
+    Removed,
+    /// HEXISTS：1 或者 0
+    Bool,
+    /// HMSET：+OK
+    Ok,
+    /// HGETALL：所有的 kv pair
+    Map,
+}
+
+impl<S, Store> RespServerStream<S, Store>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: AsyncStorage + Send + Sync + 'static,
+{
+    pub fn new(stream: S, service: Service<Store>) -> Self {
+        Self {
+            inner: stream,
+            service,
+            resp3: false,
+            ctx: ConnectionContext::default(),
+            scanned: 0,
+        }
+    }
+
+    /// 设置对端的地址，中间件可以从 ConnectionContext 里拿到
+    pub fn with_peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.ctx.peer_addr = Some(addr);
+        self
+    }
+
+    /// 按顺序处理这个连接上的命令，支持 pipeline。协议出错时回复错误并关闭连接
+    pub async fn process(mut self) -> Result<(), KvError> {
+        let mut buf = BytesMut::with_capacity(4096);
+        loop {
+            let args = match parse_command(&mut buf, &mut self.scanned) {
+                Ok(Some(args)) => args,
+                Ok(None) => {
+                    if self.inner.read_buf(&mut buf).await? == 0 {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Invalid RESP request: {:?}", e);
+                    self.reply(Reply::Error(format!("ERR Protocol error: {}", e)))
+                        .await?;
+                    return Err(e);
+                }
+            };
+            let name = match args.first() {
+                Some(name) => String::from_utf8_lossy(name).to_ascii_uppercase(),
+                None => continue,
+            };
+            debug!("Got RESP command: {} {:?}", name, &args[1..]);
+            let (reply, res) = self.handle(&name, &args[1..]).await;
+            self.reply(reply).await?;
+            if let Some(res) = res {
+                self.service.after_send(&self.ctx, &res).await;
+            }
+            if name == "QUIT" {
+                return Ok(());
+            }
+        }
+    }
+
+    /// 由 Service 执行的命令，同时返回它的 CommandResponse
+    async fn handle(
+        &mut self,
+        name: &str,
+        args: &[Bytes],
+    ) -> (Reply, Option<Arc<CommandResponse>>) {
+        match name {
+            "PING" | "ECHO" | "QUIT" | "CLIENT" | "COMMAND" | "HELLO" | "AUTH" => {
+                self.handle_local(name, args).await
+            }
+            _ => match translate(name, args) {
+                Ok((cmd, kind)) => self.execute(cmd, kind).await,
+                Err(reply) => (reply, None),
+            },
+        }
+    }
+
+    /// RESP 自己处理的命令也要经过中间件的每个阶段。AUTH 交给中间件的是 Auth 请求，
+    /// 其它命令没有对应的 CommandRequest，交给中间件的是空的请求
+    async fn handle_local(
+        &mut self,
+        name: &str,
+        args: &[Bytes],
+    ) -> (Reply, Option<Arc<CommandResponse>>) {
+        let cmd = match name {
+            "AUTH" => match auth_request(args) {
+                Ok(auth) => CommandRequest {
+                    request_data: Some(RequestData::Auth(auth)),
+                    ..Default::default()
+                },
+                Err(reply) => return (reply, None),
+            },
+            _ => CommandRequest::default(),
+        };
+        if let Some(res) = self.service.on_received(&self.ctx, &cmd).await {
+            return (to_reply(&res, ReplyKind::Ok), Some(Arc::new(res)));
+        }
+
+        let (reply, res) = match (name, cmd.request_data) {
+            (_, Some(RequestData::Auth(auth))) => self.auth(&auth),
+            ("HELLO", _) => self.hello(args),
+            (name, _) => {
+                let reply = match (name, args) {
+                    ("PING", []) => Reply::Simple("PONG".into()),
+                    ("PING", [arg]) | ("ECHO", [arg]) => Reply::Bulk(Some(arg.clone())),
+                    ("QUIT", _) | ("CLIENT", _) => Reply::Simple("OK".into()),
+                    ("COMMAND", _) => Reply::Array(vec![]),
+                    _ => Reply::Error(format!(
+                        "ERR wrong number of arguments for '{}' command",
+                        name.to_ascii_lowercase()
+                    )),
+                };
+                let res = match &reply {
+                    Reply::Error(msg) => KvError::InvalidCommand(msg.clone()).into(),
+                    _ => CommandResponse::ok(),
+                };
+                (reply, res)
+            }
+        };
+        // 中间件在发送之前把 response 改成了错误时，回复这个错误
+        let status = res.status;
+        let res = self.service.before_send(&self.ctx, res).await;
+        let reply = match res.status != status && res.status >= 400 {
+            true => to_reply(&res, ReplyKind::Ok),
+            false => reply,
+        };
+        (reply, Some(Arc::new(res)))
+    }
+
+    async fn execute(
+        &self,
+        cmd: CommandRequest,
+        kind: ReplyKind,
+    ) -> (Reply, Option<Arc<CommandResponse>>) {
+        match self.service.execute_with(&self.ctx, cmd).await.next().await {
+            Some(res) => (to_reply(&res, kind), Some(res)),
+            None => (Reply::Error("ERR no response".into()), None),
+        }
+    }
+
+    /// 认证成功时更新连接的身份，response 的 value 是用户名
+    fn auth(&mut self, auth: &Auth) -> (Reply, CommandResponse) {
+        match self.service.authenticate(auth) {
+            Ok(name) => {
+                let res = Value::from(name.as_str()).into();
+                self.ctx.principal = Some(name);
+                (Reply::Simple("OK".into()), res)
+            }
+            Err(e @ KvError::Unauthenticated(_)) => {
+                let reply = "WRONGPASS invalid username-password pair or user is disabled.";
+                (Reply::Error(reply.into()), e.into())
+            }
+            Err(e) => (Reply::Error(format!("ERR {}", e)), e.into()),
+        }
+    }
+
+    /// HELLO [protover]：切换协议版本，返回服务器的信息
+    fn hello(&mut self, args: &[Bytes]) -> (Reply, CommandResponse) {
+        if let Some(version) = args.first() {
+            match version.as_ref() {
+                b"2" => self.resp3 = false,
+                b"3" => self.resp3 = true,
+                _ => {
+                    let e = KvError::InvalidCommand("unsupported protocol version".into());
+                    let reply = Reply::Error("NOPROTO unsupported protocol version".into());
+                    return (reply, e.into());
+                }
+            }
+        }
+        let field = |k: &str, v: Reply| (bulk(k), v);
+        let reply = Reply::Map(vec![
+            field("server", bulk("kvs")),
+            field("version", bulk(env!("CARGO_PKG_VERSION"))),
+            field("proto", Reply::Integer(if self.resp3 { 3 } else { 2 })),
+            field("mode", bulk("standalone")),
+            field("role", bulk("master")),
+            field("modules", Reply::Array(vec![])),
+        ]);
+        (reply, CommandResponse::ok())
+    }
+
+    async fn reply(&mut self, reply: Reply) -> Result<(), KvError> {
+        let mut buf = BytesMut::new();
+        reply.encode(&mut buf, self.resp3);
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+}
+
+/// AUTH token 或者 AUTH username password
+fn auth_request(args: &[Bytes]) -> Result<Auth, Reply> {
+    match args {
+        [token] => Ok(Auth {
+            token: text(token)?,
+            ..Default::default()
+        }),
+        [username, password] => Ok(Auth {
+            username: text(username)?,
+            password: text(password)?,
+            ..Default::default()
+        }),
+        _ => Err(Reply::Error(
+            "ERR wrong number of arguments for 'auth' command".into(),
+        )),
+    }
+}
+
+/// 把 redis 的 hash 命令转换成 CommandRequest；不支持的命令或者参数不对时，返回错误的回复
+fn translate(name: &str, args: &[Bytes]) -> Result<(CommandRequest, ReplyKind), Reply> {
+    let table = || text(&args[0]);
+    let keys = || args[1..].iter().map(text).collect::<Result<Vec<_>, _>>();
+    let pairs = || {
+        args[1..]
+            .chunks(2)
+            .map(|kv| Ok(Kvpair::new(text(&kv[0])?, value_of(&kv[1]))))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let n = args.len();
+    match name {
+        "HGET" if n == 2 => Ok((
+            CommandRequest::new_hget(table()?, text(&args[1])?),
+            ReplyKind::Bulk,
+        )),
+        "HSET" if n >= 3 && n % 2 == 1 => Ok((
+            CommandRequest::new_hmset(table()?, pairs()?),
+            ReplyKind::Added,
+        )),
+        "HMSET" if n >= 3 && n % 2 == 1 => {
+            Ok((CommandRequest::new_hmset(table()?, pairs()?), ReplyKind::Ok))
+        }
+        "HMGET" if n >= 2 => Ok((
+            CommandRequest::new_hmget(table()?, keys()?),
+            ReplyKind::Array,
+        )),
+        "HDEL" if n >= 2 => Ok((
+            CommandRequest::new_hmdel(table()?, keys()?),
+            ReplyKind::Removed,
+        )),
+        "HEXISTS" if n == 2 => Ok((
+            CommandRequest::new_hexist(table()?, text(&args[1])?),
+            ReplyKind::Bool,
+        )),
+        "HGETALL" if n == 1 => Ok((CommandRequest::new_hgetall(table()?), ReplyKind::Map)),
+        "HGET" | "HSET" | "HMSET" | "HMGET" | "HDEL" | "HEXISTS" | "HGETALL" => {
+            Err(Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            )))
+        }
+        _ => Err(Reply::Error(format!(
+            "ERR unknown command '{}'",
+            name.to_ascii_lowercase()
+        ))),
+    }
+}
+
+fn to_reply(res: &CommandResponse, kind: ReplyKind) -> Reply {
+    // 找不到 key 在 redis 里是 nil，不是错误
+    if res.status == 404 && kind == ReplyKind::Bulk {
+        return Reply::Bulk(None);
+    }
+    match res.status {
+        200 => {}
+        401 => return Reply::Error(format!("NOAUTH {}", res.message)),
+        403 => return Reply::Error(format!("NOPERM {}", res.message)),
+        _ => return Reply::Error(format!("ERR {}", res.message)),
+    }
+    let exists = |v: &&Value| v.value.is_some();
+    match kind {
+        ReplyKind::Bulk => res.values.first().map_or(Reply::Bulk(None), value_reply),
+        ReplyKind::Array => Reply::Array(res.values.iter().map(value_reply).collect()),
+        ReplyKind::Added => Reply::Integer(res.values.iter().filter(|v| !exists(v)).count() as _),
+        ReplyKind::Removed => Reply::Integer(res.values.iter().filter(exists).count() as _),
+        ReplyKind::Bool => {
+            let exist = matches!(
+                res.values.first().and_then(|v| v.value.as_ref()),
+                Some(value::Value::Bool(true))
+            );
+            Reply::Integer(exist as _)
+        }
+        ReplyKind::Ok => Reply::Simple("OK".into()),
+        ReplyKind::Map => Reply::Map(
+            res.pairs
+                .iter()
+                .map(|pair| {
+                    let value = pair.value.as_ref().map_or(Reply::Bulk(None), value_reply);
+                    (bulk(&pair.key), value)
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// redis 的 hash 里都是字符串，数字按文本返回
+fn value_reply(v: &Value) -> Reply {
+    let data = match &v.value {
+        Some(value::Value::String(s)) => Bytes::from(s.clone()),
+        Some(value::Value::Binary(b)) => b.clone(),
+        Some(value::Value::Integer(i)) => Bytes::from(i.to_string()),
+        Some(value::Value::Float(f)) => Bytes::from(f.to_string()),
+        Some(value::Value::Bool(b)) => Bytes::from(if *b { "1" } else { "0" }),
+        None => return Reply::Bulk(None),
+    };
+    Reply::Bulk(Some(data))
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(Some(Bytes::copy_from_slice(s.as_bytes())))
+}
+
+/// table、field 等名字必须是 UTF-8，不做有损转换，避免不同的名字被转换成同一个
+fn text(arg: &Bytes) -> Result<String, Reply> {
+    std::str::from_utf8(arg)
+        .map(|s| s.to_owned())
+        .map_err(|_| Reply::Error("ERR invalid UTF-8 in table or field name".into()))
+}
+
+/// 是 UTF-8 的参数保存为 String，否则保存为 Binary
+fn value_of(arg: &Bytes) -> Value {
+    match std::str::from_utf8(arg) {
+        Ok(s) => s.into(),
+        Err(_) => arg.clone().into(),
+    }
+}
+
+impl Reply {
+    fn encode(&self, buf: &mut BytesMut, resp3: bool) {
+        // 写入 BytesMut 不会失败
+        match self {
+            Reply::Simple(s) => {
+                let _ = write!(buf, "+{}\r\n", s);
+            }
+            Reply::Error(s) => {
+                let _ = write!(buf, "-{}\r\n", s);
+            }
+            Reply::Integer(i) => {
+                let _ = write!(buf, ":{}\r\n", i);
+            }
+            Reply::Bulk(Some(data)) => {
+                let _ = write!(buf, "${}\r\n", data.len());
+                buf.extend_from_slice(data);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Reply::Bulk(None) if resp3 => buf.extend_from_slice(b"_\r\n"),
+            Reply::Bulk(None) => buf.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                let _ = write!(buf, "*{}\r\n", items.len());
+                for item in items {
+                    item.encode(buf, resp3);
+                }
+            }
+            Reply::Map(pairs) => {
+                let _ = match resp3 {
+                    true => write!(buf, "%{}\r\n", pairs.len()),
+                    false => write!(buf, "*{}\r\n", pairs.len() * 2),
+                };
+                for (k, v) in pairs {
+                    k.encode(buf, resp3);
+                    v.encode(buf, resp3);
+                }
+            }
+        }
+    }
+}
+
+/// 从 buf 中解析一个完整的命令（bulk string 的数组，或者一行 inline 命令），
+/// 数据还不完整时返回 None，buf 保持不变。scanned 记录 inline 命令已经查找过的长度，
+/// 避免每次收到数据都从头查找 CRLF
+fn parse_command(buf: &mut BytesMut, scanned: &mut usize) -> Result<Option<Vec<Bytes>>, KvError> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != b'*' {
+        // inline 命令：一行用空白分隔的参数，比如 telnet 里输入的命令。
+        // 上次的最后一个字节可能是 \r，从它开始找
+        let end = match find_crlf(buf, 0, scanned.saturating_sub(1))? {
+            Some(end) => end,
+            None => {
+                *scanned = buf.len();
+                return Ok(None);
+            }
+        };
+        *scanned = 0;
+        let line = buf.split_to(end + 2);
+        let args = line[..end]
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(Bytes::copy_from_slice)
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let mut pos = 0;
+    let count = match read_number(buf, &mut pos, b'*')? {
+        Some(count) => count,
+        None => return Ok(None),
+    };
+    // 按已经声明的长度计算命令至少有多大，超过上限时马上返回错误，不等数据收完
+    let count = count.max(0) as usize;
+    check_request_len(pos, count)?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for i in 0..count {
+        let len = match read_number(buf, &mut pos, b'$')? {
+            Some(len) if (0..=MAX_BULK_LEN).contains(&len) => len as usize,
+            Some(_) => return Err(KvError::InvalidCommand("invalid bulk length".into())),
+            None => return Ok(None),
+        };
+        check_request_len(pos + len + 2, count - i - 1)?;
+        if buf.len() < pos + len + 2 {
+            return Ok(None);
+        }
+        if &buf[pos + len..pos + len + 2] != b"\r\n" {
+            return Err(KvError::InvalidCommand("expected CRLF".into()));
+        }
+        args.push(Bytes::copy_from_slice(&buf[pos..pos + len]));
+        pos += len + 2;
+    }
+    buf.advance(pos);
+    Ok(Some(args))
+}
+
+/// 已经确定的 len 字节，加上剩下 args 个参数的最小长度，不能超过 MAX_REQUEST_LEN
+fn check_request_len(len: usize, args: usize) -> Result<(), KvError> {
+    match args
+        .checked_mul(MIN_ARG_LEN)
+        .and_then(|n| n.checked_add(len))
+    {
+        Some(n) if n <= MAX_REQUEST_LEN => Ok(()),
+        _ => Err(KvError::InvalidCommand("too big request".into())),
+    }
+}
+
+/// 读取 pos 处以 prefix 开头、CRLF 结尾的一行数字
+fn read_number(buf: &[u8], pos: &mut usize, prefix: u8) -> Result<Option<i64>, KvError> {
+    let end = match find_crlf(buf, *pos, *pos)? {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    if buf[*pos] != prefix {
+        let msg = format!("expected '{}', got '{}'", prefix as char, buf[*pos] as char);
+        return Err(KvError::InvalidCommand(msg));
+    }
+    let n = std::str::from_utf8(&buf[*pos + 1..end])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| KvError::InvalidCommand("invalid length".into()))?;
+    *pos = end + 2;
+    Ok(Some(n))
+}
+
+/// 查找 start 开始的一行的 CRLF，从 from 开始查找。一行超过 MAX_INLINE_LEN 时返回错误
+fn find_crlf(buf: &[u8], start: usize, from: usize) -> Result<Option<usize>, KvError> {
+    let pos = buf
+        .get(from..)
+        .and_then(|data| data.windows(2).position(|w| w == b"\r\n"));
+    match pos {
+        Some(i) if from + i - start <= MAX_INLINE_LEN => Ok(Some(from + i)),
+        None if buf.len() - start <= MAX_INLINE_LEN => Ok(None),
+        _ => Err(KvError::InvalidCommand("too big inline request".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_utils::*;
+    use crate::{Authenticator, ServiceInner};
+    use anyhow::Result;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn parse_command_should_handle_pipeline_and_partial_data() {
+        let mut buf =
+            BytesMut::from(&b"*2\r\n$4\r\nHGET\r\n$2\r\nt1\r\nPING  hi\r\n*1\r\n$4\r\nPI"[..]);
+        let mut scanned = 0;
+        let args = parse_command(&mut buf, &mut scanned).unwrap().unwrap();
+        assert_eq!(args, vec![Bytes::from("HGET"), Bytes::from("t1")]);
+        let args = parse_command(&mut buf, &mut scanned).unwrap().unwrap();
+        assert_eq!(args, vec![Bytes::from("PING"), Bytes::from("hi")]);
+
+        // 不完整的命令等待更多数据
+        assert_eq!(parse_command(&mut buf, &mut scanned).unwrap(), None);
+        buf.extend_from_slice(b"NG\r\n");
+        let args = parse_command(&mut buf, &mut scanned).unwrap().unwrap();
+        assert_eq!(args, vec![Bytes::from("PING")]);
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"*1\r\n+OK\r\n"[..]);
+        assert!(parse_command(&mut buf, &mut scanned).is_err());
+    }
+
+    #[test]
+    fn parse_command_should_limit_inline_length() {
+        // CRLF 被拆到两次读取里也能找到
+        let mut buf = BytesMut::from(&b"PING\r"[..]);
+        let mut scanned = 0;
+        assert_eq!(parse_command(&mut buf, &mut scanned).unwrap(), None);
+        assert_eq!(scanned, 5);
+        buf.extend_from_slice(b"\n");
+        let args = parse_command(&mut buf, &mut scanned).unwrap().unwrap();
+        assert_eq!(args, vec![Bytes::from("PING")]);
+        assert_eq!(scanned, 0);
+
+        // 没有 CRLF 的 inline 命令和长度行超过 64KB 时报错
+        let mut buf = BytesMut::from(&vec![b'a'; MAX_INLINE_LEN][..]);
+        assert_eq!(parse_command(&mut buf, &mut scanned).unwrap(), None);
+        buf.extend_from_slice(b"aa");
+        assert!(parse_command(&mut buf, &mut scanned).is_err());
+
+        let mut buf = BytesMut::from(&b"*1\r\n$"[..]);
+        buf.extend_from_slice(&vec![b'1'; MAX_INLINE_LEN + 1]);
+        assert!(parse_command(&mut buf, &mut 0).is_err());
+    }
+
+    #[test]
+    fn parse_command_should_limit_request_len() {
+        // 参数太多，还没收到数据就能确定超过了上限
+        let mut buf = BytesMut::from(&b"*200000000\r\n"[..]);
+        assert!(parse_command(&mut buf, &mut 0).is_err());
+
+        // 每个参数都不超过 512MB，但加起来超过了上限
+        let mut buf = BytesMut::from(&b"*100000000\r\n$536870912\r\n"[..]);
+        assert!(parse_command(&mut buf, &mut 0).is_err());
+
+        // 没超过上限的命令等待更多数据
+        let mut buf = BytesMut::from(&b"*3\r\n$536870912\r\n"[..]);
+        assert_eq!(parse_command(&mut buf, &mut 0).unwrap(), None);
+    }
+
+    #[test]
+    fn reply_should_encode_for_resp2_and_resp3() {
+        let reply = Reply::Map(vec![(bulk("k1"), Reply::Bulk(None))]);
+        let mut buf = BytesMut::new();
+        reply.encode(&mut buf, false);
+        assert_eq!(&buf[..], b"*2\r\n$2\r\nk1\r\n$-1\r\n");
+
+        let mut buf = BytesMut::new();
+        reply.encode(&mut buf, true);
+        assert_eq!(&buf[..], b"%1\r\n$2\r\nk1\r\n_\r\n");
+    }
+
+    #[tokio::test]
+    async fn resp_server_should_handle_hash_commands() -> Result<()> {
+        let addr = start_resp_server(memtable_service()).await?;
+        let mut client = TcpStream::connect(addr).await?;
+
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"PING\r\n", b"+PONG\r\n"),
+            (
+                b"*6\r\n$4\r\nHSET\r\n$1\r\nh\r\n$2\r\nf1\r\n$2\r\nv1\r\n$2\r\nf2\r\n$2\r\nv2\r\n",
+                b":2\r\n",
+            ),
+            (b"HSET h f1 v3\r\n", b":0\r\n"),
+            (b"HGET h f1\r\n", b"$2\r\nv3\r\n"),
+            (b"HGET h nope\r\n", b"$-1\r\n"),
+            (b"HMGET h f2 nope\r\n", b"*2\r\n$2\r\nv2\r\n$-1\r\n"),
+            (b"HEXISTS h f2\r\n", b":1\r\n"),
+            (b"HDEL h f2 nope\r\n", b":1\r\n"),
+            (b"HMSET h f3 v3\r\n", b"+OK\r\n"),
+            (
+                b"HGET h\r\n",
+                b"-ERR wrong number of arguments for 'hget' command\r\n",
+            ),
+            (b"LPUSH l a\r\n", b"-ERR unknown command 'lpush'\r\n"),
+            (
+                b"HGET h\xff f1\r\n",
+                b"-ERR invalid UTF-8 in table or field name\r\n",
+            ),
+        ];
+        for (request, expected) in cases {
+            client.write_all(request).await?;
+            assert_eq!(read_reply(&mut client).await?, *expected);
+        }
+
+        // 切换到 RESP3 后，HGETALL 返回 map
+        client.write_all(b"HELLO 3\r\n").await?;
+        assert!(read_reply(&mut client).await?.starts_with(b"%6\r\n"));
+        client.write_all(b"HDEL h f3\r\nHGETALL h\r\n").await?;
+        let data = read_reply(&mut client).await?;
+        assert_eq!(data, b":1\r\n%1\r\n$2\r\nf1\r\n$2\r\nv3\r\n");
+
+        client.write_all(b"QUIT\r\n").await?;
+        assert_eq!(read_reply(&mut client).await?, b"+OK\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resp_server_should_pass_local_commands_through_middleware() -> Result<()> {
+        // 拒绝 alice 登录，统计 RESP 自己处理的命令和发送出去的 response
+        let (received, sent) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (r, s) = (Arc::clone(&received), Arc::clone(&sent));
+        let auth = Authenticator::new()
+            .user("alice", "secret")?
+            .user("bob", "secret")?;
+        let service = ServiceInner::new(Inline::new(MemTable::new()))
+            .with_auth(auth)
+            .fn_received(move |cmd| match &cmd.request_data {
+                Some(RequestData::Auth(auth)) if auth.username == "alice" => {
+                    Err(KvError::Unauthenticated("blocked".into()))
+                }
+                None => {
+                    r.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+                _ => Ok(()),
+            })
+            .fn_after_send(move || {
+                s.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+        let addr = start_resp_server(service.into()).await?;
+        let mut client = TcpStream::connect(addr).await?;
+
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"PING\r\n", b"+PONG\r\n"),
+            (
+                b"AUTH alice secret\r\n",
+                b"-NOAUTH Unauthenticated: blocked\r\n",
+            ),
+            (b"HGET h f1\r\n", b"-NOAUTH "),
+            (b"AUTH bob secret\r\n", b"+OK\r\n"),
+            (b"HELLO 2\r\n", b"*12\r\n"),
+        ];
+        for (request, expected) in cases {
+            client.write_all(request).await?;
+            let reply = read_reply(&mut client).await?;
+            assert!(
+                reply.starts_with(expected),
+                "{:?}",
+                String::from_utf8_lossy(&reply)
+            );
+        }
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+        assert_eq!(sent.load(Ordering::SeqCst), 5);
+        Ok(())
+    }
+
+    /// 读取服务器的回复，回复都很短，等一下就能收完
+    async fn read_reply(client: &mut TcpStream) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let read =
+                tokio::time::timeout(std::time::Duration::from_millis(50), client.read(&mut buf));
+            match read.await {
+                Ok(Ok(0)) | Err(_) => return Ok(data),
+                Ok(Ok(n)) => data.extend_from_slice(&buf[..n]),
+                Ok(Err(e)) => return Err(e.into()),
+            }
+        }
+    }
+}
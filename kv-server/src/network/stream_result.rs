@@ -0,0 +1,48 @@
+use crate::{CommandResponse, KvError};
+use futures::{Stream, StreamExt};
+use std::{
+    convert::TryInto,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+};
+
+/// 客户端收到的 Response 流，第一个 Response 里的 id 会被取出来
+pub struct StreamResult {
+    pub id: u32,
+    inner: Pin<Box<dyn Stream<Item = Result<CommandResponse, KvError>> + Send>>,
+}
+
+impl StreamResult {
+    pub async fn new<T>(mut stream: T) -> Result<Self, KvError>
+    where
+        T: Stream<Item = Result<CommandResponse, KvError>> + Send + Unpin + 'static,
+    {
+        let id = match stream.next().await {
+            Some(Ok(res)) => {
+                let id: i64 = (&res).try_into()?;
+                id as u32
+            }
+            Some(Err(e)) => return Err(e),
+            None => return Err(KvError::Internal("Invalid stream".into())),
+        };
+
+        Ok(StreamResult {
+            id,
+            inner: Box::pin(stream),
+        })
+    }
+}
+
+impl Deref for StreamResult {
+    type Target = Pin<Box<dyn Stream<Item = Result<CommandResponse, KvError>> + Send>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for StreamResult {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
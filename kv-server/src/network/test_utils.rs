@@ -0,0 +1,70 @@
+use crate::kv_service_server::KvServiceServer;
+use crate::{
+    GrpcService, Inline, MemTable, ProstClientStream, ProstServerStream, RespServerStream, Service,
+    ServiceInner,
+};
+use anyhow::Result;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+
+/// 使用 MemTable 的 Service
+pub fn memtable_service() -> Service {
+    ServiceInner::new(Inline::new(MemTable::new())).into()
+}
+
+/// 在随机端口上监听，每个连接交给 handle 在单独的 task 里处理
+pub async fn spawn_listener<F, Fut>(handle: F) -> Result<SocketAddr>
+where
+    F: Fn(TcpStream, SocketAddr) -> Fut + Send + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = listener.accept().await.unwrap();
+            tokio::spawn(handle(stream, peer));
+        }
+    });
+    Ok(addr)
+}
+
+/// 启动使用帧协议的服务器，所有连接共享同一个 service
+pub async fn start_server_with(service: Service) -> Result<SocketAddr> {
+    spawn_listener(move |stream, peer| {
+        ProstServerStream::new(stream, service.clone())
+            .with_peer_addr(peer)
+            .process()
+    })
+    .await
+}
+
+/// 启动使用帧协议和 MemTable 的服务器
+pub async fn start_server() -> Result<SocketAddr> {
+    start_server_with(memtable_service()).await
+}
+
+/// 启动使用 RESP 协议的服务器
+pub async fn start_resp_server(service: Service) -> Result<SocketAddr> {
+    spawn_listener(move |stream, _| RespServerStream::new(stream, service.clone()).process()).await
+}
+
+/// 启动 gRPC 服务器
+pub async fn start_grpc_server(service: Service) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = Server::builder()
+        .add_service(KvServiceServer::new(GrpcService::new(service)))
+        .serve_with_incoming(TcpListenerStream::new(listener));
+    tokio::spawn(server);
+    Ok(addr)
+}
+
+/// 用帧协议连接到服务器
+pub async fn connect(addr: SocketAddr) -> Result<ProstClientStream<TcpStream>> {
+    Ok(ProstClientStream::new(TcpStream::connect(addr).await?))
+}
@@ -0,0 +1,382 @@
+use crate::KvError;
+use std::{fs, io::Cursor, path::Path, sync::Arc};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+/// KV Server 自己的 ALPN (Application-Layer Protocol Negotiation)
+const ALPN_KV: &str = "kv";
+
+/// 存放 TLS ServerConfig 并提供方法 accept 把底层的协议转换成 TLS
+#[derive(Clone)]
+pub struct TlsServerAcceptor {
+    inner: Arc<ServerConfig>,
+}
+
+/// 存放 TLS Client 并提供方法 connect 把底层的协议转换成 TLS
+#[derive(Clone)]
+pub struct TlsClientConnector {
+    config: Arc<ClientConfig>,
+    domain: ServerName<'static>,
+}
+
+impl TlsClientConnector {
+    /// 加载 client cert / key / CA cert，生成 ClientConfig
+    /// identity 是 (cert, key)，只有服务器要求客户端证书时才需要
+    pub fn new(
+        domain: impl Into<String>,
+        identity: Option<(&str, &str)>,
+        server_ca: &str,
+    ) -> Result<Self, KvError> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(server_ca, "CA")? {
+            roots
+                .add(cert)
+                .map_err(|_| KvError::CertificateParseError("CA", "cert"))?;
+        }
+
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        let mut config = match identity {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(load_certs(cert, "client")?, load_key(key, "client")?)?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![ALPN_KV.into()];
+
+        let domain = ServerName::try_from(domain.into())
+            .map_err(|_| KvError::CertificateParseError("server", "domain"))?;
+
+        Ok(Self {
+            config: Arc::new(config),
+            domain,
+        })
+    }
+
+    /// 从磁盘上的 PEM 文件加载证书，生成 TlsClientConnector
+    pub fn from_files(
+        domain: impl Into<String>,
+        identity: Option<(impl AsRef<Path>, impl AsRef<Path>)>,
+        server_ca: impl AsRef<Path>,
+    ) -> Result<Self, KvError> {
+        let identity = match identity {
+            Some((cert, key)) => Some((fs::read_to_string(cert)?, fs::read_to_string(key)?)),
+            None => None,
+        };
+        let server_ca = fs::read_to_string(server_ca)?;
+        let identity = identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
+        Self::new(domain, identity, &server_ca)
+    }
+
+    /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
+    pub async fn connect<S>(&self, stream: S) -> Result<client::TlsStream<S>, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let stream = TlsConnector::from(self.config.clone())
+            .connect(self.domain.clone(), stream)
+            .await?;
+
+        Ok(stream)
+    }
+}
+
+impl TlsServerAcceptor {
+    /// 加载 server cert / key，如果提供了 client_ca，就要求客户端提供由它签发的证书
+    pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self, KvError> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = match client_ca {
+            None => builder.with_no_client_auth(),
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca, "CA")? {
+                    roots
+                        .add(cert)
+                        .map_err(|_| KvError::CertificateParseError("CA", "cert"))?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+                        .build()
+                        .map_err(|_| KvError::CertificateParseError("client", "verifier"))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+        };
+
+        let mut config =
+            builder.with_single_cert(load_certs(cert, "server")?, load_key(key, "server")?)?;
+        config.alpn_protocols = vec![ALPN_KV.into()];
+
+        Ok(Self {
+            inner: Arc::new(config),
+        })
+    }
+
+    /// 使用其它的 ALPN，比如 HTTP 用 http/1.1，gRPC 用 h2
+    pub fn with_alpn(&self, protocols: &[&str]) -> Self {
+        let mut config = (*self.inner).clone();
+        config.alpn_protocols = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Self {
+            inner: Arc::new(config),
+        }
+    }
+
+    /// 从磁盘上的 PEM 文件加载证书，生成 TlsServerAcceptor
+    pub fn from_files(
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+        client_ca: Option<impl AsRef<Path>>,
+    ) -> Result<Self, KvError> {
+        let cert = fs::read_to_string(cert)?;
+        let key = fs::read_to_string(key)?;
+        let client_ca = match client_ca {
+            Some(ca) => Some(fs::read_to_string(ca)?),
+            None => None,
+        };
+        Self::new(&cert, &key, client_ca.as_deref())
+    }
+
+    /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
+    pub async fn accept<S>(&self, stream: S) -> Result<server::TlsStream<S>, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let acceptor = TlsAcceptor::from(self.inner.clone());
+        Ok(acceptor.accept(stream).await?)
+    }
+}
+
+/// 统一使用 ring 作为加密库
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// 加载 PEM 格式的证书链，name 用于出错时说明是哪个证书
+fn load_certs(pem: &str, name: &'static str) -> Result<Vec<CertificateDer<'static>>, KvError> {
+    let certs = rustls_pemfile::certs(&mut Cursor::new(pem))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| KvError::CertificateParseError(name, "cert"))?;
+    if certs.is_empty() {
+        return Err(KvError::CertificateParseError(name, "cert"));
+    }
+    Ok(certs)
+}
+
+/// 加载 PEM 格式的私钥，支持 PKCS8、PKCS1 和 SEC1 格式
+fn load_key(pem: &str, name: &'static str) -> Result<PrivateKeyDer<'static>, KvError> {
+    match rustls_pemfile::private_key(&mut Cursor::new(pem)) {
+        Ok(Some(key)) => Ok(key),
+        _ => Err(KvError::CertificateParseError(name, "key")),
+    }
+}
+
+#[cfg(test)]
+pub mod tls_utils {
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+        KeyUsagePurpose,
+    };
+
+    /// 测试用的证书，都是 PEM 格式
+    pub struct TestCerts {
+        pub ca_cert: String,
+        pub server_cert: String,
+        pub server_key: String,
+        pub client_cert: String,
+        pub client_key: String,
+    }
+
+    /// 生成一个 CA，并用它签发 server 和 client 的证书
+    pub fn generate_certs(domain: &str) -> TestCerts {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "KV CA");
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![domain.to_string()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, domain);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let server = params.signed_by(&server_key, &ca, &ca_key).unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "kv client");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client = params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        TestCerts {
+            ca_cert: ca.pem(),
+            server_cert: server.pem(),
+            server_key: server_key.serialize_pem(),
+            client_cert: client.pem(),
+            client_key: client_key.serialize_pem(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tls_utils::{generate_certs, TestCerts};
+    use super::*;
+    use crate::network::test_utils::*;
+    use crate::{assert_res_ok, CommandRequest, ProstClientStream, ProstServerStream, Value};
+    use anyhow::Result;
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    const DOMAIN: &str = "kvserver.acme.inc";
+
+    #[tokio::test]
+    async fn tls_should_work() -> Result<()> {
+        let certs = generate_certs(DOMAIN);
+        let addr = start_echo_server(&certs, false).await?;
+
+        let connector = TlsClientConnector::new(DOMAIN, None, &certs.ca_cert)?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(stream).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_client_cert_should_work() -> Result<()> {
+        let certs = generate_certs(DOMAIN);
+        let addr = start_echo_server(&certs, true).await?;
+
+        let identity = Some((certs.client_cert.as_str(), certs.client_key.as_str()));
+        let connector = TlsClientConnector::new(DOMAIN, identity, &certs.ca_cert)?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(stream).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_without_required_client_cert_should_not_work() -> Result<()> {
+        let certs = generate_certs(DOMAIN);
+        let addr = start_echo_server(&certs, true).await?;
+
+        let connector = TlsClientConnector::new(DOMAIN, None, &certs.ca_cert)?;
+        let stream = TcpStream::connect(addr).await?;
+        // TLS 1.3 下，服务器拒绝客户端证书发生在握手完成之后，所以要读一次才能发现
+        if let Ok(mut stream) = connector.connect(stream).await {
+            let _ = stream.write_all(b"hello world!").await;
+            let mut buf = [0; 12];
+            assert!(stream.read_exact(&mut buf).await.is_err());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_bad_domain_should_not_work() -> Result<()> {
+        let certs = generate_certs(DOMAIN);
+        let addr = start_echo_server(&certs, false).await?;
+
+        let connector = TlsClientConnector::new("kvserver1.acme.inc", None, &certs.ca_cert)?;
+        let stream = TcpStream::connect(addr).await?;
+        let result = connector.connect(stream).await;
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_from_files_should_work() -> Result<()> {
+        let certs = generate_certs(DOMAIN);
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("ca.cert"), &certs.ca_cert)?;
+        fs::write(path("server.cert"), &certs.server_cert)?;
+        fs::write(path("server.key"), &certs.server_key)?;
+        fs::write(path("client.cert"), &certs.client_cert)?;
+        fs::write(path("client.key"), &certs.client_key)?;
+
+        let acceptor = TlsServerAcceptor::from_files(
+            path("server.cert"),
+            path("server.key"),
+            Some(path("ca.cert")),
+        )?;
+        let identity = Some((path("client.cert"), path("client.key")));
+        let connector = TlsClientConnector::from_files(DOMAIN, identity, path("ca.cert"))?;
+
+        // 在 TLS stream 之上运行 kv 协议
+        let service = memtable_service();
+        let addr = spawn_listener(move |stream, _| {
+            let (acceptor, service) = (acceptor.clone(), service.clone());
+            async move {
+                let stream = acceptor.accept(stream).await.unwrap();
+                ProstServerStream::new(stream, service).process().await
+            }
+        })
+        .await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector.connect(stream).await?;
+        let mut client = ProstClientStream::new(stream);
+        let res = client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_pem_should_fail() {
+        let certs = generate_certs(DOMAIN);
+        let result = TlsServerAcceptor::new("not a cert", &certs.server_key, None);
+        assert!(matches!(result, Err(KvError::CertificateParseError(..))));
+        let result = TlsServerAcceptor::new(&certs.server_cert, "not a key", None);
+        assert!(matches!(result, Err(KvError::CertificateParseError(..))));
+        let result = TlsClientConnector::new(DOMAIN, None, "not a cert");
+        assert!(matches!(result, Err(KvError::CertificateParseError(..))));
+    }
+
+    async fn start_echo_server(certs: &TestCerts, client_auth: bool) -> Result<SocketAddr> {
+        let client_ca = client_auth.then_some(certs.ca_cert.as_str());
+        let acceptor = TlsServerAcceptor::new(&certs.server_cert, &certs.server_key, client_ca)?;
+
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = echo.local_addr()?;
+
+        tokio::spawn(async move {
+            let (stream, _) = echo.accept().await.unwrap();
+            let mut stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            let mut buf = [0; 12];
+            if stream.read_exact(&mut buf).await.is_ok() {
+                stream.write_all(&buf).await.unwrap();
+            }
+        });
+
+        Ok(addr)
+    }
+}
@@ -1,10 +1,13 @@
+// 由 build.rs 生成，保持生成的原样
+#[rustfmt::skip]
 pub mod abi;
 
-use crate::KvError;
+use crate::{Codec, KvError};
 use abi::{command_request::RequestData, *};
 use bytes::Bytes;
 use http::StatusCode;
 use prost::Message;
+use std::ops::Bound;
 
 impl CommandRequest {
     pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
@@ -13,6 +16,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -20,7 +24,65 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
+                chunk_size: 0,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgetall_chunked(table: impl Into<String>, chunk_size: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.into(),
+                chunk_size,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hscan(
+        table: impl Into<String>,
+        cursor: impl Into<String>,
+        count: u64,
+        pattern: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hscan(Hscan {
+                table: table.into(),
+                cursor: cursor.into(),
+                count,
+                pattern: pattern.into(),
             })),
+            ..Default::default()
+        }
+    }
+
+    /// 按 key 的顺序获取 start 到 end 之间的 kv pair，Unbounded 用空字符串表示
+    pub fn new_hrange(
+        table: impl Into<String>,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+        limit: u64,
+    ) -> Self {
+        let split = |bound: Bound<&str>| match bound {
+            Bound::Included(k) => (k.to_string(), false),
+            Bound::Excluded(k) => (k.to_string(), true),
+            Bound::Unbounded => (String::new(), false),
+        };
+        let (start, start_exclusive) = split(start);
+        let (end, end_exclusive) = split(end);
+        Self {
+            request_data: Some(RequestData::Hrange(Hrange {
+                table: table.into(),
+                start,
+                end,
+                start_exclusive,
+                end_exclusive,
+                reverse,
+                limit,
+            })),
+            ..Default::default()
         }
     }
 
@@ -30,6 +92,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -38,7 +101,25 @@ impl CommandRequest {
             request_data: Some(RequestData::Hset(Hset {
                 table: table.into(),
                 pair: Some(Kvpair::new(key, value)),
+                ttl: 0,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hset_with_ttl(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+        ttl: u64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hset(Hset {
+                table: table.into(),
+                pair: Some(Kvpair::new(key, value)),
+                ttl,
+            })),
+            ..Default::default()
         }
     }
 
@@ -47,7 +128,20 @@ impl CommandRequest {
             request_data: Some(RequestData::Hmset(Hmset {
                 table: table.into(),
                 pairs,
+                ttl: 0,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmset_with_ttl(table: impl Into<String>, pairs: Vec<Kvpair>, ttl: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmset(Hmset {
+                table: table.into(),
+                pairs,
+                ttl,
             })),
+            ..Default::default()
         }
     }
 
@@ -57,6 +151,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -66,6 +161,42 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_list_tables() -> Self {
+        Self {
+            request_data: Some(RequestData::ListTables(ListTables {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_drop_table(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::DropTable(DropTable {
+                table: table.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_rename_table(from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::RenameTable(RenameTable {
+                from: from.into(),
+                to: to.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hlen(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hlen(Hlen {
+                table: table.into(),
+            })),
+            ..Default::default()
         }
     }
 
@@ -75,6 +206,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -84,6 +216,273 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hexpire(table: impl Into<String>, key: impl Into<String>, ttl: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpire(Hexpire {
+                table: table.into(),
+                key: key.into(),
+                ttl,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hexpireat(table: impl Into<String>, key: impl Into<String>, timestamp: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpireat(Hexpireat {
+                table: table.into(),
+                key: key.into(),
+                timestamp,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_httl(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Httl(Httl {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hpersist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hpersist(Hpersist {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hincrby(table: impl Into<String>, key: impl Into<String>, delta: i64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrby(Hincrby {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hincrbyfloat(table: impl Into<String>, key: impl Into<String>, delta: f64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrbyfloat(Hincrbyfloat {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_watch(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_transaction(commands: Vec<CommandRequest>, watches: Vec<WatchedKey>) -> Self {
+        Self {
+            request_data: Some(RequestData::Transaction(Transaction { commands, watches })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_snapshot(path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Snapshot(Snapshot { path: path.into() })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_restore(path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Restore(Restore { path: path.into() })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_replicate() -> Self {
+        Self {
+            request_data: Some(RequestData::Replicate(Replicate {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_subscribe(topic: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: topic.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_unsubscribe(topic: impl Into<String>, id: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Unsubscribe(Unsubscribe {
+                topic: topic.into(),
+                id,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_publish(topic: impl Into<String>, data: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Publish(Publish {
+                topic: topic.into(),
+                data,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 用用户名和密码认证
+    pub fn new_auth(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                username: username.into(),
+                password: password.into(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 用 token 认证
+    pub fn new_auth_token(token: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                token: token.into(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 协商压缩算法，codecs 按偏好排列，threshold 为 0 时使用服务器的设置
+    pub fn new_handshake(codecs: &[Codec], threshold: usize) -> Self {
+        Self {
+            request_data: Some(RequestData::Handshake(Handshake {
+                codecs: codecs.iter().map(|c| *c as u32).collect(),
+                threshold: threshold as u64,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 设置请求的 id
+    pub fn with_id(mut self, id: u64) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// 命令的名字，用作指标的 label
+    pub fn command_name(&self) -> &'static str {
+        match &self.request_data {
+            Some(RequestData::Hget(_)) => "hget",
+            Some(RequestData::Hgetall(_)) => "hgetall",
+            Some(RequestData::Hscan(_)) => "hscan",
+            Some(RequestData::Hrange(_)) => "hrange",
+            Some(RequestData::Hmget(_)) => "hmget",
+            Some(RequestData::Hset(_)) => "hset",
+            Some(RequestData::Hmset(_)) => "hmset",
+            Some(RequestData::Hdel(_)) => "hdel",
+            Some(RequestData::Hmdel(_)) => "hmdel",
+            Some(RequestData::ListTables(_)) => "list_tables",
+            Some(RequestData::DropTable(_)) => "drop_table",
+            Some(RequestData::RenameTable(_)) => "rename_table",
+            Some(RequestData::Hlen(_)) => "hlen",
+            Some(RequestData::Hexist(_)) => "hexist",
+            Some(RequestData::Hmexist(_)) => "hmexist",
+            Some(RequestData::Hexpire(_)) => "hexpire",
+            Some(RequestData::Hexpireat(_)) => "hexpireat",
+            Some(RequestData::Httl(_)) => "httl",
+            Some(RequestData::Hpersist(_)) => "hpersist",
+            Some(RequestData::Hincrby(_)) => "hincrby",
+            Some(RequestData::Hincrbyfloat(_)) => "hincrbyfloat",
+            Some(RequestData::Watch(_)) => "watch",
+            Some(RequestData::Transaction(_)) => "transaction",
+            Some(RequestData::Snapshot(_)) => "snapshot",
+            Some(RequestData::Restore(_)) => "restore",
+            Some(RequestData::Replicate(_)) => "replicate",
+            Some(RequestData::Subscribe(_)) => "subscribe",
+            Some(RequestData::Unsubscribe(_)) => "unsubscribe",
+            Some(RequestData::Publish(_)) => "publish",
+            Some(RequestData::Auth(_)) => "auth",
+            Some(RequestData::Handshake(_)) => "handshake",
+            None => "none",
+        }
+    }
+
+    /// 是否是 topic 相关的命令，这类命令由 dispatch_stream 处理
+    pub fn is_topic_command(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(RequestData::Subscribe(_))
+                | Some(RequestData::Unsubscribe(_))
+                | Some(RequestData::Publish(_))
+        )
+    }
+
+    /// 是否是会修改数据的命令，事务里有任何一个这样的命令，整个事务就算是
+    pub fn is_write_command(&self) -> bool {
+        match &self.request_data {
+            Some(RequestData::Hset(_))
+            | Some(RequestData::Hmset(_))
+            | Some(RequestData::Hdel(_))
+            | Some(RequestData::Hmdel(_))
+            | Some(RequestData::DropTable(_))
+            | Some(RequestData::RenameTable(_))
+            | Some(RequestData::Hexpire(_))
+            | Some(RequestData::Hexpireat(_))
+            | Some(RequestData::Hpersist(_))
+            | Some(RequestData::Hincrby(_))
+            | Some(RequestData::Hincrbyfloat(_))
+            | Some(RequestData::Restore(_)) => true,
+            Some(RequestData::Transaction(tx)) => tx.commands.iter().any(|c| c.is_write_command()),
+            _ => false,
+        }
+    }
+}
+
+impl CommandResponse {
+    /// 创建一个只有 200 状态码的 response
+    pub fn ok() -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            ..Default::default()
+        }
+    }
+
+    /// 分块返回时，表示结束的 response
+    pub fn end() -> Self {
+        Self {
+            end: true,
+            ..Self::ok()
+        }
+    }
+}
+
+impl Value {
+    /// 创建一个整数类型的 Value
+    pub fn integer(i: i64) -> Self {
+        Self {
+            value: Some(value::Value::Integer(i)),
         }
     }
 }
@@ -98,6 +497,17 @@ impl Kvpair {
     }
 }
 
+impl WatchedKey {
+    /// 创建一个 watched key，version 通过 WATCH 命令获得
+    pub fn new(table: impl Into<String>, key: impl Into<String>, version: i64) -> Self {
+        Self {
+            table: table.into(),
+            key: key.into(),
+            version,
+        }
+    }
+}
+
 impl From<(String, Value)> for Kvpair {
     fn from((key, value): (String, Value)) -> Self {
         Self {
@@ -160,12 +570,21 @@ impl From<KvError> for CommandResponse {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            ..Default::default()
         };
         match e {
-            KvError::NotFound(_, _) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
-            KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::NotFound(_, _) | KvError::SubscriptionNotFound(_, _) => {
+                result.status = StatusCode::NOT_FOUND.as_u16() as _
+            }
+            KvError::InvalidCommand(_) | KvError::ConvertError(_, _) => {
+                result.status = StatusCode::BAD_REQUEST.as_u16() as _
+            }
+            KvError::WatchConflict(_, _) => result.status = StatusCode::CONFLICT.as_u16() as _,
+            KvError::ReadOnly | KvError::PermissionDenied(_, _) => {
+                result.status = StatusCode::FORBIDDEN.as_u16() as _
+            }
+            KvError::Unauthenticated(_) => result.status = StatusCode::UNAUTHORIZED.as_u16() as _,
+            KvError::NotLeader(_) => result.status = StatusCode::SERVICE_UNAVAILABLE.as_u16() as _,
             _ => {}
         }
         result
@@ -223,6 +642,58 @@ impl TryFrom<Value> for i64 {
     }
 }
 
+impl TryFrom<&Value> for i64 {
+    type Error = KvError;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::Integer(i)) => Ok(i),
+            _ => Err(KvError::ConvertError(v.clone(), "Integer")),
+        }
+    }
+}
+
+/// 从 response 的第一个 value 中取出整数，比如 subscription id
+impl TryFrom<&CommandResponse> for i64 {
+    type Error = KvError;
+
+    fn try_from(res: &CommandResponse) -> Result<Self, Self::Error> {
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return Err(KvError::Internal(res.message.clone()));
+        }
+        match res.values.first() {
+            Some(v) => v.try_into(),
+            None => Err(KvError::ConvertError(Value::default(), "Integer")),
+        }
+    }
+}
+
+/// 从 response 的第一个 value 中取出字符串，比如 HSCAN 的 cursor
+impl TryFrom<&CommandResponse> for String {
+    type Error = KvError;
+
+    fn try_from(res: &CommandResponse) -> Result<Self, Self::Error> {
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return Err(KvError::Internal(res.message.clone()));
+        }
+        match res.values.first() {
+            Some(v) => v.clone().try_into(),
+            None => Err(KvError::ConvertError(Value::default(), "String")),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::String(s)) => Ok(s),
+            _ => Err(KvError::ConvertError(v, "String")),
+        }
+    }
+}
+
 impl TryFrom<Value> for f64 {
     type Error = KvError;
 
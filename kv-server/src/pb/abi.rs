@@ -1,178 +1,2382 @@
 /// 来自客户端的命令请求
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof = "command_request::RequestData", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9")]
+    /// 请求的 id，服务器会在对应的 response 里带上同样的 id
+    /// 这样一个连接上可以同时有多个请求在处理。tag 从 100 开始，给命令留出空间
+    #[prost(uint64, tag="100")]
+    pub id: u64,
+    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31")]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
 pub mod command_request {
     #[derive(PartialOrd)]
-    #[allow(clippy::derive_partial_eq_without_eq)]
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum RequestData {
-        #[prost(message, tag = "1")]
+        #[prost(message, tag="1")]
         Hget(super::Hget),
-        #[prost(message, tag = "2")]
+        #[prost(message, tag="2")]
         Hgetall(super::Hgetall),
-        #[prost(message, tag = "3")]
+        #[prost(message, tag="3")]
         Hmget(super::Hmget),
-        #[prost(message, tag = "4")]
+        #[prost(message, tag="4")]
         Hset(super::Hset),
-        #[prost(message, tag = "5")]
+        #[prost(message, tag="5")]
         Hmset(super::Hmset),
-        #[prost(message, tag = "6")]
+        #[prost(message, tag="6")]
         Hdel(super::Hdel),
-        #[prost(message, tag = "7")]
+        #[prost(message, tag="7")]
         Hmdel(super::Hmdel),
-        #[prost(message, tag = "8")]
+        #[prost(message, tag="8")]
         Hexist(super::Hexist),
-        #[prost(message, tag = "9")]
+        #[prost(message, tag="9")]
         Hmexist(super::Hmexist),
+        #[prost(message, tag="10")]
+        Subscribe(super::Subscribe),
+        #[prost(message, tag="11")]
+        Unsubscribe(super::Unsubscribe),
+        #[prost(message, tag="12")]
+        Publish(super::Publish),
+        #[prost(message, tag="13")]
+        Hexpire(super::Hexpire),
+        #[prost(message, tag="14")]
+        Httl(super::Httl),
+        #[prost(message, tag="15")]
+        Hpersist(super::Hpersist),
+        #[prost(message, tag="16")]
+        Hincrby(super::Hincrby),
+        #[prost(message, tag="17")]
+        Hincrbyfloat(super::Hincrbyfloat),
+        #[prost(message, tag="18")]
+        Watch(super::Watch),
+        #[prost(message, tag="19")]
+        Transaction(super::Transaction),
+        #[prost(message, tag="20")]
+        Hscan(super::Hscan),
+        #[prost(message, tag="21")]
+        Hexpireat(super::Hexpireat),
+        #[prost(message, tag="22")]
+        Snapshot(super::Snapshot),
+        #[prost(message, tag="23")]
+        Restore(super::Restore),
+        #[prost(message, tag="24")]
+        Replicate(super::Replicate),
+        #[prost(message, tag="25")]
+        Auth(super::Auth),
+        #[prost(message, tag="26")]
+        Handshake(super::Handshake),
+        #[prost(message, tag="27")]
+        Hrange(super::Hrange),
+        #[prost(message, tag="28")]
+        ListTables(super::ListTables),
+        #[prost(message, tag="29")]
+        DropTable(super::DropTable),
+        #[prost(message, tag="30")]
+        RenameTable(super::RenameTable),
+        #[prost(message, tag="31")]
+        Hlen(super::Hlen),
     }
 }
 /// 服务器的响应
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandResponse {
     /// 状态码；复用 HTTP 2xx/4xx/5xx 状态码
-    #[prost(uint32, tag = "1")]
+    #[prost(uint32, tag="1")]
     pub status: u32,
     /// 如果不是 2xx，message 里包含详细的信息
-    #[prost(string, tag = "2")]
+    #[prost(string, tag="2")]
     pub message: ::prost::alloc::string::String,
     /// 成功返回的 values
-    #[prost(message, repeated, tag = "3")]
+    #[prost(message, repeated, tag="3")]
     pub values: ::prost::alloc::vec::Vec<Value>,
     /// 成功返回的 kv pairs
-    #[prost(message, repeated, tag = "4")]
+    #[prost(message, repeated, tag="4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 对应的请求的 id
+    #[prost(uint64, tag="5")]
+    pub id: u64,
+    /// 事务中每个命令各自的 response
+    #[prost(message, repeated, tag="6")]
+    pub responses: ::prost::alloc::vec::Vec<CommandResponse>,
+    /// 分块返回时，最后一个 response 的 end 为 true，不带数据
+    #[prost(bool, tag="7")]
+    pub end: bool,
 }
 /// 从 table 中获取一个 key，返回 value
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag = "2")]
+    #[prost(string, tag="2")]
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中获取所有的 Kvpair
+/// chunk_size 大于 0 时，Service 会分块返回，每个 response 最多 chunk_size 个 kv pair
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub chunk_size: u32,
+}
+/// 按 key 的顺序分页遍历 table，每次最多返回 count 个 key 匹配 pattern（glob）的 kv pair
+/// cursor 为空时从头开始，response 的 values 里是下一页的 cursor，为空时表示遍历结束
+/// cursor 记录了上一页最后遍历到的 key，遍历期间增删其它 key 不会导致 key 被跳过或者重复返回
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hscan {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub cursor: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub count: u64,
+    #[prost(string, tag="4")]
+    pub pattern: ::prost::alloc::string::String,
+}
+/// 按 key 的顺序返回 table 中 start 到 end 之间的 kv pair
+/// start / end 为空时不限制这一端，start_exclusive / end_exclusive 时不包含这一端的 key
+/// reverse 时从大到小返回，limit 为 0 时不限制数量
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hrange {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub start: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub end: ::prost::alloc::string::String,
+    #[prost(bool, tag="4")]
+    pub start_exclusive: bool,
+    #[prost(bool, tag="5")]
+    pub end_exclusive: bool,
+    #[prost(bool, tag="6")]
+    pub reverse: bool,
+    #[prost(uint64, tag="7")]
+    pub limit: u64,
 }
 /// 从 table 中获取一组 key，返回它们的 value
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmget {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
+    #[prost(string, repeated, tag="2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// 返回的值
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5")]
+    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
 pub mod value {
     #[derive(PartialOrd)]
-    #[allow(clippy::derive_partial_eq_without_eq)]
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Value {
-        #[prost(string, tag = "1")]
+        #[prost(string, tag="1")]
         String(::prost::alloc::string::String),
-        #[prost(bytes, tag = "2")]
+        #[prost(bytes, tag="2")]
         Binary(::prost::bytes::Bytes),
-        #[prost(int64, tag = "3")]
+        #[prost(int64, tag="3")]
         Integer(i64),
-        #[prost(double, tag = "4")]
+        #[prost(double, tag="4")]
         Float(f64),
-        #[prost(bool, tag = "5")]
+        #[prost(bool, tag="5")]
         Bool(bool),
     }
 }
 /// 返回的 kvpair
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Kvpair {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub key: ::prost::alloc::string::String,
-    #[prost(message, optional, tag = "2")]
+    #[prost(message, optional, tag="2")]
     pub value: ::core::option::Option<Value>,
 }
 /// 往 table 里存一个 kvpair，
 /// 如果 table 不存在就创建这个 table
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hset {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(message, optional, tag = "2")]
+    #[prost(message, optional, tag="2")]
     pub pair: ::core::option::Option<Kvpair>,
+    /// 过期时间（秒），0 表示永不过期
+    #[prost(uint64, tag="3")]
+    pub ttl: u64,
 }
 /// 往 table 中存一组 kvpair，
 /// 如果 table 不存在就创建这个 table
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmset {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(message, repeated, tag = "2")]
+    #[prost(message, repeated, tag="2")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 过期时间（秒），对所有的 kvpair 生效，0 表示永不过期
+    #[prost(uint64, tag="3")]
+    pub ttl: u64,
 }
 /// 从 table 中删除一个 key，返回它之前的值
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hdel {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag = "2")]
+    #[prost(string, tag="2")]
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中删除一组 key，返回它们之前的值
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmdel {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
+    #[prost(string, repeated, tag="2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 列出所有的 table，按名字排序，values 里是 table 的名字
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListTables {
+}
+/// 删除整个 table，返回删除的 key 的数量
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DropTable {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 把 table from 改名为 to，to 中原有的数据会被覆盖
+/// 返回 true 表示改名成功，from 不存在时返回 false
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RenameTable {
+    #[prost(string, tag="1")]
+    pub from: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub to: ::prost::alloc::string::String,
+}
+/// 返回 table 中 key 的数量
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hlen {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
 /// 查看 key 是否存在
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hexist {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag = "2")]
+    #[prost(string, tag="2")]
     pub key: ::prost::alloc::string::String,
 }
 /// 查看一组 key 是否存在
 #[derive(PartialOrd)]
-#[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmexist {
-    #[prost(string, tag = "1")]
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 给一个 key 设置过期时间（秒），返回 key 是否存在
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpire {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub ttl: u64,
+}
+/// 让一个 key 在某个时间点过期（UNIX 时间戳，毫秒），返回 key 是否存在
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpireat {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub timestamp: u64,
+}
+/// 查看一个 key 剩余的生存时间（秒）
+/// key 不存在返回 -2，没有过期时间返回 -1
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Httl {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 去掉一个 key 的过期时间，返回之前是否有过期时间
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hpersist {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 把一个 key 的整数值原子地加上 delta，返回新的值
+/// key 不存在时当作 0
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrby {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub delta: i64,
+}
+/// 把一个 key 的浮点数值原子地加上 delta，返回新的值
+/// key 不存在时当作 0，整数会被转换成浮点数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrbyfloat {
+    #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(double, tag="3")]
+    pub delta: f64,
+}
+/// 获取一组 key 当前的版本，用于 Transaction 的乐观锁检查
+/// key 的每次修改（包括删除和过期）都会换一个新的版本，删除之后再创建也不会回到原来的版本
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 事务中被观察的 key，以及通过 Watch 得到的版本
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WatchedKey {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub version: i64,
+}
+/// 原子地执行一组命令，要么全部生效，要么全部不生效
+/// 任何一个 watched key 的版本变了，事务都不会执行
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Transaction {
+    #[prost(message, repeated, tag="1")]
+    pub commands: ::prost::alloc::vec::Vec<CommandRequest>,
+    #[prost(message, repeated, tag="2")]
+    pub watches: ::prost::alloc::vec::Vec<WatchedKey>,
+}
+/// 把所有的数据保存到服务器上的快照文件里，返回保存的 kv pair 数量
+/// path 是服务器配置的快照目录中的文件名，不能包含目录
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Snapshot {
+    #[prost(string, tag="1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 从服务器上的快照文件恢复数据（覆盖同名的 key），返回恢复的 kv pair 数量
+/// path 是服务器配置的快照目录中的文件名，不能包含目录
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Restore {
+    #[prost(string, tag="1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// replica 连接到 primary 后发送的第一个请求。primary 先分块返回快照（Binary value），
+/// 然后是一个结束标记，之后在这个连接上依次发送所有修改数据的 CommandRequest
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replicate {
+}
+/// 认证当前连接，之后这个连接上的请求都以认证得到的用户身份执行，按这个用户的 ACL 检查
+/// 设置了 token 时用 token 认证，否则用 username 和 password
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(string, tag="1")]
+    pub token: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub username: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub password: ::prost::alloc::string::String,
+}
+/// 协商这个连接上 frame 使用的压缩算法和阈值，只能在连接开始时（可以在 AUTH 之后）发送
+/// codecs 是客户端支持的算法，按偏好排列：0 none、1 gzip、2 zstd、3 lz4、4 snappy
+/// threshold 是客户端希望的阈值，0 表示使用服务器的设置
+/// response 的 values 是选中的算法和阈值，之后双方都用它们编码 frame
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Handshake {
+    #[prost(uint32, repeated, tag="1")]
+    pub codecs: ::prost::alloc::vec::Vec<u32>,
+    #[prost(uint64, tag="2")]
+    pub threshold: u64,
+}
+/// 快照文件的头
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotHeader {
+    #[prost(uint32, tag="1")]
+    pub version: u32,
+    /// 创建快照的时间（UNIX 时间戳，毫秒）
+    #[prost(uint64, tag="2")]
+    pub created_at: u64,
+}
+/// 快照文件中的一个 kv pair
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotEntry {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="2")]
+    pub pair: ::core::option::Option<Kvpair>,
+    /// 过期的时间点（UNIX 时间戳，毫秒），0 表示没有过期时间
+    #[prost(uint64, tag="3")]
+    pub expire_at: u64,
+}
+/// raft 节点持久化的 term 和投票，voted_for 为 0 表示没有投票
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftHardState {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(uint64, tag="2")]
+    pub voted_for: u64,
+}
+/// raft 日志中的一个 entry
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftEntry {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(uint64, tag="2")]
+    pub index: u64,
+    /// leader 写入 entry 的时间（UNIX 时间戳，毫秒），每个节点都按这个时间应用它
+    #[prost(uint64, tag="3")]
+    pub timestamp: u64,
+    /// leader 当选时写入的空 entry 没有命令
+    #[prost(message, optional, tag="4")]
+    pub cmd: ::core::option::Option<CommandRequest>,
+}
+/// raft 节点持久化的快照，包含 index 及之前所有 entry 的结果
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftSnapshot {
+    #[prost(uint64, tag="1")]
+    pub index: u64,
+    #[prost(uint64, tag="2")]
+    pub term: u64,
+    /// 快照中最后一个 entry 的 timestamp
+    #[prost(uint64, tag="3")]
+    pub timestamp: u64,
+    /// snapshot 模块的格式
+    #[prost(bytes="bytes", tag="4")]
+    pub data: ::prost::bytes::Bytes,
+}
+/// 订阅某个主题，之后任何发布到这个主题的数据都会被收到
+/// 成功后，第一个返回的 CommandResponse 里包含一个唯一的 subscription id
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Subscribe {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+}
+/// 取消对某个主题的订阅
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unsubscribe {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub id: u32,
+}
+/// 发布数据到某个主题
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Publish {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub data: ::prost::alloc::vec::Vec<Value>,
+}
+/// Generated client implementations.
+pub mod kv_service_client {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    /// gRPC 接口，和自定义的帧协议共享同一个 Service
+    /// 每个命令对应一个 RPC，返回和帧协议一样的 CommandResponse（错误也放在 status 里）
+    #[derive(Debug, Clone)]
+    pub struct KvServiceClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl KvServiceClient<tonic::transport::Channel> {
+        /// Attempt to create a new client by connecting to a given endpoint.
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: std::convert::TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> KvServiceClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::Error: Into<StdError>,
+        T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> KvServiceClient<InterceptedService<T, F>>
+        where
+            F: tonic::service::Interceptor,
+            T::ResponseBody: Default,
+            T: tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+            >>::Error: Into<StdError> + Send + Sync,
+        {
+            KvServiceClient::new(InterceptedService::new(inner, interceptor))
+        }
+        /// Compress requests with `gzip`.
+        ///
+        /// This requires the server to support it otherwise it might respond with an
+        /// error.
+        #[must_use]
+        pub fn send_gzip(mut self) -> Self {
+            self.inner = self.inner.send_gzip();
+            self
+        }
+        /// Enable decompressing responses with `gzip`.
+        #[must_use]
+        pub fn accept_gzip(mut self) -> Self {
+            self.inner = self.inner.accept_gzip();
+            self
+        }
+        pub async fn hget(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hget>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hget");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hgetall(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hgetall>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hgetall");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hscan(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hscan>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hscan");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hrange(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hrange>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hrange");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hmget(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hmget>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hmget");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hset(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hset>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hset");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hmset(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hmset>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hmset");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hdel(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hdel>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hdel");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hmdel(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hmdel>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hmdel");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn list_tables(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListTables>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/ListTables");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn drop_table(
+            &mut self,
+            request: impl tonic::IntoRequest<super::DropTable>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/DropTable");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn rename_table(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RenameTable>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/abi.KvService/RenameTable",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hlen(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hlen>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hlen");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hexist(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hexist>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hexist");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hmexist(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hmexist>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hmexist");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hexpire(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hexpire>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hexpire");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hexpireat(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hexpireat>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hexpireat");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn httl(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Httl>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Httl");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hpersist(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hpersist>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hpersist");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hincrby(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hincrby>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Hincrby");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn hincrbyfloat(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Hincrbyfloat>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/abi.KvService/Hincrbyfloat",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn watch(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Watch>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Watch");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn transaction(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Transaction>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/abi.KvService/Transaction",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn snapshot(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Snapshot>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Snapshot");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn restore(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Restore>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Restore");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn unsubscribe(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Unsubscribe>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/abi.KvService/Unsubscribe",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn publish(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Publish>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Publish");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        /// 订阅的数据持续地返回，直到取消订阅
+        pub async fn subscribe(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Subscribe>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::CommandResponse>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Subscribe");
+            self.inner.server_streaming(request.into_request(), path, codec).await
+        }
+        /// 双向流：和帧协议一样，每个请求单独执行，response 带上请求的 id，按完成的顺序返回
+        pub async fn execute(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::CommandRequest>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::CommandResponse>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Execute");
+            self.inner.streaming(request.into_streaming_request(), path, codec).await
+        }
+    }
+}
+/// Generated server implementations.
+pub mod kv_service_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    ///Generated trait containing gRPC methods that should be implemented for use with KvServiceServer.
+    #[async_trait]
+    pub trait KvService: Send + Sync + 'static {
+        async fn hget(
+            &self,
+            request: tonic::Request<super::Hget>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hgetall(
+            &self,
+            request: tonic::Request<super::Hgetall>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hscan(
+            &self,
+            request: tonic::Request<super::Hscan>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hrange(
+            &self,
+            request: tonic::Request<super::Hrange>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hmget(
+            &self,
+            request: tonic::Request<super::Hmget>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hset(
+            &self,
+            request: tonic::Request<super::Hset>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hmset(
+            &self,
+            request: tonic::Request<super::Hmset>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hdel(
+            &self,
+            request: tonic::Request<super::Hdel>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hmdel(
+            &self,
+            request: tonic::Request<super::Hmdel>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn list_tables(
+            &self,
+            request: tonic::Request<super::ListTables>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn drop_table(
+            &self,
+            request: tonic::Request<super::DropTable>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn rename_table(
+            &self,
+            request: tonic::Request<super::RenameTable>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hlen(
+            &self,
+            request: tonic::Request<super::Hlen>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hexist(
+            &self,
+            request: tonic::Request<super::Hexist>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hmexist(
+            &self,
+            request: tonic::Request<super::Hmexist>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hexpire(
+            &self,
+            request: tonic::Request<super::Hexpire>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hexpireat(
+            &self,
+            request: tonic::Request<super::Hexpireat>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn httl(
+            &self,
+            request: tonic::Request<super::Httl>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hpersist(
+            &self,
+            request: tonic::Request<super::Hpersist>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hincrby(
+            &self,
+            request: tonic::Request<super::Hincrby>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn hincrbyfloat(
+            &self,
+            request: tonic::Request<super::Hincrbyfloat>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn watch(
+            &self,
+            request: tonic::Request<super::Watch>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn transaction(
+            &self,
+            request: tonic::Request<super::Transaction>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn snapshot(
+            &self,
+            request: tonic::Request<super::Snapshot>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn restore(
+            &self,
+            request: tonic::Request<super::Restore>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn unsubscribe(
+            &self,
+            request: tonic::Request<super::Unsubscribe>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        async fn publish(
+            &self,
+            request: tonic::Request<super::Publish>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        ///Server streaming response type for the Subscribe method.
+        type SubscribeStream: futures_core::Stream<
+                Item = Result<super::CommandResponse, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        /// 订阅的数据持续地返回，直到取消订阅
+        async fn subscribe(
+            &self,
+            request: tonic::Request<super::Subscribe>,
+        ) -> Result<tonic::Response<Self::SubscribeStream>, tonic::Status>;
+        ///Server streaming response type for the Execute method.
+        type ExecuteStream: futures_core::Stream<
+                Item = Result<super::CommandResponse, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        /// 双向流：和帧协议一样，每个请求单独执行，response 带上请求的 id，按完成的顺序返回
+        async fn execute(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::CommandRequest>>,
+        ) -> Result<tonic::Response<Self::ExecuteStream>, tonic::Status>;
+    }
+    /// gRPC 接口，和自定义的帧协议共享同一个 Service
+    /// 每个命令对应一个 RPC，返回和帧协议一样的 CommandResponse（错误也放在 status 里）
+    #[derive(Debug)]
+    pub struct KvServiceServer<T: KvService> {
+        inner: _Inner<T>,
+        accept_compression_encodings: (),
+        send_compression_encodings: (),
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: KvService> KvServiceServer<T> {
+        pub fn new(inner: T) -> Self {
+            Self::from_arc(Arc::new(inner))
+        }
+        pub fn from_arc(inner: Arc<T>) -> Self {
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+            }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for KvServiceServer<T>
+    where
+        T: KvService,
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/abi.KvService/Hget" => {
+                    #[allow(non_camel_case_types)]
+                    struct HgetSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hget>
+                    for HgetSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hget>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hget(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HgetSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hgetall" => {
+                    #[allow(non_camel_case_types)]
+                    struct HgetallSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hgetall>
+                    for HgetallSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hgetall>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hgetall(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HgetallSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hscan" => {
+                    #[allow(non_camel_case_types)]
+                    struct HscanSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hscan>
+                    for HscanSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hscan>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hscan(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HscanSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hrange" => {
+                    #[allow(non_camel_case_types)]
+                    struct HrangeSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hrange>
+                    for HrangeSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hrange>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hrange(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HrangeSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hmget" => {
+                    #[allow(non_camel_case_types)]
+                    struct HmgetSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hmget>
+                    for HmgetSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hmget>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hmget(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HmgetSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hset" => {
+                    #[allow(non_camel_case_types)]
+                    struct HsetSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hset>
+                    for HsetSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hset>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hset(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HsetSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hmset" => {
+                    #[allow(non_camel_case_types)]
+                    struct HmsetSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hmset>
+                    for HmsetSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hmset>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hmset(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HmsetSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hdel" => {
+                    #[allow(non_camel_case_types)]
+                    struct HdelSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hdel>
+                    for HdelSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hdel>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hdel(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HdelSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hmdel" => {
+                    #[allow(non_camel_case_types)]
+                    struct HmdelSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hmdel>
+                    for HmdelSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hmdel>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hmdel(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HmdelSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/ListTables" => {
+                    #[allow(non_camel_case_types)]
+                    struct ListTablesSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::ListTables>
+                    for ListTablesSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListTables>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).list_tables(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ListTablesSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/DropTable" => {
+                    #[allow(non_camel_case_types)]
+                    struct DropTableSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::DropTable>
+                    for DropTableSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::DropTable>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).drop_table(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = DropTableSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/RenameTable" => {
+                    #[allow(non_camel_case_types)]
+                    struct RenameTableSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::RenameTable>
+                    for RenameTableSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RenameTable>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).rename_table(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = RenameTableSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hlen" => {
+                    #[allow(non_camel_case_types)]
+                    struct HlenSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hlen>
+                    for HlenSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hlen>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hlen(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HlenSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hexist" => {
+                    #[allow(non_camel_case_types)]
+                    struct HexistSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hexist>
+                    for HexistSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hexist>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hexist(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HexistSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hmexist" => {
+                    #[allow(non_camel_case_types)]
+                    struct HmexistSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hmexist>
+                    for HmexistSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hmexist>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hmexist(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HmexistSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hexpire" => {
+                    #[allow(non_camel_case_types)]
+                    struct HexpireSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hexpire>
+                    for HexpireSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hexpire>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hexpire(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HexpireSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hexpireat" => {
+                    #[allow(non_camel_case_types)]
+                    struct HexpireatSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hexpireat>
+                    for HexpireatSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hexpireat>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hexpireat(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HexpireatSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Httl" => {
+                    #[allow(non_camel_case_types)]
+                    struct HttlSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Httl>
+                    for HttlSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Httl>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).httl(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HttlSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hpersist" => {
+                    #[allow(non_camel_case_types)]
+                    struct HpersistSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hpersist>
+                    for HpersistSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hpersist>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hpersist(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HpersistSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hincrby" => {
+                    #[allow(non_camel_case_types)]
+                    struct HincrbySvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hincrby>
+                    for HincrbySvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hincrby>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).hincrby(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HincrbySvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Hincrbyfloat" => {
+                    #[allow(non_camel_case_types)]
+                    struct HincrbyfloatSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Hincrbyfloat>
+                    for HincrbyfloatSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Hincrbyfloat>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).hincrbyfloat(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HincrbyfloatSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Watch" => {
+                    #[allow(non_camel_case_types)]
+                    struct WatchSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Watch>
+                    for WatchSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Watch>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).watch(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = WatchSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Transaction" => {
+                    #[allow(non_camel_case_types)]
+                    struct TransactionSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Transaction>
+                    for TransactionSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Transaction>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).transaction(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = TransactionSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Snapshot" => {
+                    #[allow(non_camel_case_types)]
+                    struct SnapshotSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Snapshot>
+                    for SnapshotSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Snapshot>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).snapshot(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SnapshotSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Restore" => {
+                    #[allow(non_camel_case_types)]
+                    struct RestoreSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Restore>
+                    for RestoreSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Restore>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).restore(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = RestoreSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Unsubscribe" => {
+                    #[allow(non_camel_case_types)]
+                    struct UnsubscribeSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Unsubscribe>
+                    for UnsubscribeSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Unsubscribe>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).unsubscribe(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = UnsubscribeSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Publish" => {
+                    #[allow(non_camel_case_types)]
+                    struct PublishSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::Publish>
+                    for PublishSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Publish>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).publish(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = PublishSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Subscribe" => {
+                    #[allow(non_camel_case_types)]
+                    struct SubscribeSvc<T: KvService>(pub Arc<T>);
+                    impl<
+                        T: KvService,
+                    > tonic::server::ServerStreamingService<super::Subscribe>
+                    for SubscribeSvc<T> {
+                        type Response = super::CommandResponse;
+                        type ResponseStream = T::SubscribeStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Subscribe>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).subscribe(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SubscribeSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Execute" => {
+                    #[allow(non_camel_case_types)]
+                    struct ExecuteSvc<T: KvService>(pub Arc<T>);
+                    impl<
+                        T: KvService,
+                    > tonic::server::StreamingService<super::CommandRequest>
+                    for ExecuteSvc<T> {
+                        type Response = super::CommandResponse;
+                        type ResponseStream = T::ExecuteStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<
+                                tonic::Streaming<super::CommandRequest>,
+                            >,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).execute(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ExecuteSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => {
+                    Box::pin(async move {
+                        Ok(
+                            http::Response::builder()
+                                .status(200)
+                                .header("grpc-status", "12")
+                                .header("content-type", "application/grpc")
+                                .body(empty_body())
+                                .unwrap(),
+                        )
+                    })
+                }
+            }
+        }
+    }
+    impl<T: KvService> Clone for KvServiceServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self {
+                inner,
+                accept_compression_encodings: self.accept_compression_encodings,
+                send_compression_encodings: self.send_compression_encodings,
+            }
+        }
+    }
+    impl<T: KvService> Clone for _Inner<T> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+    impl<T: std::fmt::Debug> std::fmt::Debug for _Inner<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl<T: KvService> tonic::transport::NamedService for KvServiceServer<T> {
+        const NAME: &'static str = "abi.KvService";
+    }
+}
@@ -0,0 +1,101 @@
+mod log;
+mod loopback;
+mod node;
+mod storage;
+
+use crate::CommandRequest;
+pub use loopback::*;
+pub use node::*;
+pub use storage::*;
+
+/// raft 节点的配置，时间都以 tick 为单位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RaftConfig {
+    /// follower 超过 [election_tick, 2 * election_tick) 个 tick 没有收到 leader 的消息，就发起选举
+    pub election_tick: u64,
+    /// leader 每隔 heartbeat_tick 个 tick 发送一次心跳
+    pub heartbeat_tick: u64,
+    /// 已经应用的 entry 超过这个数量时，生成快照并压缩日志
+    pub snapshot_threshold: u64,
+    /// 一个 AppendEntries 最多带的 entry 数量
+    pub max_append_entries: usize,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_tick: 10,
+            heartbeat_tick: 3,
+            snapshot_threshold: 1024,
+            max_append_entries: 64,
+        }
+    }
+}
+
+/// 日志中的一条记录。cmd 为 None 的是 leader 当选时写入的空 entry，
+/// 用来提交之前 term 的 entry，应用时会被跳过
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub term: u64,
+    pub index: u64,
+    /// leader 写入 entry 的时间（毫秒），每个节点都按这个时间应用它，结果和本地时钟无关
+    pub timestamp: u64,
+    pub cmd: Option<CommandRequest>,
+}
+
+/// 节点之间的消息
+#[derive(Debug, Clone, PartialEq)]
+pub enum RaftMessage {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    VoteResponse {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    /// AppendEntries 和 InstallSnapshot 的回复。成功时 match_index 是和 leader 一致的最后一个 entry，
+    /// 失败时是 follower 最后一个 entry，leader 从这里开始重试
+    AppendResponse {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    /// follower 需要的 entry 已经被压缩到快照里了，直接发送快照（snapshot 模块的格式）
+    InstallSnapshot {
+        term: u64,
+        last_index: u64,
+        last_term: u64,
+        /// 快照中最后一个 entry 的 timestamp
+        timestamp: u64,
+        data: Vec<u8>,
+    },
+}
+
+impl RaftMessage {
+    pub fn term(&self) -> u64 {
+        match self {
+            RaftMessage::RequestVote { term, .. }
+            | RaftMessage::VoteResponse { term, .. }
+            | RaftMessage::AppendEntries { term, .. }
+            | RaftMessage::AppendResponse { term, .. }
+            | RaftMessage::InstallSnapshot { term, .. } => *term,
+        }
+    }
+}
+
+/// 带上收发节点的消息，由 transport 负责送达
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub from: u64,
+    pub to: u64,
+    pub msg: RaftMessage,
+}
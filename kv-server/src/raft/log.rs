@@ -0,0 +1,158 @@
+use super::LogEntry;
+use crate::CommandRequest;
+
+/// raft 的日志。快照之前的 entry 已经被压缩掉了，只记录快照中最后一个 entry 的 index 和 term
+#[derive(Debug, Default)]
+pub(crate) struct RaftLog {
+    snapshot_index: u64,
+    snapshot_term: u64,
+    /// entries[i].index == snapshot_index + 1 + i
+    entries: Vec<LogEntry>,
+}
+
+impl RaftLog {
+    /// 用持久化的快照位置和之后的日志恢复
+    pub fn new(snapshot_index: u64, snapshot_term: u64, entries: Vec<LogEntry>) -> Self {
+        Self {
+            snapshot_index,
+            snapshot_term,
+            entries,
+        }
+    }
+
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    pub fn snapshot_term(&self) -> u64 {
+        self.snapshot_term
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// index 处 entry 的 term；已经被压缩或者还不存在时返回 None
+    pub fn term(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.get(index).map(|entry| entry.term)
+    }
+
+    pub fn get(&self, index: u64) -> Option<&LogEntry> {
+        let offset = index.checked_sub(self.snapshot_index + 1)?;
+        self.entries.get(offset as usize)
+    }
+
+    /// 从 index 开始最多 max 个 entry
+    pub fn entries_from(&self, index: u64, max: usize) -> Vec<LogEntry> {
+        let start = index.saturating_sub(self.snapshot_index + 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    /// leader 在日志末尾追加一个 entry，返回追加的 entry
+    pub fn push(&mut self, term: u64, timestamp: u64, cmd: Option<CommandRequest>) -> &LogEntry {
+        let index = self.last_index() + 1;
+        self.entries.push(LogEntry {
+            term,
+            index,
+            timestamp,
+            cmd,
+        });
+        &self.entries[self.entries.len() - 1]
+    }
+
+    /// follower 追加 leader 发来的 entry：已经有的跳过，遇到冲突的 entry 就删掉它和之后所有的 entry。
+    /// 调用者保证 entries 是连续的，并且第一个 entry 紧接着已经匹配的位置。
+    /// 返回真正写入的 entry，它们需要持久化
+    pub fn append<'a>(&mut self, entries: &'a [LogEntry]) -> &'a [LogEntry] {
+        for (i, entry) in entries.iter().enumerate() {
+            if self.term(entry.index) == Some(entry.term) {
+                continue;
+            }
+            let keep = (entry.index - self.snapshot_index - 1) as usize;
+            self.entries.truncate(keep);
+            self.entries.extend_from_slice(&entries[i..]);
+            return &entries[i..];
+        }
+        &[]
+    }
+
+    /// 生成快照之后，删掉 index 及之前所有的 entry
+    pub fn compact(&mut self, index: u64) {
+        if let Some(term) = self.term(index) {
+            let len = (index - self.snapshot_index) as usize;
+            self.entries.drain(..len);
+            self.snapshot_index = index;
+            self.snapshot_term = term;
+        }
+    }
+
+    /// 安装了其它节点的快照，之前的日志都没用了
+    pub fn reset(&mut self, index: u64, term: u64) {
+        self.entries.clear();
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(term: u64, index: u64) -> LogEntry {
+        LogEntry {
+            term,
+            index,
+            timestamp: 0,
+            cmd: Some(CommandRequest::new_hget("t1", "k1")),
+        }
+    }
+
+    #[test]
+    fn append_should_replace_conflicting_entries() {
+        let mut log = RaftLog::default();
+        for term in [1, 1, 2] {
+            log.push(term, 0, None);
+        }
+        assert_eq!((log.last_index(), log.last_term()), (3, 2));
+
+        // 已有的 entry 不变，冲突的 entry 和之后的都被替换
+        let entries = [entry(1, 2), entry(3, 3), entry(3, 4)];
+        assert_eq!(log.append(&entries), &entries[1..]);
+        assert_eq!(log.last_index(), 4);
+        assert_eq!(log.term(2), Some(1));
+        assert_eq!(log.term(3), Some(3));
+        assert!(log.get(2).unwrap().cmd.is_none());
+
+        // 重复收到旧的 entry 不会截断日志
+        assert!(log.append(&[entry(1, 2)]).is_empty());
+        assert_eq!(log.last_index(), 4);
+    }
+
+    #[test]
+    fn compact_should_keep_later_entries() {
+        let mut log = RaftLog::default();
+        for term in [1, 1, 2, 2] {
+            log.push(term, 0, None);
+        }
+        log.compact(3);
+        assert_eq!((log.snapshot_index(), log.snapshot_term()), (3, 2));
+        assert_eq!(log.term(2), None);
+        assert_eq!(log.term(3), Some(2));
+        assert_eq!(log.entries_from(1, 10).len(), 1);
+        assert_eq!(log.entries_from(4, 10)[0].index, 4);
+        assert_eq!(log.last_index(), 4);
+
+        log.reset(10, 3);
+        assert_eq!((log.last_index(), log.last_term()), (10, 3));
+        assert!(log.get(4).is_none());
+    }
+}
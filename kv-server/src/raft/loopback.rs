@@ -0,0 +1,393 @@
+use super::{MemRaftStorage, RaftConfig, RaftNode, Role};
+use crate::{CommandRequest, CommandResponse, KvError, Storage};
+use std::collections::{BTreeMap, HashSet};
+
+/// 一个请求最多等待多少个 tick
+const MAX_WAIT_TICKS: usize = 100;
+
+/// 进程内的 raft 集群：消息在节点之间直接传递，时间由调用者用 tick 推进，
+/// 所以测试是完全确定的，还可以模拟网络分区
+pub struct LoopbackCluster<Store> {
+    nodes: BTreeMap<u64, RaftNode<Store>>,
+    /// 每个节点持久化的状态，重启节点时用它恢复
+    storages: BTreeMap<u64, MemRaftStorage>,
+    config: RaftConfig,
+    /// 不能通信的节点对
+    blocked: HashSet<(u64, u64)>,
+}
+
+impl<Store: Storage> LoopbackCluster<Store> {
+    /// 创建 id 为 1..=size 的节点，new_store 为每个节点创建状态机
+    pub fn new(size: u64, config: RaftConfig, mut new_store: impl FnMut(u64) -> Store) -> Self {
+        let mut cluster = Self {
+            nodes: BTreeMap::new(),
+            storages: (1..=size)
+                .map(|id| (id, MemRaftStorage::default()))
+                .collect(),
+            config,
+            blocked: HashSet::new(),
+        };
+        for id in 1..=size {
+            // 空的 MemRaftStorage 不会出错
+            let node = cluster.open(id, new_store(id)).unwrap();
+            cluster.nodes.insert(id, node);
+        }
+        cluster
+    }
+
+    pub fn node(&self, id: u64) -> &RaftNode<Store> {
+        &self.nodes[&id]
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &RaftNode<Store>> {
+        self.nodes.values()
+    }
+
+    /// 所有节点前进一个 tick，然后把消息都送达
+    pub fn tick(&mut self) {
+        for node in self.nodes.values_mut() {
+            node.tick();
+        }
+        self.deliver();
+    }
+
+    pub fn run(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    /// 不断地传递消息，直到没有新的消息；被分区隔开的消息直接丢弃
+    pub fn deliver(&mut self) {
+        loop {
+            let msgs: Vec<_> = self
+                .nodes
+                .values_mut()
+                .flat_map(|node| node.take_messages())
+                .collect();
+            if msgs.is_empty() {
+                break;
+            }
+            for env in msgs {
+                if self.blocked.contains(&(env.from, env.to)) {
+                    continue;
+                }
+                if let Some(node) = self.nodes.get_mut(&env.to) {
+                    node.step(env);
+                }
+            }
+        }
+    }
+
+    /// 当前的 leader。分区时少数派里可能还有旧的 leader，取 term 最大的那个
+    pub fn leader(&self) -> Option<u64> {
+        self.nodes
+            .values()
+            .filter(|node| node.role() == Role::Leader)
+            .max_by_key(|node| node.term())
+            .map(|node| node.id())
+    }
+
+    /// 推进时间直到选出 leader
+    pub fn wait_for_leader(&mut self) -> Result<u64, KvError> {
+        for _ in 0..MAX_WAIT_TICKS {
+            if let Some(id) = self.leader() {
+                return Ok(id);
+            }
+            self.tick();
+        }
+        Err(KvError::NotLeader(None))
+    }
+
+    /// 通过 leader 执行一个命令，推进时间直到它被提交并应用
+    pub fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let id = self.wait_for_leader()?;
+        self.execute_on(id, cmd)
+    }
+
+    /// 通过指定的节点执行一个命令，这个节点不是 leader 时返回 NotLeader
+    pub fn execute_on(&mut self, id: u64, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let node = self.node_mut(id)?;
+        let (term, index) = node.propose(cmd)?;
+        self.deliver();
+        for _ in 0..MAX_WAIT_TICKS {
+            if let Some(res) = self.node_mut(id)?.take_response(term, index) {
+                return res;
+            }
+            self.tick();
+        }
+        Err(KvError::NotLeader(self.node(id).leader()))
+    }
+
+    /// 把一个节点和其它所有节点隔开
+    pub fn isolate(&mut self, id: u64) {
+        let others: Vec<u64> = self.nodes.keys().copied().filter(|&n| n != id).collect();
+        self.partition(&[&[id], &others]);
+    }
+
+    /// 把节点分成几组，不同组之间不能通信
+    pub fn partition(&mut self, groups: &[&[u64]]) {
+        self.blocked.clear();
+        for (i, a) in groups.iter().enumerate() {
+            for b in groups.iter().skip(i + 1) {
+                for &x in a.iter() {
+                    for &y in b.iter() {
+                        self.blocked.insert((x, y));
+                        self.blocked.insert((y, x));
+                    }
+                }
+            }
+        }
+    }
+
+    /// 恢复所有节点之间的通信
+    pub fn heal(&mut self) {
+        self.blocked.clear();
+    }
+
+    /// 模拟节点重启：丢掉节点在内存中的状态，用持久化的状态和新的 store 重新创建它
+    pub fn restart(&mut self, id: u64, store: Store) -> Result<(), KvError> {
+        let node = self.open(id, store)?;
+        self.nodes.insert(id, node);
+        Ok(())
+    }
+
+    fn open(&self, id: u64, store: Store) -> Result<RaftNode<Store>, KvError> {
+        let peers = self.storages.keys().copied().filter(|&n| n != id).collect();
+        let storage = self
+            .storages
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| KvError::Internal(format!("Raft node {} not found", id)))?;
+        RaftNode::open(id, peers, store, self.config, storage)
+    }
+
+    fn node_mut(&mut self, id: u64) -> Result<&mut RaftNode<Store>, KvError> {
+        self.nodes
+            .get_mut(&id)
+            .ok_or_else(|| KvError::Internal(format!("Raft node {} not found", id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::now_ms;
+    use crate::{assert_res_error, assert_res_ok, MemTable, Value};
+    use std::time::Duration;
+
+    fn cluster(size: u64, config: RaftConfig) -> LoopbackCluster<MemTable> {
+        LoopbackCluster::new(size, config, |_| MemTable::new())
+    }
+
+    fn hset(key: &str, value: i64) -> CommandRequest {
+        CommandRequest::new_hset("t1", key, Value::integer(value))
+    }
+
+    /// 所有能通信的节点都应用了同样的数据
+    fn assert_replicated(cluster: &LoopbackCluster<MemTable>, key: &str, value: Option<Value>) {
+        for node in cluster.nodes() {
+            assert_eq!(
+                node.store().get("t1", key).unwrap(),
+                value,
+                "node {}",
+                node.id()
+            );
+        }
+    }
+
+    #[test]
+    fn cluster_should_elect_one_leader_and_replicate() {
+        let mut cluster = cluster(3, RaftConfig::default());
+        let leader = cluster.wait_for_leader().unwrap();
+        let leaders = cluster
+            .nodes()
+            .filter(|node| node.role() == Role::Leader)
+            .count();
+        assert_eq!(leaders, 1);
+
+        let res = cluster.execute(hset("k1", 1)).unwrap();
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = cluster
+            .execute(CommandRequest::new_hincrby("t1", "k1", 2))
+            .unwrap();
+        assert_res_ok(res, &[Value::integer(3)], &[]);
+        // 读取也经过日志，得到的一定是最新的数据
+        let res = cluster
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .unwrap();
+        assert_res_ok(res, &[Value::integer(3)], &[]);
+
+        // follower 不能直接处理请求，但知道谁是 leader
+        let follower = (1..=3).find(|&id| id != leader).unwrap();
+        let result = cluster.execute_on(follower, hset("k2", 2));
+        assert!(matches!(result, Err(KvError::NotLeader(Some(id))) if id == leader));
+
+        cluster.run(5);
+        assert_replicated(&cluster, "k1", Some(Value::integer(3)));
+    }
+
+    #[test]
+    fn cluster_should_fail_over_when_leader_is_isolated() {
+        let mut cluster = cluster(5, RaftConfig::default());
+        let old = cluster.wait_for_leader().unwrap();
+        cluster.execute(hset("k1", 1)).unwrap();
+
+        // 旧的 leader 被隔开之后，剩下的大多数选出新的 leader
+        cluster.isolate(old);
+        cluster.run(30);
+        let new = cluster.leader().unwrap();
+        assert_ne!(new, old);
+        assert!(cluster.node(new).term() > cluster.node(old).term());
+
+        // 旧的 leader 还以为自己是 leader，但它提交的请求无法完成
+        assert_eq!(cluster.node(old).role(), Role::Leader);
+        assert!(cluster.execute_on(old, hset("k1", 100)).is_err());
+        let res = cluster.execute_on(new, hset("k1", 2)).unwrap();
+        assert_res_ok(res, &[Value::integer(1)], &[]);
+
+        // 恢复之后，旧的 leader 变成 follower，没有提交的 entry 被覆盖
+        cluster.heal();
+        cluster.run(30);
+        assert_eq!(cluster.node(old).role(), Role::Follower);
+        let leader = cluster.leader().unwrap();
+        let commit = cluster.node(leader).commit_index();
+        assert!(cluster.nodes().all(|node| node.commit_index() == commit));
+        assert_replicated(&cluster, "k1", Some(Value::integer(2)));
+    }
+
+    #[test]
+    fn minority_partition_should_not_commit() {
+        let mut cluster = cluster(5, RaftConfig::default());
+        let leader = cluster.wait_for_leader().unwrap();
+        let others: Vec<u64> = (1..=5).filter(|&id| id != leader).collect();
+
+        // leader 和一个 follower 在少数派
+        let minority = [leader, others[0]];
+        let majority = &others[1..];
+        cluster.partition(&[&minority, majority]);
+        assert!(cluster.execute_on(leader, hset("k1", 1)).is_err());
+
+        cluster.run(30);
+        let new = majority
+            .iter()
+            .copied()
+            .find(|&id| cluster.node(id).role() == Role::Leader)
+            .unwrap();
+        cluster.execute_on(new, hset("k2", 2)).unwrap();
+
+        cluster.heal();
+        cluster.run(30);
+        assert_replicated(&cluster, "k1", None);
+        assert_replicated(&cluster, "k2", Some(Value::integer(2)));
+    }
+
+    #[test]
+    fn lagging_follower_should_catch_up_with_snapshot() {
+        let config = RaftConfig {
+            snapshot_threshold: 10,
+            max_append_entries: 4,
+            ..Default::default()
+        };
+        let mut cluster = cluster(3, config);
+        let leader = cluster.wait_for_leader().unwrap();
+        let lagging = (1..=3).find(|&id| id != leader).unwrap();
+
+        cluster.isolate(lagging);
+        for i in 0..30 {
+            cluster
+                .execute_on(leader, hset(&format!("k{}", i), i))
+                .unwrap();
+        }
+        cluster
+            .execute_on(leader, CommandRequest::new_hdel("t1", "k0"))
+            .unwrap();
+        // leader 已经压缩了日志，落后的节点只能通过快照追上
+        assert!(cluster.node(leader).snapshot_index() > 0);
+        assert_eq!(cluster.node(lagging).last_applied(), 1);
+
+        cluster.heal();
+        cluster.run(50);
+        let leader = cluster.leader().unwrap();
+        let applied = cluster.node(leader).last_applied();
+        assert!(cluster.nodes().all(|node| node.last_applied() == applied));
+        assert_replicated(&cluster, "k0", None);
+        assert_replicated(&cluster, "k29", Some(Value::integer(29)));
+    }
+
+    #[test]
+    fn restarted_node_should_recover_persisted_state() {
+        let config = RaftConfig {
+            snapshot_threshold: 10,
+            ..Default::default()
+        };
+        let mut cluster = cluster(3, config);
+        let leader = cluster.wait_for_leader().unwrap();
+        for i in 0..15 {
+            cluster.execute(hset(&format!("k{}", i), i)).unwrap();
+        }
+        cluster.run(5);
+
+        // 重启的 follower 从快照和之后的日志恢复，term 和投票也还在
+        let follower = (1..=3).find(|&id| id != leader).unwrap();
+        let term = cluster.node(follower).term();
+        cluster.restart(follower, MemTable::new()).unwrap();
+        let node = cluster.node(follower);
+        assert_eq!(node.term(), term);
+        assert!(node.snapshot_index() > 0);
+        assert_eq!(
+            node.store().get("t1", "k0").unwrap(),
+            Some(Value::integer(0))
+        );
+        cluster.run(5);
+        assert_replicated(&cluster, "k14", Some(Value::integer(14)));
+
+        // 重启 leader 之后集群继续工作，已经提交的数据不会丢
+        cluster.restart(leader, MemTable::new()).unwrap();
+        cluster.execute(hset("k15", 15)).unwrap();
+        cluster.run(5);
+        assert_replicated(&cluster, "k0", Some(Value::integer(0)));
+        assert_replicated(&cluster, "k15", Some(Value::integer(15)));
+    }
+
+    #[test]
+    fn entries_should_be_applied_at_leader_time() {
+        let mut cluster = cluster(3, RaftConfig::default());
+        let leader = cluster.wait_for_leader().unwrap();
+        let lagging = (1..=3).find(|&id| id != leader).unwrap();
+        cluster.isolate(lagging);
+
+        // k1 在 leader 执行 HINCRBY 时还没过期
+        cluster.execute_on(leader, hset("k1", 1)).unwrap();
+        let expire_at = now_ms() + 100;
+        let cmd = CommandRequest::new_hexpireat("t1", "k1", expire_at);
+        cluster.execute_on(leader, cmd).unwrap();
+        let cmd = CommandRequest::new_hincrby("t1", "k1", 1);
+        let res = cluster.execute_on(leader, cmd).unwrap();
+        assert_res_ok(res, &[Value::integer(2)], &[]);
+
+        // 落后的节点在 k1 过期之后才应用这些 entry，结果也要和 leader 一样
+        std::thread::sleep(Duration::from_millis(150));
+        cluster.heal();
+        cluster.run(30);
+        assert_eq!(
+            cluster.node(lagging).last_applied(),
+            cluster.node(leader).last_applied()
+        );
+        assert_replicated(&cluster, "k1", None);
+    }
+
+    #[test]
+    fn single_node_cluster_should_work() {
+        let mut cluster = cluster(1, RaftConfig::default());
+        let res = cluster.execute(hset("k1", 1)).unwrap();
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        // 不能在每个节点上重复执行的命令会被拒绝
+        let result = cluster.execute(CommandRequest::new_snapshot("/tmp/kv.snap"));
+        assert!(matches!(result, Err(KvError::InvalidCommand(_))));
+        let result = cluster.execute(CommandRequest::new_publish("lobby", vec![]));
+        let res: CommandResponse = result.unwrap_err().into();
+        assert_res_error(res, 400, "cannot be proposed");
+    }
+}
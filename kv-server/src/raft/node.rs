@@ -0,0 +1,657 @@
+use super::{log::RaftLog, Envelope, LogEntry, RaftConfig, RaftMessage};
+use super::{MemRaftStorage, RaftStorage};
+use crate::command_request::RequestData;
+use crate::storage::{now_ms, with_now};
+use crate::{dispatch, snapshot, with_absolute_ttl};
+use crate::{CommandRequest, CommandResponse, KvError, RaftSnapshot, Storage};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// leader 记录的每个 follower 的复制进度
+#[derive(Debug, Clone, Copy)]
+struct Progress {
+    /// 下一个要发送的 entry
+    next: u64,
+    /// 已知和 leader 一致的最后一个 entry
+    matched: u64,
+}
+
+/// 一个 raft 节点。节点本身不做网络 I/O，选举和心跳也不依赖时钟：由调用者定期调用 tick，
+/// 把收到的消息交给 step，再用 take_messages 取出要发送的消息。
+/// 已经提交的 CommandRequest 以 leader 写入时的时间通过 dispatch 应用到 store 上，
+/// 每个节点应用的结果都一样。term、投票、日志和快照在发出依赖它们的消息之前写入 RaftStorage，
+/// 重启时用 open 恢复。
+/// 目前只有进程内的 LoopbackCluster 负责传递消息，还没有接入 kvs 的网络和 Service
+pub struct RaftNode<Store> {
+    id: u64,
+    peers: Vec<u64>,
+    config: RaftConfig,
+    store: Store,
+    storage: Box<dyn RaftStorage>,
+    /// 持久化失败之后节点停止工作
+    stopped: bool,
+
+    role: Role,
+    term: u64,
+    voted_for: Option<u64>,
+    leader: Option<u64>,
+    log: RaftLog,
+    commit_index: u64,
+    last_applied: u64,
+    /// 最近一次快照的数据，对应 log 的 snapshot_index
+    snapshot: Vec<u8>,
+    /// 快照中最后一个 entry 的 timestamp
+    snapshot_timestamp: u64,
+    /// 最后一个应用的 entry 的 timestamp
+    applied_timestamp: u64,
+
+    votes: HashSet<u64>,
+    progress: BTreeMap<u64, Progress>,
+    elapsed: u64,
+    timeout: u64,
+    rng: u64,
+    msgs: Vec<Envelope>,
+
+    /// 这个节点提交的、还没有应用的请求
+    proposals: HashSet<u64>,
+    /// 已经应用的请求的结果：index -> (term, response)
+    responses: HashMap<u64, (u64, CommandResponse)>,
+}
+
+impl<Store: Storage> RaftNode<Store> {
+    /// 创建一个节点，peers 是集群中其它节点的 id。状态只保存在内存里，重启之后就丢失了
+    pub fn new(id: u64, peers: Vec<u64>, store: Store, config: RaftConfig) -> Self {
+        let mut node = Self {
+            id,
+            peers,
+            config,
+            store,
+            storage: Box::new(MemRaftStorage::default()),
+            stopped: false,
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            log: RaftLog::default(),
+            commit_index: 0,
+            last_applied: 0,
+            snapshot: vec![],
+            snapshot_timestamp: 0,
+            applied_timestamp: 0,
+            votes: HashSet::new(),
+            progress: BTreeMap::new(),
+            elapsed: 0,
+            timeout: 0,
+            // 每个节点的随机数种子不同，选举超时时间就不同，测试也是确定的
+            rng: id.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+            msgs: vec![],
+            proposals: HashSet::new(),
+            responses: HashMap::new(),
+        };
+        node.reset_timeout();
+        node
+    }
+
+    /// 用 storage 中持久化的状态创建节点，之后的状态也写入 storage。
+    /// 有快照时 store 的数据会被替换成快照的数据，否则 store 应该是空的，日志会重新应用一遍
+    pub fn open(
+        id: u64,
+        peers: Vec<u64>,
+        store: Store,
+        config: RaftConfig,
+        storage: impl RaftStorage + 'static,
+    ) -> Result<Self, KvError> {
+        let state = storage.load()?;
+        let mut node = Self::new(id, peers, store, config);
+        node.storage = Box::new(storage);
+        node.term = state.term;
+        node.voted_for = state.voted_for;
+        node.log = match state.snapshot {
+            Some(s) => {
+                with_now(s.timestamp, || node.store.reset_from(&s.data))?;
+                node.commit_index = s.index;
+                node.last_applied = s.index;
+                node.snapshot = s.data.to_vec();
+                node.snapshot_timestamp = s.timestamp;
+                node.applied_timestamp = s.timestamp;
+                RaftLog::new(s.index, s.term, state.entries)
+            }
+            None => RaftLog::new(0, 0, state.entries),
+        };
+        Ok(node)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// 这个节点知道的 leader
+    pub fn leader(&self) -> Option<u64> {
+        self.leader
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn last_applied(&self) -> u64 {
+        self.last_applied
+    }
+
+    /// 快照中最后一个 entry 的 index，0 表示还没有生成过快照
+    pub fn snapshot_index(&self) -> u64 {
+        self.log.snapshot_index()
+    }
+
+    /// 状态机，只应该用来读取数据
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// 持久化失败之后节点停止工作，需要用 open 重新启动
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// 时间前进一个 tick
+    pub fn tick(&mut self) {
+        if self.stopped {
+            return;
+        }
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader if self.elapsed >= self.config.heartbeat_tick => {
+                self.elapsed = 0;
+                self.broadcast_append();
+            }
+            Role::Leader => {}
+            _ if self.elapsed >= self.timeout => self.campaign(),
+            _ => {}
+        }
+    }
+
+    /// 提交一个命令，返回它在日志中的 (term, index)，之后用 take_response 获取结果。
+    /// 只有 leader 能提交；命令里相对的过期时间会被换成绝对时间，保证每个节点上的结果一致
+    pub fn propose(&mut self, cmd: CommandRequest) -> Result<(u64, u64), KvError> {
+        if self.stopped {
+            return Err(KvError::Internal("Raft node is stopped".into()));
+        }
+        if self.role != Role::Leader {
+            return Err(KvError::NotLeader(self.leader));
+        }
+        // 读写服务器上的文件、topic、复制和认证相关的命令不能在每个节点上重复执行
+        let invalid = matches!(
+            cmd.request_data,
+            None | Some(RequestData::Snapshot(_))
+                | Some(RequestData::Restore(_))
+                | Some(RequestData::Replicate(_))
+                | Some(RequestData::Auth(_))
+                | Some(RequestData::Handshake(_))
+        );
+        if invalid || cmd.is_topic_command() {
+            let msg = format!("{:?} cannot be proposed to raft", cmd);
+            return Err(KvError::InvalidCommand(msg));
+        }
+
+        let index = self.push_entry(Some(with_absolute_ttl(cmd)));
+        if self.stopped {
+            return Err(KvError::Internal("Raft node is stopped".into()));
+        }
+        self.proposals.insert(index);
+        self.broadcast_append();
+        self.maybe_commit();
+        Ok((self.term, index))
+    }
+
+    /// 获取 propose 返回的请求的结果；还没有应用时返回 None。
+    /// 这个位置的 entry 被新的 leader 覆盖了，说明请求没有成功，返回 NotLeader
+    pub fn take_response(
+        &mut self,
+        term: u64,
+        index: u64,
+    ) -> Option<Result<CommandResponse, KvError>> {
+        let (applied_term, res) = self.responses.remove(&index)?;
+        if applied_term == term {
+            Some(Ok(res))
+        } else {
+            Some(Err(KvError::NotLeader(self.leader)))
+        }
+    }
+
+    /// 取出所有要发送给其它节点的消息
+    pub fn take_messages(&mut self) -> Vec<Envelope> {
+        std::mem::take(&mut self.msgs)
+    }
+
+    /// 处理其它节点发来的消息
+    pub fn step(&mut self, env: Envelope) {
+        if self.stopped {
+            return;
+        }
+        let Envelope { from, msg, .. } = env;
+        let term = msg.term();
+        if term > self.term {
+            // 发现了更新的 term，不管之前是什么角色，都变成 follower
+            let leader = match msg {
+                RaftMessage::AppendEntries { .. } | RaftMessage::InstallSnapshot { .. } => {
+                    Some(from)
+                }
+                _ => None,
+            };
+            self.become_follower(term, leader);
+        } else if term < self.term {
+            // 过期的请求：回复当前的 term，让对方知道自己已经落后了
+            match msg {
+                RaftMessage::RequestVote { .. } => self.send_vote(from, false),
+                RaftMessage::AppendEntries { .. } | RaftMessage::InstallSnapshot { .. } => {
+                    self.send_append_response(from, false, self.log.last_index())
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match msg {
+            RaftMessage::RequestVote {
+                last_log_index,
+                last_log_term,
+                ..
+            } => self.handle_vote_request(from, last_log_index, last_log_term),
+            RaftMessage::VoteResponse { granted, .. } => self.handle_vote_response(from, granted),
+            RaftMessage::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                ..
+            } => self.handle_append(from, prev_log_index, prev_log_term, entries, leader_commit),
+            RaftMessage::AppendResponse {
+                success,
+                match_index,
+                ..
+            } => self.handle_append_response(from, success, match_index),
+            RaftMessage::InstallSnapshot {
+                last_index,
+                last_term,
+                timestamp,
+                data,
+                ..
+            } => self.handle_snapshot(from, last_index, last_term, timestamp, data),
+        }
+    }
+
+    fn handle_vote_request(&mut self, from: u64, last_log_index: u64, last_log_term: u64) {
+        // 只投给日志至少和自己一样新的节点，保证新的 leader 有所有已经提交的 entry
+        let up_to_date =
+            (last_log_term, last_log_index) >= (self.log.last_term(), self.log.last_index());
+        let can_vote = self.voted_for.is_none() || self.voted_for == Some(from);
+        let granted = up_to_date && can_vote;
+        if granted {
+            self.voted_for = Some(from);
+            self.elapsed = 0;
+            self.save_hard_state();
+        }
+        self.send_vote(from, granted);
+    }
+
+    fn handle_vote_response(&mut self, from: u64, granted: bool) {
+        if self.role != Role::Candidate || !granted {
+            return;
+        }
+        self.votes.insert(from);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+        }
+    }
+
+    fn handle_append(
+        &mut self,
+        from: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        mut entries: Vec<LogEntry>,
+        leader_commit: u64,
+    ) {
+        // 同一个 term 里只有一个 leader，候选人收到它的消息就放弃选举
+        if self.role != Role::Follower {
+            self.become_follower(self.term, Some(from));
+        }
+        self.leader = Some(from);
+        self.elapsed = 0;
+
+        let last_new = prev_log_index + entries.len() as u64;
+        let (mut prev_index, mut prev_term) = (prev_log_index, prev_log_term);
+        if prev_index < self.log.snapshot_index() {
+            // 快照里的 entry 都已经提交了，一定和 leader 一致，跳过它们
+            let skip = (self.log.snapshot_index() - prev_index) as usize;
+            entries.drain(..skip.min(entries.len()));
+            prev_index = self.log.snapshot_index();
+            prev_term = self.log.snapshot_term();
+        }
+        if self.log.term(prev_index) != Some(prev_term) {
+            self.send_append_response(from, false, self.log.last_index());
+            return;
+        }
+
+        let written = self.log.append(&entries);
+        if let Some(first) = written.first() {
+            let index = first.index;
+            self.persist(|s| s.append(index, written));
+        }
+        let commit = leader_commit.min(last_new);
+        if commit > self.commit_index {
+            self.commit_index = commit;
+            self.apply();
+        }
+        self.send_append_response(from, true, last_new.max(self.log.snapshot_index()));
+    }
+
+    fn handle_append_response(&mut self, from: u64, success: bool, match_index: u64) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let last_index = self.log.last_index();
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+        if success {
+            progress.matched = progress.matched.max(match_index);
+            progress.next = progress.next.max(match_index + 1);
+            let behind = progress.next <= last_index;
+            self.maybe_commit();
+            // follower 还没有追上，继续发送后面的 entry
+            if behind {
+                self.send_append(from);
+            }
+        } else {
+            // 日志不一致，往前退，直到找到一致的位置
+            progress.next = (progress.next - 1).min(match_index + 1).max(1);
+            self.send_append(from);
+        }
+    }
+
+    fn handle_snapshot(
+        &mut self,
+        from: u64,
+        last_index: u64,
+        last_term: u64,
+        timestamp: u64,
+        data: Vec<u8>,
+    ) {
+        if self.role != Role::Follower {
+            self.become_follower(self.term, Some(from));
+        }
+        self.leader = Some(from);
+        self.elapsed = 0;
+
+        // 快照里的数据已经应用过了
+        if last_index <= self.commit_index {
+            self.send_append_response(from, true, last_index);
+            return;
+        }
+        match with_now(timestamp, || self.store.reset_from(&data)) {
+            Ok(count) => {
+                info!(
+                    "Node {} installed snapshot at {} with {} pairs",
+                    self.id, last_index, count
+                );
+                let snapshot = RaftSnapshot {
+                    index: last_index,
+                    term: last_term,
+                    timestamp,
+                    data: data.clone().into(),
+                };
+                self.persist(|s| {
+                    s.save_snapshot(snapshot)?;
+                    s.append(last_index + 1, &[])
+                });
+                self.log.reset(last_index, last_term);
+                self.snapshot = data;
+                self.snapshot_timestamp = timestamp;
+                self.applied_timestamp = timestamp;
+                self.commit_index = last_index;
+                self.last_applied = last_index;
+                self.send_append_response(from, true, last_index);
+            }
+            Err(e) => {
+                warn!("Node {} failed to install snapshot: {:?}", self.id, e);
+                self.send_append_response(from, false, self.log.last_index());
+            }
+        }
+    }
+
+    fn campaign(&mut self) {
+        self.role = Role::Candidate;
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.votes = HashSet::from([self.id]);
+        self.reset_timeout();
+        self.save_hard_state();
+        debug!("Node {} starts election at term {}", self.id, self.term);
+
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+        for to in self.peers.clone() {
+            let msg = RaftMessage::RequestVote {
+                term: self.term,
+                last_log_index: self.log.last_index(),
+                last_log_term: self.log.last_term(),
+            };
+            self.send(to, msg);
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<u64>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_hard_state();
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reset_timeout();
+    }
+
+    fn become_leader(&mut self) {
+        info!("Node {} becomes leader at term {}", self.id, self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        let next = self.log.last_index() + 1;
+        self.progress = self
+            .peers
+            .iter()
+            .map(|&id| (id, Progress { next, matched: 0 }))
+            .collect();
+        // 只能通过当前 term 的 entry 提交之前的 entry，所以当选之后马上写一个空 entry
+        self.push_entry(None);
+        self.broadcast_append();
+        self.maybe_commit();
+    }
+
+    fn broadcast_append(&mut self) {
+        for to in self.peers.clone() {
+            self.send_append(to);
+        }
+    }
+
+    fn send_append(&mut self, to: u64) {
+        let progress = match self.progress.get_mut(&to) {
+            Some(progress) => progress,
+            None => return,
+        };
+        if progress.next <= self.log.snapshot_index() {
+            // follower 需要的 entry 已经被压缩了，发送快照。先假设它会成功，失败时 follower 会回复它的位置
+            progress.next = self.log.snapshot_index() + 1;
+            let msg = RaftMessage::InstallSnapshot {
+                term: self.term,
+                last_index: self.log.snapshot_index(),
+                last_term: self.log.snapshot_term(),
+                timestamp: self.snapshot_timestamp,
+                data: self.snapshot.clone(),
+            };
+            self.send(to, msg);
+            return;
+        }
+
+        let prev_log_index = progress.next - 1;
+        let msg = RaftMessage::AppendEntries {
+            term: self.term,
+            prev_log_index,
+            prev_log_term: self.log.term(prev_log_index).unwrap_or_default(),
+            entries: self
+                .log
+                .entries_from(progress.next, self.config.max_append_entries),
+            leader_commit: self.commit_index,
+        };
+        self.send(to, msg);
+    }
+
+    /// leader 检查有没有新的 entry 被大多数节点复制了
+    fn maybe_commit(&mut self) {
+        let mut matched: Vec<_> = self.progress.values().map(|p| p.matched).collect();
+        matched.push(self.log.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index > self.commit_index && self.log.term(index) == Some(self.term) {
+            self.commit_index = index;
+            self.apply();
+            // 让 follower 尽快知道新的 commit index
+            self.broadcast_append();
+        }
+    }
+
+    /// 把已经提交的 entry 应用到 store 上，需要时生成快照。
+    /// 过期时间按 entry 的 timestamp 判断，每个节点的结果都一样
+    fn apply(&mut self) {
+        if self.stopped {
+            return;
+        }
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = match self.log.get(self.last_applied) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let (term, timestamp) = (entry.term, entry.timestamp);
+            self.applied_timestamp = timestamp;
+            if let Some(cmd) = entry.cmd.clone() {
+                let res = with_now(timestamp, || dispatch(cmd, &self.store));
+                if self.proposals.remove(&self.last_applied) {
+                    self.responses.insert(self.last_applied, (term, res));
+                }
+            }
+        }
+        if self.last_applied - self.log.snapshot_index() >= self.config.snapshot_threshold {
+            self.take_snapshot();
+        }
+    }
+
+    fn take_snapshot(&mut self) {
+        let mut data = vec![];
+        let timestamp = self.applied_timestamp;
+        match with_now(timestamp, || snapshot(&self.store, &mut data)) {
+            Ok(_) => {
+                debug!("Node {} compacts log at {}", self.id, self.last_applied);
+                let snapshot = RaftSnapshot {
+                    index: self.last_applied,
+                    term: self.log.term(self.last_applied).unwrap_or_default(),
+                    timestamp,
+                    data: data.clone().into(),
+                };
+                self.persist(|s| s.save_snapshot(snapshot));
+                self.log.compact(self.last_applied);
+                self.snapshot = data;
+                self.snapshot_timestamp = timestamp;
+            }
+            Err(e) => warn!("Node {} failed to take snapshot: {:?}", self.id, e),
+        }
+    }
+
+    fn send_vote(&mut self, to: u64, granted: bool) {
+        let msg = RaftMessage::VoteResponse {
+            term: self.term,
+            granted,
+        };
+        self.send(to, msg);
+    }
+
+    fn send_append_response(&mut self, to: u64, success: bool, match_index: u64) {
+        let msg = RaftMessage::AppendResponse {
+            term: self.term,
+            success,
+            match_index,
+        };
+        self.send(to, msg);
+    }
+
+    /// leader 在日志末尾追加一个 entry 并持久化，返回它的 index
+    fn push_entry(&mut self, cmd: Option<CommandRequest>) -> u64 {
+        let entry = self.log.push(self.term, now_ms(), cmd).clone();
+        self.persist(|s| s.append(entry.index, std::slice::from_ref(&entry)));
+        entry.index
+    }
+
+    fn save_hard_state(&mut self) {
+        let (term, voted_for) = (self.term, self.voted_for);
+        self.persist(|s| s.save_hard_state(term, voted_for));
+    }
+
+    /// 写入持久化的状态。写入失败时节点停止工作，丢掉所有还没发出的消息，
+    /// 相当于节点在这里崩溃了，之后需要用 open 从持久化的状态重新启动
+    fn persist(&mut self, f: impl FnOnce(&mut dyn RaftStorage) -> Result<(), KvError>) {
+        if self.stopped {
+            return;
+        }
+        if let Err(e) = f(self.storage.as_mut()) {
+            warn!("Node {} failed to persist raft state: {:?}", self.id, e);
+            self.stopped = true;
+            self.msgs.clear();
+        }
+    }
+
+    fn send(&mut self, to: u64, msg: RaftMessage) {
+        if self.stopped {
+            return;
+        }
+        self.msgs.push(Envelope {
+            from: self.id,
+            to,
+            msg,
+        });
+    }
+
+    fn quorum(&self) -> usize {
+        let size = self.peers.len() + 1;
+        size / 2 + 1
+    }
+
+    /// 选举超时时间在 [election_tick, 2 * election_tick) 之间随机（xorshift）
+    fn reset_timeout(&mut self) {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let tick = self.config.election_tick;
+        self.timeout = tick + self.rng % tick;
+        self.elapsed = 0;
+    }
+}
@@ -0,0 +1,251 @@
+use super::LogEntry;
+use crate::{KvError, RaftEntry, RaftHardState, RaftSnapshot};
+use prost::Message;
+use sled::{Batch, Db, Tree};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// 存放日志的 tree，key 是大端序的 index
+const LOG_TREE: &str = "log";
+const HARD_STATE_KEY: &str = "hard_state";
+const SNAPSHOT_KEY: &str = "snapshot";
+
+/// 节点重启时从持久化存储读出的状态
+#[derive(Debug, Clone, Default)]
+pub struct RaftState {
+    pub term: u64,
+    pub voted_for: Option<u64>,
+    /// 最近一次快照
+    pub snapshot: Option<RaftSnapshot>,
+    /// 快照之后的日志，index 是连续的
+    pub entries: Vec<LogEntry>,
+}
+
+/// raft 需要持久化的状态：term、投票、日志和快照。
+/// 节点在发出依赖这些状态的消息之前写入，写入的函数返回时数据必须已经落盘
+pub trait RaftStorage: Send {
+    /// 读出所有持久化的状态
+    fn load(&self) -> Result<RaftState, KvError>;
+    /// 保存当前的 term 和投票
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<u64>) -> Result<(), KvError>;
+    /// 删掉 index 不小于 from 的 entry，再写入 entries
+    fn append(&mut self, from: u64, entries: &[LogEntry]) -> Result<(), KvError>;
+    /// 保存快照，删掉快照包含的 entry
+    fn save_snapshot(&mut self, snapshot: RaftSnapshot) -> Result<(), KvError>;
+}
+
+/// 保存在内存里的 RaftStorage，不会落盘，用于测试。
+/// clone 出来的共享同一份数据，用同一份数据重新创建节点可以模拟节点重启
+#[derive(Debug, Clone, Default)]
+pub struct MemRaftStorage(Arc<Mutex<RaftState>>);
+
+impl MemRaftStorage {
+    fn state(&self) -> MutexGuard<'_, RaftState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl RaftStorage for MemRaftStorage {
+    fn load(&self) -> Result<RaftState, KvError> {
+        Ok(self.state().clone())
+    }
+
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<u64>) -> Result<(), KvError> {
+        let mut state = self.state();
+        state.term = term;
+        state.voted_for = voted_for;
+        Ok(())
+    }
+
+    fn append(&mut self, from: u64, entries: &[LogEntry]) -> Result<(), KvError> {
+        let mut state = self.state();
+        state.entries.retain(|entry| entry.index < from);
+        state.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn save_snapshot(&mut self, snapshot: RaftSnapshot) -> Result<(), KvError> {
+        let mut state = self.state();
+        state.entries.retain(|entry| entry.index > snapshot.index);
+        state.snapshot = Some(snapshot);
+        Ok(())
+    }
+}
+
+/// 使用 sled 的 RaftStorage，每次写入之后 flush
+#[derive(Debug)]
+pub struct SledRaftStorage {
+    db: Db,
+    log: Tree,
+}
+
+impl SledRaftStorage {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        // 每次写入之后都会 flush，不需要后台线程定期 flush
+        let db = sled::Config::new().path(path).flush_every_ms(None).open()?;
+        let log = db.open_tree(LOG_TREE)?;
+        Ok(Self { db, log })
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl RaftStorage for SledRaftStorage {
+    fn load(&self) -> Result<RaftState, KvError> {
+        let mut state = RaftState::default();
+        if let Some(v) = self.db.get(HARD_STATE_KEY)? {
+            let hard_state = RaftHardState::decode(v.as_ref())?;
+            state.term = hard_state.term;
+            state.voted_for = (hard_state.voted_for != 0).then_some(hard_state.voted_for);
+        }
+        if let Some(v) = self.db.get(SNAPSHOT_KEY)? {
+            state.snapshot = Some(RaftSnapshot::decode(v.as_ref())?);
+        }
+        // 保存快照之后、删除日志之前崩溃的话，快照包含的 entry 还在，跳过它们
+        let start = state.snapshot.as_ref().map_or(0, |s| s.index) + 1;
+        for item in self.log.range(start.to_be_bytes()..) {
+            let (_, v) = item?;
+            state.entries.push(RaftEntry::decode(v.as_ref())?.into());
+        }
+        Ok(state)
+    }
+
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<u64>) -> Result<(), KvError> {
+        let hard_state = RaftHardState {
+            term,
+            voted_for: voted_for.unwrap_or_default(),
+        };
+        self.db.insert(HARD_STATE_KEY, hard_state.encode_to_vec())?;
+        self.flush()
+    }
+
+    fn append(&mut self, from: u64, entries: &[LogEntry]) -> Result<(), KvError> {
+        // 删除和写入在同一个 batch 里，要么都生效，要么都不生效
+        let mut batch = Batch::default();
+        for key in self.log.range(from.to_be_bytes()..).keys() {
+            batch.remove(key?);
+        }
+        for entry in entries {
+            let value = RaftEntry::from(entry).encode_to_vec();
+            batch.insert(&entry.index.to_be_bytes(), value);
+        }
+        self.log.apply_batch(batch)?;
+        self.flush()
+    }
+
+    fn save_snapshot(&mut self, snapshot: RaftSnapshot) -> Result<(), KvError> {
+        let end = snapshot.index.to_be_bytes();
+        self.db.insert(SNAPSHOT_KEY, snapshot.encode_to_vec())?;
+        self.flush()?;
+        let mut batch = Batch::default();
+        for key in self.log.range(..=end).keys() {
+            batch.remove(key?);
+        }
+        self.log.apply_batch(batch)?;
+        self.flush()
+    }
+}
+
+impl From<&LogEntry> for RaftEntry {
+    fn from(entry: &LogEntry) -> Self {
+        Self {
+            term: entry.term,
+            index: entry.index,
+            timestamp: entry.timestamp,
+            cmd: entry.cmd.clone(),
+        }
+    }
+}
+
+impl From<RaftEntry> for LogEntry {
+    fn from(entry: RaftEntry) -> Self {
+        Self {
+            term: entry.term,
+            index: entry.index,
+            timestamp: entry.timestamp,
+            cmd: entry.cmd,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CommandRequest;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn mem_raft_storage_should_work() {
+        test_raft_storage(MemRaftStorage::default());
+    }
+
+    #[test]
+    fn sled_raft_storage_should_survive_reopen() {
+        let dir = tempdir().unwrap();
+        test_raft_storage(SledRaftStorage::new(dir.path()).unwrap());
+        // sled 在后台线程里释放文件锁，重新打开之前可能要等一会
+        let storage = (0..100)
+            .find_map(|_| {
+                let storage = SledRaftStorage::new(dir.path()).ok();
+                if storage.is_none() {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                storage
+            })
+            .unwrap();
+        let state = storage.load().unwrap();
+        assert_eq!((state.term, state.voted_for), (3, None));
+        assert_eq!(state.snapshot.unwrap().index, 2);
+        assert_eq!(indexes(&state.entries), [3, 4]);
+    }
+
+    fn entry(term: u64, index: u64) -> LogEntry {
+        LogEntry {
+            term,
+            index,
+            timestamp: 1000 + index,
+            cmd: Some(CommandRequest::new_hget("t1", "k1")),
+        }
+    }
+
+    fn indexes(entries: &[LogEntry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.index).collect()
+    }
+
+    fn test_raft_storage(mut storage: impl RaftStorage) {
+        let state = storage.load().unwrap();
+        assert_eq!((state.term, state.voted_for), (0, None));
+        assert!(state.snapshot.is_none() && state.entries.is_empty());
+
+        storage.save_hard_state(2, Some(3)).unwrap();
+        storage
+            .append(1, &[entry(1, 1), entry(2, 2), entry(2, 3)])
+            .unwrap();
+        let state = storage.load().unwrap();
+        assert_eq!((state.term, state.voted_for), (2, Some(3)));
+        assert_eq!(state.entries[1], entry(2, 2));
+
+        // 冲突的 entry 和之后的都被替换
+        storage.append(3, &[entry(3, 3), entry(3, 4)]).unwrap();
+        storage.save_hard_state(3, None).unwrap();
+        let state = storage.load().unwrap();
+        assert_eq!(indexes(&state.entries), [1, 2, 3, 4]);
+        assert_eq!(state.entries[2].term, 3);
+
+        // 快照之前的 entry 被删掉
+        let snapshot = RaftSnapshot {
+            index: 2,
+            term: 2,
+            timestamp: 1002,
+            data: vec![1, 2, 3].into(),
+        };
+        storage.save_snapshot(snapshot.clone()).unwrap();
+        let state = storage.load().unwrap();
+        assert_eq!(state.snapshot, Some(snapshot));
+        assert_eq!(indexes(&state.entries), [3, 4]);
+    }
+}
@@ -1,18 +1,35 @@
+mod auth;
 mod command_service;
+mod middleware;
+mod replication;
+mod topic;
+mod topic_service;
 
+use crate::command_request::RequestData;
+use crate::metrics::METRICS;
 use crate::*;
-pub use command_service::*;
-use std::sync::Arc;
-use tracing::debug;
+pub use auth::{Authenticator, Permission, ALL_TABLES};
+use futures::{stream, FutureExt, StreamExt};
+pub use middleware::{ConnectionContext, Middleware};
+use middleware::{FnHook, Middlewares};
+pub(crate) use replication::with_absolute_ttl;
+use replication::Replicator;
+use std::future::Future;
+use std::path::PathBuf;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::mpsc, task::JoinHandle};
+pub use topic::*;
+pub use topic_service::*;
+use tracing::{debug, info, warn};
 
 /// 对 Command 的处理的抽象
 pub trait CommandService {
     /// 处理 Command，返回 Response
-    fn execute(self, store: &impl Storage) -> CommandResponse;
+    fn execute(self, store: &impl AsyncStorage) -> impl Future<Output = CommandResponse>;
 }
 
 /// Service 数据结构
-pub struct Service<Store = MemTable> {
+pub struct Service<Store = Inline<Arc<MemTable>>> {
     inner: Arc<ServiceInner<Store>>,
 }
 impl<Store> Clone for Service<Store> {
@@ -26,42 +43,88 @@ impl<Store> Clone for Service<Store> {
 /// Service 内部数据结构
 pub struct ServiceInner<Store> {
     store: Store,
-    on_received: Vec<fn(&CommandRequest) -> Result<(), KvError>>,
-    on_executed: Vec<fn(&CommandResponse) -> Result<(), KvError>>,
-    on_before_send: Vec<fn(&mut CommandResponse) -> Result<(), KvError>>,
-    on_after_send: Vec<fn() -> Result<(), KvError>>,
+    broadcaster: Arc<Broadcaster>,
+    /// 作为 primary 时，把修改同步给 replica
+    replicator: Option<Replicator>,
+    /// 作为 replica 时，拒绝普通客户端修改数据
+    read_only: bool,
+    /// 设置之后，请求必须来自认证过的用户，并且符合它的 ACL
+    auth: Option<Authenticator>,
+    /// SNAPSHOT / RESTORE 只能读写这个目录中的文件，不设置时这两个命令会失败
+    snapshot_dir: Option<PathBuf>,
+    middlewares: Middlewares,
 }
 
-impl<Store: Storage> ServiceInner<Store> {
+impl<Store: AsyncStorage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
             store,
-            on_received: vec![],
-            on_executed: vec![],
-            on_before_send: vec![],
-            on_after_send: vec![],
+            broadcaster: Default::default(),
+            replicator: None,
+            read_only: false,
+            auth: None,
+            snapshot_dir: None,
+            middlewares: Default::default(),
         }
     }
 
-    pub fn fn_received(mut self, f: fn(&CommandRequest) -> Result<(), KvError>) -> Self {
-        self.on_received.push(f);
+    /// 允许 replica 连接到这个 service，所有修改数据的命令会串行执行
+    pub fn enable_replication(mut self) -> Self {
+        self.replicator = Some(Replicator::default());
         self
     }
-    pub fn fn_executed(mut self, f: fn(&CommandResponse) -> Result<(), KvError>) -> Self {
-        self.on_executed.push(f);
+
+    /// 作为 replica 运行，普通客户端只能读取数据
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
         self
     }
-    pub fn fn_before_send(mut self, f: fn(&mut CommandResponse) -> Result<(), KvError>) -> Self {
-        self.on_before_send.push(f);
+
+    /// 启用认证和 ACL
+    pub fn with_auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Some(auth);
         self
     }
-    pub fn fn_after_send(mut self, f: fn() -> Result<(), KvError>) -> Self {
-        self.on_after_send.push(f);
+
+    /// 允许 SNAPSHOT / RESTORE 读写这个目录中的快照文件
+    pub fn with_snapshot_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.snapshot_dir = Some(dir.into());
         self
     }
+
+    /// 添加一个中间件，按添加的顺序调用
+    pub fn middleware(mut self, m: impl Middleware) -> Self {
+        self.middlewares.push(m);
+        self
+    }
+
+    pub fn fn_received(
+        self,
+        f: impl Fn(&CommandRequest) -> Result<(), KvError> + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware(FnHook::Received(Box::new(f)))
+    }
+    pub fn fn_executed(
+        self,
+        f: impl Fn(&CommandResponse) -> Result<(), KvError> + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware(FnHook::Executed(Box::new(f)))
+    }
+    pub fn fn_before_send(
+        self,
+        f: impl Fn(&mut CommandResponse) -> Result<(), KvError> + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware(FnHook::BeforeSend(Box::new(f)))
+    }
+    pub fn fn_after_send(
+        self,
+        f: impl Fn() -> Result<(), KvError> + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware(FnHook::AfterSend(Box::new(f)))
+    }
 }
 
-impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
+impl<Store: AsyncStorage> From<ServiceInner<Store>> for Service<Store> {
     fn from(inner: ServiceInner<Store>) -> Self {
         Self {
             inner: Arc::new(inner),
@@ -69,103 +132,466 @@ impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
     }
 }
 
-impl<Store: Storage> Service<Store> {
+impl<Store: AsyncStorage> Service<Store> {
     pub fn new(store: Store) -> Self {
         Self {
             inner: Arc::new(ServiceInner::new(store)),
         }
     }
-    pub fn execute(&self, cmd: CommandRequest) -> CommandResponse {
+    /// 以匿名的身份执行命令，启用了认证时会返回 401
+    pub async fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
+        self.execute_with(&ConnectionContext::default(), cmd).await
+    }
+
+    /// 在一个连接的上下文里执行命令，启用了认证时先按 ctx.principal 检查 ACL
+    pub async fn execute_with(
+        &self,
+        ctx: &ConnectionContext,
+        cmd: CommandRequest,
+    ) -> StreamingResponse {
         debug!("Got request: {:?}", cmd);
-        if let Err(e) = self.inner.on_received.notify(&cmd) {
-            return e.into();
+        METRICS
+            .requests
+            .with_label_values(&[cmd.command_name()])
+            .inc();
+        let res = self.respond(ctx, cmd).await;
+        Box::pin(res.inspect(|res| {
+            let status = res.status.to_string();
+            METRICS.responses.with_label_values(&[&status]).inc();
+        }))
+    }
+
+    async fn respond(&self, ctx: &ConnectionContext, cmd: CommandRequest) -> StreamingResponse {
+        let middlewares = &self.inner.middlewares;
+        if let Some(res) = self.on_received(ctx, &cmd).await {
+            return once(res);
         }
-        let mut res = dispatch(cmd, &self.inner.store);
-        debug!("Executed response: {:?}", res);
-        if let Err(e) = self.inner.on_executed.notify(&res) {
-            return e.into();
+        if let Err(e) = self.authorize(ctx.principal.as_deref(), &cmd) {
+            return once(middlewares.respond(ctx, e.into()).await);
         }
-        if let Err(e) = self.inner.on_before_send.notify(&mut res) {
-            return e.into();
+        if cmd.is_topic_command() {
+            let stream = dispatch_stream(cmd, Arc::clone(&self.inner.broadcaster));
+            return middlewares.respond_stream(ctx, stream);
         }
-        if !self.inner.on_before_send.is_empty() {
-            debug!("Modified response: {:?}", res);
+        if let Some(RequestData::Hgetall(param)) = &cmd.request_data {
+            if param.chunk_size > 0 {
+                let stream = param.clone().execute_chunked(&self.inner.store).await;
+                return middlewares.respond_stream(ctx, stream);
+            }
         }
-        res
+        let res = match &self.inner.replicator {
+            _ if !cmd.is_write_command() => self.run(cmd).await,
+            _ if self.inner.read_only => KvError::ReadOnly.into(),
+            Some(replicator) => replicator.execute(cmd, |cmd| self.run(cmd)).await,
+            None => self.run(cmd).await,
+        };
+        debug!("Executed response: {:?}", res);
+        once(middlewares.respond(ctx, res).await)
     }
-}
 
-/// 事件通知（不可变事件）
-pub trait Notify<Arg> {
-    fn notify(&self, arg: &Arg) -> Result<(), KvError>;
-}
-/// 事件通知（可变事件）
-pub trait NotifyMut<Arg> {
-    fn notify(&self, arg: &mut Arg) -> Result<(), KvError>;
-}
+    /// 让中间件检查收到的请求。网络层自己处理的请求（AUTH、HANDSHAKE、RESP 的 PING 等）也要先经过它，
+    /// 返回 Some 时请求被中间件拦下，返回的 response 已经经过了 on_executed 和 on_before_send
+    pub async fn on_received(
+        &self,
+        ctx: &ConnectionContext,
+        cmd: &CommandRequest,
+    ) -> Option<CommandResponse> {
+        let middlewares = &self.inner.middlewares;
+        let res = middlewares.on_received(ctx, cmd).await?;
+        Some(middlewares.respond(ctx, res).await)
+    }
+
+    /// 网络层自己处理的请求的 response 在发送之前也要经过 on_executed 和 on_before_send
+    pub async fn before_send(
+        &self,
+        ctx: &ConnectionContext,
+        res: CommandResponse,
+    ) -> CommandResponse {
+        self.inner.middlewares.respond(ctx, res).await
+    }
 
-impl<Arg> Notify<Arg> for Vec<fn(&Arg) -> Result<(), KvError>> {
-    #[inline]
-    fn notify(&self, arg: &Arg) -> Result<(), KvError> {
-        for f in self {
-            f(arg)?
+    /// 在存储上执行命令，快照命令使用 Service 配置的快照目录
+    async fn run(&self, cmd: CommandRequest) -> CommandResponse {
+        let (store, dir) = (&self.inner.store, self.inner.snapshot_dir.as_deref());
+        match cmd.request_data {
+            Some(RequestData::Snapshot(param)) => param.execute_in(dir, store).await,
+            Some(RequestData::Restore(param)) => param.execute_in(dir, store).await,
+            _ => dispatch_async(cmd, store).await,
         }
-        Ok(())
+    }
+
+    /// 网络层把 response 写到连接上之后调用
+    pub async fn after_send(&self, ctx: &ConnectionContext, res: &CommandResponse) {
+        self.inner.middlewares.on_after_send(ctx, res).await;
     }
 }
-impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg) -> Result<(), KvError>> {
-    #[inline]
-    fn notify(&self, arg: &mut Arg) -> Result<(), KvError> {
-        for f in self {
-            f(arg)?
+
+impl<Store: AsyncStorage> Service<Store> {
+    /// 认证一个连接，成功返回用户名
+    pub fn authenticate(&self, auth: &Auth) -> Result<String, KvError> {
+        match &self.inner.auth {
+            Some(authenticator) => authenticator.authenticate(auth),
+            None => Err(KvError::InvalidCommand(
+                "Authentication is not enabled".into(),
+            )),
+        }
+    }
+
+    /// 检查 principal 是否可以执行这个命令，没有启用认证时总是可以
+    pub fn authorize(&self, principal: Option<&str>, cmd: &CommandRequest) -> Result<(), KvError> {
+        match &self.inner.auth {
+            Some(authenticator) => authenticator.authorize(principal, cmd),
+            None => Ok(()),
         }
-        Ok(())
+    }
+
+    /// primary 端：注册一个 replica，返回当前数据的快照，以及之后所有修改的接收端
+    pub async fn register_replica(
+        &self,
+    ) -> Result<(Vec<u8>, mpsc::Receiver<CommandRequest>), KvError> {
+        let replicator = self
+            .inner
+            .replicator
+            .as_ref()
+            .ok_or_else(|| KvError::InvalidCommand("Replication is not enabled".into()))?;
+        let snapshot = async { self.inner.store.snapshot().await.map(|(_, data)| data) };
+        replicator.register(snapshot).await
+    }
+
+    /// replica 端：用 primary 发来的快照替换现有的数据
+    pub async fn load_snapshot(&self, data: Vec<u8>) -> Result<u64, KvError> {
+        self.inner.store.reset_from(data).await
+    }
+
+    /// replica 端：执行 primary 发来的修改，不受只读的限制
+    pub async fn apply_replicated(&self, cmd: CommandRequest) -> CommandResponse {
+        dispatch_async(cmd, &self.inner.store).await
     }
 }
 
-// 从 Request 中得到 Response，目前处理 HGET/HGETALL/HSET
+impl<Store: AsyncStorage + Send + Sync + 'static> Service<Store> {
+    /// 启动后台任务，每隔 period 清除一次过期的 key；Service 被释放之后任务自动退出
+    pub fn spawn_expiry_task(&self, period: Duration) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let inner = match inner.upgrade() {
+                    Some(inner) => inner,
+                    None => break,
+                };
+                match inner.store.purge_expired().await {
+                    Ok(0) => {}
+                    Ok(n) => debug!("Purged {} expired keys", n),
+                    Err(e) => warn!("Failed to purge expired keys: {:?}", e),
+                }
+            }
+        })
+    }
+}
+
+impl Service<Inline<Arc<MemTable>>> {
+    /// 在后台重写 MemTable 的 AOF，重写期间不影响其它请求
+    pub fn spawn_aof_rewrite(&self) -> JoinHandle<Result<(), KvError>> {
+        let inner = Arc::clone(&self.inner);
+        tokio::task::spawn_blocking(move || inner.store.inner().rewrite_aof())
+    }
+
+    /// 启动后台任务，每隔 period 检查一次 AOF，至少有 min_size 字节并且比上次重写之后大了一倍时重写；
+    /// Service 被释放之后任务自动退出
+    pub fn spawn_aof_rewrite_task(&self, period: Duration, min_size: u64) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let service = match inner.upgrade() {
+                    Some(inner) => Service { inner },
+                    None => break,
+                };
+                if !service.inner.store.inner().aof_should_rewrite(min_size) {
+                    continue;
+                }
+                info!("Rewriting AOF");
+                match service.spawn_aof_rewrite().await {
+                    Ok(Ok(())) => info!("AOF rewritten"),
+                    Ok(Err(e)) => warn!("Failed to rewrite AOF: {:?}", e),
+                    Err(e) => warn!("AOF rewrite task failed: {:?}", e),
+                }
+            }
+        })
+    }
+}
+
+/// 把一个 CommandResponse 包装成只有一个元素的流
+fn once(res: CommandResponse) -> StreamingResponse {
+    Box::pin(stream::once(async { Arc::new(res) }))
+}
+
+// 在同步的 Storage 上执行 Request，比如在存储的事务里
 pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
+    // Inline 的 future 都已经完成，不会 pending；万一 pending 了，返回错误而不是 panic
+    dispatch_async(cmd, &Inline(store))
+        .now_or_never()
+        .unwrap_or_else(|| KvError::Internal("Dispatch on a sync storage pended".into()).into())
+}
+
+// 从 Request 中得到 Response，目前处理 HGET/HGETALL/HSET
+pub async fn dispatch_async(cmd: CommandRequest, store: &impl AsyncStorage) -> CommandResponse {
+    // timer 在 drop 时记录耗时
+    let _timer = METRICS
+        .latency
+        .with_label_values(&[cmd.command_name()])
+        .start_timer();
     match cmd.request_data {
-        Some(RequestData::Hget(param)) => param.execute(store),
-        Some(RequestData::Hgetall(param)) => param.execute(store),
-        Some(RequestData::Hmget(param)) => param.execute(store),
-        Some(RequestData::Hset(param)) => param.execute(store),
-        Some(RequestData::Hmset(param)) => param.execute(store),
-        Some(RequestData::Hdel(param)) => param.execute(store),
-        Some(RequestData::Hmdel(param)) => param.execute(store),
-        Some(RequestData::Hexist(param)) => param.execute(store),
-        Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Hget(param)) => param.execute(store).await,
+        Some(RequestData::Hgetall(param)) => param.execute(store).await,
+        Some(RequestData::Hmget(param)) => param.execute(store).await,
+        Some(RequestData::Hscan(param)) => param.execute(store).await,
+        Some(RequestData::Hrange(param)) => param.execute(store).await,
+        Some(RequestData::Hset(param)) => param.execute(store).await,
+        Some(RequestData::Hmset(param)) => param.execute(store).await,
+        Some(RequestData::Hdel(param)) => param.execute(store).await,
+        Some(RequestData::Hmdel(param)) => param.execute(store).await,
+        Some(RequestData::ListTables(param)) => param.execute(store).await,
+        Some(RequestData::DropTable(param)) => param.execute(store).await,
+        Some(RequestData::RenameTable(param)) => param.execute(store).await,
+        Some(RequestData::Hlen(param)) => param.execute(store).await,
+        Some(RequestData::Hexist(param)) => param.execute(store).await,
+        Some(RequestData::Hmexist(param)) => param.execute(store).await,
+        Some(RequestData::Hexpire(param)) => param.execute(store).await,
+        Some(RequestData::Hexpireat(param)) => param.execute(store).await,
+        Some(RequestData::Httl(param)) => param.execute(store).await,
+        Some(RequestData::Hpersist(param)) => param.execute(store).await,
+        Some(RequestData::Hincrby(param)) => param.execute(store).await,
+        Some(RequestData::Hincrbyfloat(param)) => param.execute(store).await,
+        Some(RequestData::Watch(param)) => param.execute(store).await,
+        Some(RequestData::Transaction(param)) => param.execute(store).await,
+        Some(RequestData::Snapshot(param)) => param.execute_in(None, store).await,
+        Some(RequestData::Restore(param)) => param.execute_in(None, store).await,
+        Some(RequestData::Replicate(_)) => {
+            KvError::InvalidCommand("Replicate must be the first request of a connection".into())
+                .into()
+        }
+        Some(RequestData::Auth(_)) => {
+            KvError::InvalidCommand("Auth must be handled by the connection".into()).into()
+        }
+        Some(RequestData::Handshake(_)) => {
+            KvError::InvalidCommand("Handshake must be handled by the connection".into()).into()
+        }
+        Some(RequestData::Subscribe(_))
+        | Some(RequestData::Unsubscribe(_))
+        | Some(RequestData::Publish(_)) => {
+            KvError::InvalidCommand("Topic command should be handled by dispatch_stream".into())
+                .into()
+        }
         None => KvError::InvalidCommand("Request has no data".into()).into(),
     }
 }
 
+// 从 Request 中得到 Response 的流，处理 SUBSCRIBE/UNSUBSCRIBE/PUBLISH
+pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingResponse {
+    match cmd.request_data {
+        Some(RequestData::Subscribe(param)) => param.execute(topic),
+        Some(RequestData::Unsubscribe(param)) => param.execute(topic),
+        Some(RequestData::Publish(param)) => param.execute(topic),
+        // 只有 topic 相关的命令才会走到这里
+        _ => once(KvError::InvalidCommand("Not a topic command".into()).into()),
+    }
+}
+
+#[cfg(test)]
+use crate::{Kvpair, Value};
+
+// 测试成功返回的结果
+#[cfg(test)]
+pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
+    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(res.status, 200);
+    assert_eq!(res.message, "");
+    assert_eq!(res.values, values);
+    assert_eq!(res.pairs, pairs);
+}
+// 测试失败返回的结果
+#[cfg(test)]
+pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
+    assert_eq!(res.status, code);
+    assert!(res.message.contains(msg));
+    assert_eq!(res.values, &[]);
+    assert_eq!(res.pairs, &[]);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{MemTable, Value};
+    use futures::future::{self, BoxFuture};
+    use futures::StreamExt;
     use http::StatusCode;
-    use std::thread;
+    use std::convert::TryInto;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use tracing::info;
 
-    #[test]
-    fn service_should_works() {
+    #[tokio::test]
+    async fn service_should_works() {
         // 我们需要一个 service 结构至少包含 Storage
-        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let service: Service = ServiceInner::new(Inline::new(MemTable::default())).into();
         // service 可以运行在多线程环境下，它的 clone 应该是轻量级的
         let cloned = service.clone();
-        // 创建一个线程，在 table t1 中写入 k1, v1
-        let handle = thread::spawn(move || {
-            let res = cloned.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
-            assert_res_ok(res, &[Value::default()], &[]);
-        });
-        handle.join().unwrap();
-        // 在当前线程下读取 table t1 的 k1，应该返回 v1
-        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
-        assert_res_ok(res, &["v1".into()], &[]);
-    }
-
-    #[test]
-    fn event_registration_should_work() {
+        // 创建一个 task，在 table t1 中写入 k1, v1
+        tokio::spawn(async move {
+            let mut res = cloned
+                .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+                .await;
+            let data = res.next().await.unwrap();
+            assert_res_ok((*data).clone(), &[Value::default()], &[]);
+        })
+        .await
+        .unwrap();
+        // 在当前 task 下读取 table t1 的 k1，应该返回 v1
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1")).await;
+        let data = res.next().await.unwrap();
+        assert_res_ok((*data).clone(), &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn service_pub_sub_should_work() {
+        let service: Service = ServiceInner::new(Inline::new(MemTable::default())).into();
+        let mut sub = service
+            .execute(CommandRequest::new_subscribe("lobby"))
+            .await;
+        let id: i64 = sub.next().await.unwrap().as_ref().try_into().unwrap();
+
+        let mut res = service
+            .execute(CommandRequest::new_publish("lobby", vec!["hi".into()]))
+            .await;
+        let data = res.next().await.unwrap();
+        assert_res_ok((*data).clone(), &[], &[]);
+
+        // 订阅者收到 publish 的数据
+        let data = sub.next().await.unwrap();
+        assert_res_ok((*data).clone(), &["hi".into()], &[]);
+
+        // 取消订阅后，订阅的流就结束了
+        let mut res = service
+            .execute(CommandRequest::new_unsubscribe("lobby", id as _))
+            .await;
+        let data = res.next().await.unwrap();
+        assert_res_ok((*data).clone(), &[], &[]);
+        assert!(sub.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn service_should_return_chunked_hgetall() {
+        let service: Service = ServiceInner::new(Inline::new(MemTable::default())).into();
+        for i in 0..5 {
+            let key = format!("k{}", i);
+            let cmd = CommandRequest::new_hset("t1", key, Value::integer(i));
+            service.execute(cmd).await.next().await.unwrap();
+        }
+
+        let res = service
+            .execute(CommandRequest::new_hgetall_chunked("t1", 2))
+            .await;
+        let chunks: Vec<_> = res.collect().await;
+        // 2 + 2 + 1，加上一个结束标记
+        let sizes: Vec<_> = chunks.iter().map(|v| v.pairs.len()).collect();
+        assert_eq!(sizes, vec![2, 2, 1, 0]);
+        assert!(chunks[..3].iter().all(|v| v.status == 200 && !v.end));
+        assert!(chunks[3].end);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn service_should_work_with_sled() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlockingStorage::new(SledDb::new(dir.path()));
+        let service: Service<_> = ServiceInner::new(store).into();
+        let pairs = vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "v2".into()),
+            Kvpair::new("x1", "v3".into()),
+        ];
+        let cmd = CommandRequest::new_hmset("t1", pairs);
+        service.execute(cmd).await.next().await.unwrap();
+
+        let cmd = CommandRequest::new_hget("t1", "k2");
+        let res = service.execute(cmd).await.next().await.unwrap();
+        assert_res_ok((*res).clone(), &["v2".into()], &[]);
+        let cmd = CommandRequest::new_hscan("t1", "", 0, "k*");
+        let res = service.execute(cmd).await.next().await.unwrap();
+        assert_eq!(res.pairs.len(), 2);
+        // 分块的 HGETALL 从 blocking 线程上读出的流中取数据
+        let cmd = CommandRequest::new_hgetall_chunked("t1", 2);
+        let chunks: Vec<_> = service.execute(cmd).await.collect().await;
+        let sizes: Vec<_> = chunks.iter().map(|v| v.pairs.len()).collect();
+        assert_eq!(sizes, vec![2, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn expiry_task_should_purge_expired_keys() {
+        let service: Service = ServiceInner::new(Inline::new(MemTable::default())).into();
+        let ttl = Duration::from_millis(10);
+        let store = service.inner.store.inner();
+        store
+            .set_with_ttl("t1", "k1".into(), "v1".into(), ttl)
+            .unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+
+        let handle = service.spawn_expiry_task(Duration::from_millis(20));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // 后台任务已经清理过了
+        let store = service.inner.store.inner();
+        assert_eq!(store.purge_expired().unwrap(), 0);
+        assert!(store.contains("t1", "k2").unwrap());
+
+        // Service 释放之后，后台任务退出
+        drop(service);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshot_should_use_configured_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Inline::new(MemTable::new());
+        let service: Service = ServiceInner::new(store)
+            .with_snapshot_dir(dir.path())
+            .into();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        service.execute(cmd).await.next().await.unwrap();
+
+        let cmd = CommandRequest::new_snapshot("kv.snapshot");
+        let res = service.execute(cmd).await.next().await.unwrap();
+        assert_res_ok((*res).clone(), &[Value::integer(1)], &[]);
+        assert!(dir.path().join("kv.snapshot").exists());
+        let cmd = CommandRequest::new_restore("kv.snapshot");
+        let res = service.execute(cmd).await.next().await.unwrap();
+        assert_res_ok((*res).clone(), &[Value::integer(1)], &[]);
+    }
+
+    #[tokio::test]
+    async fn aof_rewrite_task_should_compact_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        let store = MemTable::with_aof(&path, FsyncPolicy::Never).unwrap();
+        let service: Service = ServiceInner::new(Inline::new(store)).into();
+        let store = service.inner.store.inner();
+        for i in 0..100 {
+            store.set("t1", "k1".into(), Value::integer(i)).unwrap();
+        }
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        // 文件比打开时大了一倍，后台任务会重写，只留下最新的状态
+        let handle = service.spawn_aof_rewrite_task(Duration::from_millis(20), 0);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(std::fs::metadata(&path).unwrap().len() < size);
+
+        drop(service);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn event_registration_should_work() {
         fn b(cmd: &CommandRequest) -> Result<(), KvError> {
             info!("Got {:?}", cmd);
             Ok(())
@@ -182,37 +608,89 @@ mod tests {
             info!("Data is sent");
             Ok(())
         }
-        let service: Service = ServiceInner::new(MemTable::default())
+        let service: Service = ServiceInner::new(Inline::new(MemTable::default()))
             .fn_received(|_: &CommandRequest| Ok(()))
             .fn_received(b)
             .fn_executed(c)
             .fn_before_send(d)
             .fn_after_send(e)
             .into();
-        let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
-        assert_eq!(res.status, StatusCode::CREATED.as_u16() as _);
+        let mut res = service
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await;
+        let res = res.next().await.unwrap();
+        assert_eq!(res.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(res.message, "");
         assert_eq!(res.values, vec![Value::default()]);
     }
-}
-use crate::command_request::RequestData;
-#[cfg(test)]
-use crate::{Kvpair, Value};
 
-// 测试成功返回的结果
-#[cfg(test)]
-pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
-    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    assert_eq!(res.status, 200);
-    assert_eq!(res.message, "");
-    assert_eq!(res.values, values);
-    assert_eq!(res.pairs, pairs);
-}
-// 测试失败返回的结果
-#[cfg(test)]
-pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
-    assert_eq!(res.status, code);
-    assert!(res.message.contains(msg));
-    assert_eq!(res.values, &[]);
-    assert_eq!(res.pairs, &[]);
+    #[tokio::test]
+    async fn middleware_should_hold_state_and_short_circuit() {
+        // 拒绝匿名连接写 t2，并且统计看到的 response
+        struct Guard(Arc<AtomicUsize>);
+        impl Middleware for Guard {
+            fn on_received<'a>(
+                &'a self,
+                ctx: &'a ConnectionContext,
+                cmd: &'a CommandRequest,
+            ) -> BoxFuture<'a, Option<CommandResponse>> {
+                // 中间件可以在 hook 里等待异步的操作
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    match &cmd.request_data {
+                        Some(RequestData::Hset(v))
+                            if v.table == "t2" && ctx.principal.is_none() =>
+                        {
+                            Some(KvError::ReadOnly.into())
+                        }
+                        _ => None,
+                    }
+                })
+            }
+            fn on_executed<'a>(
+                &'a self,
+                _ctx: &'a ConnectionContext,
+                _res: &'a mut CommandResponse,
+            ) -> BoxFuture<'a, ()> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Box::pin(future::ready(()))
+            }
+        }
+        let executed = Arc::new(AtomicUsize::new(0));
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        let service: Service = ServiceInner::new(Inline::new(MemTable::default()))
+            .middleware(Guard(executed.clone()))
+            .fn_received(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .into();
+
+        let res = service
+            .execute(CommandRequest::new_hset("t2", "k1", "v1".into()))
+            .await;
+        let res = res.collect::<Vec<_>>().await;
+        assert_res_error((*res[0]).clone(), 403, "");
+        // 短路之后，后面的中间件收不到这个请求，但 response 仍然经过 on_executed
+        assert_eq!(received.load(Ordering::SeqCst), 0);
+        assert_eq!(executed.load(Ordering::SeqCst), 1);
+
+        let ctx = ConnectionContext {
+            principal: Some("alice".into()),
+            ..Default::default()
+        };
+        let cmd = CommandRequest::new_hset("t2", "k1", "v1".into());
+        service.execute_with(&ctx, cmd).await.next().await.unwrap();
+        // 分块的 HGETALL 每一块都经过 on_executed
+        let cmd = CommandRequest::new_hgetall_chunked("t2", 1);
+        let chunks = service
+            .execute_with(&ctx, cmd)
+            .await
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+        assert_eq!(executed.load(Ordering::SeqCst), 4);
+    }
 }
@@ -0,0 +1,297 @@
+use crate::command_request::RequestData;
+use crate::{Auth, CommandRequest, KvError};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use subtle::ConstantTimeEq;
+
+/// 授予所有 table 的权限时使用的 table 名
+pub const ALL_TABLES: &str = "*";
+
+/// 从密码计算哈希时 PBKDF2-HMAC-SHA256 的迭代次数，测试里减少迭代次数，否则 debug 构建太慢
+const PBKDF2_ROUNDS: u32 = if cfg!(test) { 1_000 } else { 600_000 };
+
+/// 对一个 table 的权限，Write 包含了 Read，Admin 包含了 Write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Read,
+    Write,
+    /// 快照、恢复和复制需要 ALL_TABLES 的 Admin 权限
+    Admin,
+}
+
+/// 用户：用 PBKDF2 加盐哈希之后的密码，以及每个 table 的权限
+#[derive(Debug, Default)]
+struct User {
+    salt: [u8; 16],
+    password_hash: Option<[u8; 32]>,
+    acl: HashMap<String, Permission>,
+}
+
+/// 认证和授权：连接通过 Auth 命令认证成为某个用户，之后的每个请求在执行前按这个用户的 ACL 检查。
+/// 密码和 token 都只保存哈希，比较哈希的时间和内容无关
+#[derive(Debug, Default)]
+pub struct Authenticator {
+    users: HashMap<String, User>,
+    /// token 的 SHA-256 和用户名。token 应该是足够长的随机串，HTTP 和 gRPC 每个请求都要认证一次，
+    /// 所以不用 PBKDF2
+    tokens: Vec<([u8; 32], String)>,
+}
+
+impl Authenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 添加一个可以用密码登录的用户。拿不到系统的随机数作为盐时返回错误
+    pub fn user(mut self, name: impl Into<String>, password: &str) -> Result<Self, KvError> {
+        let mut salt = [0; 16];
+        getrandom::getrandom(&mut salt)
+            .map_err(|e| KvError::Internal(format!("Failed to generate salt: {}", e)))?;
+        let user = self.users.entry(name.into()).or_default();
+        user.salt = salt;
+        user.password_hash = Some(derive_key(&salt, password));
+        Ok(self)
+    }
+
+    /// 添加一个 token，用它认证的连接的身份是 name 这个用户
+    pub fn token(mut self, token: &str, name: impl Into<String>) -> Self {
+        let name = name.into();
+        self.users.entry(name.clone()).or_default();
+        let hash = sha256(token);
+        self.tokens.retain(|(h, _)| *h != hash);
+        self.tokens.push((hash, name));
+        self
+    }
+
+    /// 给用户授予一个 table 的权限，table 为 ALL_TABLES 时对所有的 table 生效
+    pub fn grant(
+        mut self,
+        name: impl Into<String>,
+        table: impl Into<String>,
+        p: Permission,
+    ) -> Self {
+        let user = self.users.entry(name.into()).or_default();
+        user.acl.insert(table.into(), p);
+        self
+    }
+
+    /// 认证成功返回用户名
+    pub fn authenticate(&self, auth: &Auth) -> Result<String, KvError> {
+        let name = if !auth.token.is_empty() {
+            // 和每个 token 都比较一次，不因为提前找到而返回
+            let hash = sha256(&auth.token);
+            self.tokens
+                .iter()
+                .fold(None, |found, (h, name)| match bool::from(h.ct_eq(&hash)) {
+                    true => Some(name.clone()),
+                    false => found,
+                })
+        } else {
+            // 用户不存在或者没有密码时也计算一次哈希，不让响应时间暴露用户是否存在
+            let user = self.users.get(&auth.username);
+            let (salt, expected) = user
+                .and_then(|user| Some((user.salt, user.password_hash?)))
+                .unwrap_or_default();
+            let matched = derive_key(&salt, &auth.password).ct_eq(&expected);
+            match user.is_some_and(|user| user.password_hash.is_some()) && bool::from(matched) {
+                true => Some(auth.username.clone()),
+                false => None,
+            }
+        };
+        name.ok_or_else(|| KvError::Unauthenticated("Invalid credentials".into()))
+    }
+
+    /// 检查 principal 是否可以执行这个命令。没有认证的连接什么都不能做（除了 Auth）
+    pub fn authorize(&self, principal: Option<&str>, cmd: &CommandRequest) -> Result<(), KvError> {
+        if matches!(
+            cmd.request_data,
+            Some(RequestData::Auth(_)) | Some(RequestData::Handshake(_))
+        ) {
+            return Ok(());
+        }
+        let (name, user) = principal
+            .and_then(|name| self.users.get_key_value(name))
+            .ok_or_else(|| KvError::Unauthenticated("Authentication required".into()))?;
+        let mut required = vec![];
+        required_permissions(cmd, &mut required);
+        for (table, p) in required {
+            let granted = user.acl.get(table).or_else(|| match table {
+                ALL_TABLES => None,
+                _ => user.acl.get(ALL_TABLES),
+            });
+            if granted.is_none_or(|granted| *granted < p) {
+                return Err(KvError::PermissionDenied(name.clone(), table.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 执行命令需要的 table 权限。topic 命令只需要认证；列出 table 需要所有 table 的读权限，
+/// 快照、恢复和复制需要 Admin 权限；改名需要两个 table 的写权限
+fn required_permissions<'a>(cmd: &'a CommandRequest, required: &mut Vec<(&'a str, Permission)>) {
+    let p = match cmd.is_write_command() {
+        true => Permission::Write,
+        false => Permission::Read,
+    };
+    let table = match &cmd.request_data {
+        Some(RequestData::Hget(v)) => &v.table,
+        Some(RequestData::Hgetall(v)) => &v.table,
+        Some(RequestData::Hscan(v)) => &v.table,
+        Some(RequestData::Hrange(v)) => &v.table,
+        Some(RequestData::Hmget(v)) => &v.table,
+        Some(RequestData::Hset(v)) => &v.table,
+        Some(RequestData::Hmset(v)) => &v.table,
+        Some(RequestData::Hdel(v)) => &v.table,
+        Some(RequestData::Hmdel(v)) => &v.table,
+        Some(RequestData::DropTable(v)) => &v.table,
+        Some(RequestData::Hlen(v)) => &v.table,
+        Some(RequestData::RenameTable(v)) => {
+            required.push((&v.from, Permission::Write));
+            required.push((&v.to, Permission::Write));
+            return;
+        }
+        Some(RequestData::Hexist(v)) => &v.table,
+        Some(RequestData::Hmexist(v)) => &v.table,
+        Some(RequestData::Hexpire(v)) => &v.table,
+        Some(RequestData::Hexpireat(v)) => &v.table,
+        Some(RequestData::Httl(v)) => &v.table,
+        Some(RequestData::Hpersist(v)) => &v.table,
+        Some(RequestData::Hincrby(v)) => &v.table,
+        Some(RequestData::Hincrbyfloat(v)) => &v.table,
+        Some(RequestData::Watch(v)) => &v.table,
+        Some(RequestData::Transaction(tx)) => {
+            for cmd in &tx.commands {
+                required_permissions(cmd, required);
+            }
+            for watch in &tx.watches {
+                required.push((&watch.table, Permission::Read));
+            }
+            return;
+        }
+        Some(RequestData::ListTables(_)) => ALL_TABLES,
+        Some(RequestData::Snapshot(_))
+        | Some(RequestData::Restore(_))
+        | Some(RequestData::Replicate(_)) => {
+            required.push((ALL_TABLES, Permission::Admin));
+            return;
+        }
+        Some(RequestData::Subscribe(_))
+        | Some(RequestData::Unsubscribe(_))
+        | Some(RequestData::Publish(_))
+        | Some(RequestData::Auth(_))
+        | Some(RequestData::Handshake(_))
+        | None => return,
+    };
+    required.push((table, p));
+}
+
+fn derive_key(salt: &[u8], password: &str) -> [u8; 32] {
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    key
+}
+
+fn sha256(secret: &str) -> [u8; 32] {
+    Sha256::digest(secret.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Kvpair, Value};
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new()
+            .user("alice", "secret")
+            .unwrap()
+            .grant("alice", "t1", Permission::Write)
+            .grant("alice", ALL_TABLES, Permission::Read)
+            .token("tok", "bob")
+            .grant("bob", "t1", Permission::Read)
+    }
+
+    fn password(username: &str, password: &str) -> Auth {
+        Auth {
+            username: username.into(),
+            password: password.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn authenticate_should_check_password_and_token() {
+        let auth = authenticator();
+        assert_eq!(
+            auth.authenticate(&password("alice", "secret")).unwrap(),
+            "alice"
+        );
+        let token = Auth {
+            token: "tok".into(),
+            ..Default::default()
+        };
+        assert_eq!(auth.authenticate(&token).unwrap(), "bob");
+
+        let result = auth.authenticate(&password("alice", "wrong"));
+        assert!(matches!(result, Err(KvError::Unauthenticated(_))));
+        let result = auth.authenticate(&password("bob", ""));
+        assert!(matches!(result, Err(KvError::Unauthenticated(_))));
+        let result = auth.authenticate(&password("nobody", "secret"));
+        assert!(matches!(result, Err(KvError::Unauthenticated(_))));
+        // 只保存了加盐的哈希
+        let alice = &auth.users["alice"];
+        assert_ne!(alice.salt, [0; 16]);
+        assert_ne!(alice.password_hash, Some(derive_key(&[], "secret")));
+        assert_eq!(alice.password_hash, Some(derive_key(&alice.salt, "secret")));
+    }
+
+    #[test]
+    fn authorize_should_check_table_acl() {
+        let auth = authenticator();
+        let hset = |table: &str| CommandRequest::new_hset(table, "k1", Value::integer(1));
+        let hget = |table: &str| CommandRequest::new_hget(table, "k1");
+
+        assert!(matches!(
+            auth.authorize(None, &hget("t1")),
+            Err(KvError::Unauthenticated(_))
+        ));
+        assert!(auth.authorize(Some("alice"), &hset("t1")).is_ok());
+        assert!(auth.authorize(Some("alice"), &hget("t2")).is_ok());
+        assert!(matches!(
+            auth.authorize(Some("alice"), &hset("t2")),
+            Err(KvError::PermissionDenied(_, table)) if table == "t2"
+        ));
+        assert!(auth.authorize(Some("bob"), &hget("t1")).is_ok());
+        assert!(auth.authorize(Some("bob"), &hget("t2")).is_err());
+
+        // 事务里的每个命令都要检查
+        let tx = CommandRequest::new_transaction(
+            vec![
+                hget("t1"),
+                CommandRequest::new_hmset("t2", vec![Kvpair::new("k1", Value::integer(1))]),
+            ],
+            vec![],
+        );
+        assert!(auth.authorize(Some("alice"), &tx).is_err());
+        // 快照、恢复和复制需要 Admin 权限，所有 table 的写权限也不够
+        let auth = auth
+            .grant("alice", ALL_TABLES, Permission::Write)
+            .user("root", "root")
+            .unwrap()
+            .grant("root", ALL_TABLES, Permission::Admin);
+        let admin_cmds = [
+            CommandRequest::new_snapshot("kv.snap"),
+            CommandRequest::new_restore("kv.snap"),
+            CommandRequest::new_replicate(),
+        ];
+        for cmd in &admin_cmds {
+            assert!(matches!(
+                auth.authorize(Some("alice"), cmd),
+                Err(KvError::PermissionDenied(_, table)) if table == ALL_TABLES
+            ));
+            assert!(auth.authorize(Some("root"), cmd).is_ok());
+        }
+        assert!(auth.authorize(Some("alice"), &hset("t2")).is_ok());
+        assert!(auth.authorize(Some("root"), &hset("t2")).is_ok());
+    }
+}
@@ -1,8 +1,21 @@
+use super::once;
+use crate::command_request::RequestData;
 use crate::*;
+use futures::{future, stream, StreamExt};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::{sync::Arc, time::Duration};
+
+/// HSCAN 没有指定 count 时，一页最多返回的 kv pair 数量
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// HSCAN 每次从存储中按顺序读出的 kv pair 数量
+const SCAN_BATCH: usize = 100;
+/// HSCAN 的 cursor 是这个前缀加上上一页最后遍历到的 key，这样空的 key 也能作为 cursor
+const CURSOR_PREFIX: char = '>';
 
 impl CommandService for Hget {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get(&self.table, &self.key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.get(&self.table, &self.key).await {
             Ok(Some(v)) => v.into(),
             Ok(None) => KvError::NotFound(self.table, self.key).into(),
             Err(e) => e.into(),
@@ -11,31 +24,144 @@ impl CommandService for Hget {
 }
 
 impl CommandService for Hmget {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.get(&self.table, key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            values.push(match store.get(&self.table, key).await {
                 Ok(Some(v)) => v,
                 _ => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+            });
+        }
+        values.into()
     }
 }
 
 impl CommandService for Hgetall {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get_all(&self.table) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.get_all(&self.table).await {
             Ok(v) => v.into(),
             Err(e) => e.into(),
         }
     }
 }
 
+impl Hgetall {
+    /// 把 table 的内容分成多个 response 依次返回，每个最多 chunk_size 个 kv pair，
+    /// 最后是一个结束标记。数据在流被读取时才从 get_stream 中取出，不会一次编码成一个巨大的 response；
+    /// 但存储的 get_iter 不一定是惰性的，比如 MemTable 会先复制整个 table
+    pub async fn execute_chunked(self, store: &impl AsyncStorage) -> StreamingResponse {
+        let pairs = match store.get_stream(&self.table).await {
+            Ok(v) => v,
+            Err(e) => return once(e.into()),
+        };
+        // None 表示数据取完了，返回结束标记；取数据出错时返回错误，不再返回结束标记
+        let chunks = pairs
+            .chunks(self.chunk_size.max(1) as usize)
+            .map(|chunk| Some(chunk.into_iter().collect::<Result<Vec<_>, _>>()))
+            .chain(stream::once(async { None }));
+        let responses = chunks.scan(false, |failed, chunk| {
+            let res = match chunk {
+                _ if *failed => return future::ready(None),
+                Some(Ok(pairs)) => pairs.into(),
+                Some(Err(e)) => {
+                    *failed = true;
+                    e.into()
+                }
+                None => CommandResponse::end(),
+            };
+            future::ready(Some(Arc::new(res)))
+        });
+        Box::pin(responses)
+    }
+}
+
+impl CommandService for Hscan {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut last = match self.cursor.as_str() {
+            "" => None,
+            cursor => match cursor.strip_prefix(CURSOR_PREFIX) {
+                Some(key) => Some(key.to_owned()),
+                None => {
+                    return KvError::InvalidCommand(format!("Invalid cursor {}", cursor)).into()
+                }
+            },
+        };
+        let count = match self.count {
+            0 => DEFAULT_SCAN_COUNT,
+            n => n as usize,
+        };
+        // 每次从上一个遍历到的 key 之后继续读，遍历完整个 table 时返回空的 cursor
+        let mut pairs = Vec::new();
+        loop {
+            let start = match &last {
+                Some(key) => Bound::Excluded(key.as_str()),
+                None => Bound::Unbounded,
+            };
+            let batch = match store
+                .get_range(&self.table, start, Bound::Unbounded, false, SCAN_BATCH)
+                .await
+            {
+                Ok(v) => v,
+                Err(e) => return e.into(),
+            };
+            let done = batch.len() < SCAN_BATCH;
+            for pair in batch {
+                last = Some(pair.key.clone());
+                if glob_match(&self.pattern, &pair.key) {
+                    pairs.push(pair);
+                    if pairs.len() == count {
+                        let cursor = format!("{}{}", CURSOR_PREFIX, last.unwrap_or_default());
+                        return scan_page(cursor, pairs);
+                    }
+                }
+            }
+            if done {
+                return scan_page(String::new(), pairs);
+            }
+        }
+    }
+}
+
+impl CommandService for Hrange {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let start = bound(&self.start, self.start_exclusive);
+        let end = bound(&self.end, self.end_exclusive);
+        let limit = match self.limit {
+            0 => usize::MAX,
+            n => n as usize,
+        };
+        match store
+            .get_range(&self.table, start, end, self.reverse, limit)
+            .await
+        {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// HSCAN 的一页结果，values 里是下一页的 cursor
+fn scan_page(cursor: String, pairs: Vec<Kvpair>) -> CommandResponse {
+    CommandResponse {
+        values: vec![cursor.into()],
+        pairs,
+        ..CommandResponse::ok()
+    }
+}
+
+/// 空的 key 表示不限制这一端
+fn bound(key: &str, exclusive: bool) -> Bound<&str> {
+    match (key.is_empty(), exclusive) {
+        (true, _) => Bound::Unbounded,
+        (false, true) => Bound::Excluded(key),
+        (false, false) => Bound::Included(key),
+    }
+}
+
 impl CommandService for Hset {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
         match self.pair {
-            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
+            Some(v) => match set_with_ttl(store, &self.table, v, self.ttl).await {
                 Ok(Some(v)) => v.into(),
                 Ok(None) => Value::default().into(),
                 Err(e) => e.into(),
@@ -46,26 +172,23 @@ impl CommandService for Hset {
 }
 
 impl CommandService for Hmset {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        let pairs = self.pairs;
-        let table = self.table;
-        pairs
-            .into_iter()
-            .map(|pair| {
-                let result = store.set(&table, pair.key, pair.value.unwrap_or_default());
-                match result {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.pairs.len());
+        for pair in self.pairs {
+            values.push(
+                match set_with_ttl(store, &self.table, pair, self.ttl).await {
                     Ok(Some(v)) => v,
                     _ => Value::default(),
-                }
-            })
-            .collect::<Vec<_>>()
-            .into()
+                },
+            );
+        }
+        values.into()
     }
 }
 
 impl CommandService for Hdel {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.del(&self.table, &self.key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.del(&self.table, &self.key).await {
             Ok(Some(v)) => v.into(),
             Ok(None) => Value::default().into(),
             Err(e) => e.into(),
@@ -74,21 +197,64 @@ impl CommandService for Hdel {
 }
 
 impl CommandService for Hmdel {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.del(&self.table, key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            values.push(match store.del(&self.table, key).await {
                 Ok(Some(v)) => v,
                 _ => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+            });
+        }
+        values.into()
+    }
+}
+
+impl CommandService for ListTables {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.list_tables().await {
+            Ok(mut tables) => {
+                tables.sort();
+                tables
+                    .into_iter()
+                    .map(Value::from)
+                    .collect::<Vec<_>>()
+                    .into()
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for DropTable {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.drop_table(&self.table).await {
+            Ok(n) => Value::integer(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for RenameTable {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.rename_table(&self.from, &self.to).await {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hlen {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.count(&self.table).await {
+            Ok(n) => Value::integer(n as i64).into(),
+            Err(e) => e.into(),
+        }
     }
 }
 
 impl CommandService for Hexist {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.contains(&self.table, &self.key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.contains(&self.table, &self.key).await {
             Ok(v) => Value::from(v).into(),
             Err(e) => e.into(),
         }
@@ -96,21 +262,271 @@ impl CommandService for Hexist {
 }
 
 impl CommandService for Hmexist {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.contains(&self.table, key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            values.push(match store.contains(&self.table, key).await {
                 Ok(v) => v.into(),
                 _ => Value::default(),
-            })
-            .collect::<Vec<Value>>()
-            .into()
+            });
+        }
+        values.into()
     }
 }
 
+impl CommandService for Hexpire {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store
+            .expire(&self.table, &self.key, Duration::from_secs(self.ttl))
+            .await
+        {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hexpireat {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        // 已经过去的时间点，key 立刻过期
+        let ttl = Duration::from_millis(self.timestamp.saturating_sub(now_ms()));
+        match store.expire(&self.table, &self.key, ttl).await {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Httl {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        // 和 Redis 一样，key 不存在返回 -2，没有过期时间返回 -1
+        let ttl = match store.ttl(&self.table, &self.key).await {
+            Ok(Ttl::Missing) => -2,
+            Ok(Ttl::Persistent) => -1,
+            // 向上取整，还没过期的 key 至少返回 1 秒
+            Ok(Ttl::Expires(d)) => d.as_millis().div_ceil(1000) as i64,
+            Err(e) => return e.into(),
+        };
+        Value::integer(ttl).into()
+    }
+}
+
+impl CommandService for Hpersist {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.persist(&self.table, &self.key).await {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrby {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.incr(&self.table, &self.key, self.delta).await {
+            Ok(v) => Value::integer(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrbyfloat {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.incr_float(&self.table, &self.key, self.delta).await {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Watch {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut versions = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            match store.version(&self.table, key).await {
+                Ok(v) => versions.push(Value::integer(v)),
+                Err(e) => return e.into(),
+            }
+        }
+        versions.into()
+    }
+}
+
+impl CommandService for Transaction {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        // 事务里只能有读写存储的命令
+        let invalid = self.commands.iter().find(|cmd| {
+            cmd.is_topic_command()
+                || matches!(
+                    cmd.request_data,
+                    None | Some(RequestData::Transaction(_))
+                        | Some(RequestData::Snapshot(_))
+                        | Some(RequestData::Restore(_))
+                        | Some(RequestData::Replicate(_))
+                        | Some(RequestData::Auth(_))
+                        | Some(RequestData::Handshake(_))
+                )
+        });
+        if let Some(cmd) = invalid {
+            return KvError::InvalidCommand(format!("{:?} is not allowed in transaction", cmd))
+                .into();
+        }
+        match store.transaction(self.watches, self.commands).await {
+            Ok(responses) => CommandResponse {
+                responses,
+                ..CommandResponse::ok()
+            },
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl Snapshot {
+    /// 把所有的数据保存到快照目录 dir 中名为 path 的文件，dir 是 None 时返回错误。
+    /// 先写到临时文件再改名，写到一半失败时不会破坏已有的快照
+    pub async fn execute_in(
+        self,
+        dir: Option<&Path>,
+        store: &impl AsyncStorage,
+    ) -> CommandResponse {
+        let path = match snapshot_path(dir, &self.path) {
+            Ok(v) => v,
+            Err(e) => return e.into(),
+        };
+        match store.snapshot_file(path).await {
+            Ok(count) => Value::integer(count as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl Restore {
+    /// 从快照目录 dir 中名为 path 的文件恢复数据，dir 是 None 时返回错误
+    pub async fn execute_in(
+        self,
+        dir: Option<&Path>,
+        store: &impl AsyncStorage,
+    ) -> CommandResponse {
+        let path = match snapshot_path(dir, &self.path) {
+            Ok(v) => v,
+            Err(e) => return e.into(),
+        };
+        match store.restore_file(path).await {
+            Ok(count) => Value::integer(count as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 快照文件在 dir 中的路径。客户端只能指定一个文件名，不能包含目录和 ..，
+/// 存储读写文件时还会拒绝符号链接，这样快照命令只能读写 dir 里的文件
+fn snapshot_path(dir: Option<&Path>, name: &str) -> Result<PathBuf, KvError> {
+    let dir =
+        dir.ok_or_else(|| KvError::InvalidCommand("Snapshot directory is not configured".into()))?;
+    let invalid = || KvError::InvalidCommand(format!("Invalid snapshot file name {:?}", name));
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(file)), None) if file == name => {}
+        _ => return Err(invalid()),
+    }
+    Ok(dir.join(name))
+}
+
+/// ttl 为 0 时不设置过期时间
+async fn set_with_ttl(
+    store: &impl AsyncStorage,
+    table: &str,
+    pair: Kvpair,
+    ttl: u64,
+) -> Result<Option<Value>, KvError> {
+    let value = pair.value.unwrap_or_default();
+    match ttl {
+        0 => store.set(table, pair.key, value).await,
+        ttl => {
+            let ttl = Duration::from_secs(ttl);
+            store.set_with_ttl(table, pair.key, value, ttl).await
+        }
+    }
+}
+
+/// 简单的 glob 匹配，支持 `*`、`?`、`[abc]`、`[a-z]`、`[^a]` 和 `\` 转义，空的 pattern 匹配所有
+fn glob_match(pattern: &str, s: &str) -> bool {
+    if pattern.is_empty() {
+        return true;
+    }
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut pi, mut si) = (0, 0);
+    // 最近一个 `*` 的位置，以及它当前匹配到的字符串位置，失配时从这里回溯
+    let mut star = None;
+    while si < s.len() {
+        if pi < p.len() {
+            if p[pi] == '*' {
+                star = Some((pi, si));
+                pi += 1;
+                continue;
+            }
+            let (matched, len) = match_one(&p[pi..], s[si]);
+            if matched {
+                pi += len;
+                si += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((sp, ss)) => {
+                pi = sp + 1;
+                si = ss + 1;
+                star = Some((sp, ss + 1));
+            }
+            None => return false,
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// 用 pattern 开头的一个元素匹配字符 c，返回是否匹配以及这个元素的长度
+fn match_one(p: &[char], c: char) -> (bool, usize) {
+    match p[0] {
+        '?' => (true, 1),
+        '\\' if p.len() > 1 => (p[1] == c, 2),
+        '[' => match p.iter().skip(2).position(|v| *v == ']') {
+            Some(end) => {
+                let end = end + 2;
+                let (negate, set) = match p[1] {
+                    '^' | '!' => (true, &p[2..end]),
+                    _ => (false, &p[1..end]),
+                };
+                (in_set(set, c) != negate, end + 1)
+            }
+            // 没有闭合的 `[` 当作普通字符
+            None => (c == '[', 1),
+        },
+        v => (v == c, 1),
+    }
+}
+
+fn in_set(set: &[char], c: char) -> bool {
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            if set[i] <= c && c <= set[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if set[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
 
     #[test]
     fn hget_should_work() {
@@ -236,6 +652,417 @@ mod tests {
         assert_res_ok(res, &[true.into(), false.into()], &[]);
     }
 
+    #[test]
+    fn hset_with_ttl_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset_with_ttl("t1", "k1", "v1".into(), 100);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let cmd = CommandRequest::new_httl("t1", "k1");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[Value::integer(100)], &[]);
+
+        // 普通的 hset 会清除过期时间
+        let cmd = CommandRequest::new_hset("t1", "k1", "v2".into());
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &["v1".into()], &[]);
+        let cmd = CommandRequest::new_httl("t1", "k1");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[Value::integer(-1)], &[]);
+    }
+
+    #[test]
+    fn hmset_with_ttl_should_work() {
+        let store = MemTable::new();
+        let pairs = vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "v2".into()),
+        ];
+        let cmd = CommandRequest::new_hmset_with_ttl("t1", pairs, 10);
+        dispatch(cmd, &store);
+
+        for key in ["k1", "k2"] {
+            let res = dispatch(CommandRequest::new_httl("t1", key), &store);
+            assert_res_ok(res, &[Value::integer(10)], &[]);
+        }
+    }
+
+    #[test]
+    fn httl_with_non_exist_key_should_return_minus_two() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_httl("t1", "k1");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[Value::integer(-2)], &[]);
+    }
+
+    #[test]
+    fn hexpire_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hexpire("t1", "k1", 10);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[false.into()], &[]);
+
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+        let cmd = CommandRequest::new_hexpire("t1", "k1", 10);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[true.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        assert_res_ok(res, &[Value::integer(10)], &[]);
+
+        // ttl 为 0 的 key 立刻过期
+        let cmd = CommandRequest::new_hexpire("t1", "k1", 0);
+        dispatch(cmd, &store);
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn hexpireat_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+        let cmd = CommandRequest::new_hexpireat("t1", "k1", now_ms() + 10_000);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        assert_res_ok(res, &[Value::integer(10)], &[]);
+
+        // 过去的时间点，key 立刻过期
+        let cmd = CommandRequest::new_hexpireat("t1", "k1", 1);
+        dispatch(cmd, &store);
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn hpersist_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset_with_ttl("t1", "k1", "v1".into(), 100);
+        dispatch(cmd, &store);
+
+        let res = dispatch(CommandRequest::new_hpersist("t1", "k1"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hpersist("t1", "k1"), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        assert_res_ok(res, &[Value::integer(-1)], &[]);
+    }
+
+    #[test]
+    fn hincrby_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hincrby("score", "u1", 10);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[Value::integer(10)], &[]);
+
+        let cmd = CommandRequest::new_hincrby("score", "u1", -3);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[Value::integer(7)], &[]);
+    }
+
+    #[test]
+    fn hincrbyfloat_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hincrby("score", "u1", 10);
+        dispatch(cmd, &store);
+
+        let cmd = CommandRequest::new_hincrbyfloat("score", "u1", 0.5);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[10.5.into()], &[]);
+    }
+
+    #[test]
+    fn hincrby_with_non_numeric_value_should_return_400() {
+        let store = MemTable::new();
+        set_key_pairs("score", vec![("u1", "ten")], &store);
+
+        let cmd = CommandRequest::new_hincrby("score", "u1", 1);
+        let res = dispatch(cmd, &store);
+        assert_res_error(res, 400, "Cannot convert value");
+
+        let cmd = CommandRequest::new_hincrbyfloat("score", "u1", 1.0);
+        let res = dispatch(cmd, &store);
+        assert_res_error(res, 400, "Cannot convert value");
+    }
+
+    #[test]
+    fn watch_should_return_versions() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+        let cmd = CommandRequest::new_watch("t1", vec!["k1".into(), "k2".into()]);
+        let res = dispatch(cmd, &store);
+        let version = store.version("t1", "k1").unwrap();
+        assert!(version > 0);
+        assert_res_ok(res, &[Value::integer(version), Value::integer(0)], &[]);
+    }
+
+    #[test]
+    fn transaction_should_work() {
+        let store = MemTable::new();
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hget("t1", "k1"),
+        ];
+        let res = dispatch(CommandRequest::new_transaction(cmds, vec![]), &store);
+        assert_eq!(res.responses.len(), 2);
+        let mut responses = res.responses.clone();
+        assert_res_ok(
+            CommandResponse {
+                responses: vec![],
+                ..res
+            },
+            &[],
+            &[],
+        );
+        assert_res_ok(responses.pop().unwrap(), &["v1".into()], &[]);
+        assert_res_ok(responses.pop().unwrap(), &[Value::default()], &[]);
+    }
+
+    #[test]
+    fn transaction_with_changed_watch_should_return_409() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_watch("t1", vec!["k1".into()]), &store);
+        let version = i64::try_from(&res.values[0]).unwrap();
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+
+        let watches = vec![WatchedKey::new("t1", "k1", version)];
+        let cmds = vec![CommandRequest::new_hset("t1", "k2", "v2".into())];
+        let res = dispatch(CommandRequest::new_transaction(cmds, watches), &store);
+        assert_res_error(res, 409, "has changed");
+        assert!(!store.contains("t1", "k2").unwrap());
+    }
+
+    #[test]
+    fn transaction_with_invalid_command_should_return_400() {
+        let store = MemTable::new();
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_publish("lobby", vec![]),
+        ];
+        let res = dispatch(CommandRequest::new_transaction(cmds, vec![]), &store);
+        assert_res_error(res, 400, "not allowed in transaction");
+        assert!(!store.contains("t1", "k1").unwrap());
+    }
+
+    #[test]
+    fn hscan_should_walk_the_whole_table() {
+        let store = MemTable::new();
+        let pairs: Vec<_> = (0..25).map(|i| (format!("k{:02}", i), i)).collect();
+        for (k, v) in &pairs {
+            store.set("t1", k.clone(), Value::integer(*v)).unwrap();
+        }
+
+        let mut cursor = String::new();
+        let mut keys = vec![];
+        loop {
+            let cmd = CommandRequest::new_hscan("t1", cursor, 10, "");
+            let res = dispatch(cmd, &store);
+            assert_eq!(res.status, 200);
+            assert!(res.pairs.len() <= 10);
+            keys.extend(res.pairs.iter().map(|v| v.key.clone()));
+            cursor = String::try_from(&res).unwrap();
+            if cursor.is_empty() {
+                break;
+            }
+        }
+        let expected: Vec<_> = pairs.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn hscan_should_not_skip_keys_when_table_changes() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1"), ("k3", "v3"), ("k5", "v5")], &store);
+        let res = dispatch(CommandRequest::new_hscan("t1", "", 2, ""), &store);
+        let cursor = String::try_from(&res).unwrap();
+
+        // 已经遍历过的 key 被删除、新的 key 写在前面，都不影响后面的 key
+        store.del("t1", "k1").unwrap();
+        store.set("t1", "k0".into(), "v0".into()).unwrap();
+        store.set("t1", "k4".into(), "v4".into()).unwrap();
+        let res = dispatch(CommandRequest::new_hscan("t1", cursor, 10, ""), &store);
+        let pairs = &[
+            Kvpair::new("k4", "v4".into()),
+            Kvpair::new("k5", "v5".into()),
+        ];
+        assert_res_ok(res, &["".into()], pairs);
+
+        // 空的 key 也能作为 cursor
+        store.set("t2", "".into(), Value::integer(0)).unwrap();
+        store.set("t2", "k1".into(), Value::integer(1)).unwrap();
+        let res = dispatch(CommandRequest::new_hscan("t2", "", 1, ""), &store);
+        let cursor = String::try_from(&res).unwrap();
+        let res = dispatch(CommandRequest::new_hscan("t2", cursor, 1, ""), &store);
+        assert_eq!(res.pairs, vec![Kvpair::new("k1", Value::integer(1))]);
+
+        let res = dispatch(CommandRequest::new_hscan("t1", "k1", 1, ""), &store);
+        assert_res_error(res, 400, "Invalid cursor");
+    }
+
+    #[test]
+    fn hscan_should_filter_by_pattern() {
+        let store = MemTable::new();
+        set_key_pairs(
+            "user",
+            vec![("u1", "Tyr"), ("u2", "Lindsey"), ("admin", "Rosie")],
+            &store,
+        );
+        let cmd = CommandRequest::new_hscan("user", "", 0, "u?");
+        let res = dispatch(cmd, &store);
+        let pairs = &[
+            Kvpair::new("u1", "Tyr".into()),
+            Kvpair::new("u2", "Lindsey".into()),
+        ];
+        assert_res_ok(res, &["".into()], pairs);
+    }
+
+    #[test]
+    fn table_commands_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t2", vec![("u1", 1), ("u2", 2)], &store);
+        set_key_pairs("t1", vec![("u1", 1)], &store);
+        let res = dispatch(CommandRequest::new_list_tables(), &store);
+        assert_res_ok(res, &["t1".into(), "t2".into()], &[]);
+        let res = dispatch(CommandRequest::new_hlen("t2"), &store);
+        assert_res_ok(res, &[Value::integer(2)], &[]);
+
+        let res = dispatch(CommandRequest::new_rename_table("t2", "t3"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_drop_table("t3"), &store);
+        assert_res_ok(res, &[Value::integer(2)], &[]);
+        let res = dispatch(CommandRequest::new_list_tables(), &store);
+        assert_res_ok(res, &["t1".into()], &[]);
+
+        // 事务里不能删除整个 table
+        let cmds = vec![CommandRequest::new_drop_table("t1")];
+        let res = dispatch(CommandRequest::new_transaction(cmds, vec![]), &store);
+        assert_res_error(res, 400, "not supported in transaction");
+    }
+
+    #[test]
+    fn hrange_should_treat_empty_key_as_unbounded() {
+        let store = MemTable::new();
+        let pairs: Vec<_> = (0..5).map(|i| (format!("k{}", i), i)).collect();
+        for (k, v) in &pairs {
+            store.set("t1", k.clone(), Value::integer(*v)).unwrap();
+        }
+        let mut cmd =
+            CommandRequest::new_hrange("t1", Bound::Excluded("k1"), Bound::Unbounded, false, 0);
+        let res = dispatch(cmd.clone(), &store);
+        let keys: Vec<_> = res.pairs.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(keys, ["k2", "k3", "k4"]);
+
+        // 有 limit 时，reverse 从最大的 key 开始
+        if let Some(RequestData::Hrange(param)) = &mut cmd.request_data {
+            param.reverse = true;
+            param.limit = 2;
+        }
+        let res = dispatch(cmd, &store);
+        let keys: Vec<_> = res.pairs.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(keys, ["k4", "k3"]);
+    }
+
+    #[test]
+    fn sleddb_hscan_and_hrange_should_keep_colons_in_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        for i in 0..150 {
+            store
+                .set("t", format!("a:{:03}", i), Value::integer(i))
+                .unwrap();
+        }
+        // 没有匹配的 key 时也要遍历完整个 table
+        let res = dispatch(CommandRequest::new_hscan("t", "", 10, "zzz"), &store);
+        assert_res_ok(res, &["".into()], &[]);
+        let res = dispatch(CommandRequest::new_hscan("t", "", 2, "a:14*"), &store);
+        let keys: Vec<_> = res.pairs.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(keys, ["a:140", "a:141"]);
+
+        let cmd =
+            CommandRequest::new_hrange("t", Bound::Excluded("a:147"), Bound::Unbounded, false, 0);
+        let res = dispatch(cmd, &store);
+        let keys: Vec<_> = res.pairs.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(keys, ["a:148", "a:149"]);
+    }
+
+    #[test]
+    fn glob_match_should_work() {
+        assert!(glob_match("", "anything"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("user:*", "user:1"));
+        assert!(!glob_match("user:*", "admin:1"));
+        assert!(glob_match("*:1*", "user:12"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[!e]llo", "hello"));
+        assert!(glob_match("k[0-9]", "k7"));
+        assert!(!glob_match("k[0-9]", "kx"));
+        assert!(glob_match("a\\*b", "a*b"));
+        assert!(!glob_match("a\\*b", "axb"));
+        assert!(glob_match("[abc", "[abc"));
+    }
+
+    #[tokio::test]
+    async fn snapshot_and_restore_should_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = |path: &str| Snapshot { path: path.into() };
+        let restore = |path: &str| Restore { path: path.into() };
+
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1"), ("k2", "v2")], &store);
+        let res = snapshot("kv.snapshot")
+            .execute_in(Some(dir.path()), &Inline(&store))
+            .await;
+        assert_res_ok(res, &[Value::integer(2)], &[]);
+
+        let store = SledDb::new(dir.path().join("db"));
+        let res = restore("kv.snapshot")
+            .execute_in(Some(dir.path()), &Inline(&store))
+            .await;
+        assert_res_ok(res, &[Value::integer(2)], &[]);
+        let res = dispatch(CommandRequest::new_hget("t1", "k2"), &store);
+        assert_res_ok(res, &["v2".into()], &[]);
+
+        let res = restore("non-exist.snapshot")
+            .execute_in(Some(dir.path()), &Inline(&store))
+            .await;
+        assert_res_error(res, 500, "I/O error");
+    }
+
+    #[tokio::test]
+    async fn snapshot_should_only_use_files_in_snapshot_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MemTable::new();
+        let outside = dir.path().join("outside");
+        fs::write(&outside, b"data").unwrap();
+        let snapshots = dir.path().join("snapshots");
+        fs::create_dir(&snapshots).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&outside, snapshots.join("link")).unwrap();
+
+        let outside = outside.to_str().unwrap();
+        for name in ["../outside", outside, "a/b", "", ".", "..", "link", "kv/"] {
+            let snapshot = Snapshot { path: name.into() };
+            let res = snapshot.execute_in(Some(&snapshots), &Inline(&store)).await;
+            assert_res_error(res, 400, "Invalid snapshot file name");
+            let restore = Restore { path: name.into() };
+            let res = restore.execute_in(Some(&snapshots), &Inline(&store)).await;
+            assert_res_error(res, 400, "Invalid snapshot file name");
+        }
+        assert_eq!(fs::read(dir.path().join("outside")).unwrap(), b"data");
+
+        // 没有配置快照目录时，快照命令都会失败
+        let res = dispatch(CommandRequest::new_snapshot("kv.snapshot"), &store);
+        assert_res_error(res, 400, "Snapshot directory is not configured");
+        let res = dispatch(CommandRequest::new_restore("kv.snapshot"), &store);
+        assert_res_error(res, 400, "Snapshot directory is not configured");
+    }
+
     fn set_key_pairs<T: Into<Value>>(table: &str, pairs: Vec<(&str, T)>, store: &impl Storage) {
         pairs
             .into_iter()
@@ -0,0 +1,193 @@
+use crate::{CommandRequest, CommandResponse, KvError, StreamingResponse};
+use futures::future::{self, BoxFuture};
+use futures::StreamExt;
+use std::{net::SocketAddr, sync::Arc};
+use tracing::{debug, warn};
+
+/// 请求所在连接的上下文
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionContext {
+    /// 对端的地址，不是来自网络连接时为 None
+    pub peer_addr: Option<SocketAddr>,
+    /// 连接通过 Auth 认证得到的用户名
+    pub principal: Option<String>,
+}
+
+impl ConnectionContext {
+    pub fn new(peer_addr: Option<SocketAddr>) -> Self {
+        Self {
+            peer_addr,
+            principal: None,
+        }
+    }
+}
+
+/// Service 的中间件，在处理请求的各个阶段被调用，默认什么都不做。
+/// 每个阶段返回一个 future，中间件可以在里面做异步的 I/O（比如查询外部的限流服务）。
+/// 流式的 response（SUBSCRIBE、分块的 HGETALL）每一个都会经过 on_executed 和 on_before_send
+pub trait Middleware: Send + Sync + 'static {
+    /// 收到请求时调用，返回 Some 时不再执行这个请求，直接把它作为 response
+    fn on_received<'a>(
+        &'a self,
+        _ctx: &'a ConnectionContext,
+        _cmd: &'a CommandRequest,
+    ) -> BoxFuture<'a, Option<CommandResponse>> {
+        Box::pin(future::ready(None))
+    }
+
+    /// 命令执行完之后调用
+    fn on_executed<'a>(
+        &'a self,
+        _ctx: &'a ConnectionContext,
+        _res: &'a mut CommandResponse,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(future::ready(()))
+    }
+
+    /// 发送之前调用，可以修改 response
+    fn on_before_send<'a>(
+        &'a self,
+        _ctx: &'a ConnectionContext,
+        _res: &'a mut CommandResponse,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(future::ready(()))
+    }
+
+    /// 网络层把 response 写到连接上之后调用
+    fn on_after_send<'a>(
+        &'a self,
+        _ctx: &'a ConnectionContext,
+        _res: &'a CommandResponse,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(future::ready(()))
+    }
+}
+
+/// 用闭包实现的中间件，只处理一个阶段。闭包返回错误时，用这个错误作为 response
+#[allow(clippy::type_complexity)]
+pub(crate) enum FnHook {
+    Received(Box<dyn Fn(&CommandRequest) -> Result<(), KvError> + Send + Sync>),
+    Executed(Box<dyn Fn(&CommandResponse) -> Result<(), KvError> + Send + Sync>),
+    BeforeSend(Box<dyn Fn(&mut CommandResponse) -> Result<(), KvError> + Send + Sync>),
+    AfterSend(Box<dyn Fn() -> Result<(), KvError> + Send + Sync>),
+}
+
+/// 闭包是同步的，直接在调用时执行，返回已经完成的 future
+impl Middleware for FnHook {
+    fn on_received<'a>(
+        &'a self,
+        _ctx: &'a ConnectionContext,
+        cmd: &'a CommandRequest,
+    ) -> BoxFuture<'a, Option<CommandResponse>> {
+        let res = match self {
+            FnHook::Received(f) => f(cmd).err().map(Into::into),
+            _ => None,
+        };
+        Box::pin(future::ready(res))
+    }
+
+    fn on_executed<'a>(
+        &'a self,
+        _ctx: &'a ConnectionContext,
+        res: &'a mut CommandResponse,
+    ) -> BoxFuture<'a, ()> {
+        if let FnHook::Executed(f) = self {
+            if let Err(e) = f(res) {
+                *res = e.into();
+            }
+        }
+        Box::pin(future::ready(()))
+    }
+
+    fn on_before_send<'a>(
+        &'a self,
+        _ctx: &'a ConnectionContext,
+        res: &'a mut CommandResponse,
+    ) -> BoxFuture<'a, ()> {
+        if let FnHook::BeforeSend(f) = self {
+            if let Err(e) = f(res) {
+                *res = e.into();
+            }
+        }
+        Box::pin(future::ready(()))
+    }
+
+    fn on_after_send<'a>(
+        &'a self,
+        _ctx: &'a ConnectionContext,
+        _res: &'a CommandResponse,
+    ) -> BoxFuture<'a, ()> {
+        if let FnHook::AfterSend(f) = self {
+            // 已经发出去了，只能记录下来
+            if let Err(e) = f() {
+                warn!("After send hook failed: {:?}", e);
+            }
+        }
+        Box::pin(future::ready(()))
+    }
+}
+
+/// 按注册的顺序调用的一组中间件
+#[derive(Clone, Default)]
+pub(crate) struct Middlewares(Vec<Arc<dyn Middleware>>);
+
+impl Middlewares {
+    pub fn push(&mut self, m: impl Middleware) {
+        self.0.push(Arc::new(m));
+    }
+
+    /// 第一个返回 response 的中间件让请求短路
+    pub async fn on_received(
+        &self,
+        ctx: &ConnectionContext,
+        cmd: &CommandRequest,
+    ) -> Option<CommandResponse> {
+        for m in &self.0 {
+            if let Some(res) = m.on_received(ctx, cmd).await {
+                return Some(res);
+            }
+        }
+        None
+    }
+
+    /// 执行完的 response 依次经过 on_executed 和 on_before_send
+    pub async fn respond(
+        &self,
+        ctx: &ConnectionContext,
+        mut res: CommandResponse,
+    ) -> CommandResponse {
+        if self.0.is_empty() {
+            return res;
+        }
+        for m in &self.0 {
+            m.on_executed(ctx, &mut res).await;
+        }
+        for m in &self.0 {
+            m.on_before_send(ctx, &mut res).await;
+        }
+        debug!("Modified response: {:?}", res);
+        res
+    }
+
+    /// 对流里的每个 response 调用 respond
+    pub fn respond_stream(
+        &self,
+        ctx: &ConnectionContext,
+        stream: StreamingResponse,
+    ) -> StreamingResponse {
+        if self.0.is_empty() {
+            return stream;
+        }
+        let (middlewares, ctx) = (self.clone(), ctx.clone());
+        Box::pin(stream.then(move |res| {
+            let (middlewares, ctx) = (middlewares.clone(), ctx.clone());
+            async move { Arc::new(middlewares.respond(&ctx, (*res).clone()).await) }
+        }))
+    }
+
+    pub async fn on_after_send(&self, ctx: &ConnectionContext, res: &CommandResponse) {
+        for m in &self.0 {
+            m.on_after_send(ctx, res).await;
+        }
+    }
+}
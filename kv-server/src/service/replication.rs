@@ -0,0 +1,179 @@
+use crate::command_request::RequestData;
+use crate::storage::expire_at;
+use crate::*;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tracing::warn;
+
+/// 每个 replica 最多积压的修改数量，跟不上的 replica 会被断开，重新连接后全量同步
+const REPLICATION_BACKLOG: usize = 4096;
+
+/// primary 端记录所有的 replica，把修改数据的命令按执行的顺序发送给它们
+#[derive(Debug, Default)]
+pub struct Replicator {
+    /// 修改数据的命令都持有这个锁执行，发送的顺序就是执行的顺序。
+    /// 执行命令时要等待存储，所以用 tokio 的 Mutex
+    replicas: Mutex<Vec<mpsc::Sender<CommandRequest>>>,
+}
+
+impl Replicator {
+    /// 执行一个修改数据的命令，成功后发送给所有的 replica
+    pub async fn execute<F>(
+        &self,
+        cmd: CommandRequest,
+        f: impl FnOnce(CommandRequest) -> F,
+    ) -> CommandResponse
+    where
+        F: Future<Output = CommandResponse>,
+    {
+        let mut replicas = self.replicas.lock().await;
+        if let Some(RequestData::Restore(_)) = cmd.request_data {
+            // 从文件恢复的数据没法用命令同步，断开所有的 replica，让它们重新全量同步
+            replicas.clear();
+            return f(cmd).await;
+        }
+
+        // 在执行之前把过期时间换成绝对时间，和 primary 上的过期时间一致
+        let replicated = (!replicas.is_empty()).then(|| to_replicated(cmd.clone()));
+        let res = f(cmd).await;
+        if let Some(replicated) = replicated.filter(|_| res.status == 200) {
+            replicas.retain(|tx| match tx.try_send(replicated.clone()) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Drop replica: {:?}", e);
+                    false
+                }
+            });
+        }
+        res
+    }
+
+    /// 注册一个 replica：在锁内生成快照，这样快照之后的修改正好都会发送给这个 replica
+    pub async fn register(
+        &self,
+        snapshot: impl Future<Output = Result<Vec<u8>, KvError>>,
+    ) -> Result<(Vec<u8>, mpsc::Receiver<CommandRequest>), KvError> {
+        let mut replicas = self.replicas.lock().await;
+        let data = snapshot.await?;
+        let (tx, rx) = mpsc::channel(REPLICATION_BACKLOG);
+        replicas.push(tx);
+        Ok((data, rx))
+    }
+}
+
+/// 把命令转换成发送给 replica 的形式：事务去掉 watch，primary 上已经检查过了
+fn to_replicated(cmd: CommandRequest) -> CommandRequest {
+    let mut cmd = with_absolute_ttl(cmd);
+    if let Some(RequestData::Transaction(tx)) = &mut cmd.request_data {
+        tx.watches.clear();
+    }
+    cmd
+}
+
+/// 把相对的过期时间换成 HEXPIREAT，这样命令晚一些在其它节点上执行，也能得到同样的过期时间
+pub(crate) fn with_absolute_ttl(cmd: CommandRequest) -> CommandRequest {
+    let mut cmds = normalize(cmd);
+    match cmds.len() {
+        1 => cmds.remove(0),
+        _ => CommandRequest::new_transaction(cmds, vec![]),
+    }
+}
+
+fn normalize(cmd: CommandRequest) -> Vec<CommandRequest> {
+    // ttl 由客户端指定，可能很大，计算时间点时不能溢出
+    let expire_at = |ttl: u64| expire_at(Duration::from_secs(ttl));
+    match cmd.request_data {
+        Some(RequestData::Hset(param)) if param.ttl > 0 => {
+            let pair = param.pair.clone().unwrap_or_default();
+            let value = pair.value.unwrap_or_default();
+            vec![
+                CommandRequest::new_hset(&param.table, &pair.key, value),
+                CommandRequest::new_hexpireat(param.table, pair.key, expire_at(param.ttl)),
+            ]
+        }
+        Some(RequestData::Hmset(param)) if param.ttl > 0 => {
+            let timestamp = expire_at(param.ttl);
+            let expires = param
+                .pairs
+                .iter()
+                .map(|pair| CommandRequest::new_hexpireat(&param.table, &pair.key, timestamp));
+            let set = CommandRequest::new_hmset(&param.table, param.pairs.clone());
+            std::iter::once(set).chain(expires).collect()
+        }
+        Some(RequestData::Hexpire(param)) => vec![CommandRequest::new_hexpireat(
+            param.table,
+            param.key,
+            expire_at(param.ttl),
+        )],
+        Some(RequestData::Transaction(param)) => {
+            let cmds = param.commands.into_iter().flat_map(normalize).collect();
+            vec![CommandRequest::new_transaction(cmds, param.watches)]
+        }
+        data => vec![CommandRequest {
+            request_data: data,
+            ..Default::default()
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::now_ms;
+    use crate::{Kvpair, Value, WatchedKey};
+
+    #[test]
+    fn replicated_command_should_use_absolute_expiry() {
+        let cmd = CommandRequest::new_hset_with_ttl("t1", "k1", "v1".into(), 10);
+        let before = now_ms() + 10_000;
+        let replicated = to_replicated(cmd);
+        let after = now_ms() + 10_000;
+
+        let tx = match replicated.request_data {
+            Some(RequestData::Transaction(tx)) => tx,
+            v => panic!("Expect transaction, got {:?}", v),
+        };
+        assert_eq!(tx.commands.len(), 2);
+        assert_eq!(
+            tx.commands[0],
+            CommandRequest::new_hset("t1", "k1", "v1".into())
+        );
+        match &tx.commands[1].request_data {
+            Some(RequestData::Hexpireat(param)) => {
+                assert!(param.timestamp >= before && param.timestamp <= after)
+            }
+            v => panic!("Expect hexpireat, got {:?}", v),
+        }
+
+        // 很大的 ttl 不会溢出成已经过期的时间点
+        let cmd = CommandRequest::new_hexpire("t1", "k1", u64::MAX);
+        match to_replicated(cmd).request_data {
+            Some(RequestData::Hexpireat(param)) => assert_eq!(param.timestamp, u64::MAX),
+            v => panic!("Expect hexpireat, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn replicated_transaction_should_be_flattened() {
+        let watches = vec![WatchedKey::new("t1", "k1", 0)];
+        let cmds = vec![
+            CommandRequest::new_hmset_with_ttl("t1", vec![Kvpair::new("k1", Value::integer(1))], 5),
+            CommandRequest::new_hincrby("t1", "k2", 1),
+        ];
+        let replicated = to_replicated(CommandRequest::new_transaction(cmds, watches));
+
+        let tx = match replicated.request_data {
+            Some(RequestData::Transaction(tx)) => tx,
+            v => panic!("Expect transaction, got {:?}", v),
+        };
+        // 事务里不能再有事务，而且不需要再检查 watch
+        assert!(tx.watches.is_empty());
+        assert_eq!(tx.commands.len(), 3);
+        assert!(!tx
+            .commands
+            .iter()
+            .any(|c| matches!(c.request_data, Some(RequestData::Transaction(_)))));
+        assert_eq!(tx.commands[2], CommandRequest::new_hincrby("t1", "k2", 1));
+    }
+}
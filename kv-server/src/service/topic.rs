@@ -0,0 +1,172 @@
+use crate::{CommandResponse, KvError, Value};
+use dashmap::{DashMap, DashSet};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// 每个订阅的 channel 里最多缓存的数据
+const BROADCAST_CAPACITY: usize = 128;
+
+/// 下一个 subscription id
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// 获取下一个 subscription id
+fn get_next_subscription_id() -> u32 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// 对主题发布和订阅的抽象
+pub trait Topic: Send + Sync + 'static {
+    /// 订阅某个主题，返回接收数据的 channel
+    fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>>;
+    /// 取消对主题的订阅
+    fn unsubscribe(self, name: String, id: u32) -> Result<u32, KvError>;
+    /// 往主题里发布一个数据
+    fn publish(self, name: String, value: Arc<CommandResponse>);
+}
+
+/// 用于主题发布和订阅的数据结构
+#[derive(Debug, Default)]
+pub struct Broadcaster {
+    /// 所有的主题，以及每个主题下的 subscription id
+    topics: DashMap<String, DashSet<u32>>,
+    /// 所有的订阅，subscription id 到发送端的映射
+    subscriptions: DashMap<u32, mpsc::Sender<Arc<CommandResponse>>>,
+}
+
+impl Broadcaster {
+    /// 从主题和订阅列表中移除一个订阅，订阅的 Sender 被 drop 后，对应的 stream 就结束了
+    fn remove_subscription(&self, name: &str, id: u32) -> Option<u32> {
+        // 主题不存在，或者主题里没有这个 id，说明这个订阅不属于这个主题
+        let topic = self.topics.get(name)?;
+        topic.remove(&id)?;
+        if topic.is_empty() {
+            info!("Topic {} is empty, removed", name);
+            drop(topic);
+            // 再次确认是空的，避免删除期间有新的订阅加入
+            self.topics.remove_if(name, |_, v| v.is_empty());
+        }
+        debug!("Subscription {} is removed", id);
+        self.subscriptions.remove(&id).map(|(id, _)| id)
+    }
+}
+
+impl Topic for Arc<Broadcaster> {
+    fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>> {
+        let id = get_next_subscription_id();
+        let (tx, rx) = mpsc::channel(BROADCAST_CAPACITY);
+
+        // channel 是新建的，一定有空间放下第一个消息：subscription id
+        let res: CommandResponse = Value::integer(id as _).into();
+        if let Err(e) = tx.try_send(Arc::new(res)) {
+            warn!("Failed to send subscription id {}. Error: {:?}", id, e);
+        }
+
+        // 先放入 subscription table，再加入主题，这样 publish 时一定能找到 Sender
+        self.subscriptions.insert(id, tx);
+        self.topics.entry(name).or_default().insert(id);
+        debug!("Subscription {} is added", id);
+
+        rx
+    }
+
+    fn unsubscribe(self, name: String, id: u32) -> Result<u32, KvError> {
+        match self.remove_subscription(&name, id) {
+            Some(id) => Ok(id),
+            None => Err(KvError::SubscriptionNotFound(name, id)),
+        }
+    }
+
+    fn publish(self, name: String, value: Arc<CommandResponse>) {
+        tokio::spawn(async move {
+            let ids = match self.topics.get(&name) {
+                // 复制一份 id 列表，不要在发送数据时持有 DashMap 的锁
+                Some(topic) => topic.iter().map(|id| *id).collect::<Vec<_>>(),
+                None => return,
+            };
+
+            let mut failed = vec![];
+            for id in ids {
+                let tx = match self.subscriptions.get(&id) {
+                    Some(tx) => tx.clone(),
+                    None => continue,
+                };
+                if let Err(e) = tx.send(value.clone()).await {
+                    warn!("Publish to {} failed! error: {:?}", id, e);
+                    failed.push(id);
+                }
+            }
+
+            // 接收端已经关闭的订阅，直接清除
+            for id in failed {
+                self.remove_subscription(&name, id);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_res_ok;
+    use std::convert::TryInto;
+
+    #[tokio::test]
+    async fn pub_sub_should_work() {
+        let b = Arc::new(Broadcaster::default());
+        let lobby = "lobby".to_string();
+
+        // subscribe
+        let mut stream1 = b.clone().subscribe(lobby.clone());
+        let mut stream2 = b.clone().subscribe(lobby.clone());
+
+        // publish
+        let v: Value = "hello".into();
+        b.clone().publish(lobby.clone(), Arc::new(v.clone().into()));
+
+        // subscribers 应该能收到 publish 的数据
+        let id1 = get_id(&mut stream1).await;
+        let id2 = get_id(&mut stream2).await;
+        assert_ne!(id1, id2);
+
+        let res1 = stream1.recv().await.unwrap();
+        let res2 = stream2.recv().await.unwrap();
+        assert_eq!(res1, res2);
+        assert_res_ok((*res1).clone(), &[v], &[]);
+
+        // 如果 subscriber 取消订阅，则收不到新数据
+        let result = b.clone().unsubscribe(lobby.clone(), id1 as _).unwrap();
+        assert_eq!(result, id1 as u32);
+
+        // publish
+        let v: Value = "world".into();
+        b.clone().publish(lobby.clone(), Arc::new(v.clone().into()));
+
+        assert!(stream1.recv().await.is_none());
+        let res2 = stream2.recv().await.unwrap();
+        assert_res_ok((*res2).clone(), &[v], &[]);
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_unknown_id_should_fail() {
+        let b = Arc::new(Broadcaster::default());
+        let mut stream = b.clone().subscribe("lobby".into());
+        let id = get_id(&mut stream).await;
+
+        // 用别的主题去取消订阅会失败
+        let result = b.clone().unsubscribe("other".into(), id as _);
+        assert!(matches!(result, Err(KvError::SubscriptionNotFound(..))));
+
+        // 不存在的 id 也会失败
+        let result = b.unsubscribe("lobby".into(), u32::MAX);
+        assert!(matches!(result, Err(KvError::SubscriptionNotFound(..))));
+    }
+
+    async fn get_id(res: &mut mpsc::Receiver<Arc<CommandResponse>>) -> u32 {
+        let id: i64 = res.recv().await.unwrap().as_ref().try_into().unwrap();
+        id as u32
+    }
+}
@@ -1,10 +1,24 @@
+mod aof;
+mod blocking;
 pub mod memory;
 mod sleddb;
+mod snapshot;
+mod transaction;
 
 use crate::KvError;
-use crate::{Kvpair, Value};
+use crate::{value, CommandRequest, CommandResponse, Kvpair, Value, WatchedKey};
+pub use aof::FsyncPolicy;
+pub use blocking::{BlockingStorage, Inline};
+use futures::stream::BoxStream;
 pub use memory::*;
 pub use sleddb::*;
+pub use snapshot::{restore, snapshot};
+use std::cell::Cell;
+use std::future::Future;
+use std::io::Write;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// 对存储的抽象，我们不关心数据存在哪儿，但需要定义外界如何和存储打交道
 pub trait Storage {
@@ -18,8 +32,273 @@ pub trait Storage {
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
     /// 遍历 HashTable，返回所有 kv pair（这个接口不好）
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
+    /// 列出所有的 HashTable
+    fn list_tables(&self) -> Result<Vec<String>, KvError>;
+    /// HashTable 中 key 的数量，table 不存在时是 0
+    fn count(&self, table: &str) -> Result<usize, KvError>;
+    /// 删除整个 HashTable，返回删除的 key 的数量
+    fn drop_table(&self, table: &str) -> Result<usize, KvError>;
+    /// 把 HashTable from 改名为 to，to 中原有的数据会被覆盖；from 不存在时返回 false
+    fn rename_table(&self, from: &str, to: &str) -> Result<bool, KvError>;
     /// 遍历 HashTable，返回 kv pair 的 Iterator
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError>;
+    /// 按 key 的顺序返回 HashTable 中 start 到 end 之间的 kv pair，reverse 时从大到小，最多 limit 个
+    fn get_range(
+        &self,
+        table: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError>;
+    /// 设置一个 key 的 value，并在 ttl 之后过期，返回旧的 value
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError>;
+    /// 给一个已存在的 key 设置过期时间，key 不存在时返回 false
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError>;
+    /// 查看一个 key 剩余的生存时间
+    fn ttl(&self, table: &str, key: &str) -> Result<Ttl, KvError>;
+    /// 去掉一个 key 的过期时间，如果之前有过期时间，返回 true
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError>;
+    /// 清除所有已经过期的 key，返回清除的数量
+    fn purge_expired(&self) -> Result<usize, KvError>;
+    /// 原子地把 key 的整数值加上 delta，key 不存在时当作 0，返回新的值
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError>;
+    /// 原子地把 key 的数值加上 delta，key 不存在时当作 0，返回新的值
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError>;
+    /// key 当前的版本，key 不存在（或者已经过期）时是 0。包括过期时间在内的每次修改都会换一个新的版本，
+    /// 版本来自整个存储递增的计数器，所以 key 被删除后重新写入也不会回到原来的版本
+    fn version(&self, table: &str, key: &str) -> Result<i64, KvError>;
+    /// 在一个事务里依次执行 cmds，返回每个命令的 response。如果 watches 中有 key 的版本变了，
+    /// 或者某个命令的存储操作出错，整个事务的修改都不会生效
+    fn transaction(
+        &self,
+        watches: &[WatchedKey],
+        cmds: Vec<CommandRequest>,
+    ) -> Result<Vec<CommandResponse>, KvError>;
+    /// 把所有的数据写成快照，返回 kv pair 的数量。缺省的实现逐个 table 遍历，不是同一个时间点的数据，
+    /// 快照期间的并发修改可能只有一部分出现在快照里（比如 SledDb）
+    fn snapshot_to(&self, writer: &mut dyn Write) -> Result<u64, KvError>
+    where
+        Self: Sized,
+    {
+        snapshot(self, writer)
+    }
+    /// 用快照替换所有的数据，返回恢复的 kv pair 数量，快照无效时不会修改数据。
+    /// 缺省的实现先逐个删除再写入，期间其它操作会看到不完整的数据
+    fn reset_from(&self, data: &[u8]) -> Result<u64, KvError>
+    where
+        Self: Sized,
+    {
+        snapshot::reset_from(self, data)
+    }
+}
+
+/// 异步的存储，Service 通过它访问数据，等待存储的时候不会阻塞 tokio 的 worker。
+/// 同步的 Storage 可以用 BlockingStorage 包装成 AsyncStorage，不会阻塞的存储（比如 MemTable）用 Inline 包装。
+/// 返回的 future 必须是 Send 的，这样 Service 才能在任意的 worker 上执行命令
+pub trait AsyncStorage {
+    /// 从一个 HashTable 里获取一个 key 的 value
+    fn get(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value
+    fn set(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    /// 查看 HashTable 中是否有 key
+    fn contains(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send;
+    /// 从 HashTable 中删除一个 key
+    fn del(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    /// 遍历 HashTable，返回所有 kv pair
+    fn get_all(&self, table: &str) -> impl Future<Output = Result<Vec<Kvpair>, KvError>> + Send;
+    /// 遍历 HashTable，返回 kv pair 的 Stream，数据在 Stream 被读取时才取出。
+    /// 取数据出错时，Stream 返回一个错误之后结束
+    fn get_stream(
+        &self,
+        table: &str,
+    ) -> impl Future<Output = Result<BoxStream<'static, Result<Kvpair, KvError>>, KvError>> + Send;
+    /// 列出所有的 HashTable
+    fn list_tables(&self) -> impl Future<Output = Result<Vec<String>, KvError>> + Send;
+    /// HashTable 中 key 的数量，table 不存在时是 0
+    fn count(&self, table: &str) -> impl Future<Output = Result<usize, KvError>> + Send;
+    /// 删除整个 HashTable，返回删除的 key 的数量
+    fn drop_table(&self, table: &str) -> impl Future<Output = Result<usize, KvError>> + Send;
+    /// 把 HashTable from 改名为 to，to 中原有的数据会被覆盖；from 不存在时返回 false
+    fn rename_table(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send;
+    /// 按 key 的顺序返回 HashTable 中 start 到 end 之间的 kv pair，reverse 时从大到小，最多 limit 个
+    fn get_range(
+        &self,
+        table: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<Kvpair>, KvError>> + Send;
+    /// 设置一个 key 的 value，并在 ttl 之后过期，返回旧的 value
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    /// 给一个已存在的 key 设置过期时间，key 不存在时返回 false
+    fn expire(
+        &self,
+        table: &str,
+        key: &str,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send;
+    /// 查看一个 key 剩余的生存时间
+    fn ttl(&self, table: &str, key: &str) -> impl Future<Output = Result<Ttl, KvError>> + Send;
+    /// 去掉一个 key 的过期时间，如果之前有过期时间，返回 true
+    fn persist(&self, table: &str, key: &str)
+        -> impl Future<Output = Result<bool, KvError>> + Send;
+    /// 清除所有已经过期的 key，返回清除的数量
+    fn purge_expired(&self) -> impl Future<Output = Result<usize, KvError>> + Send;
+    /// 原子地把 key 的整数值加上 delta，key 不存在时当作 0，返回新的值
+    fn incr(
+        &self,
+        table: &str,
+        key: &str,
+        delta: i64,
+    ) -> impl Future<Output = Result<i64, KvError>> + Send;
+    /// 原子地把 key 的数值加上 delta，key 不存在时当作 0，返回新的值
+    fn incr_float(
+        &self,
+        table: &str,
+        key: &str,
+        delta: f64,
+    ) -> impl Future<Output = Result<f64, KvError>> + Send;
+    /// key 当前的版本，和 Storage::version 一样
+    fn version(&self, table: &str, key: &str) -> impl Future<Output = Result<i64, KvError>> + Send;
+    /// 在一个事务里依次执行 cmds，和 Storage::transaction 一样
+    fn transaction(
+        &self,
+        watches: Vec<WatchedKey>,
+        cmds: Vec<CommandRequest>,
+    ) -> impl Future<Output = Result<Vec<CommandResponse>, KvError>> + Send;
+    /// 所有数据的快照，返回 kv pair 的数量和快照的内容
+    fn snapshot(&self) -> impl Future<Output = Result<(u64, Vec<u8>), KvError>> + Send;
+    /// 把所有数据的快照直接写到文件 path，返回 kv pair 的数量。path 不能是符号链接
+    fn snapshot_file(&self, path: PathBuf) -> impl Future<Output = Result<u64, KvError>> + Send;
+    /// 从快照文件 path 恢复数据，返回恢复的 kv pair 数量，已经存在的同名 key 会被覆盖。
+    /// path 不能是符号链接
+    fn restore_file(&self, path: PathBuf) -> impl Future<Output = Result<u64, KvError>> + Send;
+    /// 用快照替换所有的数据，和 Storage::reset_from 一样
+    fn reset_from(&self, data: Vec<u8>) -> impl Future<Output = Result<u64, KvError>> + Send;
+}
+
+/// 一个 key 剩余的生存时间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ttl {
+    /// key 不存在（或者已经过期）
+    Missing,
+    /// key 存在，但没有过期时间
+    Persistent,
+    /// key 会在这段时间之后过期
+    Expires(Duration),
+}
+
+/// 计算 incr 之后的值，只有整数可以做整数加法
+pub(crate) fn incr_value(value: Option<&Value>, delta: i64) -> Result<i64, KvError> {
+    let current = match value {
+        Some(v) => i64::try_from(v)?,
+        None => 0,
+    };
+    current
+        .checked_add(delta)
+        .ok_or_else(|| KvError::InvalidCommand(format!("{} + {} overflows", current, delta)))
+}
+
+/// 计算 incr_float 之后的值，整数和浮点数都可以做浮点数加法
+pub(crate) fn incr_float_value(value: Option<&Value>, delta: f64) -> Result<f64, KvError> {
+    let current = match value {
+        None => 0.0,
+        Some(v) => match v.value {
+            Some(value::Value::Float(f)) => f,
+            Some(value::Value::Integer(i)) => i as f64,
+            _ => return Err(KvError::ConvertError(v.clone(), "Float")),
+        },
+    };
+    let result = current + delta;
+    if !result.is_finite() {
+        return Err(KvError::InvalidCommand(format!(
+            "{} + {} is not finite",
+            current, delta
+        )));
+    }
+    Ok(result)
+}
+
+/// start 在 end 之后，或者相等但有一端不包含时，范围里没有 key（BTreeMap::range 会 panic）
+pub(crate) fn is_empty_range(start: Bound<&str>, end: Bound<&str>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+            s >= e
+        }
+        _ => false,
+    }
+}
+
+thread_local! {
+    /// 设置时当前线程上的 now_ms 返回这个时间
+    static FIXED_NOW: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// 当前时间，从 UNIX_EPOCH 开始的毫秒数。过期时间用它来表示，这样可以持久化
+pub(crate) fn now_ms() -> u64 {
+    FIXED_NOW.with(Cell::get).unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    })
+}
+
+/// 执行 f 的期间，当前线程上的 now_ms 固定返回 now。
+/// raft 用它让每个节点都按 leader 写入 entry 时的时间判断过期，应用的结果和本地时钟无关
+pub(crate) fn with_now<T>(now: u64, f: impl FnOnce() -> T) -> T {
+    // f panic 时也要恢复原来的设置
+    struct Restore(Option<u64>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            FIXED_NOW.with(|t| t.set(self.0));
+        }
+    }
+    let _restore = Restore(FIXED_NOW.with(|t| t.replace(Some(now))));
+    f()
+}
+
+/// 从现在开始，经过 ttl 之后的时间点
+pub(crate) fn expire_at(ttl: Duration) -> u64 {
+    // 超过 u64 的毫秒数不能截断，否则会变成很近的时间点
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    now_ms().saturating_add(ttl)
 }
 
 struct StorageIter<T> {
@@ -47,6 +326,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{sync::Arc, thread};
     use tempfile::tempdir;
     #[test]
     fn memtable_basic_interface_should_work() {
@@ -54,6 +334,23 @@ mod tests {
         test_basi_interface(store);
     }
     #[test]
+    fn expire_at_should_saturate_large_ttl() {
+        assert_eq!(expire_at(Duration::from_secs(u64::MAX)), u64::MAX);
+        // 毫秒数截断成 u64 之后只剩几百毫秒
+        assert_eq!(
+            expire_at(Duration::from_secs(u64::MAX / 1000 + 1)),
+            u64::MAX
+        );
+
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert!(store
+            .expire("t1", "k1", Duration::from_secs(u64::MAX))
+            .unwrap());
+        let ttl = store.ttl("t1", "k1").unwrap();
+        assert!(matches!(ttl, Ttl::Expires(d) if d > Duration::from_secs(3600)));
+    }
+    #[test]
     fn memtable_get_all_should_work() {
         let store = MemTable::new();
         test_get_all(store);
@@ -76,6 +373,253 @@ mod tests {
         let store = SledDb::new(dir);
         test_get_iter(store);
     }
+    #[test]
+    fn memtable_range_should_work() {
+        let store = MemTable::new();
+        test_get_range(store);
+    }
+    #[test]
+    fn sleddb_range_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_get_range(store);
+    }
+    #[test]
+    fn memtable_table_management_should_work() {
+        let store = MemTable::new();
+        test_table_management(store);
+    }
+    #[test]
+    fn sleddb_table_management_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_table_management(store);
+    }
+    #[test]
+    fn memtable_ttl_should_work() {
+        let store = MemTable::new();
+        test_ttl(store);
+    }
+    #[test]
+    fn memtable_purge_expired_should_work() {
+        let store = MemTable::new();
+        test_purge_expired(store);
+    }
+    #[test]
+    fn sleddb_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_ttl(store);
+    }
+    #[test]
+    fn sleddb_purge_expired_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_purge_expired(store);
+    }
+    #[test]
+    fn memtable_incr_should_work() {
+        let store = MemTable::new();
+        test_incr(store);
+    }
+    #[test]
+    fn memtable_concurrent_incr_should_be_atomic() {
+        let store = MemTable::new();
+        test_concurrent_incr(store);
+    }
+    #[test]
+    fn sleddb_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_incr(store);
+    }
+    #[test]
+    fn sleddb_concurrent_incr_should_be_atomic() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_concurrent_incr(store);
+    }
+    #[test]
+    fn memtable_transaction_should_work() {
+        let store = MemTable::new();
+        test_transaction(store);
+    }
+    #[test]
+    fn memtable_concurrent_transaction_should_be_serializable() {
+        let store = MemTable::new();
+        test_concurrent_transaction(store);
+    }
+    #[test]
+    fn sleddb_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_transaction(store);
+    }
+    #[test]
+    fn sleddb_concurrent_transaction_should_be_serializable() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_concurrent_transaction(store);
+    }
+    #[test]
+    fn memtable_version_should_change_on_every_mutation() {
+        let store = MemTable::new();
+        test_version(store);
+    }
+    #[test]
+    fn sleddb_version_should_change_on_every_mutation() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_version(store);
+    }
+    #[test]
+    fn memtable_aof_should_restore_data() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        {
+            let store = MemTable::with_aof(&path, FsyncPolicy::Always).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.set("t1", "k2".into(), "v2".into()).unwrap();
+            store.del("t1", "k2").unwrap();
+            store.incr("t1", "counter", 3).unwrap();
+            store
+                .set_with_ttl("t1", "k3".into(), "v3".into(), Duration::from_secs(100))
+                .unwrap();
+            store
+                .set_with_ttl("t1", "k4".into(), "v4".into(), Duration::from_millis(50))
+                .unwrap();
+            // 失败的事务不会被记录
+            let cmds = vec![
+                CommandRequest::new_hset("t2", "k1", "v1".into()),
+                CommandRequest::new_hincrby("t1", "k1", 1),
+            ];
+            assert!(store.transaction(&[], cmds).is_err());
+            let cmds = vec![
+                CommandRequest::new_hset("t2", "k2", "v2".into()),
+                CommandRequest::new_hincrby("t1", "counter", 1),
+            ];
+            store.transaction(&[], cmds).unwrap();
+            store.set("t3", "k1".into(), "v1".into()).unwrap();
+            store.rename_table("t3", "t4").unwrap();
+            store.set("t5", "k1".into(), "v1".into()).unwrap();
+            store.drop_table("t5").unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let store = MemTable::with_aof(&path, FsyncPolicy::Always).unwrap();
+        let mut data = store.get_all("t1").unwrap();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            data,
+            vec![
+                Kvpair::new("counter", Value::integer(4)),
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k3", "v3".into()),
+            ]
+        );
+        assert!(matches!(store.ttl("t1", "k3").unwrap(), Ttl::Expires(_)));
+        assert_eq!(
+            store.get_all("t2").unwrap(),
+            vec![Kvpair::new("k2", "v2".into())]
+        );
+        let mut tables = store.list_tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, ["t1", "t2", "t4"]);
+    }
+    #[test]
+    fn memtable_aof_rewrite_should_compact_log() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        {
+            let store = MemTable::with_aof(&path, FsyncPolicy::Never).unwrap();
+            for i in 0..100 {
+                store.set("t1", "k1".into(), Value::integer(i)).unwrap();
+            }
+            store
+                .set_with_ttl("t1", "k2".into(), "v2".into(), Duration::from_secs(100))
+                .unwrap();
+            let size = std::fs::metadata(&path).unwrap().len();
+            store.rewrite_aof().unwrap();
+            assert!(std::fs::metadata(&path).unwrap().len() < size);
+            // 重写之后的修改继续写到新的日志里
+            store.set("t1", "k3".into(), "v3".into()).unwrap();
+        }
+
+        let store = MemTable::with_aof(&path, FsyncPolicy::Never).unwrap();
+        let mut data = store.get_all("t1").unwrap();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            data,
+            vec![
+                Kvpair::new("k1", Value::integer(99)),
+                Kvpair::new("k2", "v2".into()),
+                Kvpair::new("k3", "v3".into()),
+            ]
+        );
+        assert!(matches!(store.ttl("t1", "k2").unwrap(), Ttl::Expires(_)));
+    }
+    #[test]
+    fn memtable_reset_from_should_replace_all_data() {
+        test_reset_from(MemTable::new());
+    }
+    #[test]
+    fn sleddb_reset_from_should_replace_all_data() {
+        let dir = tempdir().unwrap();
+        test_reset_from(SledDb::new(dir.path()));
+    }
+    #[test]
+    fn memtable_reset_from_should_be_logged() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        let src = MemTable::new();
+        src.set("t2", "k1".into(), "v1".into()).unwrap();
+        let mut data = vec![];
+        src.snapshot_to(&mut data).unwrap();
+        {
+            let store = MemTable::with_aof(&path, FsyncPolicy::Never).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.reset_from(&data).unwrap();
+        }
+
+        let store = MemTable::with_aof(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(store.list_tables().unwrap(), ["t2"]);
+        assert_eq!(store.get("t2", "k1").unwrap(), Some("v1".into()));
+    }
+    #[test]
+    fn memtable_without_aof_should_not_rewrite() {
+        let store = MemTable::new();
+        assert!(store.rewrite_aof().is_err());
+    }
+    #[test]
+    fn sleddb_should_reject_table_names_with_colon() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        store.set("a", "k1".into(), "v1".into()).unwrap();
+        // a:b 的 key 会落在 a 的前缀下面，drop_table("a") 会把它们一起删掉
+        let result = store.set("a:b", "k2".into(), "v2".into());
+        assert!(matches!(result, Err(KvError::InvalidCommand(_))));
+        assert!(store.get("a:b", "k2").is_err());
+        assert!(store.drop_table("a:b").is_err());
+        assert!(store.rename_table("a", "a:b").is_err());
+        assert_eq!(store.list_tables().unwrap(), ["a"]);
+        assert_eq!(
+            store.get_all("a").unwrap(),
+            [Kvpair::new("k1", "v1".into())]
+        );
+    }
+    #[test]
+    fn sleddb_ttl_should_survive_reopen() {
+        let dir = tempdir().unwrap();
+        {
+            let store = SledDb::new(dir.path());
+            store
+                .set_with_ttl("t1", "k1".into(), "v1".into(), Duration::from_millis(50))
+                .unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+        let store = SledDb::new(dir.path());
+        assert!(store.get("t1", "k1").unwrap().is_none());
+    }
 
     fn test_basi_interface(store: impl Storage) {
         // 第一次 set 会创建 table，插入 key 并返回 None（之前没值）
@@ -119,6 +663,414 @@ mod tests {
             ]
         )
     }
+    fn test_ttl(store: impl Storage) {
+        let short = Duration::from_millis(50);
+        let long = Duration::from_secs(100);
+
+        // 带 ttl 的 key 在过期之前可以正常访问
+        let v = store.set_with_ttl("t1", "k1".into(), "v1".into(), short);
+        assert!(v.unwrap().is_none());
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(matches!(store.ttl("t1", "k1").unwrap(), Ttl::Expires(d) if d <= short));
+        assert_eq!(store.ttl("t1", "k2").unwrap(), Ttl::Persistent);
+        assert_eq!(store.ttl("t1", "k3").unwrap(), Ttl::Missing);
+
+        // 过期之后，get / contains / get_all / get_iter 都看不到它
+        thread::sleep(short * 2);
+        assert!(store.get("t1", "k1").unwrap().is_none());
+        assert!(!store.contains("t1", "k1").unwrap());
+        assert_eq!(store.ttl("t1", "k1").unwrap(), Ttl::Missing);
+        assert_eq!(
+            store.get_all("t1").unwrap(),
+            vec![Kvpair::new("k2", "v2".into())]
+        );
+        let data: Vec<_> = store.get_iter("t1").unwrap().collect();
+        assert_eq!(data, vec![Kvpair::new("k2", "v2".into())]);
+
+        // 对已过期的 key set，旧值是 None
+        store
+            .set_with_ttl("t1", "k1".into(), "v1".into(), short)
+            .unwrap();
+        thread::sleep(short * 2);
+        assert!(store.set("t1", "k1".into(), "v2".into()).unwrap().is_none());
+
+        // 普通的 set 会清除过期时间
+        store
+            .set_with_ttl("t1", "k1".into(), "v1".into(), long)
+            .unwrap();
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+        assert_eq!(store.ttl("t1", "k1").unwrap(), Ttl::Persistent);
+
+        // expire 只对存在的 key 生效
+        assert!(!store.expire("t1", "k3", short).unwrap());
+        assert!(store.expire("t1", "k1", long).unwrap());
+        assert!(matches!(store.ttl("t1", "k1").unwrap(), Ttl::Expires(d) if d > short));
+
+        // persist 去掉过期时间
+        assert!(store.persist("t1", "k1").unwrap());
+        assert!(!store.persist("t1", "k1").unwrap());
+        assert!(!store.persist("t1", "k3").unwrap());
+        assert_eq!(store.ttl("t1", "k1").unwrap(), Ttl::Persistent);
+
+        // del 一个过期的 key，返回 None
+        store.expire("t1", "k1", short).unwrap();
+        thread::sleep(short * 2);
+        assert!(store.del("t1", "k1").unwrap().is_none());
+    }
+    fn test_purge_expired(store: impl Storage) {
+        let short = Duration::from_millis(50);
+        store
+            .set_with_ttl("t1", "k1".into(), "v1".into(), short)
+            .unwrap();
+        store
+            .set_with_ttl("t2", "k1".into(), "v1".into(), short)
+            .unwrap();
+        store
+            .set_with_ttl("t2", "k2".into(), "v2".into(), Duration::from_secs(100))
+            .unwrap();
+        store.set("t2", "k3".into(), "v3".into()).unwrap();
+
+        assert_eq!(store.purge_expired().unwrap(), 0);
+        thread::sleep(short * 2);
+        assert_eq!(store.purge_expired().unwrap(), 2);
+        assert_eq!(store.purge_expired().unwrap(), 0);
+
+        let mut data = store.get_all("t2").unwrap();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            data,
+            vec![
+                Kvpair::new("k2", "v2".into()),
+                Kvpair::new("k3", "v3".into())
+            ]
+        );
+    }
+    fn test_incr(store: impl Storage) {
+        // key 不存在时当作 0
+        assert_eq!(store.incr("t1", "k1", 5).unwrap(), 5);
+        assert_eq!(store.incr("t1", "k1", -7).unwrap(), -2);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(Value::integer(-2)));
+
+        // 浮点数加法可以作用在整数上，结果是浮点数
+        assert_eq!(store.incr_float("t1", "k1", 0.5).unwrap(), -1.5);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some((-1.5).into()));
+        assert_eq!(store.incr_float("t1", "k2", 1.5).unwrap(), 1.5);
+
+        // 浮点数不能做整数加法
+        let result = store.incr("t1", "k1", 1);
+        assert!(matches!(result, Err(KvError::ConvertError(..))));
+
+        // 非数字的值都会失败，并且原来的值不变
+        store.set("t1", "k3".into(), "hello".into()).unwrap();
+        let result = store.incr("t1", "k3", 1);
+        assert!(matches!(result, Err(KvError::ConvertError(..))));
+        let result = store.incr_float("t1", "k3", 1.0);
+        assert!(matches!(result, Err(KvError::ConvertError(..))));
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("hello".into()));
+
+        // 溢出会失败
+        store.incr("t1", "k4", i64::MAX).unwrap();
+        assert!(store.incr("t1", "k4", 1).is_err());
+
+        // incr 保留过期时间，已过期的 key 当作不存在
+        let ttl = Duration::from_millis(50);
+        store
+            .set_with_ttl("t1", "k5".into(), Value::integer(10), ttl)
+            .unwrap();
+        assert_eq!(store.incr("t1", "k5", 1).unwrap(), 11);
+        assert!(matches!(store.ttl("t1", "k5").unwrap(), Ttl::Expires(_)));
+        thread::sleep(ttl * 2);
+        assert_eq!(store.incr("t1", "k5", 1).unwrap(), 1);
+        assert_eq!(store.ttl("t1", "k5").unwrap(), Ttl::Persistent);
+    }
+    fn test_concurrent_incr(store: impl Storage + Send + Sync + 'static) {
+        let store = Arc::new(store);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        store.incr("t1", "counter", 1).unwrap();
+                        store.incr_float("t1", "total", 0.5).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(store.incr("t1", "counter", 0).unwrap(), 800);
+        assert_eq!(store.incr_float("t1", "total", 0.0).unwrap(), 400.0);
+    }
+    fn test_transaction(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let version = store.version("t1", "k1").unwrap();
+
+        // watched key 没有变化，所有的命令都会执行
+        let watches = vec![WatchedKey::new("t1", "k1", version)];
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "k2", "v2".into()),
+            CommandRequest::new_hincrby("t2", "counter", 1),
+            CommandRequest::new_hget("t1", "k2"),
+        ];
+        let responses = store.transaction(&watches, cmds).unwrap();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[2].values, vec!["v2".into()]);
+        assert_eq!(store.get("t2", "counter").unwrap(), Some(Value::integer(1)));
+
+        // watched key 变了，事务不会执行
+        store.set("t1", "k1".into(), "v3".into()).unwrap();
+        let cmds = vec![CommandRequest::new_hset("t1", "k2", "v4".into())];
+        let result = store.transaction(&watches, cmds);
+        assert!(matches!(result, Err(KvError::WatchConflict(..))));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+
+        // 不存在的 key 也可以 watch
+        let watches = vec![WatchedKey::new(
+            "t1",
+            "k3",
+            store.version("t1", "k3").unwrap(),
+        )];
+        let cmds = vec![CommandRequest::new_hset("t1", "k3", "v3".into())];
+        assert!(store.transaction(&watches, cmds).is_ok());
+
+        // watch 的时候不存在，之后被创建又删除了，事务也不会执行
+        let watches = vec![WatchedKey::new(
+            "t1",
+            "k6",
+            store.version("t1", "k6").unwrap(),
+        )];
+        store.set("t1", "k6".into(), "v6".into()).unwrap();
+        store.del("t1", "k6").unwrap();
+        let cmds = vec![CommandRequest::new_hset("t1", "k6", "v6".into())];
+        let result = store.transaction(&watches, cmds);
+        assert!(matches!(result, Err(KvError::WatchConflict(..))));
+        assert_eq!(store.get("t1", "k6").unwrap(), None);
+
+        // 某个命令出错，之前的修改都会回滚
+        store
+            .set_with_ttl("t1", "k4".into(), "v4".into(), Duration::from_secs(100))
+            .unwrap();
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "k5", "v5".into()),
+            CommandRequest::new_hdel("t1", "k2"),
+            CommandRequest::new_hpersist("t1", "k4"),
+            CommandRequest::new_hincrby("t2", "counter", 1),
+            CommandRequest::new_hincrby("t1", "k1", 1),
+        ];
+        let result = store.transaction(&[], cmds);
+        assert!(matches!(result, Err(KvError::ConvertError(..))));
+        assert!(store.get("t1", "k5").unwrap().is_none());
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        assert!(matches!(store.ttl("t1", "k4").unwrap(), Ttl::Expires(_)));
+        assert_eq!(store.get("t2", "counter").unwrap(), Some(Value::integer(1)));
+    }
+    fn test_version(store: impl Storage) {
+        assert_eq!(store.version("t1", "k1").unwrap(), 0);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let v1 = store.version("t1", "k1").unwrap();
+        assert!(v1 > 0);
+        store.get("t1", "k1").unwrap();
+        assert_eq!(store.version("t1", "k1").unwrap(), v1);
+
+        // 写入相同的值、修改过期时间都会换一个新的版本
+        let mut versions = vec![v1];
+        let mut check = |store: &dyn Storage| {
+            let v = store.version("t1", "k1").unwrap();
+            assert!(!versions.contains(&v));
+            versions.push(v);
+        };
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        check(&store);
+        store.expire("t1", "k1", Duration::from_secs(100)).unwrap();
+        check(&store);
+        store.persist("t1", "k1").unwrap();
+        check(&store);
+        store
+            .set_with_ttl(
+                "t1",
+                "k1".into(),
+                Value::integer(1),
+                Duration::from_secs(100),
+            )
+            .unwrap();
+        check(&store);
+        store.incr("t1", "k1", 1).unwrap();
+        check(&store);
+
+        // 删除也会换一个新的版本，重新写入也不会回到原来的版本
+        store.del("t1", "k1").unwrap();
+        check(&store);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        check(&store);
+
+        // 不存在的 key 被创建又删除之后，版本和 WATCH 时看到的不一样
+        let missing = store.version("t1", "k2").unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.del("t1", "k2").unwrap();
+        assert_ne!(store.version("t1", "k2").unwrap(), missing);
+        let missing = store.version("t1", "k2").unwrap();
+        store.set("t3", "k2".into(), "v2".into()).unwrap();
+        store.rename_table("t3", "t1").unwrap();
+        store.drop_table("t1").unwrap();
+        assert_ne!(store.version("t1", "k2").unwrap(), missing);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        check(&store);
+
+        // 过期也会换一个新的版本
+        store
+            .set_with_ttl("t1", "k2".into(), "v2".into(), Duration::from_millis(50))
+            .unwrap();
+        let v2 = store.version("t1", "k2").unwrap();
+        assert!(v2 > 0);
+        thread::sleep(Duration::from_millis(100));
+        assert_ne!(store.version("t1", "k2").unwrap(), v2);
+
+        // 改名之后，目标 table 中的 key 版本也会变
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        store.rename_table("t2", "t1").unwrap();
+        check(&store);
+    }
+    fn test_reset_from(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        let version = store.version("t1", "k1").unwrap();
+
+        let src = MemTable::new();
+        src.set("t1", "k1".into(), "v2".into()).unwrap();
+        let ttl = Duration::from_secs(100);
+        src.set_with_ttl("t3", "k1".into(), "v3".into(), ttl)
+            .unwrap();
+        let mut data = vec![];
+        src.snapshot_to(&mut data).unwrap();
+
+        // 无效的快照不会修改数据
+        let result = store.reset_from(&data[..data.len() - 1]);
+        assert!(matches!(result, Err(KvError::SnapshotError(_))));
+        assert_eq!(store.get("t2", "k1").unwrap(), Some("v1".into()));
+
+        assert_eq!(store.reset_from(&data).unwrap(), 2);
+        let mut tables = store.list_tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, ["t1", "t3"]);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v2".into()));
+        assert!(matches!(store.ttl("t3", "k1").unwrap(), Ttl::Expires(_)));
+        // 换进来的数据有新的版本，WATCH 能发现变化
+        assert_ne!(store.version("t1", "k1").unwrap(), version);
+    }
+    fn test_concurrent_transaction(store: impl Storage + Send + Sync + 'static) {
+        let store = Arc::new(store);
+        store
+            .set("t1", "counter".into(), Value::integer(0))
+            .unwrap();
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        // 读出当前的值，加一后写回，版本变了就重试
+                        loop {
+                            // 先读版本再读值，读值之前如果有修改，事务会因为版本不同而重试
+                            let version = store.version("t1", "counter").unwrap();
+                            let value = store.get("t1", "counter").unwrap();
+                            let n = i64::try_from(value.as_ref().unwrap()).unwrap();
+                            let watches = vec![WatchedKey::new("t1", "counter", version)];
+                            let cmd =
+                                CommandRequest::new_hset("t1", "counter", Value::integer(n + 1));
+                            match store.transaction(&watches, vec![cmd]) {
+                                Ok(_) => break,
+                                Err(KvError::WatchConflict(..)) => continue,
+                                Err(e) => panic!("{:?}", e),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(
+            store.get("t1", "counter").unwrap(),
+            Some(Value::integer(200))
+        );
+    }
+    fn test_get_range(store: impl Storage) {
+        use Bound::*;
+        for k in ["d", "b", "e", "a", "c"] {
+            store.set("t1", k.into(), k.into()).unwrap();
+        }
+        // 其它 table 的 key 不会出现在结果里
+        store.set("t10", "c".into(), "c".into()).unwrap();
+        store.set("t2", "c".into(), "c".into()).unwrap();
+        let range = |start, end, reverse, limit| -> Vec<String> {
+            let pairs = store.get_range("t1", start, end, reverse, limit).unwrap();
+            pairs.into_iter().map(|p| p.key).collect()
+        };
+
+        assert_eq!(range(Included("b"), Excluded("d"), false, 10), ["b", "c"]);
+        assert_eq!(range(Excluded("b"), Included("d"), false, 10), ["c", "d"]);
+        assert_eq!(range(Included("c"), Unbounded, false, 10), ["c", "d", "e"]);
+        assert_eq!(range(Unbounded, Unbounded, true, 2), ["e", "d"]);
+        assert_eq!(range(Unbounded, Excluded("c"), true, 10), ["b", "a"]);
+        // start 在 end 之后，或者相等但不包含时，范围是空的
+        assert!(range(Included("d"), Included("b"), false, 10).is_empty());
+        assert!(range(Excluded("c"), Excluded("c"), false, 10).is_empty());
+        assert_eq!(range(Included("c"), Included("c"), false, 10), ["c"]);
+
+        // 过期的 key 不会返回，也不占 limit
+        let short = Duration::from_millis(50);
+        store
+            .set_with_ttl("t1", "bb".into(), "bb".into(), short)
+            .unwrap();
+        assert_eq!(range(Excluded("a"), Unbounded, false, 2), ["b", "bb"]);
+        thread::sleep(short * 2);
+        assert_eq!(range(Excluded("a"), Unbounded, false, 2), ["b", "c"]);
+    }
+    fn test_table_management(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.set("t2", "k3".into(), "v3".into()).unwrap();
+        let tables = || {
+            let mut tables = store.list_tables().unwrap();
+            tables.sort();
+            tables
+        };
+        // 读不存在的 table 不会创建它
+        assert_eq!(store.get("t3", "k1").unwrap(), None);
+        assert!(store.get_all("t3").unwrap().is_empty());
+        assert_eq!(tables(), ["t1", "t2"]);
+        assert_eq!(store.count("t1").unwrap(), 2);
+        assert_eq!(store.count("t3").unwrap(), 0);
+
+        // 改名会覆盖 to 中原有的数据，from 不存在时什么都不做
+        assert!(store.rename_table("t1", "t2").unwrap());
+        assert!(!store.rename_table("t1", "t2").unwrap());
+        assert_eq!(tables(), ["t2"]);
+        let keys: Vec<_> = store
+            .get_range("t2", Bound::Unbounded, Bound::Unbounded, false, 10)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.key)
+            .collect();
+        assert_eq!(keys, ["k1", "k2"]);
+
+        // 过期的 key 不计数，只有过期的 key 的 table 不会被列出来
+        let short = Duration::from_millis(50);
+        store
+            .set_with_ttl("t2", "k4".into(), "v4".into(), short)
+            .unwrap();
+        store
+            .set_with_ttl("t3", "k1".into(), "v1".into(), short)
+            .unwrap();
+        thread::sleep(short * 2);
+        assert_eq!(tables(), ["t2"]);
+        assert_eq!(store.count("t2").unwrap(), 2);
+        assert_eq!(store.drop_table("t2").unwrap(), 2);
+        assert_eq!(store.drop_table("t2").unwrap(), 0);
+        assert!(tables().is_empty());
+    }
     fn test_get_iter(store: impl Storage) {
         store.set("t2", "k1".into(), "v1".into()).unwrap();
         store.set("t2", "k2".into(), "v2".into()).unwrap();
@@ -0,0 +1,307 @@
+use crate::{CommandRequest, KvError};
+use prost::{encoding::decode_varint, Message};
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+/// AOF 什么时候把数据刷到磁盘上
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// 每条日志都 fsync，最安全也最慢
+    Always,
+    /// 后台每秒 fsync 一次，机器宕机最多丢失一秒的数据
+    #[default]
+    EverySec,
+    /// 从不主动 fsync，由操作系统决定
+    Never,
+}
+
+/// append-only file：每条日志是一个 length-delimited 编码的 CommandRequest
+#[derive(Debug)]
+pub(crate) struct Aof {
+    path: PathBuf,
+    writer: Arc<Mutex<AofWriter>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct AofWriter {
+    file: File,
+    policy: FsyncPolicy,
+    /// 有还没有 fsync 的数据
+    dirty: bool,
+    /// 正在重写时，新的日志也要写一份到这里，重写完成后追加到新的文件
+    rewrite_buffer: Option<Vec<u8>>,
+    /// 文件当前的大小
+    size: u64,
+    /// 打开或者上次重写之后文件的大小
+    base_size: u64,
+}
+
+impl Aof {
+    /// 打开 AOF，返回其中所有的日志用于重放。
+    /// 最后一条日志如果不完整（比如写到一半时宕机），会被丢弃并从文件中截掉
+    pub fn open(
+        path: impl AsRef<Path>,
+        policy: FsyncPolicy,
+    ) -> Result<(Self, Vec<CommandRequest>), KvError> {
+        let path = path.as_ref().to_path_buf();
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let (records, valid) = decode_records(&data)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if valid < data.len() {
+            warn!(
+                "AOF {:?} has an incomplete record at {}, truncating",
+                path, valid
+            );
+            file.set_len(valid as u64)?;
+        }
+
+        let writer = Arc::new(Mutex::new(AofWriter {
+            file,
+            policy,
+            dirty: false,
+            rewrite_buffer: None,
+            size: valid as u64,
+            base_size: valid as u64,
+        }));
+        if policy == FsyncPolicy::EverySec {
+            spawn_fsync_thread(Arc::downgrade(&writer));
+        }
+        Ok((Self { path, writer }, records))
+    }
+
+    /// 持有 AOF 的锁，在锁内修改数据并写日志，日志的顺序就和修改的顺序一致
+    pub fn lock(&self) -> MutexGuard<'_, AofWriter> {
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// 文件至少有 min_size 字节，并且比打开或者上次重写之后大了一倍时，应该重写
+    pub fn should_rewrite(&self, min_size: u64) -> bool {
+        let writer = self.lock();
+        writer.rewrite_buffer.is_none()
+            && writer.size >= min_size
+            && writer.size >= writer.base_size.saturating_mul(2)
+    }
+
+    /// 重写 AOF。snapshot 在开始缓存新的日志之后才被调用，重写期间的修改会在缓冲区里，
+    /// 追加到 snapshot 之后。日志记录的都是 key 的最终状态，重复执行也没有关系，
+    /// 所以 snapshot 不需要和并发的修改互斥
+    pub fn rewrite<I>(&self, snapshot: impl FnOnce() -> I) -> Result<(), KvError>
+    where
+        I: IntoIterator<Item = CommandRequest>,
+    {
+        {
+            let mut writer = self.lock();
+            if writer.rewrite_buffer.is_some() {
+                return Err(KvError::Internal("AOF rewrite is in progress".into()));
+            }
+            writer.rewrite_buffer = Some(vec![]);
+        }
+
+        let tmp = self.path.with_extension("rewrite");
+        let result = self.write_snapshot(&tmp, snapshot());
+        let mut writer = self.lock();
+        let buffer = writer.rewrite_buffer.take().unwrap_or_default();
+        let result = result.and_then(|_| {
+            // 追加重写期间的日志，然后用新的文件替换旧的
+            let mut file = OpenOptions::new().append(true).open(&tmp)?;
+            file.write_all(&buffer)?;
+            file.sync_all()?;
+            fs::rename(&tmp, &self.path)?;
+            writer.file = OpenOptions::new().append(true).open(&self.path)?;
+            writer.dirty = false;
+            writer.size = writer.file.metadata()?.len();
+            writer.base_size = writer.size;
+            Ok(())
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    fn write_snapshot(
+        &self,
+        path: &Path,
+        snapshot: impl IntoIterator<Item = CommandRequest>,
+    ) -> Result<(), KvError> {
+        let mut file = BufWriter::new(File::create(path)?);
+        for record in snapshot {
+            file.write_all(&record.encode_length_delimited_to_vec())?;
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    }
+}
+
+impl AofWriter {
+    /// 追加一条日志
+    pub fn append(&mut self, record: &CommandRequest) -> Result<(), KvError> {
+        let data = record.encode_length_delimited_to_vec();
+        self.file.write_all(&data)?;
+        self.size += data.len() as u64;
+        if let Some(buffer) = self.rewrite_buffer.as_mut() {
+            buffer.extend_from_slice(&data);
+        }
+        match self.policy {
+            FsyncPolicy::Always => self.file.sync_data()?,
+            _ => self.dirty = true,
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), KvError> {
+        if self.dirty {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AofWriter {
+    fn drop(&mut self) {
+        if self.policy != FsyncPolicy::Never {
+            let _ = self.sync();
+        }
+    }
+}
+
+/// 每秒 fsync 一次，AOF 被释放之后线程自动退出
+fn spawn_fsync_thread(writer: Weak<Mutex<AofWriter>>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => break,
+        };
+        let mut writer = writer.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = writer.sync() {
+            warn!("Failed to fsync AOF: {:?}", e);
+        }
+    });
+}
+
+/// 解码所有完整的日志，返回日志和完整部分的长度。数据完整但解码失败说明文件损坏，返回错误
+fn decode_records(data: &[u8]) -> Result<(Vec<CommandRequest>, usize), KvError> {
+    let mut records = vec![];
+    let mut buf = data;
+    while !buf.is_empty() {
+        let valid = data.len() - buf.len();
+        let mut rest = buf;
+        let len = match decode_varint(&mut rest) {
+            Ok(len) if len as usize <= rest.len() => len as usize,
+            _ => return Ok((records, valid)),
+        };
+        records.push(CommandRequest::decode(&rest[..len])?);
+        buf = &rest[len..];
+    }
+    Ok((records, data.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+    use tempfile::tempdir;
+
+    #[test]
+    fn aof_should_replay_appended_records() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        {
+            let (aof, records) = Aof::open(&path, FsyncPolicy::Always).unwrap();
+            assert!(records.is_empty());
+            let mut writer = aof.lock();
+            writer
+                .append(&CommandRequest::new_hset("t1", "k1", "v1".into()))
+                .unwrap();
+            writer
+                .append(&CommandRequest::new_hdel("t1", "k1"))
+                .unwrap();
+        }
+        let (_, records) = Aof::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                CommandRequest::new_hset("t1", "k1", "v1".into()),
+                CommandRequest::new_hdel("t1", "k1")
+            ]
+        );
+    }
+
+    #[test]
+    fn aof_should_truncate_incomplete_record() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        let record = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut data = record.encode_length_delimited_to_vec();
+        let len = data.len();
+        data.extend_from_slice(&record.encode_length_delimited_to_vec()[..len - 2]);
+        fs::write(&path, &data).unwrap();
+
+        let (_, records) = Aof::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(records, vec![record]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len as u64);
+    }
+
+    #[test]
+    fn aof_rewrite_should_keep_records_appended_during_rewrite() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        let (aof, _) = Aof::open(&path, FsyncPolicy::Never).unwrap();
+        for i in 0..10 {
+            let cmd = CommandRequest::new_hset("t1", "k1", Value::integer(i));
+            aof.lock().append(&cmd).unwrap();
+        }
+
+        aof.rewrite(|| {
+            // 模拟重写期间发生的修改
+            let cmd = CommandRequest::new_hset("t1", "k2", "v2".into());
+            aof.lock().append(&cmd).unwrap();
+            vec![CommandRequest::new_hset("t1", "k1", Value::integer(9))]
+        })
+        .unwrap();
+        let cmd = CommandRequest::new_hdel("t1", "k2");
+        aof.lock().append(&cmd).unwrap();
+
+        drop(aof);
+        let (_, records) = Aof::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                CommandRequest::new_hset("t1", "k1", Value::integer(9)),
+                CommandRequest::new_hset("t1", "k2", "v2".into()),
+                CommandRequest::new_hdel("t1", "k2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn aof_should_rewrite_after_doubling_in_size() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        let (aof, _) = Aof::open(&path, FsyncPolicy::Never).unwrap();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let size = cmd.encode_length_delimited_to_vec().len() as u64;
+        aof.lock().append(&cmd).unwrap();
+        // 太小的文件不需要重写
+        assert!(!aof.should_rewrite(size * 2));
+        assert!(aof.should_rewrite(size));
+
+        aof.rewrite(|| vec![cmd.clone()]).unwrap();
+        assert!(!aof.should_rewrite(size));
+        aof.lock().append(&cmd).unwrap();
+        assert!(aof.should_rewrite(size));
+    }
+}
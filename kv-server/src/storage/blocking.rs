@@ -0,0 +1,475 @@
+use crate::storage::snapshot::{open_file, write_file};
+use crate::storage::{restore, AsyncStorage, Storage, Ttl};
+use crate::{CommandRequest, CommandResponse, KvError, Kvpair, Value, WatchedKey};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::future::{ready, Future};
+use std::ops::{Bound, Deref};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::spawn_blocking;
+
+/// get_stream 时，每次在 blocking 线程上读出的 kv pair 数量
+const STREAM_BATCH: usize = 128;
+
+/// 把同步的 Storage 包装成 AsyncStorage，每个操作都放在 spawn_blocking 的线程上执行，
+/// 比如 sled 的磁盘 I/O 不会阻塞 tokio 的 worker
+#[derive(Debug)]
+pub struct BlockingStorage<S>(Arc<S>);
+
+impl<S> Clone for BlockingStorage<S> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<S: Storage + Send + Sync + 'static> BlockingStorage<S> {
+    pub fn new(store: S) -> Self {
+        Self(Arc::new(store))
+    }
+
+    /// 被包装的 Storage
+    pub fn inner(&self) -> &S {
+        &self.0
+    }
+
+    /// 在 blocking 线程上执行 f
+    fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&S) -> Result<T, KvError> + Send + 'static,
+    ) -> impl Future<Output = Result<T, KvError>> + Send + 'static {
+        let store = Arc::clone(&self.0);
+        async move {
+            match spawn_blocking(move || f(&store)).await {
+                Ok(result) => result,
+                Err(e) => Err(KvError::Internal(format!("Storage task failed: {}", e))),
+            }
+        }
+    }
+}
+
+impl<S: Storage + Send + Sync + 'static> AsyncStorage for BlockingStorage<S> {
+    fn get(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.get(&table, &key))
+    }
+    fn set(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send {
+        let table = table.to_owned();
+        self.run(move |s| s.set(&table, key, value))
+    }
+    fn contains(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.contains(&table, &key))
+    }
+    fn del(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.del(&table, &key))
+    }
+    fn get_all(&self, table: &str) -> impl Future<Output = Result<Vec<Kvpair>, KvError>> + Send {
+        let table = table.to_owned();
+        self.run(move |s| s.get_all(&table))
+    }
+    fn get_stream(
+        &self,
+        table: &str,
+    ) -> impl Future<Output = Result<BoxStream<'static, Result<Kvpair, KvError>>, KvError>> + Send
+    {
+        let table = table.to_owned();
+        let iter = self.run(move |s| s.get_iter(&table));
+        async move {
+            let iter = iter.await?;
+            // 遍历时也可能读磁盘，同样放在 blocking 线程上。上一批被读完之后才去取下一批，
+            // 取完就释放线程，客户端读得慢时不会一直占着 blocking 线程。
+            // 取数据的 task 失败时，返回一个错误然后结束，不会悄悄地少返回数据
+            let batches = stream::unfold(Some(iter), |iter| async move {
+                let mut iter = iter?;
+                let joined = spawn_blocking(move || {
+                    let batch: Vec<_> = iter.by_ref().take(STREAM_BATCH).collect();
+                    (batch, iter)
+                })
+                .await;
+                match joined {
+                    Ok((batch, iter)) => {
+                        let next = (batch.len() == STREAM_BATCH).then_some(iter);
+                        let batch: Vec<_> = batch.into_iter().map(Ok).collect();
+                        Some((stream::iter(batch), next))
+                    }
+                    Err(e) => {
+                        let e = KvError::Internal(format!("Storage task failed: {}", e));
+                        Some((stream::iter(vec![Err(e)]), None))
+                    }
+                }
+            });
+            Ok(Box::pin(batches.flatten()) as BoxStream<'static, _>)
+        }
+    }
+    fn list_tables(&self) -> impl Future<Output = Result<Vec<String>, KvError>> + Send {
+        self.run(|s| s.list_tables())
+    }
+    fn count(&self, table: &str) -> impl Future<Output = Result<usize, KvError>> + Send {
+        let table = table.to_owned();
+        self.run(move |s| s.count(&table))
+    }
+    fn drop_table(&self, table: &str) -> impl Future<Output = Result<usize, KvError>> + Send {
+        let table = table.to_owned();
+        self.run(move |s| s.drop_table(&table))
+    }
+    fn rename_table(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send {
+        let (from, to) = (from.to_owned(), to.to_owned());
+        self.run(move |s| s.rename_table(&from, &to))
+    }
+    fn get_range(
+        &self,
+        table: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<Kvpair>, KvError>> + Send {
+        let table = table.to_owned();
+        let (start, end) = (start.map(str::to_owned), end.map(str::to_owned));
+        self.run(move |s| {
+            let start = start.as_ref().map(String::as_str);
+            let end = end.as_ref().map(String::as_str);
+            s.get_range(&table, start, end, reverse, limit)
+        })
+    }
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send {
+        let table = table.to_owned();
+        self.run(move |s| s.set_with_ttl(&table, key, value, ttl))
+    }
+    fn expire(
+        &self,
+        table: &str,
+        key: &str,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.expire(&table, &key, ttl))
+    }
+    fn ttl(&self, table: &str, key: &str) -> impl Future<Output = Result<Ttl, KvError>> + Send {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.ttl(&table, &key))
+    }
+    fn persist(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.persist(&table, &key))
+    }
+    fn purge_expired(&self) -> impl Future<Output = Result<usize, KvError>> + Send {
+        self.run(|s| s.purge_expired())
+    }
+    fn incr(
+        &self,
+        table: &str,
+        key: &str,
+        delta: i64,
+    ) -> impl Future<Output = Result<i64, KvError>> + Send {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.incr(&table, &key, delta))
+    }
+    fn incr_float(
+        &self,
+        table: &str,
+        key: &str,
+        delta: f64,
+    ) -> impl Future<Output = Result<f64, KvError>> + Send {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.incr_float(&table, &key, delta))
+    }
+    fn version(&self, table: &str, key: &str) -> impl Future<Output = Result<i64, KvError>> + Send {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.version(&table, &key))
+    }
+    fn transaction(
+        &self,
+        watches: Vec<WatchedKey>,
+        cmds: Vec<CommandRequest>,
+    ) -> impl Future<Output = Result<Vec<CommandResponse>, KvError>> + Send {
+        self.run(move |s| s.transaction(&watches, cmds))
+    }
+    fn snapshot(&self) -> impl Future<Output = Result<(u64, Vec<u8>), KvError>> + Send {
+        self.run(|s| {
+            let mut data = vec![];
+            let count = s.snapshot_to(&mut data)?;
+            Ok((count, data))
+        })
+    }
+    /// 快照一边生成一边写到文件里，文件的读写都在 blocking 线程上
+    fn snapshot_file(&self, path: PathBuf) -> impl Future<Output = Result<u64, KvError>> + Send {
+        self.run(move |s| write_file(&path, |writer| s.snapshot_to(writer)))
+    }
+    fn restore_file(&self, path: PathBuf) -> impl Future<Output = Result<u64, KvError>> + Send {
+        self.run(move |s| restore(s, open_file(&path)?))
+    }
+    fn reset_from(&self, data: Vec<u8>) -> impl Future<Output = Result<u64, KvError>> + Send {
+        self.run(move |s| s.reset_from(&data))
+    }
+}
+
+/// 在当前线程上直接执行同步的 Storage，返回的 future 都已经完成。
+/// 适合 MemTable 这样不会阻塞的存储；同步的 dispatch 也通过它执行命令，比如存储的事务里的命令
+#[derive(Debug, Clone)]
+pub struct Inline<S>(pub S);
+
+impl<S: Storage> Inline<Arc<S>> {
+    pub fn new(store: S) -> Self {
+        Self(Arc::new(store))
+    }
+
+    /// 被包装的 Storage
+    pub fn inner(&self) -> &S {
+        &self.0
+    }
+}
+
+impl<S> AsyncStorage for Inline<S>
+where
+    S: Deref,
+    S::Target: Storage + Sized,
+{
+    fn get(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send {
+        ready(self.0.get(table, key))
+    }
+    fn set(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send {
+        ready(self.0.set(table, key, value))
+    }
+    fn contains(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send {
+        ready(self.0.contains(table, key))
+    }
+    fn del(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send {
+        ready(self.0.del(table, key))
+    }
+    fn get_all(&self, table: &str) -> impl Future<Output = Result<Vec<Kvpair>, KvError>> + Send {
+        ready(self.0.get_all(table))
+    }
+    fn get_stream(
+        &self,
+        table: &str,
+    ) -> impl Future<Output = Result<BoxStream<'static, Result<Kvpair, KvError>>, KvError>> + Send
+    {
+        let result = self.0.get_iter(table);
+        ready(result.map(|iter| Box::pin(stream::iter(iter.map(Ok))) as BoxStream<'static, _>))
+    }
+    fn list_tables(&self) -> impl Future<Output = Result<Vec<String>, KvError>> + Send {
+        ready(self.0.list_tables())
+    }
+    fn count(&self, table: &str) -> impl Future<Output = Result<usize, KvError>> + Send {
+        ready(self.0.count(table))
+    }
+    fn drop_table(&self, table: &str) -> impl Future<Output = Result<usize, KvError>> + Send {
+        ready(self.0.drop_table(table))
+    }
+    fn rename_table(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send {
+        ready(self.0.rename_table(from, to))
+    }
+    fn get_range(
+        &self,
+        table: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<Kvpair>, KvError>> + Send {
+        ready(self.0.get_range(table, start, end, reverse, limit))
+    }
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send {
+        ready(self.0.set_with_ttl(table, key, value, ttl))
+    }
+    fn expire(
+        &self,
+        table: &str,
+        key: &str,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send {
+        ready(self.0.expire(table, key, ttl))
+    }
+    fn ttl(&self, table: &str, key: &str) -> impl Future<Output = Result<Ttl, KvError>> + Send {
+        ready(self.0.ttl(table, key))
+    }
+    fn persist(
+        &self,
+        table: &str,
+        key: &str,
+    ) -> impl Future<Output = Result<bool, KvError>> + Send {
+        ready(self.0.persist(table, key))
+    }
+    fn purge_expired(&self) -> impl Future<Output = Result<usize, KvError>> + Send {
+        ready(self.0.purge_expired())
+    }
+    fn incr(
+        &self,
+        table: &str,
+        key: &str,
+        delta: i64,
+    ) -> impl Future<Output = Result<i64, KvError>> + Send {
+        ready(self.0.incr(table, key, delta))
+    }
+    fn incr_float(
+        &self,
+        table: &str,
+        key: &str,
+        delta: f64,
+    ) -> impl Future<Output = Result<f64, KvError>> + Send {
+        ready(self.0.incr_float(table, key, delta))
+    }
+    fn version(&self, table: &str, key: &str) -> impl Future<Output = Result<i64, KvError>> + Send {
+        ready(self.0.version(table, key))
+    }
+    fn transaction(
+        &self,
+        watches: Vec<WatchedKey>,
+        cmds: Vec<CommandRequest>,
+    ) -> impl Future<Output = Result<Vec<CommandResponse>, KvError>> + Send {
+        ready(self.0.transaction(&watches, cmds))
+    }
+    fn snapshot(&self) -> impl Future<Output = Result<(u64, Vec<u8>), KvError>> + Send {
+        let mut data = vec![];
+        ready(self.0.snapshot_to(&mut data).map(|count| (count, data)))
+    }
+    fn snapshot_file(&self, path: PathBuf) -> impl Future<Output = Result<u64, KvError>> + Send {
+        ready(write_file(&path, |writer| self.0.snapshot_to(writer)))
+    }
+    fn restore_file(&self, path: PathBuf) -> impl Future<Output = Result<u64, KvError>> + Send {
+        ready(open_file(&path).and_then(|reader| restore(&*self.0, reader)))
+    }
+    fn reset_from(&self, data: Vec<u8>) -> impl Future<Output = Result<u64, KvError>> + Send {
+        ready(self.0.reset_from(&data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb};
+    use futures::{FutureExt, StreamExt, TryStreamExt};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn blocking_storage_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BlockingStorage::new(SledDb::new(dir.path()));
+        assert!(store
+            .set("t1", "k1".into(), "v1".into())
+            .await
+            .unwrap()
+            .is_none());
+        store.set("t1", "k2".into(), "v2".into()).await.unwrap();
+        store.set("t1", "k3".into(), "v3".into()).await.unwrap();
+        assert_eq!(store.get("t1", "k1").await.unwrap(), Some("v1".into()));
+        assert_eq!(store.del("t1", "k3").await.unwrap(), Some("v3".into()));
+        assert!(!store.contains("t1", "k3").await.unwrap());
+
+        // 借用的 bound 会被复制到 blocking 线程上
+        let start = Bound::Excluded("k1");
+        let pairs = store.get_range("t1", start, Bound::Unbounded, false, 10);
+        assert_eq!(pairs.await.unwrap(), vec![Kvpair::new("k2", "v2".into())]);
+
+        let stream = store.get_stream("t1").await.unwrap();
+        let mut pairs: Vec<_> = stream.try_collect().await.unwrap();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let expected = vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "v2".into()),
+        ];
+        assert_eq!(pairs, expected);
+
+        // 快照可以加载到另一个存储里
+        let (count, data) = store.snapshot().await.unwrap();
+        assert_eq!(count, 2);
+        let other = Inline::new(MemTable::new());
+        other.set("t2", "k1".into(), "v1".into()).await.unwrap();
+        assert_eq!(other.reset_from(data).await.unwrap(), 2);
+        assert_eq!(other.list_tables().await.unwrap(), vec!["t1".to_string()]);
+        assert_eq!(other.get_all("t1").await.unwrap().len(), 2);
+
+        // 快照也可以直接写到文件里，再从文件恢复
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.snapshot");
+        assert_eq!(store.snapshot_file(path.clone()).await.unwrap(), 2);
+        let other = Inline::new(MemTable::new());
+        assert_eq!(other.restore_file(path).await.unwrap(), 2);
+        assert_eq!(other.get("t1", "k2").await.unwrap(), Some("v2".into()));
+
+        // 超过一批的数据分几次取出来
+        for i in 0..STREAM_BATCH * 2 + 1 {
+            let key = format!("k{}", i);
+            store
+                .set("t2", key, Value::integer(i as i64))
+                .await
+                .unwrap();
+        }
+        let stream = store.get_stream("t2").await.unwrap();
+        assert_eq!(stream.count().await, STREAM_BATCH * 2 + 1);
+    }
+
+    #[test]
+    fn inline_should_never_pend() {
+        let store = MemTable::new();
+        let inline = Inline(&store);
+        let res = inline.set("t1", "k1".into(), "v1".into()).now_or_never();
+        assert!(res.unwrap().unwrap().is_none());
+        let res = inline.get_stream("t1").now_or_never().unwrap().unwrap();
+        let pairs: Vec<_> = futures::executor::block_on_stream(res)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(pairs, vec![Kvpair::new("k1", "v1".into())]);
+    }
+}
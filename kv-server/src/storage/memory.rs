@@ -1,55 +1,857 @@
-use crate::storage::StorageIter;
-use crate::{KvError, Kvpair, Storage, Value};
+use crate::command_request::RequestData;
+use crate::storage::aof::{Aof, FsyncPolicy};
+use crate::storage::transaction::{self, not_supported, TxError};
+use crate::storage::{
+    expire_at, incr_float_value, incr_value, is_empty_range, now_ms, restore, snapshot, StorageIter,
+};
+use crate::{
+    CommandRequest, CommandResponse, DropTable, Hdel, Hexpireat, Hset, KvError, Kvpair,
+    RenameTable, Storage, Ttl, Value, WatchedKey,
+};
 use dashmap::{mapref::one::Ref, DashMap};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+/// 所有 MemTable 共用的版本计数器，reset_from 换进来的数据也不会和之前的版本重复
+static VERSION: AtomicU64 = AtomicU64::new(0);
 
 /// 使用 DashMap 构建的 MemTable，实现了 Storage trait
-#[derive(Clone, Debug, Default)]
+#[derive(Debug, Default)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: DashMap<String, Table>,
+    /// 普通的操作拿读锁，事务拿写锁，这样事务执行的中间状态不会被其它操作看到
+    lock: RwLock<()>,
+    /// 开启 AOF 时，所有的修改都会记录到日志里
+    aof: Option<Aof>,
+    /// 最近一次删除 key 时分配的版本，作为所有不存在的 key 的版本。
+    /// 这样 key 被创建又删除之后，版本不会回到 WATCH 时看到的值；代价是删除任何 key
+    /// 都会让 WATCH 了不存在的 key 的事务失败
+    deleted: AtomicU64,
+}
+
+/// clone 出来的 MemTable 只是一份内存中的拷贝，不会写 AOF
+impl Clone for MemTable {
+    fn clone(&self) -> Self {
+        Self {
+            tables: self.tables.clone(),
+            lock: Default::default(),
+            aof: None,
+            deleted: AtomicU64::new(self.deleted.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// 不加锁地访问 MemTable，调用者需要持有 MemTable 的锁
+#[derive(Clone, Copy)]
+struct Unlocked<'a>(&'a MemTable);
+
+/// 事务中对 MemTable 的访问：修改一个 key 之前先记下它原来的 entry，出错时回滚
+struct MemTableTx<'a> {
+    inner: Unlocked<'a>,
+    undo: RefCell<HashMap<(String, String), Option<Entry>>>,
+    errors: TxError<KvError>,
 }
+
+/// 一个 HashTable，key 有序地存放在 BTreeMap 里，所以可以按范围遍历。
+/// 每个 table 有自己的锁，不同 table 上的操作互不影响
+#[derive(Debug, Default)]
+struct Table(RwLock<BTreeMap<String, Entry>>);
+
+impl Table {
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<String, Entry>> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// 没有过期的 key 的数量
+    fn live_count(&self, now: u64) -> usize {
+        self.read().values().filter(|v| !v.is_expired(now)).count()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<String, Entry>> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Clone for Table {
+    fn clone(&self) -> Self {
+        Self(RwLock::new(self.read().clone()))
+    }
+}
+
+/// MemTable 里存储的值，带有可选的过期时间和版本
+#[derive(Clone, Debug)]
+struct Entry {
+    value: Value,
+    /// 过期的时间点，从 UNIX_EPOCH 开始的毫秒数
+    expire_at: Option<u64>,
+    /// 最近一次修改这个 entry 时分配的版本
+    version: u64,
+}
+
+impl Entry {
+    fn new(value: Value, expire_at: Option<u64>, version: u64) -> Self {
+        Self {
+            value,
+            expire_at,
+            version,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expire_at, Some(t) if t <= now)
+    }
+
+    /// 如果没有过期，返回 value
+    fn into_live_value(self, now: u64) -> Option<Value> {
+        (!self.is_expired(now)).then_some(self.value)
+    }
+}
+
 impl MemTable {
     /// 创建一个缺省的 MemTable
     pub fn new() -> Self {
         Self::default()
     }
+    /// 创建一个开启 AOF 的 MemTable：先重放 path 中的日志恢复数据，之后所有的修改都会追加到日志里
+    pub fn with_aof(path: impl AsRef<Path>, policy: FsyncPolicy) -> Result<Self, KvError> {
+        let (aof, records) = Aof::open(path, policy)?;
+        let mut table = Self::new();
+        for record in records {
+            Unlocked(&table).apply_record(record)?;
+        }
+        table.aof = Some(aof);
+        Ok(table)
+    }
+    /// 重写 AOF，新的日志只包含当前的数据。重写期间其它操作可以正常进行，
+    /// 但这个函数会阻塞到重写完成，应该在后台线程中调用
+    pub fn rewrite_aof(&self) -> Result<(), KvError> {
+        let aof = self
+            .aof
+            .as_ref()
+            .ok_or_else(|| KvError::Internal("AOF is not enabled".into()))?;
+        aof.rewrite(|| {
+            let names: Vec<_> = self.tables.iter().map(|v| v.key().clone()).collect();
+            // 每次持有读锁收集一个 table 的日志，不会看到事务执行的中间状态，
+            // 写文件时也不会阻塞其它操作
+            names
+                .into_iter()
+                .flat_map(move |name| self.shared(|s| s.table_records(&name)))
+        })
+    }
+    /// AOF 至少有 min_size 字节，并且比启动或者上次重写之后大了一倍时返回 true。没有开启 AOF 时总是 false
+    pub fn aof_should_rewrite(&self, min_size: u64) -> bool {
+        self.aof
+            .as_ref()
+            .is_some_and(|aof| aof.should_rewrite(min_size))
+    }
+    /// 持有读锁执行普通的操作
+    fn shared<T>(&self, f: impl FnOnce(Unlocked<'_>) -> T) -> T {
+        let _guard = self.lock.read().unwrap_or_else(PoisonError::into_inner);
+        f(Unlocked(self))
+    }
+    /// 持有读锁执行修改操作。开启 AOF 时还要持有 AOF 的锁，保证日志的顺序和修改的顺序一致，
+    /// 修改成功后把 key 最新的状态写入日志。
+    /// 代价是开启 AOF 之后，所有的修改（包括不同 table 上的）都在 AOF 的锁上串行执行，
+    /// 每次都要等日志写入文件，FsyncPolicy::Always 时还要等 fsync
+    fn mutate<T>(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl FnOnce(Unlocked<'_>) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        self.shared(|s| {
+            let aof = match &self.aof {
+                Some(aof) => aof,
+                None => return f(s),
+            };
+            let mut writer = aof.lock();
+            let result = f(s)?;
+            writer.append(&s.state_record(table, key))?;
+            Ok(result)
+        })
+    }
+    /// 持有写锁执行对整个 table 的修改，其它操作不会看到中间状态。
+    /// 开启 AOF 时，修改成功后把命令本身写入日志
+    fn mutate_tables<T>(
+        &self,
+        record: CommandRequest,
+        f: impl FnOnce(Unlocked<'_>) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        let _guard = self.lock.write().unwrap_or_else(PoisonError::into_inner);
+        let result = f(Unlocked(self))?;
+        if let Some(aof) = &self.aof {
+            aof.lock().append(&record)?;
+        }
+        Ok(result)
+    }
+    /// 把事务修改过的所有 key 的状态作为一条日志写入 AOF，重放时要么全部生效，要么全部不生效
+    fn log_transaction(&self, tx: &MemTableTx<'_>) -> Result<(), KvError> {
+        let aof = match &self.aof {
+            Some(aof) => aof,
+            None => return Ok(()),
+        };
+        let records: Vec<_> = tx
+            .undo
+            .borrow()
+            .keys()
+            .map(|(table, key)| tx.inner.state_record(table, key))
+            .collect();
+        if records.is_empty() {
+            return Ok(());
+        }
+        let record = CommandRequest::new_transaction(records, vec![]);
+        aof.lock().append(&record)
+    }
+}
+
+impl<'a> Unlocked<'a> {
+    /// 为一次修改分配新的版本
+    fn next_version(self) -> u64 {
+        VERSION.fetch_add(1, Ordering::Relaxed) + 1
+    }
+    /// 删除了 key 之后调用，给不存在的 key 分配新的版本
+    fn mark_deleted(self) {
+        self.0
+            .deleted
+            .fetch_max(self.next_version(), Ordering::Relaxed);
+    }
+    /// 读操作使用，table 不存在时不创建
+    fn table(self, name: &str) -> Option<Ref<'a, String, Table>> {
+        self.0.tables.get(name)
+    }
     /// 如果名为 name 的 hash table 不存在，则创建，否则返回
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
-        match self.tables.get(name) {
+    fn get_or_create_table(self, name: &str) -> Ref<'a, String, Table> {
+        match self.0.tables.get(name) {
             Some(table) => table,
             None => {
-                let entry = self.tables.entry(name.into()).or_default();
+                let entry = self.0.tables.entry(name.into()).or_default();
                 entry.downgrade()
             }
         }
     }
+    /// 写入一个 entry，如果旧的 entry 已经过期，返回 None
+    fn insert(&self, table: &str, key: String, entry: Entry) -> Option<Value> {
+        let table = self.get_or_create_table(table);
+        let now = now_ms();
+        let old = table.write().insert(key, entry);
+        old.and_then(|old| old.into_live_value(now))
+    }
+    /// 在 table 的写锁内读出旧值、计算并写入新值，保证 read-modify-write 是原子的。
+    /// 已过期的 key 当作不存在，没过期的 key 保留它的过期时间
+    fn update<T>(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl FnOnce(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError> {
+        let table = self.get_or_create_table(table);
+        let now = now_ms();
+        let mut map = table.write();
+        let live = map.get(key).filter(|v| !v.is_expired(now));
+        let (value, result) = f(live.map(|v| &v.value))?;
+        let expire_at = live.and_then(|v| v.expire_at);
+        map.insert(
+            key.into(),
+            Entry::new(value, expire_at, self.next_version()),
+        );
+        Ok(result)
+    }
+    /// key 当前状态对应的 AOF 日志：存在时是 HSET（有过期时间再加上 HEXPIREAT），不存在时是 HDEL。
+    /// 日志和修改它的操作无关，重放时不依赖当时的时间，重复执行也没有关系
+    fn state_record(self, table: &str, key: &str) -> CommandRequest {
+        let entry = self
+            .0
+            .tables
+            .get(table)
+            .and_then(|t| t.read().get(key).cloned());
+        entry_record(table, key, entry.as_ref())
+    }
+    /// 一个 table 里所有 key 的 AOF 日志
+    fn table_records(self, name: &str) -> Vec<CommandRequest> {
+        let now = now_ms();
+        match self.0.tables.get(name) {
+            Some(table) => table
+                .read()
+                .iter()
+                .filter(|(_, v)| !v.is_expired(now))
+                .map(|(k, v)| entry_record(name, k, Some(v)))
+                .collect(),
+            None => vec![],
+        }
+    }
+    /// 重放一条 AOF 日志。直接修改数据，不检查过期时间
+    fn apply_record(self, record: CommandRequest) -> Result<(), KvError> {
+        match record.request_data {
+            Some(RequestData::Hset(Hset {
+                table,
+                pair: Some(pair),
+                ..
+            })) => {
+                let value = pair.value.unwrap_or_default();
+                self.get_or_create_table(&table)
+                    .write()
+                    .insert(pair.key, Entry::new(value, None, self.next_version()));
+            }
+            Some(RequestData::Hdel(Hdel { table, key })) => {
+                if let Some(table) = self.table(&table) {
+                    table.write().remove(&key);
+                }
+                self.mark_deleted();
+            }
+            Some(RequestData::Hexpireat(Hexpireat {
+                table,
+                key,
+                timestamp,
+            })) => {
+                if let Some(table) = self.table(&table) {
+                    if let Some(entry) = table.write().get_mut(&key) {
+                        entry.expire_at = Some(timestamp);
+                        entry.version = self.next_version();
+                    }
+                }
+            }
+            Some(RequestData::DropTable(DropTable { table })) => {
+                self.drop_table(&table)?;
+            }
+            Some(RequestData::RenameTable(RenameTable { from, to })) => {
+                self.rename_table(&from, &to)?;
+            }
+            Some(RequestData::Transaction(tx)) => {
+                for record in tx.commands {
+                    self.apply_record(record)?;
+                }
+            }
+            data => {
+                let msg = format!("Unexpected AOF record: {:?}", data);
+                return Err(KvError::Internal(msg));
+            }
+        }
+        Ok(())
+    }
+    /// 读取 key 对应的 entry；如果已经过期，顺便把它删除（lazy expiry）
+    fn get_live_entry(&self, table: &str, key: &str) -> Option<Entry> {
+        let table = self.table(table)?;
+        let now = now_ms();
+        let entry = table.read().get(key).cloned()?;
+        if entry.is_expired(now) {
+            // 拿到写锁之前，这个 key 可能已经被重新设置了
+            let mut map = table.write();
+            if map.get(key).is_some_and(|v| v.is_expired(now)) {
+                map.remove(key);
+                self.mark_deleted();
+            }
+            return None;
+        }
+        Some(entry)
+    }
 }
-impl Storage for MemTable {
+
+fn entry_record(table: &str, key: &str, entry: Option<&Entry>) -> CommandRequest {
+    match entry.filter(|v| !v.is_expired(now_ms())) {
+        Some(Entry {
+            value,
+            expire_at: None,
+            ..
+        }) => CommandRequest::new_hset(table, key, value.clone()),
+        Some(Entry {
+            value,
+            expire_at: Some(t),
+            ..
+        }) => CommandRequest::new_transaction(
+            vec![
+                CommandRequest::new_hset(table, key, value.clone()),
+                CommandRequest::new_hexpireat(table, key, *t),
+            ],
+            vec![],
+        ),
+        None => CommandRequest::new_hdel(table, key),
+    }
+}
+
+impl Storage for Unlocked<'_> {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))
+        Ok(self.get_live_entry(table, key).map(|v| v.value))
     }
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key, value))
+        Ok(self.insert(table, key, Entry::new(value, None, self.next_version())))
     }
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.contains_key(key))
+        Ok(self.get_live_entry(table, key).is_some())
     }
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.remove(key).map(|(_, v)| v))
+        let table = match self.table(table) {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        let now = now_ms();
+        let old = table.write().remove(key);
+        if old.is_some() {
+            self.mark_deleted();
+        }
+        Ok(old.and_then(|v| v.into_live_value(now)))
     }
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table
+        let table = match self.table(table) {
+            Some(table) => table,
+            None => return Ok(vec![]),
+        };
+        let now = now_ms();
+        let pairs = table
+            .read()
+            .iter()
+            .filter(|(_, v)| !v.is_expired(now))
+            .map(|(k, v)| Kvpair::new(k, v.value.clone()))
+            .collect();
+        Ok(pairs)
+    }
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let now = now_ms();
+        Ok(self
+            .0
+            .tables
             .iter()
-            .map(|v| Kvpair::new(v.key(), v.value().clone()))
+            .filter(|t| t.read().values().any(|v| !v.is_expired(now)))
+            .map(|t| t.key().clone())
             .collect())
     }
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let table = self.get_or_create_table(table).clone();
-        let it = StorageIter::new(table.into_iter());
-        Ok(Box::new(it))
+    fn count(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.table(table).map_or(0, |t| t.live_count(now_ms())))
+    }
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let removed = match self.0.tables.remove(table) {
+            Some((_, table)) => table,
+            None => return Ok(0),
+        };
+        self.mark_deleted();
+        Ok(removed.live_count(now_ms()))
+    }
+    fn rename_table(&self, from: &str, to: &str) -> Result<bool, KvError> {
+        let now = now_ms();
+        if from == to {
+            return Ok(self.count(from)? > 0);
+        }
+        // 没有 key 的 table 当作不存在，不会覆盖 to
+        let table = match self.0.tables.remove_if(from, |_, t| t.live_count(now) > 0) {
+            Some((_, table)) => table,
+            None => return Ok(false),
+        };
+        // 移过去的 key 分配新的版本，来回改名之后也不会回到原来的版本
+        for entry in table.write().values_mut() {
+            entry.version = self.next_version();
+        }
+        self.0.tables.insert(to.into(), table);
+        self.mark_deleted();
+        Ok(true)
+    }
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        // 返回的 Iterator 不能借用 MemTable，所以要先复制整个 table
+        let table = match self.table(table) {
+            Some(table) => table.read().clone(),
+            None => Default::default(),
+        };
+        let now = now_ms();
+        let iter = table
+            .into_iter()
+            .filter_map(move |(k, v)| v.into_live_value(now).map(|v| (k, v)));
+        Ok(Box::new(StorageIter::new(iter)))
+    }
+    fn get_range(
+        &self,
+        table: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let table = match self.table(table) {
+            Some(table) if !is_empty_range(start, end) => table,
+            _ => return Ok(vec![]),
+        };
+        let now = now_ms();
+        let map = table.read();
+        let iter = map
+            .range::<str, _>((start, end))
+            .filter(|(_, v)| !v.is_expired(now));
+        let to_pair = |(k, v): (&String, &Entry)| Kvpair::new(k, v.value.clone());
+        let pairs = match reverse {
+            true => iter.rev().take(limit).map(to_pair).collect(),
+            false => iter.take(limit).map(to_pair).collect(),
+        };
+        Ok(pairs)
+    }
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let entry = Entry::new(value, Some(expire_at(ttl)), self.next_version());
+        Ok(self.insert(table, key, entry))
+    }
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let table = match self.table(table) {
+            Some(table) => table,
+            None => return Ok(false),
+        };
+        let now = now_ms();
+        // 持有 table 的写锁，检查和修改是原子的
+        let updated = match table.write().get_mut(key) {
+            Some(v) if !v.is_expired(now) => {
+                v.expire_at = Some(expire_at(ttl));
+                v.version = self.next_version();
+                true
+            }
+            _ => false,
+        };
+        Ok(updated)
+    }
+    fn ttl(&self, table: &str, key: &str) -> Result<Ttl, KvError> {
+        let now = now_ms();
+        Ok(match self.get_live_entry(table, key) {
+            None => Ttl::Missing,
+            Some(Entry {
+                expire_at: None, ..
+            }) => Ttl::Persistent,
+            Some(Entry {
+                expire_at: Some(t), ..
+            }) => Ttl::Expires(Duration::from_millis(t.saturating_sub(now))),
+        })
+    }
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let table = match self.table(table) {
+            Some(table) => table,
+            None => return Ok(false),
+        };
+        let now = now_ms();
+        let persisted = match table.write().get_mut(key) {
+            Some(v) if !v.is_expired(now) && v.expire_at.is_some() => {
+                v.expire_at = None;
+                v.version = self.next_version();
+                true
+            }
+            _ => false,
+        };
+        Ok(persisted)
+    }
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let now = now_ms();
+        let mut count = 0;
+        for table in self.0.tables.iter() {
+            table.write().retain(|_, v| {
+                let expired = v.is_expired(now);
+                count += expired as usize;
+                !expired
+            });
+        }
+        if count > 0 {
+            self.mark_deleted();
+        }
+        Ok(count)
+    }
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.update(table, key, |v| {
+            let n = incr_value(v, delta)?;
+            Ok((Value::integer(n), n))
+        })
+    }
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.update(table, key, |v| {
+            let n = incr_float_value(v, delta)?;
+            Ok((n.into(), n))
+        })
+    }
+    fn version(&self, table: &str, key: &str) -> Result<i64, KvError> {
+        // 不存在（包括已经过期）的 key 的版本是最近一次删除的版本
+        let version = match self.get_live_entry(table, key) {
+            Some(entry) => entry.version,
+            None => self.0.deleted.load(Ordering::Relaxed),
+        };
+        Ok(version as i64)
+    }
+    fn transaction(
+        &self,
+        _watches: &[WatchedKey],
+        _cmds: Vec<CommandRequest>,
+    ) -> Result<Vec<CommandResponse>, KvError> {
+        Err(not_supported("Nested transaction"))
+    }
+}
+
+impl Storage for MemTable {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.shared(|s| s.get(table, key))
+    }
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.mutate(table, &key.clone(), |s| s.set(table, key, value))
+    }
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.shared(|s| s.contains(table, key))
+    }
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.mutate(table, key, |s| s.del(table, key))
+    }
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.shared(|s| s.get_all(table))
+    }
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.shared(|s| s.list_tables())
+    }
+    fn count(&self, table: &str) -> Result<usize, KvError> {
+        self.shared(|s| s.count(table))
+    }
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let record = CommandRequest::new_drop_table(table);
+        self.mutate_tables(record, |s| s.drop_table(table))
+    }
+    fn rename_table(&self, from: &str, to: &str) -> Result<bool, KvError> {
+        let record = CommandRequest::new_rename_table(from, to);
+        self.mutate_tables(record, |s| s.rename_table(from, to))
+    }
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.shared(|s| s.get_iter(table))
+    }
+    fn get_range(
+        &self,
+        table: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.shared(|s| s.get_range(table, start, end, reverse, limit))
+    }
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.mutate(table, &key.clone(), |s| {
+            s.set_with_ttl(table, key, value, ttl)
+        })
+    }
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.mutate(table, key, |s| s.expire(table, key, ttl))
+    }
+    fn ttl(&self, table: &str, key: &str) -> Result<Ttl, KvError> {
+        self.shared(|s| s.ttl(table, key))
+    }
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.mutate(table, key, |s| s.persist(table, key))
+    }
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.shared(|s| s.purge_expired())
+    }
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.mutate(table, key, |s| s.incr(table, key, delta))
+    }
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.mutate(table, key, |s| s.incr_float(table, key, delta))
+    }
+    fn version(&self, table: &str, key: &str) -> Result<i64, KvError> {
+        self.shared(|s| s.version(table, key))
+    }
+    fn transaction(
+        &self,
+        watches: &[WatchedKey],
+        cmds: Vec<CommandRequest>,
+    ) -> Result<Vec<CommandResponse>, KvError> {
+        // 事务执行期间，其它的操作都要等待
+        let _guard = self.lock.write().unwrap_or_else(PoisonError::into_inner);
+        let tx = MemTableTx::new(Unlocked(self));
+        let result = transaction::run(&tx, &tx.errors, watches, cmds).and_then(|responses| {
+            self.log_transaction(&tx)?;
+            Ok(responses)
+        });
+        if result.is_err() {
+            tx.rollback();
+        }
+        result
+    }
+    /// 持有写锁复制所有的数据，快照是同一个时间点的。写快照时不持有锁，代价是要多占用一份内存
+    fn snapshot_to(&self, writer: &mut dyn Write) -> Result<u64, KvError> {
+        let copy = {
+            let _guard = self.lock.write().unwrap_or_else(PoisonError::into_inner);
+            self.clone()
+        };
+        snapshot(&copy, writer)
+    }
+    /// 先把快照恢复到一个新的 MemTable 里，再持有写锁整体换进来，
+    /// 其它操作要么看到原来的数据，要么看到快照的数据
+    fn reset_from(&self, data: &[u8]) -> Result<u64, KvError> {
+        let fresh = MemTable::new();
+        let count = restore(&fresh, data)?;
+        let _guard = self.lock.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(aof) = &self.aof {
+            let mut writer = aof.lock();
+            for table in self.tables.iter() {
+                writer.append(&CommandRequest::new_drop_table(table.key()))?;
+            }
+            for table in fresh.tables.iter() {
+                for record in Unlocked(&fresh).table_records(table.key()) {
+                    writer.append(&record)?;
+                }
+            }
+        }
+        self.tables.clear();
+        Unlocked(self).mark_deleted();
+        for (name, table) in fresh.tables {
+            self.tables.insert(name, table);
+        }
+        Ok(count)
+    }
+}
+
+impl<'a> MemTableTx<'a> {
+    fn new(inner: Unlocked<'a>) -> Self {
+        Self {
+            inner,
+            undo: Default::default(),
+            errors: Default::default(),
+        }
+    }
+
+    /// 第一次修改一个 key 之前，记下它原来的 entry（包括已经过期的）
+    fn save(&self, table: &str, key: &str) {
+        let mut undo = self.undo.borrow_mut();
+        undo.entry((table.into(), key.into()))
+            .or_insert_with(|| self.inner.table(table)?.read().get(key).cloned());
+    }
+
+    /// 把修改过的 key 恢复成事务开始前的样子
+    fn rollback(self) {
+        for ((table, key), entry) in self.undo.into_inner() {
+            match entry {
+                Some(entry) => {
+                    let table = self.inner.get_or_create_table(&table);
+                    table.write().insert(key, entry);
+                }
+                None => {
+                    if let Some(table) = self.inner.table(&table) {
+                        table.write().remove(&key);
+                    }
+                    self.inner.mark_deleted();
+                }
+            }
+        }
+    }
+}
+
+impl Storage for MemTableTx<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.errors.record(self.inner.get(table, key))
+    }
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.save(table, &key);
+        self.errors.record(self.inner.set(table, key, value))
+    }
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.errors.record(self.inner.contains(table, key))
+    }
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.save(table, key);
+        self.errors.record(self.inner.del(table, key))
+    }
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.errors.record(self.inner.get_all(table))
+    }
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.errors.record(self.inner.list_tables())
+    }
+    fn count(&self, table: &str) -> Result<usize, KvError> {
+        self.errors.record(self.inner.count(table))
+    }
+    fn drop_table(&self, _table: &str) -> Result<usize, KvError> {
+        self.errors.record(Err(not_supported("Dropping a table")))
+    }
+    fn rename_table(&self, _from: &str, _to: &str) -> Result<bool, KvError> {
+        self.errors.record(Err(not_supported("Renaming a table")))
+    }
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.errors.record(self.inner.get_iter(table))
+    }
+    fn get_range(
+        &self,
+        table: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.errors
+            .record(self.inner.get_range(table, start, end, reverse, limit))
+    }
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.save(table, &key);
+        self.errors
+            .record(self.inner.set_with_ttl(table, key, value, ttl))
+    }
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.save(table, key);
+        self.errors.record(self.inner.expire(table, key, ttl))
+    }
+    fn ttl(&self, table: &str, key: &str) -> Result<Ttl, KvError> {
+        self.errors.record(self.inner.ttl(table, key))
+    }
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.save(table, key);
+        self.errors.record(self.inner.persist(table, key))
+    }
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.errors
+            .record(Err(not_supported("Purging expired keys")))
+    }
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.save(table, key);
+        self.errors.record(self.inner.incr(table, key, delta))
+    }
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.save(table, key);
+        self.errors.record(self.inner.incr_float(table, key, delta))
+    }
+    fn version(&self, table: &str, key: &str) -> Result<i64, KvError> {
+        self.errors.record(self.inner.version(table, key))
+    }
+    fn transaction(
+        &self,
+        _watches: &[WatchedKey],
+        _cmds: Vec<CommandRequest>,
+    ) -> Result<Vec<CommandResponse>, KvError> {
+        self.errors.record(Err(not_supported("Nested transaction")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_should_not_create_table() {
+        let store = MemTable::new();
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert!(!store.contains("t1", "k1").unwrap());
+        assert!(store.get_all("t1").unwrap().is_empty());
+        assert_eq!(store.count("t1").unwrap(), 0);
+        assert_eq!(store.del("t1", "k1").unwrap(), None);
+        assert!(!store.expire("t1", "k1", Duration::from_secs(1)).unwrap());
+        assert_eq!(store.ttl("t1", "k1").unwrap(), Ttl::Missing);
+        assert!(store.tables.is_empty());
+
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.tables.len(), 1);
     }
 }
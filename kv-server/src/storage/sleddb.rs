@@ -1,26 +1,429 @@
-use crate::storage::StorageIter;
-use crate::{KvError, Kvpair, Storage, Value};
-use sled::{Db, IVec};
+use crate::storage::snapshot::{decode, restore_entries};
+use crate::storage::transaction::{self, not_supported, TxError};
+use crate::storage::{
+    expire_at, incr_float_value, incr_value, is_empty_range, now_ms, StorageIter,
+};
+use crate::{CommandRequest, CommandResponse, KvError, Kvpair, Storage, Ttl, Value, WatchedKey};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree as TxTree, UnabortableTransactionError,
+};
+use sled::{Db, IVec, Transactional, Tree};
+use std::ops::Bound;
 use std::path::Path;
 use std::str;
+use std::time::Duration;
+
+/// 存放过期时间的 tree，key 和主 tree 中的 key 相同，value 是大端序的过期时间点（毫秒）
+const EXPIRY_TREE: &str = "__expiry__";
+/// 存放版本的 tree，key 和主 tree 中的 key 相同，value 是大端序的版本，
+/// 和数据在同一个事务里修改。版本由 sled 的 generate_id 分配，重启之后也不会重复。
+/// 删除 key 时也分配新的版本并保留下来，删除之后再创建的 key 不会回到 WATCH 时看到的版本
+const VERSION_TREE: &str = "__version__";
 
 #[derive(Debug)]
-pub struct SledDb(Db);
+pub struct SledDb {
+    db: Db,
+    expiry: Tree,
+    versions: Tree,
+}
 
 impl SledDb {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        let db = sled::open(path).unwrap();
+        let expiry = db.open_tree(EXPIRY_TREE).unwrap();
+        let versions = db.open_tree(VERSION_TREE).unwrap();
+        Self {
+            db,
+            expiry,
+            versions,
+        }
     }
 
     // 在 sleddb 里，因为它可以 scan_prefix，我们用 prefix
     // 来模拟一个 table。当然，还可以用其它方案。
-    fn get_full_key(table: &str, key: &str) -> String {
-        format!("{}:{}", table, key)
+    fn get_full_key(table: &str, key: &str) -> Result<String, KvError> {
+        Ok(format!("{}{}", SledDb::get_table_prefix(table)?, key))
     }
 
     // 遍历 table 的 key 时，我们直接把 prefix: 当成 table
-    fn get_table_prefix(table: &str) -> String {
-        format!("{}:", table)
+    fn get_table_prefix(table: &str) -> Result<String, KvError> {
+        // table 名里有 ':' 的话，会和另一个 table 的前缀混在一起，比如 a 和 a:b
+        if table.contains(':') {
+            return Err(KvError::InvalidCommand(format!(
+                "Table name {:?} cannot contain ':' in sled",
+                table
+            )));
+        }
+        Ok(format!("{}:", table))
+    }
+
+    /// table 中所有的 key（完整的 key，包括 table 的前缀）
+    fn table_keys(&self, table: &str) -> Result<Vec<IVec>, KvError> {
+        let prefix = SledDb::get_table_prefix(table)?;
+        let keys = self.db.scan_prefix(prefix).keys();
+        Ok(keys.collect::<Result<_, _>>()?)
+    }
+
+    /// prefix 开头的 key 里有没有没过期的
+    fn has_live_key(&self, prefix: &[u8], now: u64) -> Result<bool, KvError> {
+        for k in self.db.scan_prefix(prefix).keys() {
+            match self.expiry.get(k?)? {
+                Some(t) if decode_deadline(&t) <= now => continue,
+                _ => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+
+    /// 在一个事务里把 src 中没过期的数据移到 to_prefix 下，并删掉 src 和 dst 中所有的 key。
+    /// first 为 true 时，src 中没有没过期的数据就当作 from 不存在，什么都不做，返回 false
+    fn move_keys(
+        &self,
+        src: &[IVec],
+        dst: &[IVec],
+        (from_prefix, to_prefix): (&str, &str),
+        first: bool,
+        now: u64,
+    ) -> Result<bool, KvError> {
+        self.transact(|db, expiry, versions| {
+            let mut moved = vec![];
+            for k in src {
+                let deadline = expiry.get(k)?;
+                if let Some(v) = live_value(db.get(k)?, deadline.clone(), now) {
+                    moved.push((&k[from_prefix.len()..], v, deadline));
+                }
+            }
+            if moved.is_empty() && first {
+                return Ok(false);
+            }
+            for k in dst.iter().chain(src) {
+                db.remove(k)?;
+                expiry.remove(k)?;
+                bump_version(versions, k)?;
+            }
+            for (key, v, deadline) in moved {
+                let name = [to_prefix.as_bytes(), key].concat();
+                db.insert(name.as_slice(), v)?;
+                bump_version(versions, &name)?;
+                if let Some(t) = deadline {
+                    expiry.insert(name, t)?;
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    /// 同时操作数据、过期时间和版本的事务
+    fn transact<T>(
+        &self,
+        f: impl Fn(&TxTree, &TxTree, &TxTree) -> ConflictableTransactionResult<T, KvError>,
+    ) -> Result<T, KvError> {
+        let trees = (&*self.db, &self.expiry, &self.versions);
+        Ok(trees.transaction(|(db, expiry, versions)| f(db, expiry, versions))?)
+    }
+
+    /// 如果 key 已经过期，就把它删除（lazy expiry），并返回 true
+    fn remove_if_expired(&self, name: &str, now: u64) -> Result<bool, KvError> {
+        match self.expiry.get(name)? {
+            Some(t) if decode_deadline(&t) <= now => {
+                // 在事务中再检查一次，避免删掉刚刚被重新设置的 key
+                self.transact(|db, expiry, versions| match expiry.get(name)? {
+                    Some(t) if decode_deadline(&t) <= now => {
+                        expiry.remove(name)?;
+                        db.remove(name)?;
+                        bump_version(versions, name.as_bytes())?;
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                })
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// 写入一个 key，同时更新它的过期时间。旧的 value 如果已经过期，返回 None
+    fn insert(
+        &self,
+        table: &str,
+        key: &str,
+        value: Value,
+        deadline: Option<u64>,
+    ) -> Result<Option<Value>, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        let data: Vec<u8> = value.try_into()?;
+        let now = now_ms();
+        let old = self.transact(|db, expiry, versions| {
+            let old = db.insert(name.as_bytes(), data.as_slice())?;
+            let old_deadline = match deadline {
+                Some(t) => expiry.insert(name.as_bytes(), &t.to_be_bytes())?,
+                None => expiry.remove(name.as_bytes())?,
+            };
+            bump_version(versions, name.as_bytes())?;
+            Ok(live_value(old, old_deadline, now))
+        })?;
+        flip(old.map(|v| v.as_ref().try_into()))
+    }
+
+    /// 在同时包含数据和过期时间的事务中做 read-modify-write，冲突时由 sled 重试。
+    /// 只修改数据本身，所以没过期的 key 会保留它的过期时间，过期的 key 当作不存在
+    fn update<T>(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl Fn(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        let now = now_ms();
+        self.transact(|db, expiry, versions| {
+            let old = match expiry.get(name.as_bytes())? {
+                Some(t) if decode_deadline(&t) <= now => {
+                    expiry.remove(name.as_bytes())?;
+                    None
+                }
+                _ => db.get(name.as_bytes())?,
+            };
+            let current: Option<Value> = flip(old.map(|v| v.as_ref().try_into()))
+                .map_err(ConflictableTransactionError::Abort)?;
+            let (value, result) =
+                f(current.as_ref()).map_err(ConflictableTransactionError::Abort)?;
+            let data: Vec<u8> = value
+                .try_into()
+                .map_err(ConflictableTransactionError::Abort)?;
+            db.insert(name.as_bytes(), data)?;
+            bump_version(versions, name.as_bytes())?;
+            Ok(result)
+        })
+    }
+}
+
+/// 事务中对 SledDb 的访问，所有的读写都在同一个 sled 事务里
+struct SledTx<'a> {
+    db: &'a TxTree,
+    expiry: &'a TxTree,
+    versions: &'a TxTree,
+    now: u64,
+    errors: TxError<TxFailure>,
+}
+
+/// 事务中的错误：sled 的冲突要交给 sled 重试整个事务，其它错误直接中止事务
+enum TxFailure {
+    Sled(UnabortableTransactionError),
+    Kv(KvError),
+}
+
+impl From<UnabortableTransactionError> for TxFailure {
+    fn from(e: UnabortableTransactionError) -> Self {
+        Self::Sled(e)
+    }
+}
+
+impl From<KvError> for TxFailure {
+    fn from(e: KvError) -> Self {
+        Self::Kv(e)
+    }
+}
+
+impl From<TxFailure> for ConflictableTransactionError<KvError> {
+    fn from(e: TxFailure) -> Self {
+        match e {
+            TxFailure::Sled(e) => e.into(),
+            TxFailure::Kv(e) => ConflictableTransactionError::Abort(e),
+        }
+    }
+}
+
+impl<'a> SledTx<'a> {
+    fn new(db: &'a TxTree, expiry: &'a TxTree, versions: &'a TxTree) -> Self {
+        Self {
+            db,
+            expiry,
+            versions,
+            now: now_ms(),
+            errors: Default::default(),
+        }
+    }
+
+    /// 执行一个操作，出错时记下原始的错误
+    fn run<T>(&self, f: impl FnOnce() -> Result<T, TxFailure>) -> Result<T, KvError> {
+        self.errors.record(f())
+    }
+
+    /// 读出没过期的数据和它的过期时间，已经过期的 key 顺便删除
+    fn live(&self, name: &str) -> Result<Option<(IVec, Option<u64>)>, TxFailure> {
+        let deadline = self.expiry.get(name)?.map(|t| decode_deadline(&t));
+        if matches!(deadline, Some(t) if t <= self.now) {
+            self.expiry.remove(name)?;
+            self.db.remove(name)?;
+            bump_version(self.versions, name.as_bytes())?;
+            return Ok(None);
+        }
+        Ok(self.db.get(name)?.map(|v| (v, deadline)))
+    }
+
+    fn get_value(&self, name: &str) -> Result<Option<Value>, TxFailure> {
+        let value = self.live(name)?.map(|(v, _)| v.as_ref().try_into());
+        Ok(flip(value)?)
+    }
+
+    fn insert(
+        &self,
+        name: &str,
+        value: Value,
+        deadline: Option<u64>,
+    ) -> Result<Option<Value>, TxFailure> {
+        let old = self.get_value(name)?;
+        let data: Vec<u8> = value.try_into()?;
+        self.db.insert(name, data)?;
+        match deadline {
+            Some(t) => self.expiry.insert(name, &t.to_be_bytes())?,
+            None => self.expiry.remove(name)?,
+        };
+        bump_version(self.versions, name.as_bytes())?;
+        Ok(old)
+    }
+
+    /// 没过期的 key 保留它的过期时间
+    fn update<T>(
+        &self,
+        name: &str,
+        f: impl FnOnce(Option<&Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, TxFailure> {
+        let current = self.get_value(name)?;
+        let (value, result) = f(current.as_ref())?;
+        let data: Vec<u8> = value.try_into()?;
+        self.db.insert(name, data)?;
+        bump_version(self.versions, name.as_bytes())?;
+        Ok(result)
+    }
+}
+
+impl Storage for SledTx<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.run(|| self.get_value(&SledDb::get_full_key(table, key)?))
+    }
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.run(|| self.insert(&SledDb::get_full_key(table, &key)?, value, None))
+    }
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.run(|| Ok(self.live(&SledDb::get_full_key(table, key)?)?.is_some()))
+    }
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        self.run(|| {
+            let old = self.get_value(&name)?;
+            self.db.remove(name.as_str())?;
+            self.expiry.remove(name.as_str())?;
+            bump_version(self.versions, name.as_bytes())?;
+            Ok(old)
+        })
+    }
+    fn get_all(&self, _table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.run(|| Err(not_supported("Scanning a table").into()))
+    }
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.run(|| Err(not_supported("Listing tables").into()))
+    }
+    fn count(&self, _table: &str) -> Result<usize, KvError> {
+        self.run(|| Err(not_supported("Scanning a table").into()))
+    }
+    fn drop_table(&self, _table: &str) -> Result<usize, KvError> {
+        self.run(|| Err(not_supported("Dropping a table").into()))
+    }
+    fn rename_table(&self, _from: &str, _to: &str) -> Result<bool, KvError> {
+        self.run(|| Err(not_supported("Renaming a table").into()))
+    }
+    fn get_iter(&self, _table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.run(|| Err(not_supported("Scanning a table").into()))
+    }
+    fn get_range(
+        &self,
+        _table: &str,
+        _start: Bound<&str>,
+        _end: Bound<&str>,
+        _reverse: bool,
+        _limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.run(|| Err(not_supported("Scanning a table").into()))
+    }
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let name = SledDb::get_full_key(table, &key)?;
+        self.run(|| self.insert(&name, value, Some(expire_at(ttl))))
+    }
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        self.run(|| {
+            if self.live(&name)?.is_none() {
+                return Ok(false);
+            }
+            self.expiry
+                .insert(name.as_str(), &expire_at(ttl).to_be_bytes())?;
+            bump_version(self.versions, name.as_bytes())?;
+            Ok(true)
+        })
+    }
+    fn ttl(&self, table: &str, key: &str) -> Result<Ttl, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        self.run(|| {
+            Ok(match self.live(&name)? {
+                None => Ttl::Missing,
+                Some((_, None)) => Ttl::Persistent,
+                Some((_, Some(t))) => Ttl::Expires(Duration::from_millis(t - self.now)),
+            })
+        })
+    }
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        self.run(|| match self.live(&name)? {
+            Some((_, Some(_))) => {
+                self.expiry.remove(name.as_str())?;
+                bump_version(self.versions, name.as_bytes())?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        })
+    }
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.run(|| Err(not_supported("Purging expired keys").into()))
+    }
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        self.run(|| {
+            self.update(&name, |v| {
+                let n = incr_value(v, delta)?;
+                Ok((Value::integer(n), n))
+            })
+        })
+    }
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        self.run(|| {
+            self.update(&name, |v| {
+                let n = incr_float_value(v, delta)?;
+                Ok((n.into(), n))
+            })
+        })
+    }
+    fn version(&self, table: &str, key: &str) -> Result<i64, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        // 已经过期的 key 在 live 里被删除，版本也会更新
+        self.run(|| {
+            self.live(&name)?;
+            Ok(decode_version(self.versions.get(name.as_str())?))
+        })
+    }
+    fn transaction(
+        &self,
+        _watches: &[WatchedKey],
+        _cmds: Vec<CommandRequest>,
+    ) -> Result<Vec<CommandResponse>, KvError> {
+        self.run(|| Err(not_supported("Nested transaction").into()))
     }
 }
 
@@ -28,36 +431,310 @@ fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
     x.map_or(Ok(None), |v| v.map(Some))
 }
 
+/// 过期时间存成 8 字节大端序，数据不对时当作永不过期
+fn decode_deadline(v: &[u8]) -> u64 {
+    v.try_into().map(u64::from_be_bytes).unwrap_or(u64::MAX)
+}
+
+/// 从来没有写过的 key 当作版本 0
+fn decode_version(v: Option<IVec>) -> i64 {
+    v.and_then(|v| v.as_ref().try_into().ok())
+        .map_or(0, |v| u64::from_be_bytes(v) as i64)
+}
+
+/// 给 key 分配一个新的版本，id 从 0 开始，所以要加 1 和从来没有写过的 key 区分开
+fn bump_version(versions: &TxTree, name: &[u8]) -> Result<(), UnabortableTransactionError> {
+    let version = versions.generate_id()? + 1;
+    versions.insert(name, &version.to_be_bytes())?;
+    Ok(())
+}
+
+/// 旧的 value 如果已经过期，就当它不存在
+fn live_value(value: Option<IVec>, deadline: Option<IVec>, now: u64) -> Option<IVec> {
+    match deadline {
+        Some(t) if decode_deadline(&t) <= now => None,
+        _ => value,
+    }
+}
+
+impl From<TransactionError<KvError>> for KvError {
+    fn from(e: TransactionError<KvError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        }
+    }
+}
+
 impl Storage for SledDb {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, key);
-        let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
+        let name = SledDb::get_full_key(table, key)?;
+        if self.remove_if_expired(&name, now_ms())? {
+            return Ok(None);
+        }
+        let result = self.db.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
         flip(result)
     }
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
-        let data: Vec<u8> = value.try_into()?;
-        let result = self.0.insert(name, data)?.map(|v| v.as_ref().try_into());
-        flip(result)
+        self.insert(table, &key, value, None)
     }
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let name = SledDb::get_full_key(table, &key);
-        Ok(self.0.contains_key(name)?)
+        let name = SledDb::get_full_key(table, key)?;
+        if self.remove_if_expired(&name, now_ms())? {
+            return Ok(false);
+        }
+        Ok(self.db.contains_key(name)?)
     }
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
-        let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
-        flip(result)
+        let name = SledDb::get_full_key(table, key)?;
+        let now = now_ms();
+        let old = self.transact(|db, expiry, versions| {
+            let old = db.remove(name.as_bytes())?;
+            let old_deadline = expiry.remove(name.as_bytes())?;
+            bump_version(versions, name.as_bytes())?;
+            Ok(live_value(old, old_deadline, now))
+        })?;
+        flip(old.map(|v| v.as_ref().try_into()))
     }
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
-        Ok(result)
+        Ok(self.get_iter(table)?.collect())
+    }
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = vec![];
+        let mut start = vec![];
+        let now = now_ms();
+        // 找到一个 table 之后，直接跳到下一个 table 的第一个 key（';' 是 ':' 的下一个字符）。
+        // 只剩下过期的 key 的 table 当作不存在
+        while let Some(item) = self.db.range(start.as_slice()..).next() {
+            let (k, _) = item?;
+            match k.iter().position(|b| *b == b':') {
+                Some(i) => {
+                    if self.has_live_key(&k[..=i], now)? {
+                        tables.push(String::from_utf8_lossy(&k[..i]).into_owned());
+                    }
+                    start = [&k[..i], b";"].concat();
+                }
+                None => start = [k.as_ref(), &[0]].concat(),
+            }
+        }
+        Ok(tables)
+    }
+    fn count(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.get_iter(table)?.count())
+    }
+    fn drop_table(&self, table: &str) -> Result<usize, KvError> {
+        let now = now_ms();
+        let mut count = 0;
+        // 事务里不能扫描，事务之前扫描出来的 key 可能不全：删完之后再扫描一次，
+        // 直到 table 里没有 key 为止
+        loop {
+            let keys = self.table_keys(table)?;
+            if keys.is_empty() {
+                return Ok(count);
+            }
+            count += self.transact(|db, expiry, versions| {
+                let mut count = 0;
+                for k in &keys {
+                    let old = db.remove(k)?;
+                    let old_deadline = expiry.remove(k)?;
+                    bump_version(versions, k)?;
+                    count += live_value(old, old_deadline, now).is_some() as usize;
+                }
+                Ok(count)
+            })?;
+        }
+    }
+    fn rename_table(&self, from: &str, to: &str) -> Result<bool, KvError> {
+        if from == to {
+            return Ok(self.count(from)? > 0);
+        }
+        let (from_prefix, to_prefix) = (
+            SledDb::get_table_prefix(from)?,
+            SledDb::get_table_prefix(to)?,
+        );
+        let now = now_ms();
+        let mut dst = self.table_keys(to)?;
+        let mut renamed = false;
+        // 和 drop_table 一样，改名之后再扫描 from，把扫描之后才写入 from 的 key 也移过去。
+        // 之后的几轮只移动 from 中的 key，不再清空 to
+        loop {
+            let src = self.table_keys(from)?;
+            if src.is_empty() {
+                return Ok(renamed);
+            }
+            let prefixes = (from_prefix.as_str(), to_prefix.as_str());
+            let moved = self.move_keys(&src, &dst, prefixes, !renamed, now)?;
+            if !moved {
+                return Ok(false);
+            }
+            renamed = true;
+            dst.clear();
+        }
+    }
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        let prefix = SledDb::get_table_prefix(table)?;
+        let expiry = self.expiry.clone();
+        let now = now_ms();
+        // 过期但还没有被清理的 key，遍历时直接跳过
+        let iter = self.db.scan_prefix(prefix).filter(move |v| match v {
+            Ok((k, _)) => !matches!(expiry.get(k), Ok(Some(t)) if decode_deadline(&t) <= now),
+            Err(_) => true,
+        });
+        Ok(Box::new(StorageIter::new(iter)))
+    }
+    fn get_range(
+        &self,
+        table: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        if is_empty_range(start, end) {
+            return Ok(vec![]);
+        }
+        // 加上 table 的前缀之后，key 的顺序不变；不限制的一端就是 table 的开头或结尾
+        let start = match start {
+            Bound::Included(k) => Bound::Included(SledDb::get_full_key(table, k)?),
+            Bound::Excluded(k) => Bound::Excluded(SledDb::get_full_key(table, k)?),
+            Bound::Unbounded => Bound::Included(SledDb::get_table_prefix(table)?),
+        };
+        let end = match end {
+            Bound::Included(k) => Bound::Included(SledDb::get_full_key(table, k)?),
+            Bound::Excluded(k) => Bound::Excluded(SledDb::get_full_key(table, k)?),
+            // ';' 是 ':' 的下一个字符
+            Bound::Unbounded => Bound::Excluded(format!("{};", table)),
+        };
+        let iter = self.db.range::<String, _>((start, end));
+        let iter: Box<dyn Iterator<Item = _>> = match reverse {
+            true => Box::new(iter.rev()),
+            false => Box::new(iter),
+        };
+        let now = now_ms();
+        let mut pairs = vec![];
+        for item in iter {
+            if pairs.len() >= limit {
+                break;
+            }
+            let (k, v) = item?;
+            // 过期但还没有被清理的 key 直接跳过
+            if matches!(self.expiry.get(&k)?, Some(t) if decode_deadline(&t) <= now) {
+                continue;
+            }
+            pairs.push(Ok((k, v)).into());
+        }
+        Ok(pairs)
+    }
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.insert(table, &key, value, Some(expire_at(ttl)))
+    }
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        let now = now_ms();
+        if self.remove_if_expired(&name, now)? {
+            return Ok(false);
+        }
+        let deadline = expire_at(ttl);
+        self.transact(|db, expiry, versions| {
+            if db.get(name.as_bytes())?.is_none() {
+                return Ok(false);
+            }
+            expiry.insert(name.as_bytes(), &deadline.to_be_bytes())?;
+            bump_version(versions, name.as_bytes())?;
+            Ok(true)
+        })
+    }
+    fn ttl(&self, table: &str, key: &str) -> Result<Ttl, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        let now = now_ms();
+        if self.remove_if_expired(&name, now)? || !self.db.contains_key(name.as_bytes())? {
+            return Ok(Ttl::Missing);
+        }
+        Ok(match self.expiry.get(name.as_bytes())? {
+            Some(t) => Ttl::Expires(Duration::from_millis(
+                decode_deadline(&t).saturating_sub(now),
+            )),
+            None => Ttl::Persistent,
+        })
+    }
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        if self.remove_if_expired(&name, now_ms())? {
+            return Ok(false);
+        }
+        self.transact(|_, expiry, versions| {
+            if expiry.remove(name.as_bytes())?.is_none() {
+                return Ok(false);
+            }
+            bump_version(versions, name.as_bytes())?;
+            Ok(true)
+        })
+    }
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let now = now_ms();
+        let mut count = 0;
+        for item in self.expiry.iter() {
+            let (k, t) = item?;
+            if decode_deadline(&t) > now {
+                continue;
+            }
+            if let Ok(name) = str::from_utf8(&k) {
+                count += self.remove_if_expired(name, now)? as usize;
+            }
+        }
+        Ok(count)
+    }
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.update(table, key, |v| {
+            let n = incr_value(v, delta)?;
+            Ok((Value::integer(n), n))
+        })
+    }
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.update(table, key, |v| {
+            let n = incr_float_value(v, delta)?;
+            Ok((n.into(), n))
+        })
+    }
+    fn version(&self, table: &str, key: &str) -> Result<i64, KvError> {
+        let name = SledDb::get_full_key(table, key)?;
+        // 已经过期的 key 在这里被删除，版本也会更新
+        self.remove_if_expired(&name, now_ms())?;
+        Ok(decode_version(self.versions.get(name.as_bytes())?))
+    }
+    fn transaction(
+        &self,
+        watches: &[WatchedKey],
+        cmds: Vec<CommandRequest>,
+    ) -> Result<Vec<CommandResponse>, KvError> {
+        // sled 发现冲突时会重新执行整个闭包，所以每次都要从头执行所有的命令
+        self.transact(|db, expiry, versions| {
+            let tx = SledTx::new(db, expiry, versions);
+            let result = transaction::run(&tx, &tx.errors, watches, cmds.iter().cloned());
+            result.map_err(Into::into)
+        })
     }
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let iter = StorageIter::new(self.0.scan_prefix(prefix));
-        Ok(Box::new(iter))
+    /// 在一个事务里删除所有的 key 并写入快照的数据，其它操作要么看到原来的数据，要么看到快照的数据。
+    /// 代价是整个快照都在一个事务里，占用的内存和快照的大小成正比
+    fn reset_from(&self, data: &[u8]) -> Result<u64, KvError> {
+        let entries = decode(data)?;
+        let keys = self.db.iter().keys().collect::<Result<Vec<_>, _>>()?;
+        self.transact(|db, expiry, versions| {
+            for k in &keys {
+                db.remove(k)?;
+                expiry.remove(k)?;
+                bump_version(versions, k)?;
+            }
+            let tx = SledTx::new(db, expiry, versions);
+            let result = restore_entries(&tx, entries.iter().cloned());
+            result.map_err(|e| tx.errors.take().unwrap_or(TxFailure::Kv(e)).into())
+        })
     }
 }
 
@@ -72,9 +749,8 @@ impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
         }
     }
 }
+/// 去掉 table 的前缀，key 本身可以包含 ':'
 fn ivec_to_key(ivec: &[u8]) -> &str {
     let s = str::from_utf8(ivec).unwrap();
-    let mut iter = s.split(":");
-    iter.next();
-    iter.next().unwrap()
+    s.split_once(':').map_or(s, |(_, key)| key)
 }
@@ -0,0 +1,291 @@
+use crate::storage::{expire_at, now_ms};
+use crate::{KvError, SnapshotEntry, SnapshotHeader, Storage, Ttl};
+use crc32fast::Hasher;
+use prost::Message;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// 快照文件以它开头
+const MAGIC: &[u8] = b"KVSNAP";
+const VERSION: u32 = 1;
+
+// 快照文件的格式：
+// MAGIC | SnapshotHeader | SnapshotEntry ... | 0 | CRC32
+// header 和 entry 都是 length-delimited 编码的 protobuf，长度为 0 的记录表示 entry 结束，
+// 最后 4 个字节是前面所有数据的 CRC32（大端序）
+
+/// 把 store 中所有的数据写入 writer，返回写入的 kv pair 数量。
+/// 每个 table 单独遍历，快照期间的并发修改可能只有一部分出现在快照里
+pub fn snapshot(store: &impl Storage, writer: impl Write) -> Result<u64, KvError> {
+    let mut writer = ChecksumWriter::new(writer);
+    writer.write_all(MAGIC)?;
+    let header = SnapshotHeader {
+        version: VERSION,
+        created_at: now_ms(),
+    };
+    writer.write_all(&header.encode_length_delimited_to_vec())?;
+
+    let mut count = 0;
+    for table in store.list_tables()? {
+        for pair in store.get_iter(&table)? {
+            // 遍历之后才过期的 key 直接跳过
+            let expire_at = match store.ttl(&table, &pair.key)? {
+                Ttl::Missing => continue,
+                Ttl::Persistent => 0,
+                Ttl::Expires(d) => expire_at(d),
+            };
+            let entry = SnapshotEntry {
+                table: table.clone(),
+                pair: Some(pair),
+                expire_at,
+            };
+            writer.write_all(&entry.encode_length_delimited_to_vec())?;
+            count += 1;
+        }
+    }
+
+    writer.write_all(&[0])?;
+    writer.finish()?;
+    Ok(count)
+}
+
+/// 从 reader 中读取快照，把数据写入 store，返回恢复的 kv pair 数量。
+/// 快照会先被完整读入内存并校验，校验失败时不会修改 store；已经存在的同名 key 会被覆盖，
+/// 快照中已经过期的 key 会被跳过
+pub fn restore(store: &impl Storage, mut reader: impl Read) -> Result<u64, KvError> {
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+    restore_entries(store, decode(&data)?)
+}
+
+/// 把快照写到文件 path，write 负责写入快照的内容。先写到同一个目录中的临时文件，再改名为 path，
+/// 写到一半失败时不会破坏已有的快照。path 不能是符号链接
+pub(crate) fn write_file(
+    path: &Path,
+    write: impl FnOnce(&mut dyn Write) -> Result<u64, KvError>,
+) -> Result<u64, KvError> {
+    check_not_symlink(path)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.tmp", name));
+    // 上次失败时可能留下了临时文件；create_new 不会打开已经存在的文件或者符号链接
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)
+        .map_err(KvError::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            let count = write(&mut writer)?;
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            fs::rename(&tmp, path)?;
+            Ok(count)
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// 打开快照文件 path 用来恢复数据。path 不能是符号链接
+pub(crate) fn open_file(path: &Path) -> Result<BufReader<File>, KvError> {
+    check_not_symlink(path)?;
+    Ok(BufReader::new(File::open(path)?))
+}
+
+/// 快照命令只能读写快照目录里的文件，符号链接可能指向目录之外
+fn check_not_symlink(path: &Path) -> Result<(), KvError> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => Err(KvError::InvalidCommand(format!(
+            "Invalid snapshot file name {:?}",
+            path.file_name().unwrap_or_default()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// 校验快照并解出所有的 entry
+pub(crate) fn decode(data: &[u8]) -> Result<Vec<SnapshotEntry>, KvError> {
+    let body = verify(data)?;
+
+    let mut buf = &body[MAGIC.len()..];
+    let header = SnapshotHeader::decode_length_delimited(&mut buf)?;
+    if header.version != VERSION {
+        return Err(KvError::SnapshotError("unsupported version"));
+    }
+    let mut entries = vec![];
+    loop {
+        match buf.first() {
+            Some(0) => break,
+            Some(_) => entries.push(SnapshotEntry::decode_length_delimited(&mut buf)?),
+            None => return Err(KvError::SnapshotError("missing end marker")),
+        }
+    }
+    Ok(entries)
+}
+
+/// 把解出来的 entry 写入 store，已经过期的跳过
+pub(crate) fn restore_entries(
+    store: &impl Storage,
+    entries: impl IntoIterator<Item = SnapshotEntry>,
+) -> Result<u64, KvError> {
+    let mut count = 0;
+    let now = now_ms();
+    for entry in entries {
+        let pair = entry.pair.unwrap_or_default();
+        let value = pair.value.unwrap_or_default();
+        match entry.expire_at {
+            0 => store.set(&entry.table, pair.key, value)?,
+            t if t <= now => continue,
+            t => {
+                let ttl = Duration::from_millis(t - now);
+                store.set_with_ttl(&entry.table, pair.key, value, ttl)?
+            }
+        };
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// 先清空 store 再从快照恢复，让 store 的数据和快照完全一致。快照无效时不会修改 store
+pub(crate) fn reset_from(store: &impl Storage, data: &[u8]) -> Result<u64, KvError> {
+    let entries = decode(data)?;
+    for table in store.list_tables()? {
+        for pair in store.get_iter(&table)? {
+            store.del(&table, &pair.key)?;
+        }
+    }
+    restore_entries(store, entries)
+}
+
+/// 检查 magic 和 checksum，返回去掉 checksum 的数据
+fn verify(data: &[u8]) -> Result<&[u8], KvError> {
+    if data.len() < MAGIC.len() + 4 || !data.starts_with(MAGIC) {
+        return Err(KvError::SnapshotError("not a snapshot file"));
+    }
+    let (body, checksum) = data.split_at(data.len() - 4);
+    if crc32fast::hash(body).to_be_bytes() != checksum {
+        return Err(KvError::SnapshotError("checksum mismatch"));
+    }
+    Ok(body)
+}
+
+/// 写入数据的同时计算 CRC32，finish 时写入 checksum
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(),
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), KvError> {
+        self.hasher.update(data);
+        self.inner.write_all(data)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), KvError> {
+        let checksum = self.hasher.finalize();
+        self.inner.write_all(&checksum.to_be_bytes())?;
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Kvpair, MemTable, SledDb, Value};
+    use tempfile::tempdir;
+
+    #[test]
+    fn sleddb_snapshot_should_restore_to_memtable() {
+        let dir = tempdir().unwrap();
+        let src = SledDb::new(dir.path());
+        fill(&src);
+        let dst = MemTable::new();
+        test_snapshot_restore(&src, &dst);
+    }
+
+    #[test]
+    fn memtable_snapshot_should_restore_to_sleddb() {
+        let src = MemTable::new();
+        fill(&src);
+        let dir = tempdir().unwrap();
+        let dst = SledDb::new(dir.path());
+        test_snapshot_restore(&src, &dst);
+    }
+
+    #[test]
+    fn corrupted_snapshot_should_be_rejected() {
+        let src = MemTable::new();
+        fill(&src);
+        let mut data = vec![];
+        snapshot(&src, &mut data).unwrap();
+
+        // 修改任何一个字节都会被发现，store 保持不变
+        let dst = MemTable::new();
+        let mut corrupted = data.clone();
+        corrupted[MAGIC.len() + 5] ^= 0xff;
+        let result = restore(&dst, corrupted.as_slice());
+        assert!(matches!(result, Err(KvError::SnapshotError(_))));
+        assert!(dst.list_tables().unwrap().is_empty());
+
+        // 截断的快照也会被拒绝
+        let result = restore(&dst, &data[..data.len() - 1]);
+        assert!(matches!(result, Err(KvError::SnapshotError(_))));
+        let result = restore(&dst, &b"hello"[..]);
+        assert!(matches!(result, Err(KvError::SnapshotError(_))));
+    }
+
+    fn fill(store: &impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), Value::integer(2)).unwrap();
+        store.set("t2", "k1".into(), 1.5.into()).unwrap();
+        let ttl = Duration::from_secs(100);
+        store
+            .set_with_ttl("t2", "k2".into(), "v2".into(), ttl)
+            .unwrap();
+        // 已经过期的 key 不会出现在快照里
+        let ttl = Duration::from_millis(1);
+        store
+            .set_with_ttl("t3", "k1".into(), "v1".into(), ttl)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    fn test_snapshot_restore(src: &impl Storage, dst: &impl Storage) {
+        let mut data = vec![];
+        assert_eq!(snapshot(src, &mut data).unwrap(), 4);
+        assert_eq!(restore(dst, data.as_slice()).unwrap(), 4);
+
+        let mut tables = dst.list_tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, vec!["t1", "t2"]);
+        let mut pairs = dst.get_all("t1").unwrap();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            pairs,
+            vec![
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k2", Value::integer(2))
+            ]
+        );
+        assert_eq!(dst.get("t2", "k1").unwrap(), Some(1.5.into()));
+        assert_eq!(dst.get("t2", "k2").unwrap(), Some("v2".into()));
+        assert!(matches!(dst.ttl("t2", "k2").unwrap(), Ttl::Expires(_)));
+        assert_eq!(dst.ttl("t1", "k1").unwrap(), Ttl::Persistent);
+    }
+}
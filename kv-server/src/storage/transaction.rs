@@ -0,0 +1,70 @@
+use crate::{dispatch, CommandRequest, CommandResponse, KvError, Storage, WatchedKey};
+use std::cell::RefCell;
+
+/// 事务中第一个出错的存储操作。命令出错时 dispatch 只会返回一个 response，
+/// 所以需要在存储这一层记下原始的错误，用来中止整个事务
+pub(crate) struct TxError<E> {
+    error: RefCell<Option<E>>,
+}
+
+impl<E> Default for TxError<E> {
+    fn default() -> Self {
+        Self {
+            error: RefCell::new(None),
+        }
+    }
+}
+
+impl<E> TxError<E> {
+    /// 记下出错的结果，返回给调用者的错误只是一个占位，事务最终会返回原始的错误
+    pub fn record<T>(&self, result: Result<T, E>) -> Result<T, KvError> {
+        result.map_err(|e| {
+            self.error.borrow_mut().get_or_insert(e);
+            KvError::Internal("Transaction aborted".into())
+        })
+    }
+
+    pub fn take(&self) -> Option<E> {
+        self.error.borrow_mut().take()
+    }
+}
+
+/// 先检查 watched key 的版本，然后依次执行事务中的命令；
+/// 任何一个命令的存储操作出错，就停止执行并返回这个错误，由调用者回滚
+pub(crate) fn run<S, E>(
+    store: &S,
+    errors: &TxError<E>,
+    watches: &[WatchedKey],
+    cmds: impl IntoIterator<Item = CommandRequest>,
+) -> Result<Vec<CommandResponse>, E>
+where
+    S: Storage,
+    E: From<KvError>,
+{
+    if let Err(e) = check_watches(store, watches) {
+        return Err(errors.take().unwrap_or_else(|| e.into()));
+    }
+    let mut responses = vec![];
+    for cmd in cmds {
+        let res = dispatch(cmd, store);
+        if let Some(e) = errors.take() {
+            return Err(e);
+        }
+        responses.push(res);
+    }
+    Ok(responses)
+}
+
+fn check_watches(store: &impl Storage, watches: &[WatchedKey]) -> Result<(), KvError> {
+    for w in watches {
+        if store.version(&w.table, &w.key)? != w.version {
+            return Err(KvError::WatchConflict(w.table.clone(), w.key.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// 事务里不支持的操作
+pub(crate) fn not_supported(op: &str) -> KvError {
+    KvError::InvalidCommand(format!("{} is not supported in transaction", op))
+}